@@ -1,17 +1,19 @@
 use clap::Parser;
 use clap::Subcommand;
-use clap::command;
+use clap::ValueEnum;
 use console::Term;
+use lib::CompiledFsm;
 use lib::FSMCursor;
 use lib::FSMNodeWrapper;
 use lib::ToCSV;
-use lib::frontend::create_graph_from_ebnf;
+use lib::frontend::{CompileOptions, Ebnf, GrammarModules, frontend, frontend_for_file, frontends};
 use std::fs::File;
 use std::io::BufRead;
 use std::io::Write;
 use std::io::read_to_string;
 use std::io::stdin;
 use std::os::unix::net::UnixStream;
+use std::sync::Arc;
 
 use bufstream::BufStream;
 use lib::protocol::Request;
@@ -23,6 +25,16 @@ struct Args {
     command: NightfurySubcommand,
 }
 
+/// how a generated FSM is written
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+enum FsmFormat {
+    /// the tab separated text format, easy to read and diff
+    #[default]
+    Text,
+    /// the smaller binary format the server loads without parsing any text
+    Bin,
+}
+
 #[derive(Subcommand, Debug)]
 enum NightfurySubcommand {
     /// generates an FSM from a provided ebnf
@@ -31,6 +43,19 @@ enum NightfurySubcommand {
         path: Option<String>,
         #[arg(short, long)]
         out: Option<String>,
+        /// rule a cursor may start at, can be given multiple times; defaults to the first rule
+        #[arg(short, long)]
+        entry: Vec<String>,
+        /// dialect whose `@if` sections are kept; only the shared parts are used if omitted
+        #[arg(short, long)]
+        dialect: Option<String>,
+        /// syntax the grammar is written in, e.g. `w3c`; guessed from the file's suffix if
+        /// omitted, nightfury's own ebnf if that doesn't tell
+        #[arg(short, long)]
+        frontend: Option<String>,
+        /// format the FSM is written in
+        #[arg(long, value_enum, default_value_t)]
+        format: FsmFormat,
     },
     /// WIP: (debug) send requests to a server instance; NOTE: there is currently no way of retaining state
     /// between calls.
@@ -47,6 +72,10 @@ enum NightfurySubcommand {
         #[arg(short, long)]
         name: Option<String>,
 
+        /// entry rule to start at, uses the fsm's default entry if omitted
+        #[arg(short, long)]
+        entry: Option<String>,
+
         /// list capabilities
         #[arg(short, long)]
         list: bool,
@@ -61,6 +90,9 @@ enum NightfurySubcommand {
     },
     Chat {
         fsm_path: String,
+        /// entry rule to start at, uses the fsm's default entry if omitted
+        #[arg(short, long)]
+        entry: Option<String>,
     },
 }
 
@@ -73,38 +105,73 @@ fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
     match args.command {
-        NightfurySubcommand::Generate { path, out } => {
-            let ebnf = match path {
-                Some(path) => std::fs::read_to_string(path),
-                None => read_to_string(stdin()),
+        NightfurySubcommand::Generate {
+            path,
+            out,
+            entry,
+            dialect,
+            frontend: frontend_name,
+            format,
+        } => {
+            let frontend = match frontend_name {
+                Some(name) => match frontend(&name) {
+                    Some(frontend) => Some(frontend),
+                    None => {
+                        let known: Vec<&str> = frontends().iter().map(|f| f.name()).collect();
+                        eprintln!(
+                            "Unknown frontend '{name}', known ones are: {}",
+                            known.join(", ")
+                        );
+                        return Ok(());
+                    }
+                },
+                None => path
+                    .as_deref()
+                    .and_then(|path| frontend_for_file(path))
+                    .map(|(frontend, _)| frontend),
             };
-            match ebnf {
-                Ok(ebnf) => {
-                    let out = out.as_ref().map_or("./nightfury.fsm", |s| s);
-                    let root = create_graph_from_ebnf(&ebnf);
-                    match root {
-                        Ok(root) => {
-                            let out_file = File::create_new(out);
-                            match out_file {
-                                Ok(mut out_file) => {
-                                    out_file.write_all(root.to_csv().as_bytes())?;
-                                }
-                                Err(e) => eprintln!("{e}"),
-                            }
-                        }
-                        Err(err) => {
-                            eprintln!("Error creating ebnf:");
-                            eprintln!("{err}");
+            // imports are resolved relative to the grammar file, or the working directory for
+            // grammars read from stdin
+            let syntax = frontend.unwrap_or(&Ebnf);
+            let modules = match path {
+                Some(path) => GrammarModules::load_as(path, syntax),
+                None => match read_to_string(stdin()) {
+                    Ok(source) => GrammarModules::from_source_as("<stdin>", &source, ".", syntax),
+                    Err(e) => {
+                        eprintln!("Error reading ebnf: {e}");
+                        return Ok(());
+                    }
+                },
+            };
+            let out = out.as_ref().map_or("./nightfury.fsm", |s| s);
+            let entries: Vec<&str> = entry.iter().map(String::as_str).collect();
+            match modules.compile_with(&CompileOptions {
+                entries: &entries,
+                dialect: dialect.as_deref(),
+                frontend,
+            }) {
+                Ok(compiled) => {
+                    eprint!("{}", modules.report_warnings(&compiled.warnings));
+                    let out_file = File::create_new(out);
+                    match out_file {
+                        Ok(mut out_file) => {
+                            let fsm = match format {
+                                FsmFormat::Text => compiled.entries.to_csv().into_bytes(),
+                                FsmFormat::Bin => CompiledFsm::new(&compiled.entries).to_bytes(),
+                            };
+                            out_file.write_all(&fsm)?;
                         }
+                        Err(e) => eprintln!("{e}"),
                     }
                 }
-                Err(e) => eprintln!("Error reading ebnf: {e}"),
+                Err(errors) => eprint!("{}", modules.report(&errors)),
             }
         }
         NightfurySubcommand::Send {
             input,
             reset,
             name,
+            entry,
             list,
             sock_path,
         } => {
@@ -116,7 +183,7 @@ fn main() -> std::io::Result<()> {
             }
 
             if let Some(name) = name {
-                send_request(Request::Initialize(&name), &mut stream)?;
+                send_request(Request::Initialize(&name, entry.as_deref()), &mut stream)?;
                 stream.flush()?;
                 stream.read_until(0, &mut Vec::new())?;
                 if reset {
@@ -141,13 +208,16 @@ fn main() -> std::io::Result<()> {
                 Err(err) => eprintln!("{err}"),
             }
         }
-        NightfurySubcommand::Chat { fsm_path } => {
-            let fsm = FSMNodeWrapper::from_csv_file(&fsm_path);
+        NightfurySubcommand::Chat { fsm_path, entry } => {
+            // both formats written by generate work
+            let fsm = std::fs::read(&fsm_path).map(|bytes| CompiledFsm::load(&bytes));
             match fsm {
-                Ok(root) => {
-                    println!("FSM:");
-                    root.borrow().dbg();
-                    let mut cursor = FSMCursor::new(&root);
+                Ok(Ok(fsm)) => {
+                    let fsm = Arc::new(fsm);
+                    let Some(mut cursor) = FSMCursor::from_compiled(&fsm, entry.as_deref()) else {
+                        eprintln!("Unknown entry '{}'", entry.unwrap_or_default());
+                        return Ok(());
+                    };
 
                     let terminal = Term::stdout();
                     while !cursor.is_done() {
@@ -166,6 +236,7 @@ fn main() -> std::io::Result<()> {
                         std::io::stdout().flush()?;
                     }
                 }
+                Ok(Err(err)) => eprintln!("{err}"),
                 Err(err) => eprintln!("{err}"),
             }
         }
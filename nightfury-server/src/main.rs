@@ -1,19 +1,64 @@
-#![feature(if_let_guard)]
+use lib::frontend::{CompileOptions, GrammarFrontend, GrammarModules, frontend_for_file};
 use lib::protocol::{ReadRequest, WriteResponse};
-use lib::{AdvanceResult, FSMNodeWrapper, ToCSV, get_test_fsm};
+use lib::{AdvanceResult, CompiledFsm, EntryPoints, get_test_fsm};
 use std::collections::HashMap;
 use std::fs::{File, read_dir};
 use std::io::Write;
-use std::io::read_to_string;
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
 use std::process::exit;
 use std::sync::Arc;
-use std::sync::RwLock;
 use std::{env, thread};
 
 use bufstream::BufStream;
 use lib::FSMCursor;
 use lib::protocol::{Request, Response};
+use memmap2::Mmap;
+
+/// compiles a grammar from the fsm dir, once without any dialect as `name` and once for every
+/// dialect it has sections for as `name-dialect`
+fn compile_grammar(
+    name: &str,
+    path: &Path,
+    frontend: &dyn GrammarFrontend,
+) -> Vec<(String, EntryPoints)> {
+    let modules = GrammarModules::load_as(path, frontend);
+    let mut ret = Vec::new();
+    let dialects = modules.dialects();
+    let variants = std::iter::once(None).chain(dialects.iter().map(|d| Some(d.as_str())));
+    for dialect in variants {
+        let fsm_name = match dialect {
+            Some(dialect) => format!("{name}-{dialect}"),
+            None => name.to_string(),
+        };
+        match modules.compile_with(&CompileOptions {
+            dialect,
+            frontend: Some(frontend),
+            ..Default::default()
+        }) {
+            Ok(compiled) => {
+                eprint!("{}", modules.report_warnings(&compiled.warnings));
+                ret.push((fsm_name, compiled.entries));
+            }
+            Err(errors) => {
+                eprintln!("Error compiling fsm '{fsm_name}':");
+                eprint!("{}", modules.report(&errors));
+            }
+        }
+    }
+    ret
+}
+
+/// loads a generated FSM in either the text or the binary format, binary ones keep reading their
+/// node and edge tables from the mapped file
+fn load_fsm(path: &Path) -> Result<CompiledFsm, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    // SAFETY: the FSM reads from the mapping for as long as the server runs, so the files in the
+    // fsm dir mustn't be changed in place meanwhile. Restart the server after generating them
+    // again.
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(CompiledFsm::load_shared(Arc::new(map))?)
+}
 
 fn handle_request(
     req: Request,
@@ -31,6 +76,7 @@ fn handle_request(
         Request::Advance(str) => {
             str.chars().try_for_each(|c| match cursor.advancex(c) {
                 Some(AdvanceResult::Expanded(s)) => Response::Expanded(&s).write(stream),
+                Some(AdvanceResult::Snippet(s)) => Response::Snippet(&s).write(stream),
                 Some(AdvanceResult::ExpandedAfterUserdef(s)) => {
                     Response::RegexFull.write(stream)?;
                     Response::Expanded(&s).write(stream)
@@ -81,18 +127,19 @@ fn main() -> std::io::Result<()> {
     .unwrap();
     let listener = UnixListener::bind(&sock_addr)?;
     let mut handles = Vec::new();
-    let fsms = Arc::new(RwLock::new(HashMap::new()));
+    // compiled FSMs are shared between the connections as is, cursors don't lock anything
+    let mut fsms = HashMap::new();
 
-    fsms.write()
-        .unwrap()
-        .insert("c".to_string(), get_test_fsm());
+    fsms.insert(
+        "c".to_string(),
+        Arc::new(CompiledFsm::new(&EntryPoints::from(get_test_fsm()))),
+    );
 
     let fsm_dir = env::var("NIGHTFURY_FSMDIR").unwrap_or("./nightfury_fsms".to_string());
     if std::path::Path::new(&fsm_dir).is_dir() {
         for fsm in read_dir(fsm_dir)? {
             match fsm {
                 Ok(fsm) => {
-                    let mut fsms = fsms.write().unwrap();
                     let file_name = fsm.file_name();
                     #[cfg(debug_assertions)]
                     if file_name == ".gitkeep" {
@@ -105,19 +152,23 @@ fn main() -> std::io::Result<()> {
                     //     fsms.insert(file_name.to_string(), FSMNodeWrapper::from_csv(&csv));
                     // }
                     match file_name.to_str() {
-                        Some(fsm_name) => {
-                            let mut fsm_name = fsm_name.to_string();
-                            if fsm_name.ends_with(".fsm") {
-                                fsm_name = fsm_name[..fsm_name.len() - 4].to_string();
+                        Some(file_name) => {
+                            if let Some((frontend, stem)) = frontend_for_file(file_name) {
+                                for (fsm_name, fsm) in compile_grammar(stem, &fsm.path(), frontend)
+                                {
+                                    println!("Loaded fsm '{fsm_name}'");
+                                    fsms.insert(fsm_name, Arc::new(CompiledFsm::new(&fsm)));
+                                }
+                                continue;
+                            }
+                            let fsm_name = file_name.strip_suffix(".fsm").unwrap_or(file_name);
+                            match load_fsm(&fsm.path()) {
+                                Ok(compiled) => {
+                                    println!("Loaded fsm '{fsm_name}'");
+                                    fsms.insert(fsm_name.to_string(), Arc::new(compiled));
+                                }
+                                Err(err) => eprintln!("Error loading fsm '{fsm_name}': {err}"),
                             }
-                            println!("Loaded fsm '{fsm_name}'");
-                            fsms.insert(
-                                fsm_name,
-                                // TODO: cleanup
-                                FSMNodeWrapper::from_csv(
-                                    &read_to_string(File::open(fsm.path()).unwrap()).unwrap(),
-                                ),
-                            );
                         }
                         None => {
                             eprintln!("Filename isn't valid Unicode!");
@@ -132,6 +183,7 @@ fn main() -> std::io::Result<()> {
         }
     }
 
+    let fsms = Arc::new(fsms);
     // accept connections and process them, spawning a new thread for each one
     for stream in listener.incoming() {
         match stream {
@@ -148,28 +200,37 @@ fn main() -> std::io::Result<()> {
                     while let Ok(req) = stream.read_request(&mut buf) {
                         println!("req: {req:?}");
                         match req {
-                            Request::Initialize(name)
-                                if let Some(fsm) = fsms_clone.read().unwrap().get(name) =>
+                            Request::Initialize(name, entry)
+                                if let Some(cursor) = fsms_clone
+                                    .get(name)
+                                    .and_then(|fsm| FSMCursor::from_compiled(fsm, entry))
+                                    // any grammar the server knows may be injected
+                                    .map(|cursor| {
+                                        cursor.with_languages(Arc::clone(&fsms_clone))
+                                    }) =>
                             {
-                                if cursors.len() == u8::MAX.into() {
+                                if cursors.len() == usize::from(u8::MAX) {
                                     server_err(&mut stream, "Cursor limit exceeded")?;
                                     continue;
                                 }
                                 current_cursor = cursors.len();
-                                cursors.push(FSMCursor::new(fsm));
+                                cursors.push(cursor);
                                 Response::CursorHandle(cursors.len() as u8).write(&mut stream)?;
                             }
-                            Request::Initialize(ref name) => {
+                            Request::Initialize(name, Some(entry))
+                                if fsms_clone.contains_key(name) =>
+                            {
+                                server_err(
+                                    &mut stream,
+                                    &format!("Language '{name}' has no entry '{entry}'"),
+                                )?;
+                            }
+                            Request::Initialize(ref name, _) => {
                                 server_err(&mut stream, &format!("Unknown language '{name}'"))?;
                             }
                             Request::GetCapabilities => {
                                 Response::Capabilities(
-                                    fsms_clone
-                                        .read()
-                                        .unwrap()
-                                        .keys()
-                                        .map(|s| s.as_str())
-                                        .collect(),
+                                    fsms_clone.keys().map(|s| s.as_str()).collect(),
                                 )
                                 .write(&mut stream)?;
                             }
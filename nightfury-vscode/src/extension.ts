@@ -47,6 +47,7 @@ enum ResponseType {
   CursorHandle = 4,
   InvalidChar = 5,
   RegexStart = 6,
+  Snippet = 7,
 }
 type SingleByteResponse = { cc: ResponseType.Ok | ResponseType.RegexFull | ResponseType.RegexStart | ResponseType.InvalidChar };
 type OkResponse = { cc: ResponseType.Ok };
@@ -55,8 +56,9 @@ type RegexFullResposne = { cc: ResponseType.RegexFull };
 type RegexStartResponse = { cc: ResponseType.RegexStart };
 type CursorHandleResponse = { cc: ResponseType.CursorHandle, handle: number };
 type InvalidCharResponse = { cc: ResponseType.InvalidChar };
+type SnippetResponse = { cc: ResponseType.Snippet, snippet: string };
 type ExpandedResponse = { cc: null, expanded: string };
-type Response = OkResponse | ErrorResponse | RegexFullResposne | RegexStartResponse | CursorHandleResponse | InvalidCharResponse | ExpandedResponse;
+type Response = OkResponse | ErrorResponse | RegexFullResposne | RegexStartResponse | CursorHandleResponse | InvalidCharResponse | SnippetResponse | ExpandedResponse;
 
 function connect(path: string, callback: (socket: net.Socket) => void): net.Socket | null {
   access(path, constants.F_OK, (err) => {
@@ -208,6 +210,22 @@ async function insertExpansion(expaned: string, insert: boolean = false) {
   }
 }
 
+async function insertSnippet(snippet: string) {
+  const editor = vscode.window.activeTextEditor;
+  if (!editor) {
+    console.warn("editor is undefined!");
+    return;
+  }
+  const range = getTextToReplace(editor.selection.active.translate(0, 1));
+  if (!range) {
+    console.warn("Range is undefined!");
+    return;
+  }
+  // the server moved on into the first slot, the user's input starts there
+  await editor.insertSnippet(new vscode.SnippetString(snippet), range);
+  bumpSSOToCursor();
+}
+
 function isSingleByteResponse(respId: number): boolean {
   switch (respId) {
     case 0:
@@ -267,6 +285,8 @@ function parseResponse(raw: Buffer): Response {
       return { cc: id!, msg: raw.toString('utf8', 1, raw.length - 1) };
     case 0x4:
       return { cc: id!, handle: raw.at(1)! };
+    case 0x7:
+      return { cc: id!, snippet: raw.toString('utf8', 1, raw.length - 1) };
     default:
       ret = { cc: null, expanded: raw.toString('utf8', 0, raw.length - 1) };
       return ret;
@@ -306,6 +326,10 @@ async function handleResponse(response: Response) {
       currentlyInRegex = true;
       console.log("Regex Start");
       return;
+    case ResponseType.Snippet:
+      console.log(`expanding to snippet '${response.snippet}'`);
+      await insertSnippet(response.snippet);
+      return;
     case null:
       if (lastReq?.cc === RequestType.Revert) {
         console.log(prevShortStartOffs);
@@ -424,8 +448,16 @@ export function activate(context: vscode.ExtensionContext) {
   });
 
 
+  // tabbing between the slots of a snippet doesn't change the text, so the server has to be told
+  const disposableNextSlot = vscode.commands.registerCommand('nightfury-vscode.nextSlot', async () => {
+    sendChar('\t');
+    await vscode.commands.executeCommand('jumpToNextSnippetPlaceholder');
+    bumpSSOToCursor();
+  });
+
   context.subscriptions.push(disposableGetCaps);
   context.subscriptions.push(disposableActivateNightfury);
+  context.subscriptions.push(disposableNextSlot);
 }
 
 function buildRequest(req: Request) {
@@ -442,10 +474,8 @@ function buildRequest(req: Request) {
       buf.fill(req.cc);
       return buf;
     default:
-      buf = Buffer.allocUnsafe(req.text.length + 1);
-      buf.fill(req.text, 0, req.text.length);
-      buf.writeUint8(0, req.text.length);
-      return buf;
+      // the server counts bytes, JS strings count UTF-16 units
+      return Buffer.concat([Buffer.from(req.text, 'utf8'), Buffer.from([0])]);
   }
 }
 
@@ -461,7 +491,8 @@ function sendRevert(callback?: ((err?: Error | null) => void) | undefined) {
 function sendChar(char: string, callback?: ((err?: Error | null) => void) | undefined) {
   // if (char.length > 1) { throw new Error("not a char!"); }
 
-  const reqObj = Advance(char[0]);
+  // a whole code point, char[0] would split characters outside the BMP
+  const reqObj = Advance(String.fromCodePoint(char.codePointAt(0)!));
   send(reqObj, callback);
 }
 
@@ -2,7 +2,7 @@
 
 extern crate test;
 
-use lib::frontend::create_graph_from_ebnf;
+use lib::frontend::{CompileOptions, IncrementalCompiler, create_graph_from_ebnf};
 use std::fs::read_to_string;
 use test::Bencher;
 
@@ -11,3 +11,12 @@ fn benchmark(b: &mut Bencher) {
     let ebnf = read_to_string("../js.ebnf").unwrap();
     b.iter(|| create_graph_from_ebnf(&ebnf));
 }
+
+#[bench]
+fn benchmark_incremental(b: &mut Bencher) {
+    let ebnf = read_to_string("../js.ebnf").unwrap();
+    let edited = ebnf.replace("'/=';", "'/=' | '%=';");
+    let mut compiler = IncrementalCompiler::new();
+    let mut versions = [&ebnf, &edited].into_iter().cycle();
+    b.iter(|| compiler.compile(versions.next().unwrap(), &CompileOptions::default()));
+}
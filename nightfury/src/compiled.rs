@@ -0,0 +1,560 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use debug_print::debug_println;
+use regex::Regex;
+
+mod binary;
+pub use binary::FormatError;
+
+use crate::ToCSV;
+use crate::fsm::{EntryPoints, FSMNode, FSMNodeWrapper, NodeType};
+
+/// index of a node in a [CompiledFsm]
+pub type NodeIdx = u32;
+/// the calls a node of a [CompiledFsm] is nested in, innermost last
+pub type CallStack = Vec<NodeIdx>;
+/// bytes a [CompiledFsm] can read its node and edge tables from in place, e.g. a mapped file
+pub type SharedBytes = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// bytes each node takes up in the node table
+const NODE_SIZE: usize = 12;
+const FIELD_VALUE: usize = 0;
+const FIELD_CHILDREN: usize = 4;
+const FIELD_CALLEE: usize = 8;
+/// callee field of a node that isn't a call
+const NO_CALLEE: u32 = u32::MAX;
+/// set in the callee field of a call that nothing but the end of its rule follows, so there's
+/// nothing to come back to
+const TAIL_CALL: u32 = 1 << 31;
+
+/// node and edge table of a [CompiledFsm], read in place
+/// A node is three little endian `u32`s: the index of its value, where its children start in the
+/// edge table and its callee along with [TAIL_CALL]. The children of a node end where the ones of
+/// the next node start. The edge table holds the index of each child.
+/// Compiled FSMs own their tables, loaded ones view them in the file.
+#[derive(Clone)]
+struct Tables {
+    bytes: SharedBytes,
+    /// offset of the node table in bytes
+    nodes: usize,
+    len: usize,
+    /// offset of the edge table in bytes
+    edges: usize,
+    edge_cnt: usize,
+}
+
+impl Default for Tables {
+    fn default() -> Self {
+        Self {
+            bytes: Arc::new(Vec::new()),
+            nodes: 0,
+            len: 0,
+            edges: 0,
+            edge_cnt: 0,
+        }
+    }
+}
+
+impl Debug for Tables {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tables")
+            .field("len", &self.len)
+            .field("edge_cnt", &self.edge_cnt)
+            .finish()
+    }
+}
+
+impl Tables {
+    fn bytes(&self) -> &[u8] {
+        (*self.bytes).as_ref()
+    }
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(
+            self.bytes()[offset..offset + 4]
+                .try_into()
+                .expect("took 4 bytes"),
+        )
+    }
+    fn field(&self, idx: NodeIdx, field: usize) -> u32 {
+        self.u32_at(self.nodes + idx as usize * NODE_SIZE + field)
+    }
+    /// range of the children of `idx` in the edge table
+    fn children(&self, idx: NodeIdx) -> (usize, usize) {
+        let start = self.field(idx, FIELD_CHILDREN) as usize;
+        let end = match idx as usize + 1 {
+            next if next < self.len => self.field(next as NodeIdx, FIELD_CHILDREN) as usize,
+            _ => self.edge_cnt,
+        };
+        (start, end)
+    }
+    fn node_table(&self) -> &[u8] {
+        &self.bytes()[self.nodes..self.nodes + self.len * NODE_SIZE]
+    }
+    fn edge_table(&self) -> &[u8] {
+        &self.bytes()[self.edges..self.edges + self.edge_cnt * 4]
+    }
+}
+
+/// frozen version of the graphs of [EntryPoints], the cursor walks this one
+/// Nodes refer to each other by their index, so it's shared between threads without any locks
+/// or reference counts. The nodes only keep the index of their value, every distinct value is
+/// stored once.
+#[derive(Debug, Default)]
+pub struct CompiledFsm {
+    tables: Tables,
+    /// the values of all nodes
+    values: Vec<NodeType>,
+    /// entry rules with their roots, the first one is the default entry
+    entries: Vec<(String, NodeIdx)>,
+    /// the grammar's `@layout`, see [crate::FSMCursor::with_layout]
+    layout: Option<Regex>,
+    /// the grammar's `@indent`, see [crate::FSMCursor::with_indent]
+    indent: Option<String>,
+}
+
+impl CompiledFsm {
+    pub fn new(entries: &EntryPoints) -> Self {
+        let roots: Vec<&FSMNodeWrapper> = entries
+            .names()
+            .map(|name| entries.get(Some(name)).expect("names are entries"))
+            .collect();
+        let mut ret = Self::from_roots(&roots);
+        ret.entries = entries
+            .names()
+            .map(|name| name.to_string())
+            .zip(ret.entries.iter().map(|(_, root)| *root))
+            .collect();
+        ret.layout = entries.layout().cloned();
+        ret.indent = entries.indent().map(str::to_string);
+        ret
+    }
+    /// compiles the graphs reachable from `roots`, their entries are unnamed
+    /// Nodes keep the order of [FSMNode::all_nodes], so in a numbered graph a node's index is its
+    /// id.
+    pub(crate) fn from_roots(roots: &[&FSMNodeWrapper]) -> Self {
+        let order = FSMNode::all_nodes(roots);
+        // the callee field needs the top bit for TAIL_CALL
+        assert!(order.len() < TAIL_CALL as usize, "FSM has too many nodes");
+        let index: HashMap<usize, NodeIdx> = order
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| (node.borrow().id(), idx as NodeIdx))
+            .collect();
+        let entries = roots
+            .iter()
+            .map(|root| (String::new(), index[&root.borrow().id()]))
+            .collect();
+        let mut values = Vec::new();
+        // the text format tells values apart, NodeType can't be hashed
+        let mut value_index: HashMap<String, u32> = HashMap::new();
+        let mut nodes = Vec::with_capacity(order.len() * NODE_SIZE);
+        let mut edges = Vec::new();
+        let mut edge_cnt: u32 = 0;
+        for node in &order {
+            let node = node.borrow();
+            let value = *value_index.entry(node.value.to_csv()).or_insert_with(|| {
+                values.push(node.value.clone());
+                (values.len() - 1) as u32
+            });
+            let start = edge_cnt;
+            for child in &node.children {
+                edges.extend_from_slice(&index[&child.borrow().id()].to_le_bytes());
+                edge_cnt += 1;
+            }
+            let callee = match &node.callee {
+                Some(callee) if returns_right_away(&node) => {
+                    index[&callee.borrow().id()] | TAIL_CALL
+                }
+                Some(callee) => index[&callee.borrow().id()],
+                None => NO_CALLEE,
+            };
+            for field in [value, start, callee] {
+                nodes.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        let len = order.len();
+        nodes.extend_from_slice(&edges);
+        Self {
+            tables: Tables {
+                bytes: Arc::new(nodes),
+                nodes: 0,
+                len,
+                edges: len * NODE_SIZE,
+                edge_cnt: edge_cnt as usize,
+            },
+            values,
+            entries,
+            layout: None,
+            indent: None,
+        }
+    }
+    pub fn value(&self, idx: NodeIdx) -> &NodeType {
+        &self.values[self.tables.field(idx, FIELD_VALUE) as usize]
+    }
+    pub fn children(&self, idx: NodeIdx) -> impl ExactSizeIterator<Item = NodeIdx> + Clone + '_ {
+        let (start, end) = self.tables.children(idx);
+        self.tables.bytes()[self.tables.edges + start * 4..self.tables.edges + end * 4]
+            .chunks_exact(4)
+            .map(|child| u32::from_le_bytes(child.try_into().expect("chunks of 4 bytes")))
+    }
+    /// entry of the graph a [NodeType::Call] goes through
+    pub fn callee(&self, idx: NodeIdx) -> Option<NodeIdx> {
+        match self.tables.field(idx, FIELD_CALLEE) {
+            NO_CALLEE => None,
+            callee => Some(callee & !TAIL_CALL),
+        }
+    }
+    /// whether nothing but the end of the rule follows the call `idx`
+    pub fn is_tail_call(&self, idx: NodeIdx) -> bool {
+        let callee = self.tables.field(idx, FIELD_CALLEE);
+        callee != NO_CALLEE && callee & TAIL_CALL != 0
+    }
+    pub fn len(&self) -> usize {
+        self.tables.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tables.len == 0
+    }
+    /// root of the entry `name`, or of the default entry if `name` is None
+    pub fn entry(&self, name: Option<&str>) -> Option<NodeIdx> {
+        match name {
+            Some(name) => self
+                .entries
+                .iter()
+                .find(|(entry, _)| entry == name)
+                .map(|(_, root)| *root),
+            None => self.entries.first().map(|(_, root)| *root),
+        }
+    }
+    pub fn layout(&self) -> Option<&Regex> {
+        self.layout.as_ref()
+    }
+    pub fn indent(&self) -> Option<&str> {
+        self.indent.as_deref()
+    }
+    /// the Keywords and UserDefinedCombos that may follow any of `positions`, each a node with the
+    /// call stacks it may be nested in
+    pub fn successors(&self, positions: &[(NodeIdx, Vec<CallStack>)]) -> Successors {
+        let mut walk = SuccessorWalk::new(self, None);
+        for (node, stacks) in positions {
+            for stack in stacks {
+                let stack = walk.intern(stack);
+                walk.visit(*node, stack);
+            }
+        }
+        walk.ret
+    }
+    /// the Keywords and UserDefinedCombos that may follow `node`, no matter where the rule it's in
+    /// is called from
+    pub(crate) fn successors_from_any_caller(
+        &self,
+        node: NodeIdx,
+        calls: &CallGraph,
+    ) -> Successors {
+        let mut walk = SuccessorWalk::new(self, Some(calls));
+        walk.visit(node, EMPTY_STACK);
+        walk.ret
+    }
+}
+
+/// whether nothing but the end of the rule follows `call`
+fn returns_right_away(call: &FSMNode) -> bool {
+    // a call looping back to itself doesn't return right away either
+    let mut visited = HashSet::new();
+    let mut todo: Vec<FSMNodeWrapper> = call.children.clone();
+    while let Some(node) = todo.pop() {
+        let node = node.borrow();
+        if !node.is_null() {
+            return false;
+        }
+        for child in &node.children {
+            if visited.insert(child.borrow().id()) {
+                todo.push(child.clone());
+            }
+        }
+    }
+    true
+}
+
+/// how deep calls may be nested, bounds the walk through left recursive rules
+const MAX_CALL_DEPTH: usize = 64;
+
+/// what may follow a node, see [CompiledFsm::successors]
+#[derive(Debug, Default)]
+pub struct Successors {
+    /// the Keywords and UserDefinedCombos, each with the call stacks it can be reached with
+    pub nodes: Vec<(NodeIdx, Vec<CallStack>)>,
+    /// whether the walk can return from the outermost graph
+    pub can_end: bool,
+}
+
+/// which calls go to each rule, needed to return from a rule without knowing its caller
+pub(crate) struct CallGraph {
+    /// entry of the rule graph each node is part of
+    rule_of: Vec<Option<NodeIdx>>,
+    /// calls of each rule, by its entry
+    callers: HashMap<NodeIdx, Vec<NodeIdx>>,
+}
+
+impl CallGraph {
+    pub(crate) fn new(fsm: &CompiledFsm) -> Self {
+        let mut rule_of = vec![None; fsm.len()];
+        let mut callers: HashMap<NodeIdx, Vec<NodeIdx>> = HashMap::new();
+        for idx in 0..fsm.len() as NodeIdx {
+            if let Some(callee) = fsm.callee(idx) {
+                callers.entry(callee).or_default().push(idx);
+            }
+        }
+        let entries = (0..fsm.len() as NodeIdx)
+            .filter_map(|idx| fsm.callee(idx))
+            .chain(fsm.entries.iter().map(|(_, root)| *root));
+        for entry in entries {
+            if rule_of[entry as usize].is_some() {
+                continue;
+            }
+            rule_of[entry as usize] = Some(entry);
+            let mut todo = vec![entry];
+            while let Some(node) = todo.pop() {
+                for child in fsm.children(node) {
+                    if rule_of[child as usize].is_none() {
+                        rule_of[child as usize] = Some(entry);
+                        todo.push(child);
+                    }
+                }
+            }
+        }
+        Self { rule_of, callers }
+    }
+}
+
+/// a call stack met by a [SuccessorWalk], interned so visiting a node doesn't copy its stack
+type StackId = u32;
+/// the stack of a node nested in no call at all
+const EMPTY_STACK: StackId = 0;
+
+/// the innermost call of an interned stack
+#[derive(Clone, Copy)]
+struct Frame {
+    call: NodeIdx,
+    /// the stack around `call`
+    outer: StackId,
+    depth: usize,
+}
+
+/// depth first search for successors through Null nodes and calls
+struct SuccessorWalk<'a> {
+    fsm: &'a CompiledFsm,
+    /// returns from the outermost rule to all of its callers instead of ending, if given
+    calls: Option<&'a CallGraph>,
+    /// the stacks met so far by their id, the one of [EMPTY_STACK] is never looked at
+    frames: Vec<Frame>,
+    /// ids of the stacks met so far, by the stack around the innermost call and that call
+    stack_ids: HashMap<(StackId, NodeIdx), StackId>,
+    visited: HashSet<(NodeIdx, StackId)>,
+    /// successors pushed so far along with the stack they were reached with
+    pushed: HashSet<(NodeIdx, StackId)>,
+    /// index of each successor in ret
+    found: HashMap<NodeIdx, usize>,
+    ret: Successors,
+}
+
+impl<'a> SuccessorWalk<'a> {
+    fn new(fsm: &'a CompiledFsm, calls: Option<&'a CallGraph>) -> Self {
+        Self {
+            fsm,
+            calls,
+            frames: vec![Frame {
+                call: 0,
+                outer: EMPTY_STACK,
+                depth: 0,
+            }],
+            stack_ids: HashMap::new(),
+            visited: HashSet::new(),
+            pushed: HashSet::new(),
+            found: HashMap::new(),
+            ret: Successors::default(),
+        }
+    }
+    fn intern(&mut self, stack: &[NodeIdx]) -> StackId {
+        stack
+            .iter()
+            .fold(EMPTY_STACK, |outer, &call| self.push_call(outer, call))
+    }
+    /// the stack of `call` nested in `outer`
+    fn push_call(&mut self, outer: StackId, call: NodeIdx) -> StackId {
+        let next = self.frames.len() as StackId;
+        let id = *self.stack_ids.entry((outer, call)).or_insert(next);
+        if id == next {
+            let depth = self.frames[outer as usize].depth + 1;
+            self.frames.push(Frame { call, outer, depth });
+        }
+        id
+    }
+    fn stack(&self, mut id: StackId) -> CallStack {
+        let mut ret = Vec::with_capacity(self.frames[id as usize].depth);
+        while id != EMPTY_STACK {
+            let frame = self.frames[id as usize];
+            ret.push(frame.call);
+            id = frame.outer;
+        }
+        ret.reverse();
+        ret
+    }
+    /// adds `node` reached with `stack`, unless it's already known to be reached that way
+    fn push(&mut self, node: NodeIdx, stack: StackId) {
+        if !self.pushed.insert((node, stack)) {
+            return;
+        }
+        let stack = self.stack(stack);
+        match self.found.get(&node) {
+            Some(&idx) => self.ret.nodes[idx].1.push(stack),
+            None => {
+                self.found.insert(node, self.ret.nodes.len());
+                self.ret.nodes.push((node, vec![stack]));
+            }
+        }
+    }
+    /// collects what may follow `node` when it's nested in `stack`
+    fn visit(&mut self, node: NodeIdx, stack: StackId) {
+        if !self.visited.insert((node, stack)) {
+            return;
+        }
+        let children = self.fsm.children(node);
+        if children.len() == 0 {
+            self.leave(node, stack);
+            return;
+        }
+        // tokens right after the node come first, the cursor prefers them
+        for child in children.clone() {
+            if !matches!(self.fsm.value(child), NodeType::Null | NodeType::Call(_)) {
+                self.push(child, stack);
+            }
+        }
+        for child in children {
+            match self.fsm.value(child) {
+                NodeType::Null => self.visit(child, stack),
+                NodeType::Call(_) => self.enter(child, stack),
+                _ => {}
+            }
+        }
+    }
+    fn enter(&mut self, call: NodeIdx, stack: StackId) {
+        if self.frames[stack as usize].depth >= MAX_CALL_DEPTH {
+            debug_println!("giving up on call {call} nested too deep");
+            return;
+        }
+        let callee = self
+            .fsm
+            .callee(call)
+            .expect("calls go through the graph of a rule");
+        // nothing to come back to, this keeps right recursion from growing the stack
+        if self.fsm.is_tail_call(call) {
+            self.visit(callee, stack);
+        } else {
+            let stack = self.push_call(stack, call);
+            self.visit(callee, stack);
+        }
+    }
+    /// the end of a rule graph was reached at `node`
+    fn leave(&mut self, node: NodeIdx, stack: StackId) {
+        if stack != EMPTY_STACK {
+            let Frame { call, outer, .. } = self.frames[stack as usize];
+            self.visit(call, outer);
+        } else if let Some(calls) = self.calls
+            && let Some(callers) =
+                calls.rule_of[node as usize].and_then(|entry| calls.callers.get(&entry))
+        {
+            for &caller in callers {
+                self.visit(caller, EMPTY_STACK);
+            }
+        } else {
+            self.ret.can_end = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::frontend::{create_entry_points_from_ebnf, create_graph_from_ebnf};
+    use crate::{FSMCursor, FSMNode};
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CompiledFsm>();
+        assert_send_sync::<FSMCursor>();
+    }
+
+    #[test]
+    fn test_compile() {
+        let bnf = r"
+        stmt ::= expr ';';
+        expr ::= 'x' [ '+' expr ];
+    ";
+        let root = create_graph_from_ebnf(bnf).unwrap();
+        let fsm = CompiledFsm::new(&EntryPoints::from(root.clone()));
+        assert_eq!(FSMNode::all_nodes(&[&root]).len(), fsm.len());
+        assert_eq!(Some(0), fsm.entry(None));
+        for idx in 0..fsm.len() as NodeIdx {
+            assert!(fsm.children(idx).all(|child| (child as usize) < fsm.len()));
+            assert_eq!(
+                matches!(fsm.value(idx), NodeType::Call(_)),
+                fsm.callee(idx).is_some()
+            );
+        }
+        // nothing follows the call of stmt and the recursive one, stmt goes on with ';' after expr
+        let mut tail_calls: Vec<bool> = (0..fsm.len() as NodeIdx)
+            .filter(|idx| fsm.callee(*idx).is_some())
+            .map(|call| fsm.is_tail_call(call))
+            .collect();
+        tail_calls.sort();
+        assert_eq!(vec![false, true, true], tail_calls);
+    }
+
+    #[test]
+    fn test_entries() {
+        let bnf = r"
+        stmt ::= expr ';';
+        expr ::= 'x';
+    ";
+        let entries = create_entry_points_from_ebnf(bnf, &["stmt", "expr"]).unwrap();
+        let fsm = CompiledFsm::new(&entries);
+        let expr = fsm.entry(Some("expr")).unwrap();
+        assert_eq!(fsm.entry(Some("stmt")), fsm.entry(None));
+        assert_ne!(fsm.entry(None), Some(expr));
+        assert_eq!(None, fsm.entry(Some("missing")));
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let bnf = r"
+        stmt ::= 'let' ident '=' expr ';';
+        ident ::= #'[a-z]+';
+        expr ::= #'[0-9]+' | ( '(' expr ')' );
+    ";
+        let entries = create_entry_points_from_ebnf(bnf, &[]).unwrap();
+        let fsm = Arc::new(CompiledFsm::new(&entries));
+        let handles: Vec<_> = ["la=((1));", "lb=2;", "lc=(((3)));"]
+            .into_iter()
+            .map(|input| {
+                let fsm = Arc::clone(&fsm);
+                thread::spawn(move || {
+                    let mut cursor = FSMCursor::from_compiled(&fsm, None).unwrap();
+                    input.chars().for_each(|char| {
+                        cursor.advancex(char);
+                    });
+                    cursor.is_done()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap());
+        }
+    }
+}
@@ -0,0 +1,613 @@
+//! binary format of a [CompiledFsm]
+//!
+//! Integers are unsigned LEB128 varints unless noted otherwise:
+//!
+//! - magic: [MAGIC]
+//! - version: little endian `u32`, see [VERSION]
+//! - string table: the number of strings, then each one as its length and its UTF-8 bytes
+//! - entries: their number, then the name and root of each
+//! - layout and indent: one optional string each
+//! - value table: the number of values, then the values
+//! - node and edge table: their lengths as little endian `u32`s, then the tables as described in
+//!   [super::Tables]
+//! - checksum: little endian 64 bit FNV-1a of everything before it
+//!
+//! A value starts with a byte holding its kind, followed by its fields. Calls add the name of the
+//! rule, UserDefinedCombos their regex and final characters as one string. Keywords add a byte
+//! with their flags and casing, then their short, expanded, closing token, separator and injected
+//! language. Strings are referred to by their index in the table, optional ones by the index plus
+//! one with 0 for none. Every string is stored once no matter how many values use it, so every
+//! regex is only compiled once while loading.
+//!
+//! Loading checks the checksum and every index, then decodes the values, i.e. the keywords get
+//! their own strings and the regexes are compiled. The node and edge tables are neither decoded
+//! nor copied, the FSM reads them from the bytes it's loaded from, see
+//! [CompiledFsm::from_shared_bytes].
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use super::{
+    CompiledFsm, FIELD_CALLEE, FIELD_VALUE, NO_CALLEE, NODE_SIZE, NodeIdx, SharedBytes, TAIL_CALL,
+    Tables,
+};
+use crate::fsm::{Casing, Keyword, NodeType};
+
+/// start of every binary FSM, text FSMs can't start with it
+pub const MAGIC: [u8; 4] = *b"NFSM";
+/// bumped whenever the layout changes, files of other versions are rejected
+pub const VERSION: u32 = 3;
+
+const KIND_NULL: u8 = 0;
+const KIND_KEYWORD: u8 = 1;
+const KIND_USERDEF: u8 = 2;
+const KIND_CALL: u8 = 3;
+
+const FLAG_FIXED_SHORT: u8 = 1;
+const FLAG_NO_AUTOEXPAND: u8 = 1 << 1;
+const FLAG_BLOCK: u8 = 1 << 2;
+const FLAG_SNIPPET: u8 = 1 << 3;
+/// the casing takes up the bits above the flags, 0 for none and its position in [Casing::ALL]
+/// plus one otherwise
+const CASING_SHIFT: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    /// the data doesn't start with [MAGIC]
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// the data ends before the checksum
+    Truncated,
+    ChecksumMismatch,
+    /// the checksum matched, but something in the file doesn't make sense
+    Invalid(String),
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a binary FSM"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "binary FSM has version {version}, only version {VERSION} is supported"
+            ),
+            Self::Truncated => write!(f, "binary FSM is truncated"),
+            Self::ChecksumMismatch => write!(f, "binary FSM is corrupted, its checksum is off"),
+            Self::Invalid(msg) => write!(f, "invalid binary FSM: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// 64 bit FNV-1a
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn push_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// collects the strings of the table, each one only once
+#[derive(Default)]
+struct Strings<'a> {
+    index: HashMap<&'a str, u32>,
+    table: Vec<&'a str>,
+}
+
+impl<'a> Strings<'a> {
+    fn add(&mut self, str: &'a str) -> u32 {
+        *self.index.entry(str).or_insert_with(|| {
+            self.table.push(str);
+            (self.table.len() - 1) as u32
+        })
+    }
+    /// index plus one, 0 for none
+    fn add_opt(&mut self, str: Option<&'a str>) -> u32 {
+        str.map_or(0, |str| self.add(str) + 1)
+    }
+}
+
+/// reads the file front to back
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FormatError> {
+        let end = self.pos.checked_add(len).ok_or(FormatError::Truncated)?;
+        let ret = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(FormatError::Truncated)?;
+        self.pos = end;
+        Ok(ret)
+    }
+    fn byte(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().expect("took 4 bytes"),
+        ))
+    }
+    fn varint(&mut self) -> Result<u32, FormatError> {
+        let mut ret = 0u32;
+        for shift in (0..32).step_by(7) {
+            let byte = self.byte()?;
+            ret |= u32::from(byte & 0x7f)
+                .checked_shl(shift)
+                .filter(|bits| bits >> shift == u32::from(byte & 0x7f))
+                .ok_or_else(|| FormatError::Invalid("varint doesn't fit 32 bits".to_string()))?;
+            if byte & 0x80 == 0 {
+                return Ok(ret);
+            }
+        }
+        Err(FormatError::Invalid(
+            "varint doesn't fit 32 bits".to_string(),
+        ))
+    }
+    /// a count of elements taking up at least a byte each, checked against what's left so a
+    /// broken count can't make anyone allocate huge buffers
+    fn count(&mut self) -> Result<usize, FormatError> {
+        let count = self.varint()? as usize;
+        if count > self.bytes.len() - self.pos {
+            return Err(FormatError::Truncated);
+        }
+        Ok(count)
+    }
+}
+
+/// the strings of the table, borrowed from the file
+struct StringTable<'a> {
+    strings: Vec<&'a str>,
+    regexes: HashMap<u32, Regex>,
+}
+
+impl<'a> StringTable<'a> {
+    fn get(&self, idx: u32) -> Result<&'a str, FormatError> {
+        self.strings
+            .get(idx as usize)
+            .copied()
+            .ok_or_else(|| FormatError::Invalid(format!("string {idx} doesn't exist")))
+    }
+    fn get_opt(&self, idx: u32) -> Result<Option<&'a str>, FormatError> {
+        match idx {
+            0 => Ok(None),
+            idx => self.get(idx - 1).map(Some),
+        }
+    }
+    fn regex(&mut self, idx: u32) -> Result<Regex, FormatError> {
+        if let Some(regex) = self.regexes.get(&idx) {
+            return Ok(regex.clone());
+        }
+        let pattern = self.get(idx)?;
+        let regex = Regex::new(pattern)
+            .map_err(|err| FormatError::Invalid(format!("invalid regex '{pattern}': {err}")))?;
+        self.regexes.insert(idx, regex.clone());
+        Ok(regex)
+    }
+}
+
+impl CompiledFsm {
+    /// the FSM in the binary format, identical FSMs give identical bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut strings = Strings::default();
+        let entries: Vec<(u32, NodeIdx)> = self
+            .entries
+            .iter()
+            .map(|(name, root)| (strings.add(name), *root))
+            .collect();
+        let layout = strings.add_opt(self.layout.as_ref().map(Regex::as_str));
+        let indent = strings.add_opt(self.indent.as_deref());
+        let finals: Vec<String> = self
+            .values
+            .iter()
+            .map(|value| match value {
+                NodeType::UserDefinedCombo(_, finals) => finals.iter().collect(),
+                _ => String::new(),
+            })
+            .collect();
+        let mut values = Vec::new();
+        for (value, finals) in self.values.iter().zip(&finals) {
+            match value {
+                NodeType::Null => values.push(KIND_NULL),
+                NodeType::Keyword(keyword) => {
+                    values.push(KIND_KEYWORD);
+                    let mut flags = 0;
+                    for (set, flag) in [
+                        (keyword.fixed_short, FLAG_FIXED_SHORT),
+                        (keyword.no_autoexpand, FLAG_NO_AUTOEXPAND),
+                        (keyword.block, FLAG_BLOCK),
+                        (keyword.snippet, FLAG_SNIPPET),
+                    ] {
+                        if set {
+                            flags |= flag;
+                        }
+                    }
+                    let casing = keyword.casing.map_or(0, |casing| {
+                        Casing::ALL.iter().position(|c| *c == casing).unwrap() as u8 + 1
+                    });
+                    values.push(flags | casing << CASING_SHIFT);
+                    for field in [
+                        strings.add(&keyword.short),
+                        strings.add(&keyword.expanded),
+                        strings.add_opt(keyword.closing_token.as_deref()),
+                        strings.add(&keyword.separator),
+                        strings.add_opt(keyword.inject.as_deref()),
+                    ] {
+                        push_varint(&mut values, field);
+                    }
+                }
+                NodeType::UserDefinedCombo(regex, _) => {
+                    values.push(KIND_USERDEF);
+                    push_varint(&mut values, strings.add(regex.as_str()));
+                    push_varint(&mut values, strings.add(finals));
+                }
+                NodeType::Call(name) => {
+                    values.push(KIND_CALL);
+                    push_varint(&mut values, strings.add(name));
+                }
+            }
+        }
+
+        let mut ret = MAGIC.to_vec();
+        ret.extend_from_slice(&VERSION.to_le_bytes());
+        push_varint(&mut ret, strings.table.len() as u32);
+        for str in &strings.table {
+            push_varint(&mut ret, str.len() as u32);
+            ret.extend_from_slice(str.as_bytes());
+        }
+        push_varint(&mut ret, entries.len() as u32);
+        for (name, root) in entries {
+            push_varint(&mut ret, name);
+            push_varint(&mut ret, root);
+        }
+        push_varint(&mut ret, layout);
+        push_varint(&mut ret, indent);
+        push_varint(&mut ret, self.values.len() as u32);
+        ret.extend_from_slice(&values);
+        ret.extend_from_slice(&(self.tables.len as u32).to_le_bytes());
+        ret.extend_from_slice(&(self.tables.edge_cnt as u32).to_le_bytes());
+        ret.extend_from_slice(self.tables.node_table());
+        ret.extend_from_slice(self.tables.edge_table());
+        let checksum = checksum(&ret);
+        ret.extend_from_slice(&checksum.to_le_bytes());
+        ret
+    }
+
+    /// reads an FSM in the binary format, copying its tables, see [Self::from_shared_bytes]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FormatError> {
+        Self::from_shared_bytes(Arc::new(bytes.to_vec()))
+    }
+
+    /// reads an FSM in the binary format, the node and edge tables stay in `bytes` and are read
+    /// from there whenever the cursor moves
+    pub fn from_shared_bytes(bytes: SharedBytes) -> Result<Self, FormatError> {
+        let data: &[u8] = (*bytes).as_ref();
+        if !data.starts_with(&MAGIC) {
+            return Err(FormatError::BadMagic);
+        }
+        let mut reader = Reader {
+            bytes: data,
+            pos: MAGIC.len(),
+        };
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let Some(body_len) = data.len().checked_sub(8).filter(|len| *len >= reader.pos) else {
+            return Err(FormatError::Truncated);
+        };
+        let (body, stored) = data.split_at(body_len);
+        if checksum(body) != u64::from_le_bytes(stored.try_into().expect("8 bytes")) {
+            return Err(FormatError::ChecksumMismatch);
+        }
+        reader.bytes = body;
+
+        let count = reader.count()?;
+        let mut strings = Vec::with_capacity(count);
+        for i in 0..count {
+            let len = reader.varint()? as usize;
+            let str = std::str::from_utf8(reader.take(len)?)
+                .map_err(|_| FormatError::Invalid(format!("string {i} isn't valid UTF-8")))?;
+            strings.push(str);
+        }
+        let mut strings = StringTable {
+            strings,
+            regexes: HashMap::new(),
+        };
+
+        let count = reader.count()?;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let name = strings.get(reader.varint()?)?.to_string();
+            entries.push((name, reader.varint()?));
+        }
+        let layout = match reader.varint()? {
+            0 => None,
+            layout => Some(strings.regex(layout - 1)?),
+        };
+        let indent = strings.get_opt(reader.varint()?)?.map(str::to_string);
+
+        let count = reader.count()?;
+        let mut values = Vec::with_capacity(count);
+        for _ in 0..count {
+            let value =
+                match reader.byte()? {
+                    KIND_NULL => NodeType::Null,
+                    KIND_KEYWORD => {
+                        let flags = reader.byte()?;
+                        let casing = flags >> CASING_SHIFT;
+                        NodeType::Keyword(Keyword {
+                            short: strings.get(reader.varint()?)?.to_string(),
+                            expanded: strings.get(reader.varint()?)?.to_string(),
+                            closing_token: strings.get_opt(reader.varint()?)?.map(str::to_string),
+                            fixed_short: flags & FLAG_FIXED_SHORT != 0,
+                            no_autoexpand: flags & FLAG_NO_AUTOEXPAND != 0,
+                            separator: strings.get(reader.varint()?)?.to_string(),
+                            block: flags & FLAG_BLOCK != 0,
+                            snippet: flags & FLAG_SNIPPET != 0,
+                            inject: strings.get_opt(reader.varint()?)?.map(str::to_string),
+                            casing: match casing {
+                                0 => None,
+                                casing => Some(*Casing::ALL.get(casing as usize - 1).ok_or_else(
+                                    || FormatError::Invalid(format!("unknown casing {casing}")),
+                                )?),
+                            },
+                        })
+                    }
+                    KIND_USERDEF => NodeType::UserDefinedCombo(
+                        strings.regex(reader.varint()?)?,
+                        strings.get(reader.varint()?)?.chars().collect(),
+                    ),
+                    KIND_CALL => NodeType::Call(strings.get(reader.varint()?)?.to_string()),
+                    kind => return Err(FormatError::Invalid(format!("unknown kind {kind}"))),
+                };
+            values.push(value);
+        }
+
+        let len = reader.u32()? as usize;
+        let edge_cnt = reader.u32()? as usize;
+        let nodes = reader.pos;
+        let table_len = len
+            .checked_mul(NODE_SIZE)
+            .and_then(|nodes| edge_cnt.checked_mul(4)?.checked_add(nodes))
+            .ok_or(FormatError::Truncated)?;
+        reader.take(table_len)?;
+        if reader.pos != body.len() {
+            return Err(FormatError::Invalid(
+                "trailing data after the tables".to_string(),
+            ));
+        }
+
+        let ret = Self {
+            tables: Tables {
+                bytes: bytes.clone(),
+                nodes,
+                len,
+                edges: nodes + len * NODE_SIZE,
+                edge_cnt,
+            },
+            values,
+            entries,
+            layout,
+            indent,
+        };
+        ret.check_indices()?;
+        Ok(ret)
+    }
+
+    /// reads an FSM in either the binary format or the text one written by
+    /// [crate::ToCSV::to_csv]
+    pub fn load(bytes: &[u8]) -> Result<Self, FormatError> {
+        if bytes.starts_with(&MAGIC) {
+            return Self::from_bytes(bytes);
+        }
+        Self::from_text(bytes)
+    }
+
+    /// like [Self::load], but a binary FSM reads its tables from `bytes` in place, see
+    /// [Self::from_shared_bytes]
+    pub fn load_shared(bytes: SharedBytes) -> Result<Self, FormatError> {
+        if (*bytes).as_ref().starts_with(&MAGIC) {
+            return Self::from_shared_bytes(bytes);
+        }
+        Self::from_text((*bytes).as_ref())
+    }
+
+    fn from_text(bytes: &[u8]) -> Result<Self, FormatError> {
+        let csv = std::str::from_utf8(bytes)
+            .map_err(|_| FormatError::Invalid("text FSM isn't valid UTF-8".to_string()))?;
+        Ok(Self::new(&crate::ToCSV::from_csv(csv)))
+    }
+
+    /// makes sure every index in the FSM points at something, so the cursor can't go out of
+    /// bounds
+    fn check_indices(&self) -> Result<(), FormatError> {
+        let len = self.tables.len as u64;
+        let invalid = |what: String| Err(FormatError::Invalid(what));
+        if self.entries.is_empty() {
+            return invalid("there are no entries".to_string());
+        }
+        for (name, root) in &self.entries {
+            if u64::from(*root) >= len {
+                return invalid(format!("entry '{name}' is out of range"));
+            }
+        }
+        for idx in 0..self.tables.len as NodeIdx {
+            let Some(value) = self
+                .values
+                .get(self.tables.field(idx, FIELD_VALUE) as usize)
+            else {
+                return invalid(format!("value of node {idx} is out of range"));
+            };
+            let (start, end) = self.tables.children(idx);
+            if start > end || end > self.tables.edge_cnt {
+                return invalid(format!("children of node {idx} are out of range"));
+            }
+            let is_call = matches!(value, NodeType::Call(_));
+            match self.tables.field(idx, FIELD_CALLEE) {
+                NO_CALLEE if is_call => return invalid(format!("call {idx} has no callee")),
+                NO_CALLEE => {}
+                callee if !is_call || u64::from(callee & !TAIL_CALL) >= len => {
+                    return invalid(format!("callee of node {idx} is invalid"));
+                }
+                _ => {}
+            }
+        }
+        let edges = self.tables.edge_table().chunks_exact(4);
+        for edge in edges.map(|edge| u32::from_le_bytes(edge.try_into().expect("4 bytes"))) {
+            if u64::from(edge) >= len {
+                return invalid(format!("edge to node {edge} is out of range"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::FSMCursor;
+    use crate::frontend::create_entry_points_from_ebnf;
+    use crate::fsm::EntryPoints;
+
+    const BNF: &str = r#"
+    @layout ::= #'[ \t\n]*';
+    @indent ::= '  ';
+    @case ::= 'upper';
+    stmt ::= ( 'let' ident '=' expr ';' ) | ( '{' @closing("}") @block { stmt } '}' );
+    ident ::= #'[a-z]+';
+    expr ::= ident | #'[0-9]+' | ( '(' expr ')' );
+    "#;
+
+    fn compiled() -> CompiledFsm {
+        let entries = create_entry_points_from_ebnf(BNF, &["stmt", "expr"]).unwrap();
+        CompiledFsm::new(&entries)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let fsm = compiled();
+        let bytes = fsm.to_bytes();
+        assert!(bytes.starts_with(&MAGIC));
+        let loaded = CompiledFsm::from_bytes(&bytes).unwrap();
+        assert_eq!(bytes, loaded.to_bytes());
+        assert_eq!(fsm.len(), loaded.len());
+        assert_eq!(fsm.entry(Some("expr")), loaded.entry(Some("expr")));
+        assert_eq!(Some("  "), loaded.indent());
+        assert_eq!(
+            fsm.layout().map(Regex::as_str),
+            loaded.layout().map(Regex::as_str)
+        );
+
+        let loaded = Arc::new(loaded);
+        let mut cursor = FSMCursor::from_compiled(&loaded, None).unwrap();
+        for c in "l a=(1);".chars() {
+            cursor.advancex(c);
+        }
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_values_stored_once() {
+        for grammar in [
+            include_str!("../../../sql.ebnf"),
+            include_str!("../../../js.ebnf"),
+        ] {
+            let entries = create_entry_points_from_ebnf(grammar, &[]).unwrap();
+            let fsm = CompiledFsm::from_bytes(&CompiledFsm::new(&entries).to_bytes()).unwrap();
+            assert!(fsm.values.len() < fsm.len());
+            let nulls = fsm.values.iter().filter(|value| **value == NodeType::Null);
+            assert_eq!(1, nulls.count());
+        }
+    }
+
+    #[test]
+    fn test_deterministic() {
+        assert_eq!(compiled().to_bytes(), compiled().to_bytes());
+    }
+
+    #[test]
+    fn test_load_text() {
+        let entries = create_entry_points_from_ebnf(BNF, &[]).unwrap();
+        let csv = crate::ToCSV::to_csv(&entries);
+        let fsm = CompiledFsm::load(csv.as_bytes()).unwrap();
+        assert_eq!(CompiledFsm::new(&entries).to_bytes(), fsm.to_bytes());
+        let bytes = fsm.to_bytes();
+        assert_eq!(bytes, CompiledFsm::load(&bytes).unwrap().to_bytes());
+    }
+
+    #[test]
+    fn test_rejected() {
+        let bytes = compiled().to_bytes();
+        assert_eq!(
+            Some(FormatError::BadMagic),
+            CompiledFsm::from_bytes(b"0\ti\tint\n").err()
+        );
+
+        let mut newer = bytes.clone();
+        newer[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            Some(FormatError::UnsupportedVersion(VERSION + 1)),
+            CompiledFsm::from_bytes(&newer).err()
+        );
+
+        let mut corrupted = bytes.clone();
+        corrupted[bytes.len() / 2] ^= 1;
+        assert_eq!(
+            Some(FormatError::ChecksumMismatch),
+            CompiledFsm::from_bytes(&corrupted).err()
+        );
+
+        assert_eq!(
+            Some(FormatError::Truncated),
+            CompiledFsm::from_bytes(&bytes[..6]).err()
+        );
+        assert!(CompiledFsm::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_index() {
+        let fsm = CompiledFsm::new(&EntryPoints::from(crate::get_test_fsm()));
+        let mut bytes = fsm.to_bytes();
+        // point the last edge past the last node and fix up the checksum
+        let body_len = bytes.len() - 8;
+        bytes[body_len - 4..body_len].copy_from_slice(&(fsm.len() as NodeIdx).to_le_bytes());
+        let checksum = checksum(&bytes[..body_len]);
+        bytes[body_len..].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            CompiledFsm::from_bytes(&bytes),
+            Err(FormatError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_shared_bytes() {
+        let bytes: SharedBytes = Arc::new(compiled().to_bytes());
+        let fsm = CompiledFsm::load_shared(bytes.clone()).unwrap();
+        // the tables are read from the very bytes the FSM was loaded from
+        assert!(Arc::ptr_eq(&bytes, &fsm.tables.bytes));
+        assert_eq!((*bytes).as_ref(), fsm.to_bytes());
+
+        let fsm = Arc::new(fsm);
+        let mut cursor = FSMCursor::from_compiled(&fsm, Some("expr")).unwrap();
+        for c in "(1)".chars() {
+            cursor.advancex(c);
+        }
+        assert!(cursor.is_done());
+    }
+}
@@ -22,9 +22,18 @@ impl EscapeSequenceState {
     }
 }
 
+/// # Panics
+/// on escape sequences it doesn't know about, see [try_resolve_escape_sequences]
 pub fn resolve_escape_sequences(input: &str) -> String {
+    try_resolve_escape_sequences(input).unwrap_or_else(|c| panic!("Invalid escape sequence {}", c))
+}
+
+/// like [resolve_escape_sequences], but hands out the character after the first unknown
+/// backslash instead of panicking
+pub fn try_resolve_escape_sequences(input: &str) -> Result<String, char> {
     let mut state = Nothing;
     let mut tmpc = 0;
+    let mut invalid = None;
     let ret = input.chars().filter_map(|c| match state {
         Nothing if c == '\\' => {
             state.advance();
@@ -44,7 +53,10 @@ pub fn resolve_escape_sequences(input: &str) -> String {
                 '\'' => Some('\''),
                 '"' => Some('"'),
                 '\\' => Some('\\'),
-                _ => panic!("Invalid escape sequence {}", c),
+                _ => {
+                    invalid.get_or_insert(c);
+                    None
+                }
             };
             if reset {
                 state.reset();
@@ -69,9 +81,27 @@ pub fn resolve_escape_sequences(input: &str) -> String {
         _ => Some(c),
     });
     let mut ret: String = ret.collect();
+    if let Some(c) = invalid {
+        return Err(c);
+    }
     if let LowHex = state {
         ret.push((tmpc >> 4) as char);
     }
+    Ok(ret)
+}
+
+/// the inverse of [resolve_escape_sequences], keeps control characters out of the CSV
+pub fn escape_sequences(input: &str) -> String {
+    let mut ret = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\t' => ret.push_str("\\t"),
+            '\r' => ret.push_str("\\r"),
+            c => ret.push(c),
+        }
+    }
     ret
 }
 
@@ -103,4 +133,16 @@ mod tests {
     fn test_multiple() {
         assert_eq!("\n\t", resolve_escape_sequences("\\n\\t"));
     }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(Err('d'), try_resolve_escape_sequences("\\d+"));
+    }
+
+    #[test]
+    fn test_escape_roundtrip() {
+        let input = "{\n\t\\}";
+        assert_eq!("{\\n\\t\\\\}", escape_sequences(input));
+        assert_eq!(input, resolve_escape_sequences(&escape_sequences(input)));
+    }
 }
@@ -1,12 +1,50 @@
 use std::collections::HashMap;
 
 use debug_print::debug_println;
-use ebnf::{Expression, Grammar, Node, RegexExtKind, SymbolKind};
+use ebnf::{Expression, Grammar};
 use regex::Regex;
 
 use super::FSMLock;
 use super::FSMRc;
-use crate::FSMNode;
+use crate::esc_seq::try_resolve_escape_sequences;
+use crate::{EntryPoints, FSMNode, NodeType};
+
+mod abnf;
+mod annotation;
+mod antlr;
+mod dialect;
+mod error;
+mod exception;
+mod incremental;
+mod iso14977;
+mod layout;
+mod left_recursion;
+mod lexical;
+mod macros;
+mod module;
+mod source;
+mod syntax;
+mod tree_sitter;
+mod w3c;
+pub use abnf::Abnf;
+use annotation::Annotations;
+pub use antlr::Antlr;
+pub use dialect::dialects;
+pub use ebnf::{Node, RegexExtKind, SymbolKind};
+pub use error::{
+    GrammarError, GrammarErrorKind, GrammarWarning, GrammarWarningKind, Span, report,
+    report_warnings,
+};
+use incremental::RuleCache;
+pub use incremental::{IncrementalCompiler, RuleChanges};
+pub use iso14977::Iso14977;
+pub use module::GrammarModules;
+use source::{SourceMap, TokenKind};
+pub use syntax::{
+    Ebnf, GrammarFrontend, GrammarIr, exception, frontend, frontend_for_file, frontends, literal,
+};
+pub use tree_sitter::TreeSitter;
+pub use w3c::W3c;
 
 pub fn print_parsed_ebnf(syntax: &str) {
     let grammar = ebnf::get_grammar(syntax).unwrap();
@@ -15,62 +53,101 @@ pub fn print_parsed_ebnf(syntax: &str) {
     }
 }
 
-enum TerminalState {
-    Stub,
-    Created,
+/// the rules handle_node built so far for the graph of one entry
+struct Terminals<'a> {
+    /// entries of the rule graphs, by rule name
+    built: HashMap<String, FSMRc<FSMLock<FSMNode>>>,
+    cache: Option<&'a mut RuleCache>,
+}
+
+impl<'a> Terminals<'a> {
+    fn new(capacity: usize, cache: Option<&'a mut RuleCache>) -> Self {
+        Self {
+            built: HashMap::with_capacity(capacity),
+            cache,
+        }
+    }
+}
+
+/// the entry of the graph of the rule `name`, building it first if needed
+/// Every rule is only built once, references to it call its graph, see [crate::NodeType::Call].
+fn rule_entry(
+    grammar: &mut Grammar,
+    name: &str,
+    terminals: &mut Terminals,
+    annotations: &Annotations,
+) -> FSMRc<FSMLock<FSMNode>> {
+    if let Some(entry) = terminals.built.get(name) {
+        debug_println!("Found {name} in cache!");
+        return entry.clone();
+    }
+    if let Some(template) = terminals
+        .cache
+        .as_deref()
+        .and_then(|cache| cache.template(name))
+    {
+        debug_println!("Reusing terminal {name} from an earlier compilation");
+        let (entry, calls) = FSMNode::clone_rule(template);
+        terminals.built.insert(name.to_string(), entry.clone());
+        for call in calls {
+            let NodeType::Call(callee) = call.borrow().value.clone() else {
+                unreachable!("clone_rule only hands out calls")
+            };
+            let callee = rule_entry(grammar, &callee, terminals, annotations);
+            call.borrow_mut().callee = Some(callee);
+        }
+        return entry;
+    }
+    debug_println!("Creating terminal {name}...");
+    let terminal = find_terminal(grammar, name).expect("checked by check_grammar");
+    let rhs = terminal.rhs.clone();
+    let entry = FSMNode::new_null(None);
+    debug_println!("term_root: {}", entry.borrow().short_id());
+    // inserted before building it, so recursive references can already call it
+    terminals.built.insert(name.to_string(), entry.clone());
+    handle_node(grammar, rhs, &entry, terminals, annotations);
+    // the rule returns from its leaves, this gives it a single one
+    FSMNode::add_child_to_all_leaves(&entry, &FSMNode::new_null(None));
+    debug_println!("Finish terminal");
+    debug_println!("young {}:", name);
+    entry.borrow().dbg();
+    if let Some(cache) = terminals.cache.as_deref_mut() {
+        // minify and friends change the graph later on, the template has to stay as it is now
+        cache.store(name, FSMNode::clone_rule(&entry).0);
+    }
+    entry
 }
 
 fn handle_node(
     grammar: &mut Grammar,
     cur_node: Node,
     cur_root: &FSMRc<FSMLock<FSMNode>>,
-    terminals: &mut HashMap<String, (FSMRc<FSMLock<FSMNode>>, TerminalState)>,
+    terminals: &mut Terminals,
+    annotations: &Annotations,
 ) -> FSMRc<FSMLock<FSMNode>> {
     debug_println!("handle_node got {:?}", cur_node);
-    
+
     match cur_node {
         Node::String(str) => {
-            FSMNode::new_keyword_with_parent(str.to_string(), FSMRc::clone(cur_root))
+            let ret = FSMNode::from_keyword(annotations.keyword(&str));
+            FSMNode::add_child_cycle_safe(cur_root, &ret);
+            ret
+        }
+        Node::RegexString(r) => {
+            FSMNode::new_userdef(Regex::new(&r).expect("checked by check_grammar"), cur_root)
         }
-        Node::RegexString(r) => FSMNode::new_userdef(Regex::new(&r).unwrap(), cur_root),
         Node::Terminal(name) => {
-            if terminals.contains_key(&name) {
-                debug_println!("Found {name} in cache!");
-                let term = terminals.get(&name).unwrap();
-                let term_clone = match term.1 {
-                    TerminalState::Stub => term.0.clone(),
-                    TerminalState::Created => term.0.borrow().deep_clone(),
-                };
-                debug_println!("linking back to {}", term_clone.borrow().short_id());
-                FSMNode::add_child_cycle_safe(cur_root, &term_clone);
-                debug_println!("after add:");
-                cur_root.borrow().dbg();
-                term_clone
-            } else {
-                debug_println!("Creating terminal {name}...");
-                let terminal =
-                    find_terminal(grammar, &name).expect("Terminal reference '{name}' not found!");
-                let term_root = FSMNode::new_null(None);
-                debug_println!("term_root: {}", term_root.borrow().short_id());
-                terminals.insert(
-                    name.to_string(),
-                    (FSMRc::clone(&term_root), TerminalState::Stub),
-                );
-                handle_node(grammar, terminal.rhs.clone(), &term_root, terminals);
-                debug_println!("Finish terminal");
-                debug_println!("young {}:", name);
-                term_root.borrow().dbg();
-                terminals.insert(name, (FSMRc::clone(&term_root), TerminalState::Created));
-                let ret = term_root.borrow().deep_clone();
-                FSMNode::add_child_cycle_safe(cur_root, &ret);
-                ret
-            }
+            let entry = rule_entry(grammar, &name, terminals, annotations);
+            let call = FSMNode::new_call(name, &entry);
+            debug_println!("calling {}", entry.borrow().short_id());
+            FSMNode::add_child_cycle_safe(cur_root, &call);
+            call
         }
         Node::Multiple(nodes) => {
             let mut cur_treenode = cur_root.clone();
             nodes.into_iter().for_each(|node| {
                 debug_println!("Multiple at {node:?}");
-                let tree_bit = handle_node(grammar, node, &cur_treenode, terminals);
+                let tree_bit = handle_node(grammar, node, &cur_treenode, terminals, annotations);
                 debug_println!("Multiple got back:");
                 tree_bit.borrow().dbg();
                 // NOTE: this will only work as long as the other node handlers nicely merge their
@@ -81,22 +158,22 @@ fn handle_node(
             cur_treenode
         }
         Node::RegexExt(node, RegexExtKind::Optional) | Node::Optional(node) => {
-            let tree_bit = handle_node(grammar, *node, cur_root, terminals);
+            let tree_bit = handle_node(grammar, *node, cur_root, terminals, annotations);
             let dummy = FSMNode::new_null(None);
             FSMNode::add_child_to_all_leaves(&tree_bit, &dummy);
             FSMNode::add_child_cycle_safe(cur_root, &dummy);
             tree_bit
         }
         Node::Symbol(n1, SymbolKind::Concatenation, n2) => {
-            let t1 = handle_node(grammar, *n1, cur_root, terminals);
-            let _ = handle_node(grammar, *n2, &t1, terminals);
+            let t1 = handle_node(grammar, *n1, cur_root, terminals, annotations);
+            let _ = handle_node(grammar, *n2, &t1, terminals, annotations);
             t1
         }
         Node::Symbol(n1, SymbolKind::Alternation, n2) => {
             let root = FSMNode::new_null(Some(cur_root)); // need this here otherwise minify will
             // crash?
-            let _ = handle_node(grammar, *n1, &root, terminals);
-            let _ = handle_node(grammar, *n2, &root, terminals);
+            let _ = handle_node(grammar, *n1, &root, terminals, annotations);
+            let _ = handle_node(grammar, *n2, &root, terminals, annotations);
             let child = FSMNode::new_null(None);
             debug_println!("Alternation dummy child: {}", child.borrow().short_id());
             FSMNode::add_child_to_all_leaves(&root, &child);
@@ -104,26 +181,38 @@ fn handle_node(
             root.borrow().dbg();
             root
         }
-        Node::Group(node) => handle_node(grammar, *node, cur_root, terminals),
+        Node::Group(node) => handle_node(grammar, *node, cur_root, terminals, annotations),
+        Node::RegexExt(node, RegexExtKind::Repeat0) => handle_node(
+            grammar,
+            Node::Repeat(node),
+            cur_root,
+            terminals,
+            annotations,
+        ),
+        Node::RegexExt(node, RegexExtKind::Repeat1) => {
+            // returning the subroot instead of a leaf matters here, Multiple would otherwise race
+            // from the Repeat's exit back into its loop
+            let once = handle_node(grammar, (*node).clone(), cur_root, terminals, annotations);
+            let leaf = once.borrow().race_to_leaf().unwrap_or(once.clone());
+            handle_node(grammar, Node::Repeat(node), &leaf, terminals, annotations);
+            once
+        }
         Node::Repeat(node) => {
             // need to guarantee this is a null so search_rec won't prematurely stop, e.g. when
             // cur_root is a Keyword
             let dummy_parent = FSMNode::new_null(Some(cur_root));
-            let subroot = handle_node(grammar, *node, &dummy_parent, terminals);
+            let subroot = handle_node(grammar, *node, &dummy_parent, terminals, annotations);
 
             let dummy = FSMNode::new_null(None);
             debug_println!("Repeat dummy child: {}", dummy.borrow().short_id());
-            FSMNode::add_child_to_all_leaves(&subroot, &dummy);
+            // the exit mustn't loop back itself, racing to a leaf from there would end up inside
+            // the loop once the Repeat is nested in another Multiple
+            FSMNode::add_children_to_all_leaves(&subroot, &[dummy.clone(), dummy_parent.clone()]);
             // must have the option to skip it entirely
             FSMNode::add_child_cycle_safe(cur_root, &dummy);
-
-            FSMNode::add_child_to_all_leaves(&subroot, &dummy_parent);
             dummy_parent
         }
-        _ => {
-            eprintln!("Unimplemented: {cur_node:?}");
-            todo!()
-        }
+        Node::Unknown => unreachable!("rejected by check_grammar"),
     }
 }
 
@@ -131,33 +220,672 @@ fn find_terminal<'a>(grammer: &'a Grammar, name: &'a str) -> Option<&'a Expressi
     grammer.expressions.iter().find(|expr| expr.lhs == name)
 }
 
-/// creates a graph from the provided ebnf
-/// Returns an error if the ebnf crate deems the input to be invalid
-pub fn create_graph_from_ebnf(ebnf: &str) -> Result<FSMRc<FSMLock<FSMNode>>, String> {
-    match ebnf::get_grammar(ebnf) {
-        Ok(mut grammar) => {
-            let root = FSMNode::new_null(None);
-            let root_node = grammar.expressions.first().ok_or("Empty BNF!")?.to_owned();
-            let mut terminals = HashMap::with_capacity(grammar.expressions.len());
-            handle_node(
-                &mut grammar,
-                Node::Terminal(root_node.lhs.to_owned()),
-                &root,
-                &mut terminals,
-            );
-            // sanity op, is_done() won't cancel preemptively
-            FSMNode::add_child_to_all_leaves(&root, &FSMNode::new_null(None));
-            FSMNode::minify(&root);
-            // UserdefCombos will want to know their Keyword children's first character to
-            // properly be able to finish
-            FSMNode::set_userdef_links(&root);
-            debug_println!("Total node cnt: {}", root.borrow().node_cnt());
-            // for (name, term) in terminals.iter() {
-            //     println!("Term {}", name);
-            //     term.0.borrow().dbg();
-            // }
-            Ok(root)
-        }
-        Err(err) => Err(err.to_string()),
+fn check_node(
+    grammar: &Grammar,
+    node: &Node,
+    rule: &str,
+    source_map: &SourceMap,
+    errors: &mut Vec<GrammarError>,
+) {
+    let err = match node {
+        Node::String(str) if annotation::split_tag(str).0.is_empty() => Some(GrammarError::new(
+            GrammarErrorKind::UnsupportedConstruct("empty string literal".to_string()),
+            Some(rule),
+            source_map.find_in_rule(rule, TokenKind::Str, ""),
+        )),
+        Node::String(str) => {
+            let literal = annotation::split_tag(str).0;
+            try_resolve_escape_sequences(literal).err().map(|c| {
+                GrammarError::new(
+                    GrammarErrorKind::InvalidEscape(c),
+                    Some(rule),
+                    source_map.find_in_rule(rule, TokenKind::Str, literal),
+                )
+            })
+        }
+        Node::RegexString(r) => Regex::new(r).err().map(|err| {
+            GrammarError::new(
+                GrammarErrorKind::InvalidRegex {
+                    regex: r.to_string(),
+                    // the regex crate's messages span multiple lines and repeat the regex
+                    reason: err
+                        .to_string()
+                        .lines()
+                        .last()
+                        .unwrap_or_default()
+                        .trim_start_matches("error: ")
+                        .to_string(),
+                },
+                Some(rule),
+                source_map.find_in_rule(rule, TokenKind::Regex, r),
+            )
+        }),
+        // resolved by exception::resolve_exceptions
+        node if source::is_exception_marker(node) => None,
+        Node::Terminal(name) if find_terminal(grammar, name).is_none() => Some(GrammarError::new(
+            GrammarErrorKind::UndefinedNonterminal(name.to_string()),
+            Some(rule),
+            source_map.find_in_rule(rule, TokenKind::Ident, name),
+        )),
+        Node::Terminal(_) => None,
+        Node::Multiple(nodes) => {
+            nodes
+                .iter()
+                .for_each(|node| check_node(grammar, node, rule, source_map, errors));
+            None
+        }
+        Node::RegexExt(node, _) | Node::Optional(node) | Node::Group(node) | Node::Repeat(node) => {
+            check_node(grammar, node, rule, source_map, errors);
+            None
+        }
+        Node::Symbol(n1, _, n2) => {
+            check_node(grammar, n1, rule, source_map, errors);
+            check_node(grammar, n2, rule, source_map, errors);
+            None
+        }
+        Node::Unknown => Some(GrammarError::new(
+            GrammarErrorKind::UnsupportedConstruct("unknown node".to_string()),
+            Some(rule),
+            source_map.rule_span(rule),
+        )),
+    };
+    if let Some(err) = err
+        && !errors.contains(&err)
+    {
+        errors.push(err);
+    }
+}
+
+/// walks the whole grammar once and collects everything handle_node would choke on
+fn check_grammar(grammar: &Grammar, source_map: &SourceMap) -> Vec<GrammarError> {
+    let mut errors = Vec::new();
+    if grammar.expressions.is_empty() {
+        errors.push(GrammarError::new(
+            GrammarErrorKind::EmptyGrammar,
+            None,
+            None,
+        ));
+    }
+    for expr in &grammar.expressions {
+        check_node(grammar, &expr.rhs, &expr.lhs, source_map, &mut errors);
+    }
+    errors
+}
+
+/// lowers `source` with the frontend picked in `options` and runs all grammar passes, collecting
+/// every problem found along the way
+fn parse_grammar(
+    source: &str,
+    options: &CompileOptions,
+    warnings: &mut Vec<GrammarWarning>,
+) -> Result<GrammarIr, Vec<GrammarError>> {
+    if source.trim().is_empty() {
+        return Err(vec![GrammarError::new(
+            GrammarErrorKind::EmptyGrammar,
+            None,
+            None,
+        )]);
+    }
+    if let Some(dialect) = options.dialect
+        && !dialects(source).iter().any(|known| known == dialect)
+    {
+        return Err(vec![GrammarError::new(
+            GrammarErrorKind::UnknownDialect(dialect.to_string()),
+            None,
+            None,
+        )]);
+    }
+    let frontend = options.frontend.unwrap_or(&Ebnf);
+    let mut errors = Vec::new();
+    let Some(mut ir) = frontend.lower(source, options.dialect, &mut errors, warnings) else {
+        debug_assert!(!errors.is_empty(), "{} gave up silently", frontend.name());
+        return Err(errors);
+    };
+    let source_map = SourceMap::new(source);
+    let grammar = &mut ir.grammar;
+    errors.extend(check_grammar(grammar, &source_map));
+    exception::resolve_exceptions(grammar, &source_map, &mut errors);
+    left_recursion::eliminate_left_recursion(grammar, &source_map, &mut errors, warnings);
+    if errors.is_empty() {
+        lexical::collapse_lexical_rules(grammar);
+        Ok(ir)
+    } else {
+        Err(errors)
+    }
+}
+
+/// builds the graph starting at the rule `entry`, which has to exist
+/// Rules found in `cache` are taken from there instead of being built again.
+fn build_graph(
+    grammar: &mut Grammar,
+    annotations: &Annotations,
+    entry: &str,
+    cache: Option<&mut RuleCache>,
+) -> FSMRc<FSMLock<FSMNode>> {
+    let root = FSMNode::new_null(None);
+    let mut terminals = Terminals::new(grammar.expressions.len(), cache);
+    handle_node(
+        grammar,
+        Node::Terminal(entry.to_owned()),
+        &root,
+        &mut terminals,
+        annotations,
+    );
+    // sanity op, is_done() won't cancel preemptively
+    FSMNode::add_child_to_all_leaves(&root, &FSMNode::new_null(None));
+    FSMNode::minify(&root);
+    FSMNode::resolve_call_conflicts(&root);
+    // UserdefCombos will want to know their Keyword children's first character to
+    // properly be able to finish
+    FSMNode::set_userdef_links(&root);
+    debug_println!("Total node cnt: {}", FSMNode::node_cnt(&root));
+    // for (name, term) in terminals.iter() {
+    //     println!("Term {}", name);
+    //     term.0.borrow().dbg();
+    // }
+    root
+}
+
+/// creates a graph from the provided ebnf, starting at its first rule
+/// Returns every problem found in the grammar if it can't be turned into a graph, see [report]
+/// for printing them
+pub fn create_graph_from_ebnf(ebnf: &str) -> Result<FSMRc<FSMLock<FSMNode>>, Vec<GrammarError>> {
+    create_entry_points_from_ebnf(ebnf, &[]).map(|entries| entries.default_root().clone())
+}
+
+/// creates one graph per rule in `entries`, the first one becoming the default entry
+/// If `entries` is empty, the first rule of the grammar is used, just like
+/// [create_graph_from_ebnf] does.
+pub fn create_entry_points_from_ebnf(
+    ebnf: &str,
+    entries: &[&str],
+) -> Result<EntryPoints, Vec<GrammarError>> {
+    compile_ebnf(ebnf, entries).map(|compiled| compiled.entries)
+}
+
+#[derive(Debug)]
+pub struct CompiledGrammar {
+    pub entries: EntryPoints,
+    /// changes made to the grammar while compiling it, see [report_warnings] for printing them
+    pub warnings: Vec<GrammarWarning>,
+}
+
+/// everything [compile_ebnf_with] can be told about how to compile a grammar
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompileOptions<'a> {
+    /// rules a cursor may start at, the first one being the default entry; the first rule of the
+    /// grammar if empty
+    pub entries: &'a [&'a str],
+    /// dialect whose `@if` sections are kept, only the parts shared by all dialects if None
+    pub dialect: Option<&'a str>,
+    /// syntax the grammar is written in, nightfury's own [Ebnf] if None
+    pub frontend: Option<&'a dyn GrammarFrontend>,
+}
+
+/// like [create_entry_points_from_ebnf], but also hands out the warnings
+pub fn compile_ebnf(ebnf: &str, entries: &[&str]) -> Result<CompiledGrammar, Vec<GrammarError>> {
+    compile_ebnf_with(
+        ebnf,
+        &CompileOptions {
+            entries,
+            ..Default::default()
+        },
+    )
+}
+
+pub fn compile_ebnf_with(
+    ebnf: &str,
+    options: &CompileOptions,
+) -> Result<CompiledGrammar, Vec<GrammarError>> {
+    let mut warnings = Vec::new();
+    let parsed = parse_grammar(ebnf, options, &mut warnings)?;
+    let entries = build_entries(parsed, options.entries, None)?;
+    Ok(CompiledGrammar { entries, warnings })
+}
+
+/// builds the graphs of all `entries`, see [CompileOptions::entries]
+fn build_entries(
+    parsed: GrammarIr,
+    entries: &[&str],
+    mut cache: Option<&mut RuleCache>,
+) -> Result<EntryPoints, Vec<GrammarError>> {
+    let GrammarIr {
+        mut grammar,
+        annotations,
+        layout,
+        indent,
+        ..
+    } = parsed;
+    let first_rule = grammar.expressions[0].lhs.clone();
+    let entries = if entries.is_empty() {
+        vec![first_rule.as_str()]
+    } else {
+        entries.to_vec()
+    };
+    let errors: Vec<GrammarError> = entries
+        .iter()
+        .filter(|entry| find_terminal(&grammar, entry).is_none())
+        .map(|entry| {
+            GrammarError::new(
+                GrammarErrorKind::UnknownEntryRule(entry.to_string()),
+                None,
+                None,
+            )
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    // every entry gets its own terminal cache, so minify can't trip over nodes shared between
+    // entries
+    let entries = EntryPoints::new(
+        entries
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.to_string(),
+                    build_graph(&mut grammar, &annotations, entry, cache.as_deref_mut()),
+                )
+            })
+            .collect(),
+    )
+    .with_layout(layout.map(|layout| Regex::new(&layout).expect("checked by extract_layout")))
+    .with_indent(indent);
+    let roots: Vec<&FSMRc<FSMLock<FSMNode>>> = entries
+        .names()
+        .map(|name| entries.get(Some(name)).expect("names are entries"))
+        .collect();
+    FSMNode::number(&roots);
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_undefined_nonterminal() {
+        let ebnf = "t1 ::= 'a' t2;\nt2 ::= 'b' t3;";
+        let errors = create_graph_from_ebnf(ebnf).unwrap_err();
+        assert_eq!(
+            vec![GrammarError::new(
+                GrammarErrorKind::UndefinedNonterminal("t3".to_string()),
+                Some("t2"),
+                Some(Span {
+                    line: 2,
+                    column: 12,
+                    len: 2
+                }),
+            )],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_errors_collected() {
+        let ebnf = r"
+        t1 ::= t2 | t4;
+        t2 ::= #'[a-z' 'b';
+        t3 ::= 'c' -;
+        ";
+        let errors = create_graph_from_ebnf(ebnf).unwrap_err();
+        assert_eq!(3, errors.len());
+        assert_eq!(
+            GrammarErrorKind::UndefinedNonterminal("t4".to_string()),
+            errors[0].kind
+        );
+        assert!(matches!(
+            &errors[1].kind,
+            GrammarErrorKind::InvalidRegex { regex, .. } if regex == "[a-z"
+        ));
+        assert_eq!(Some(3), errors[1].span.map(|s| s.line));
+        assert_eq!(Some("t3".to_string()), errors[2].rule);
+        assert!(matches!(
+            errors[2].kind,
+            GrammarErrorKind::UnsupportedConstruct(_)
+        ));
+    }
+
+    #[test]
+    fn test_empty_grammar() {
+        let errors = create_graph_from_ebnf("  \n").unwrap_err();
+        assert_eq!(GrammarErrorKind::EmptyGrammar, errors[0].kind);
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let errors = create_graph_from_ebnf("t1 ::= 'a';\nt2 ::= 'b'").unwrap_err();
+        assert_eq!(1, errors.len());
+        assert!(matches!(errors[0].kind, GrammarErrorKind::Syntax(_)));
+        assert_eq!(Some(2), errors[0].span.map(|s| s.line));
+    }
+
+    #[test]
+    fn test_left_recursion_rewritten() {
+        let ebnf = "expr ::= ( expr '+' term ) | term;\nterm ::= ( expr '*' ) | 'x';";
+        let warnings = compile_ebnf(ebnf, &[]).unwrap().warnings;
+        assert_eq!(
+            vec![
+                GrammarWarning::new(
+                    GrammarWarningKind::LeftRecursionRewritten,
+                    Some("expr"),
+                    Some(Span {
+                        line: 1,
+                        column: 1,
+                        len: 4
+                    }),
+                ),
+                GrammarWarning::new(
+                    GrammarWarningKind::LeftRecursionRewritten,
+                    Some("term"),
+                    Some(Span {
+                        line: 2,
+                        column: 1,
+                        len: 4
+                    }),
+                ),
+            ],
+            warnings
+        );
+        assert!(
+            compile_ebnf("t1 ::= 'a' t1;", &[])
+                .unwrap()
+                .warnings
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_left_recursion_unresolvable() {
+        let errors = create_graph_from_ebnf("t1 ::= t1 'a';").unwrap_err();
+        assert!(matches!(errors[0].kind, GrammarErrorKind::LeftRecursion(_)));
+        assert_eq!(Some("t1".to_string()), errors[0].rule);
+
+        // hidden behind a rule that may be skipped
+        let errors =
+            create_graph_from_ebnf("t1 ::= ( t2 t1 'a' ) | 'b';\nt2 ::= [ 'c' ];").unwrap_err();
+        assert_eq!(1, errors.len());
+        assert!(matches!(errors[0].kind, GrammarErrorKind::LeftRecursion(_)));
+    }
+
+    #[test]
+    fn test_collapse_lexical() {
+        let ebnf = r#"
+        t1 ::= number { ',' number } ';';
+        number ::= [ sign ] digit { digit };
+        sign ::= '+' | '-';
+        digit ::= #'[0-9]';
+        "#;
+        let grammar = parse_grammar(ebnf, &CompileOptions::default(), &mut Vec::new())
+            .unwrap()
+            .grammar;
+        let rhs = |name| &find_terminal(&grammar, name).unwrap().rhs;
+        let Node::RegexString(number) = rhs("number") else {
+            panic!("number wasn't collapsed: {:?}", rhs("number"));
+        };
+        let number = Regex::new(number).unwrap();
+        for prefix in ["", "-", "+1", "42"] {
+            assert!(number.is_match(prefix), "{prefix}");
+        }
+        for invalid in ["1-", "--", "a"] {
+            assert!(!number.is_match(invalid), "{invalid}");
+        }
+        // entry rules, choices between characters and single regexes stay as they are
+        assert!(matches!(rhs("t1"), Node::Multiple(_)));
+        assert!(matches!(rhs("sign"), Node::Symbol(..)));
+        assert!(matches!(rhs("digit"), Node::RegexString(r) if r == "[0-9]"));
+    }
+
+    #[test]
+    fn test_collapse_lexical_tokens() {
+        // column_name is a whole token, a list of them is structure and stays as it is
+        let ebnf = r#"
+        select ::= 'SELECT' select_list ';';
+        select_list ::= "*" | ( column_name { "," column_name } );
+        column_name ::= identifier;
+        identifier ::= #'[A-Za-z][0-9A-Za-z_]*';
+        "#;
+        let grammar = parse_grammar(ebnf, &CompileOptions::default(), &mut Vec::new())
+            .unwrap()
+            .grammar;
+        let rhs = |name| &find_terminal(&grammar, name).unwrap().rhs;
+        assert!(matches!(rhs("select_list"), Node::Symbol(..)));
+        // just another name for identifier, it's still collapsed to accept unfinished input
+        assert!(matches!(rhs("column_name"), Node::RegexString(_)));
+    }
+
+    #[test]
+    fn test_annotations() {
+        let ebnf =
+            r#"t1 ::= 'insert' @short("ii") @noexpand '(' @closing(")") 'x' '`' @inject("sql");"#;
+        let GrammarIr {
+            grammar,
+            annotations,
+            ..
+        } = parse_grammar(ebnf, &CompileOptions::default(), &mut Vec::new()).unwrap();
+        let Node::Multiple(nodes) = &grammar.expressions[0].rhs else {
+            panic!("expected a sequence, got {:?}", grammar.expressions[0].rhs);
+        };
+        let keywords: Vec<_> = nodes
+            .iter()
+            .map(|node| match node {
+                Node::String(str) => annotations.keyword(str),
+                node => panic!("expected a literal, got {node:?}"),
+            })
+            .collect();
+        assert_eq!("insert", keywords[0].expanded);
+        assert_eq!("ii", keywords[0].short);
+        assert!(keywords[0].fixed_short && keywords[0].no_autoexpand);
+        assert_eq!(Some(")".to_string()), keywords[1].closing_token);
+        assert_eq!(crate::fsm::Keyword::new("x".to_string(), None), keywords[2]);
+        assert_eq!(Some("sql".to_string()), keywords[3].inject);
+    }
+
+    #[test]
+    fn test_invalid_annotations() {
+        let ebnf = "t1 ::= 'a' @short 'b' @bogus;\nt2 ::= t1 @noexpand;";
+        let errors = create_graph_from_ebnf(ebnf).unwrap_err();
+        let kinds: Vec<_> = errors.iter().map(|err| err.kind.to_string()).collect();
+        assert_eq!(
+            vec![
+                "invalid annotation: `@short` takes a string argument",
+                "invalid annotation: unknown annotation `@bogus`",
+                "invalid annotation: annotations have to follow a string literal",
+            ],
+            kinds
+        );
+        assert_eq!(Some("t2".to_string()), errors[2].rule);
+        assert_eq!(
+            Some(Span {
+                line: 2,
+                column: 11,
+                len: 9
+            }),
+            errors[2].span
+        );
+    }
+
+    #[test]
+    fn test_escapes() {
+        let ebnf = r#"t1 ::= 'a\b' 'b' @separator("\q") '{' @block;"#;
+        let errors = create_graph_from_ebnf(ebnf).unwrap_err();
+        let kinds: Vec<_> = errors.iter().map(|err| err.kind.to_string()).collect();
+        assert_eq!(
+            vec![
+                "invalid annotation: unknown escape sequence `\\q` in `@separator`",
+                "invalid annotation: `@block` needs a `@closing` token to know where the block ends",
+                "unknown escape sequence `\\b`",
+            ],
+            kinds
+        );
+        assert_eq!(
+            Some(Span {
+                line: 1,
+                column: 8,
+                len: 5
+            }),
+            errors[2].span
+        );
+
+        let ebnf = r#"t1 ::= 'a\n' @short("\t");"#;
+        let GrammarIr {
+            grammar,
+            annotations,
+            ..
+        } = parse_grammar(ebnf, &CompileOptions::default(), &mut Vec::new()).unwrap();
+        let Node::String(literal) = &grammar.expressions[0].rhs else {
+            panic!("expected a literal, got {:?}", grammar.expressions[0].rhs);
+        };
+        let keyword = annotations.keyword(literal);
+        assert_eq!(
+            ("\t", "a\n"),
+            (keyword.short.as_str(), keyword.expanded.as_str())
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let ebnf = r"
+        t1 ::= list<'a'> pair<list<'b'>, 'c'> list<'a'>;
+        list<X> ::= X { ',' X };
+        pair<A, B> ::= A '=' B;
+        ";
+        let grammar = parse_grammar(ebnf, &CompileOptions::default(), &mut Vec::new())
+            .unwrap()
+            .grammar;
+        let names: Vec<_> = grammar
+            .expressions
+            .iter()
+            .map(|expr| expr.lhs.as_str())
+            .collect();
+        assert_eq!(
+            vec!["t1", "list<'a'>", "list<'b'>", "pair<list<'b'>, 'c'>"],
+            names
+        );
+        assert_eq!(
+            r#"Multiple([Terminal("list<'b'>"), String("="), String("c")])"#,
+            format!("{:?}", grammar.expressions[3].rhs)
+        );
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let ebnf = "t1 ::= list<'a', 'b'> list t2<'c'>;\nt2 ::= 'x';\nlist<X> ::= X { ',' Y };";
+        let errors = create_graph_from_ebnf(ebnf).unwrap_err();
+        let kinds: Vec<_> = errors.iter().map(|err| err.kind.to_string()).collect();
+        assert_eq!(
+            vec![
+                "`Y` is neither a parameter nor a rule",
+                "`list` takes 1 argument but 2 were given",
+                "`list` takes 1 argument but 0 were given",
+                "`t2` takes 0 arguments but 1 was given",
+            ],
+            kinds
+        );
+        assert_eq!(Some("list".to_string()), errors[0].rule);
+        assert_eq!(
+            Some(Span {
+                line: 3,
+                column: 21,
+                len: 1
+            }),
+            errors[0].span
+        );
+    }
+
+    #[test]
+    fn test_dialects() {
+        let ebnf = "t1 ::= 'a' @if(pg) 'b' @else 'c' @end;\n@if(lite, pg)\nt2 ::= 'd';\n@end";
+        assert_eq!(vec!["pg", "lite"], dialects(ebnf));
+        let select = |dialect| {
+            let mut errors = Vec::new();
+            let text = dialect::select_dialect(ebnf, dialect, &SourceMap::new(ebnf), &mut errors);
+            assert!(errors.is_empty());
+            text.split_whitespace().collect::<Vec<_>>().join(" ")
+        };
+        assert_eq!("t1 ::= 'a' 'b' ; t2 ::= 'd';", select(Some("pg")));
+        assert_eq!("t1 ::= 'a' 'c' ; t2 ::= 'd';", select(Some("lite")));
+        assert_eq!("t1 ::= 'a' 'c' ;", select(None));
+
+        let errors = compile_ebnf_with(
+            ebnf,
+            &CompileOptions {
+                dialect: Some("mysql"),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            vec![GrammarErrorKind::UnknownDialect("mysql".to_string())],
+            errors.into_iter().map(|err| err.kind).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_dialect_errors() {
+        let ebnf = "t1 ::= 'a' @end @if(pg 'b';\nt2 ::= @if(pg) 'c' @else 'd' @else;";
+        let errors = create_graph_from_ebnf(ebnf).unwrap_err();
+        let kinds: Vec<_> = errors.iter().map(|err| err.kind.to_string()).collect();
+        assert_eq!(
+            vec![
+                "invalid directive: `@end` without `@if`",
+                "invalid directive: expected `@if(dialect, ...)`",
+                "invalid directive: `@if` already has an `@else`",
+                "invalid directive: `@if` is missing its `@end`",
+            ],
+            kinds
+        );
+        assert_eq!(Some("t1".to_string()), errors[0].rule);
+        assert_eq!(
+            Some(Span {
+                line: 1,
+                column: 12,
+                len: 4
+            }),
+            errors[0].span
+        );
+    }
+
+    #[test]
+    fn test_layout_errors() {
+        let ebnf = "@layout ::= ' ';\n@separator ::= ' ';\n@separator ::= '';\n@spacing ::= ' ';\n@case ::= 'title';\nt1 ::= 'a';";
+        let errors = create_graph_from_ebnf(ebnf).unwrap_err();
+        let kinds: Vec<_> = errors.iter().map(|err| err.kind.to_string()).collect();
+        assert_eq!(
+            vec![
+                "invalid layout rule: `@layout` has to be a single regex, e.g. #'[ \\t\\n]*'",
+                "invalid layout rule: `@separator` is defined more than once",
+                "invalid layout rule: unknown layout rule `@spacing`",
+                "invalid layout rule: `@case` has to be one of 'upper', 'lower', 'keep', 'typed'",
+            ],
+            kinds
+        );
+        assert_eq!(Some("@separator".to_string()), errors[1].rule);
+        assert_eq!(
+            Some(Span {
+                line: 3,
+                column: 1,
+                len: 18
+            }),
+            errors[1].span
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let ebnf = "t1 ::= 'a' t2;";
+        let errors = create_graph_from_ebnf(ebnf).unwrap_err();
+        assert_eq!(
+            "error: undefined nonterminal `t2`
+ --> test.ebnf:1:12
+  |
+1 | t1 ::= 'a' t2;
+  |            ^^
+  = in rule `t1`
+
+error: could not compile grammar due to 1 previous error
+",
+            report(&errors, ebnf, "test.ebnf")
+        );
     }
 }
@@ -0,0 +1,528 @@
+//! ABNF as defined by RFC 5234 (and RFC 7405's `%s` and `%i`), the syntax most protocol specs use
+//!
+//! ```text
+//! scheme      = ALPHA *( ALPHA / DIGIT / "+" / "-" / "." )
+//! dec-octet   = DIGIT / %x31-39 DIGIT / "1" 2DIGIT
+//! request     = method SP uri CRLF
+//! method      =/ "PATCH"   ; incremental alternative
+//! ```
+//!
+//! Rule names are case-insensitive in ABNF, they're lowercased and get their dashes replaced, so
+//! `dec-octet` becomes the rule `dec_octet`. The core rules of RFC 5234's appendix B (`ALPHA`,
+//! `DIGIT`, `CRLF`, ...) turn into regexes unless the grammar defines them itself. Quoted strings
+//! are case-insensitive as well, a grammar using them makes all of its keywords case-insensitive,
+//! just like `@case ::= 'keep';` would. Prose values (`<...>`) can't be completed and are
+//! reported as unsupported.
+use ebnf::{Node, RegexExtKind};
+
+use super::error::{GrammarError, GrammarErrorKind, GrammarWarning, GrammarWarningKind};
+use super::syntax::{GrammarFrontend, GrammarIr, Scanner, alternation, literal, sequence};
+use crate::fsm::Casing;
+
+/// the largest number of optional repetitions spelled out, e.g. for `1*4DIGIT`
+const MAX_OPTIONAL_REPETITIONS: usize = 8;
+
+/// RFC 5234 appendix B.1
+const CORE_RULES: [(&str, &str); 16] = [
+    ("alpha", "[A-Za-z]"),
+    ("bit", "[01]"),
+    ("char", r"[\x{01}-\x{7F}]"),
+    ("cr", r"\r"),
+    ("crlf", r"\r\n"),
+    ("ctl", r"[\x{00}-\x{1F}\x{7F}]"),
+    ("digit", "[0-9]"),
+    ("dquote", "\""),
+    ("hexdig", "[0-9A-Fa-f]"),
+    ("htab", r"\t"),
+    ("lf", r"\n"),
+    ("lwsp", r"(?:[ \t]|\r\n[ \t])*"),
+    ("octet", r"[\x{00}-\x{FF}]"),
+    ("sp", " "),
+    ("vchar", r"[\x{21}-\x{7E}]"),
+    ("wsp", r"[ \t]"),
+];
+
+#[derive(Debug)]
+pub struct Abnf;
+
+impl GrammarFrontend for Abnf {
+    fn name(&self) -> &'static str {
+        "abnf"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["abnf"]
+    }
+
+    fn lower(
+        &self,
+        source: &str,
+        _dialect: Option<&str>,
+        errors: &mut Vec<GrammarError>,
+        warnings: &mut Vec<GrammarWarning>,
+    ) -> Option<GrammarIr> {
+        let errors_before = errors.len();
+        let mut parser = Parser {
+            scanner: Scanner::new(source, errors),
+            warnings,
+            rules: Vec::new(),
+            referenced: Vec::new(),
+            case_insensitive: false,
+        };
+        parser.rules();
+        if parser.scanner.errors.len() > errors_before {
+            return None;
+        }
+        let mut rules = parser.rules;
+        for (name, regex) in CORE_RULES {
+            let defined = rules.iter().any(|(rule, _)| rule == name);
+            if !defined && parser.referenced.iter().any(|rule| rule == name) {
+                rules.push((name.to_string(), Node::RegexString(regex.to_string())));
+            }
+        }
+        let mut ir = GrammarIr::new(rules);
+        if parser.case_insensitive {
+            ir.annotations.case = Some(Casing::Keep);
+        }
+        Some(ir)
+    }
+}
+
+fn is_rule_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-'
+}
+
+/// rule names are case-insensitive and may only consist of letters, digits and `_` in nightfury
+fn rule_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace('-', "_")
+}
+
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+    warnings: &'a mut Vec<GrammarWarning>,
+    rules: Vec<(String, Node)>,
+    /// every rule name used, to know which core rules are needed
+    referenced: Vec<String>,
+    /// set once a case-insensitive string containing letters shows up
+    case_insensitive: bool,
+}
+
+impl Parser<'_> {
+    /// skips whitespace and comments inside of a rule, which continues on the next line as long as
+    /// that one is indented
+    fn skip_trivia(&mut self) {
+        loop {
+            self.scanner.take_while(|c| matches!(c, ' ' | '\t' | '\r'));
+            match self.scanner.peek() {
+                Some(';') => {
+                    self.scanner.take_while(|c| c != '\n');
+                }
+                Some('\n') => {
+                    let next_line = &self.scanner.rest()[1..];
+                    if !next_line.starts_with([' ', '\t', '\r', '\n', ';']) {
+                        return;
+                    }
+                    self.scanner.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// skips everything up to the start of the next rule
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.scanner.take_while(char::is_whitespace);
+            if self.scanner.peek() != Some(';') {
+                return;
+            }
+            self.scanner.take_while(|c| c != '\n');
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_trivia();
+        self.scanner.peek()
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        self.skip_trivia();
+        self.scanner.eat(text)
+    }
+
+    fn at_rule_end(&mut self) -> bool {
+        matches!(self.peek(), None | Some('\n'))
+    }
+
+    fn rules(&mut self) {
+        loop {
+            self.skip_blank_lines();
+            if self.scanner.at_end() {
+                return;
+            }
+            if self.rule().is_none() {
+                // skip to the next line starting with a rule name, so every broken rule gets
+                // reported at once
+                loop {
+                    self.scanner.take_while(|c| c != '\n');
+                    self.scanner.bump();
+                    if self.scanner.peek().is_none_or(|c| c.is_ascii_alphabetic()) {
+                        break;
+                    }
+                }
+            }
+            self.scanner.rule = None;
+        }
+    }
+
+    fn rule(&mut self) -> Option<()> {
+        if !self.scanner.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            self.scanner.unexpected("a rule name");
+            return None;
+        }
+        let start = self.scanner.pos;
+        let name = rule_name(self.scanner.take_while(is_rule_name_char));
+        self.scanner.rule = Some(name.clone());
+        let incremental = self.eat("=/");
+        if !incremental && !self.eat("=") {
+            self.scanner.unexpected("`=` or `=/`");
+            return None;
+        }
+        let rhs = self.alternation()?;
+        if !self.at_rule_end() {
+            self.scanner.unexpected("the end of the rule");
+            return None;
+        }
+        if !incremental {
+            self.rules.push((name, rhs));
+            return Some(());
+        }
+        let Some((_, alts)) = self.rules.iter_mut().find(|(rule, _)| *rule == name) else {
+            let len = self.scanner.source[start..].find('=').unwrap_or(0);
+            self.scanner.error_at(
+                start,
+                len,
+                "`=/` adds alternatives to a rule, which has to be defined first",
+            );
+            return None;
+        };
+        let previous = std::mem::replace(alts, Node::Multiple(Vec::new()));
+        *alts = alternation(vec![previous, rhs]);
+        Some(())
+    }
+
+    fn alternation(&mut self) -> Option<Node> {
+        let mut alts = vec![self.concatenation()?];
+        while self.eat("/") {
+            alts.push(self.concatenation()?);
+        }
+        Some(alternation(alts))
+    }
+
+    fn concatenation(&mut self) -> Option<Node> {
+        let mut items = vec![self.repetition()?];
+        while !self.at_rule_end() && !matches!(self.peek(), Some('/' | ')' | ']')) {
+            items.push(self.repetition()?);
+        }
+        Some(sequence(items))
+    }
+
+    /// an element, optionally preceded by `n`, `*`, `n*`, `*m` or `n*m`
+    fn repetition(&mut self) -> Option<Node> {
+        self.skip_trivia();
+        let start = self.scanner.pos;
+        let min = self.scanner.take_while(|c| c.is_ascii_digit());
+        let star = self.scanner.eat("*");
+        let max = if star {
+            self.scanner.take_while(|c| c.is_ascii_digit())
+        } else {
+            ""
+        };
+        let len = self.scanner.pos - start;
+        let element = self.element()?;
+        if min.is_empty() && !star {
+            return Some(element);
+        }
+        let (Ok(min), Ok(max)) = (
+            if min.is_empty() { Ok(0) } else { min.parse() },
+            match max.is_empty() {
+                true if star => Ok(None),
+                true => min.parse().map(Some),
+                false => max.parse().map(Some),
+            },
+        ) else {
+            self.scanner
+                .error_at(start, len, "repetition count out of range");
+            return None;
+        };
+        if max.is_some_and(|max| max < min) {
+            self.scanner.error_at(
+                start,
+                len,
+                "a repetition can't allow fewer occurrences than it requires",
+            );
+            return None;
+        }
+        let max = match max {
+            Some(max) if max - min > MAX_OPTIONAL_REPETITIONS => {
+                let span = self.scanner.span(start, len);
+                self.warnings.push(GrammarWarning::new(
+                    GrammarWarningKind::RepetitionUnbounded { max },
+                    self.scanner.rule.as_deref(),
+                    Some(span),
+                ));
+                None
+            }
+            max => max,
+        };
+        Some(repeat(element, min, max))
+    }
+
+    fn element(&mut self) -> Option<Node> {
+        let Some(c) = self.peek() else {
+            self.scanner.unexpected("an element");
+            return None;
+        };
+        match c {
+            '(' => {
+                self.scanner.bump();
+                let inner = self.alternation()?;
+                if !self.eat(")") {
+                    self.scanner.unexpected("`)`");
+                    return None;
+                }
+                Some(Node::Group(Box::new(inner)))
+            }
+            '[' => {
+                self.scanner.bump();
+                let inner = self.alternation()?;
+                if !self.eat("]") {
+                    self.scanner.unexpected("`]`");
+                    return None;
+                }
+                Some(Node::Optional(Box::new(inner)))
+            }
+            '"' => self.char_val(true),
+            '%' => self.num_val(),
+            '<' => {
+                let start = self.scanner.pos;
+                let prose = self.scanner.quoted('>')?;
+                self.scanner.error_at_kind(
+                    GrammarErrorKind::UnsupportedConstruct(format!("prose value `<{prose}>`")),
+                    start,
+                    prose.len() + 2,
+                );
+                None
+            }
+            c if c.is_ascii_alphabetic() => {
+                let name = rule_name(self.scanner.take_while(is_rule_name_char));
+                self.referenced.push(name.clone());
+                Some(Node::Terminal(name))
+            }
+            _ => {
+                self.scanner.unexpected("an element");
+                None
+            }
+        }
+    }
+
+    /// `"text"`, the empty string matching nothing at all
+    fn char_val(&mut self, case_insensitive: bool) -> Option<Node> {
+        let text = self.scanner.quoted('"')?;
+        if text.is_empty() {
+            return Some(Node::Multiple(Vec::new()));
+        }
+        if case_insensitive && text.chars().any(char::is_alphabetic) {
+            self.case_insensitive = true;
+        }
+        Some(literal(text))
+    }
+
+    /// `%x41`, `%d13.10`, `%x30-39` as well as `%s"text"` and `%i"text"`
+    fn num_val(&mut self) -> Option<Node> {
+        let start = self.scanner.pos;
+        self.scanner.bump();
+        let radix = match self.scanner.bump() {
+            Some('s' | 'S') if self.scanner.peek() == Some('"') => return self.char_val(false),
+            Some('i' | 'I') if self.scanner.peek() == Some('"') => return self.char_val(true),
+            Some('x' | 'X') => 16,
+            Some('d' | 'D') => 10,
+            Some('b' | 'B') => 2,
+            _ => {
+                self.scanner
+                    .error_at(start, 2, "expected `%x`, `%d`, `%b`, `%s\"` or `%i\"`");
+                return None;
+            }
+        };
+        let mut chars = vec![self.num_char(radix)?];
+        if self.scanner.eat("-") {
+            let last = self.num_char(radix)?;
+            if last < chars[0] {
+                self.scanner.error_at(
+                    start,
+                    self.scanner.pos - start,
+                    "the range ends before it starts",
+                );
+                return None;
+            }
+            return Some(Node::RegexString(format!(
+                "[\\x{{{:X}}}-\\x{{{:X}}}]",
+                chars[0] as u32, last as u32
+            )));
+        }
+        while self.scanner.eat(".") {
+            chars.push(self.num_char(radix)?);
+        }
+        Some(literal(&chars.into_iter().collect::<String>()))
+    }
+
+    fn num_char(&mut self, radix: u32) -> Option<char> {
+        let start = self.scanner.pos;
+        let digits = self.scanner.take_while(|c| c.is_digit(radix));
+        let c = u32::from_str_radix(digits, radix)
+            .ok()
+            .and_then(char::from_u32);
+        if c.is_none() {
+            self.scanner.error_at(
+                start,
+                digits.len(),
+                "expected the value of a unicode scalar value",
+            );
+        }
+        c
+    }
+}
+
+/// `element` at least `min` and at most `max` times
+fn repeat(element: Node, min: usize, max: Option<usize>) -> Node {
+    let mut items = vec![element.clone(); min];
+    match max {
+        None if min == 0 => items.push(Node::Repeat(Box::new(element))),
+        None => {
+            items.pop();
+            items.push(Node::RegexExt(Box::new(element), RegexExtKind::Repeat1));
+        }
+        Some(max) if max > min => {
+            let mut optional = Node::Optional(Box::new(element.clone()));
+            for _ in min + 1..max {
+                optional =
+                    Node::Optional(Box::new(Node::Multiple(vec![element.clone(), optional])));
+            }
+            items.push(optional);
+        }
+        Some(_) => {}
+    }
+    sequence(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FSMCursor;
+    use crate::frontend::{CompileOptions, compile_ebnf_with};
+
+    fn lower(source: &str) -> Result<Vec<(String, String)>, Vec<GrammarError>> {
+        let mut errors = Vec::new();
+        match Abnf.lower(source, None, &mut errors, &mut Vec::new()) {
+            Some(ir) => Ok(ir
+                .grammar
+                .expressions
+                .into_iter()
+                .map(|expr| (expr.lhs, format!("{:?}", expr.rhs)))
+                .collect()),
+            None => Err(errors),
+        }
+    }
+
+    #[test]
+    fn test_lower() {
+        let rules = lower(
+            "; RFC 3986\r\n\
+             Dec-Octet = DIGIT / %x31-39 DIGIT ; 10-99\r\n\
+             \x20         / \"1\" 2DIGIT\r\n\
+             \r\n\
+             h16 = 1*4HEXDIG\r\n\
+             ls = %x0D.0A / %s\"ab\"\r\n\
+             dec-octet =/ 0*1\"25\"\r\n",
+        )
+        .unwrap();
+        let names: Vec<&str> = rules.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(vec!["dec_octet", "h16", "ls", "digit", "hexdig"], names);
+        assert_eq!(
+            r#"Symbol(Symbol(Terminal("digit"), Alternation, Symbol(Multiple([RegexString("[\\x{31}-\\x{39}]"), Terminal("digit")]), Alternation, Multiple([String("1"), Multiple([Terminal("digit"), Terminal("digit")])]))), Alternation, Optional(String("25")))"#,
+            rules[0].1
+        );
+        assert_eq!(
+            r#"Multiple([Terminal("hexdig"), Optional(Multiple([Terminal("hexdig"), Optional(Multiple([Terminal("hexdig"), Optional(Terminal("hexdig"))]))]))])"#,
+            rules[1].1
+        );
+        assert_eq!(
+            r#"Symbol(String("\\r\\n"), Alternation, String("ab"))"#,
+            rules[2].1
+        );
+        assert_eq!(r#"RegexString("[0-9]")"#, rules[3].1);
+    }
+
+    #[test]
+    fn test_repetition() {
+        let element = || Node::Terminal("x".to_string());
+        let repeat = |min, max| format!("{:?}", repeat(element(), min, max));
+        assert_eq!(r#"Repeat(Terminal("x"))"#, repeat(0, None));
+        assert_eq!(
+            r#"Multiple([Terminal("x"), RegexExt(Terminal("x"), Repeat1)])"#,
+            repeat(2, None)
+        );
+        assert_eq!(r#"Multiple([])"#, repeat(0, Some(0)));
+        assert_eq!(r#"Optional(Terminal("x"))"#, repeat(0, Some(1)));
+
+        let mut warnings = Vec::new();
+        Abnf.lower("a = 1*20\"x\"\n", None, &mut Vec::new(), &mut warnings)
+            .unwrap();
+        assert_eq!(
+            GrammarWarningKind::RepetitionUnbounded { max: 20 },
+            warnings[0].kind
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = lower("a = \"x\" )\nb = <prose>\nc =/ \"y\"\nd = 3*2\"z\"").unwrap_err();
+        assert_eq!(4, errors.len());
+        assert_eq!(Some("a"), errors[0].rule.as_deref());
+        assert!(matches!(
+            errors[1].kind,
+            GrammarErrorKind::UnsupportedConstruct(_)
+        ));
+        assert_eq!(2, errors[1].span.as_ref().unwrap().line);
+        assert_eq!(3, errors[2].span.as_ref().unwrap().line);
+        assert_eq!(4, errors[3].span.as_ref().unwrap().line);
+        assert!(lower("a = %x110000").is_err());
+        assert!(lower("a = %x39-30").is_err());
+    }
+
+    #[test]
+    fn test_compile() {
+        let compiled = compile_ebnf_with(
+            "request = method SP \"/\" CRLF\nmethod = %s\"GET\" / %s\"POST\"\n",
+            &CompileOptions {
+                frontend: Some(&Abnf),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut cursor = FSMCursor::new(compiled.entries.default_root());
+        assert_eq!("GET", cursor.advance('G').unwrap());
+
+        // quoted strings are case-insensitive
+        let compiled = compile_ebnf_with(
+            "method = \"GET\" / \"POST\"\n",
+            &CompileOptions {
+                frontend: Some(&Abnf),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut cursor = FSMCursor::new(compiled.entries.default_root());
+        assert_eq!("POST", cursor.advance('p').unwrap());
+    }
+}
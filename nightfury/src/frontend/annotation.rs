@@ -0,0 +1,256 @@
+//! keyword annotations like `'INSERT INTO' @short("ii")`
+//!
+//! Annotations follow a string literal and control the Keyword it turns into:
+//! - `@short("..")` fixes the keyword's short, conflict resolution won't touch it
+//! - `@noexpand` makes the user type the keyword out instead of expanding it from its short
+//! - `@closing("..")` pairs the keyword with a closing token, e.g. `'(' @closing(")")`
+//! - `@separator("..")` is inserted after the keyword instead of the grammar's separator, see
+//!   [super::layout]
+//! - `@block` indents everything between the keyword and its `@closing` token one level deeper
+//! - `@snippet` expands the keyword together with the fixed shape following it, user-defined
+//!   tokens in it become tab stops
+//! - `@inject("lang")` hands whatever follows the keyword to the grammar registered as `lang`, up
+//!   to the token the host grammar expects next; `"lang;entry"` picks an entry rule of it
+//!
+//! The ebnf crate doesn't know about any of this, so annotations are cut out of the source
+//! before parsing. The literal they belong to gets tagged with the index of its annotations, the
+//! tag travels through all grammar passes as part of the literal until handle_node builds the
+//! keyword.
+use super::error::{GrammarError, GrammarErrorKind};
+use super::source::{SourceMap, Token, TokenKind, blank, tokenize};
+use crate::esc_seq::{resolve_escape_sequences, try_resolve_escape_sequences};
+use crate::fsm::{Casing, Keyword};
+
+/// separates a literal from the index of its annotations
+const ANNOTATION_MARKER: char = '\u{1}';
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct KeywordAnnotation {
+    short: Option<String>,
+    no_autoexpand: bool,
+    closing_token: Option<String>,
+    separator: Option<String>,
+    block: bool,
+    snippet: bool,
+    inject: Option<String>,
+}
+
+/// the annotations found in a grammar, indexed by the tags left in its literals
+#[derive(Debug, Default)]
+pub(crate) struct Annotations {
+    keywords: Vec<KeywordAnnotation>,
+    /// the grammar's `@separator`, used for keywords without one of their own
+    pub separator: String,
+    /// the grammar's `@case`
+    pub case: Option<Casing>,
+}
+
+impl Annotations {
+    /// builds the keyword for `literal`, which may carry an annotation tag
+    /// Escape sequences have been checked by check_grammar and extract_annotations.
+    pub fn keyword(&self, literal: &str) -> Keyword {
+        let (expanded, idx) = split_tag(literal);
+        let expanded = resolve_escape_sequences(expanded);
+        let mut keyword = Keyword::new(expanded.clone(), None);
+        // punctuation usually goes right next to whatever follows it
+        if expanded.ends_with(|c: char| c.is_alphanumeric() || c == '_') {
+            keyword.separator = self.separator.clone();
+        }
+        if let Some(annotation) = idx.and_then(|idx| self.keywords.get(idx)) {
+            if let Some(short) = &annotation.short {
+                keyword.short = resolve_escape_sequences(short);
+                keyword.fixed_short = true;
+            }
+            keyword.no_autoexpand = annotation.no_autoexpand;
+            keyword.block = annotation.block;
+            keyword.snippet = annotation.snippet;
+            keyword.inject = annotation.inject.clone();
+            keyword.closing_token = annotation
+                .closing_token
+                .as_deref()
+                .map(resolve_escape_sequences);
+            if let Some(separator) = &annotation.separator {
+                keyword.separator = resolve_escape_sequences(separator);
+            }
+        }
+        keyword.casing = self.case;
+        keyword.short = keyword.fold(&keyword.short);
+        keyword
+    }
+}
+
+/// splits an annotation tag off a literal
+pub(crate) fn split_tag(literal: &str) -> (&str, Option<usize>) {
+    match literal.split_once(ANNOTATION_MARKER) {
+        Some((literal, idx)) => (literal, idx.parse().ok()),
+        None => (literal, None),
+    }
+}
+
+/// grammar source with all annotations cut out, offsets are the same as in the original
+pub(crate) struct Annotated {
+    pub text: String,
+    pub annotations: Annotations,
+}
+
+struct Annotation<'a> {
+    name: &'a str,
+    arg: Option<&'a str>,
+}
+
+/// parses the annotation starting at `tokens[0]`, returns the number of tokens it took up
+fn parse_annotation<'a>(tokens: &[Token<'a>]) -> (Result<Annotation<'a>, String>, usize) {
+    let at = tokens[0];
+    let Some(name) = tokens
+        .get(1)
+        .filter(|name| name.kind == TokenKind::Ident && name.offset == at.end())
+    else {
+        return (Err("expected an annotation name after `@`".to_string()), 1);
+    };
+    let annotation = |arg| Annotation {
+        name: name.text,
+        arg,
+    };
+    match tokens.get(2..5) {
+        Some([open, arg, close])
+            if open.is_punct("(") && arg.kind == TokenKind::Str && close.is_punct(")") =>
+        {
+            (Ok(annotation(Some(arg.content()))), 5)
+        }
+        _ if tokens.get(2).is_some_and(|open| open.is_punct("(")) => (
+            Err(format!(
+                "expected a single string argument for `@{}`",
+                name.text
+            )),
+            3,
+        ),
+        _ => (Ok(annotation(None)), 2),
+    }
+}
+
+fn apply(annotation: &Annotation, to: &mut KeywordAnnotation) -> Result<(), String> {
+    let name = annotation.name;
+    if let Some(Err(c)) = annotation.arg.map(try_resolve_escape_sequences) {
+        return Err(format!("unknown escape sequence `\\{c}` in `@{name}`"));
+    }
+    match (name, annotation.arg) {
+        ("short" | "closing" | "inject", None) => Err(format!("`@{name}` takes a string argument")),
+        ("short" | "closing" | "inject", Some("")) => {
+            Err(format!("the argument of `@{name}` can't be empty"))
+        }
+        ("short", Some(short)) => {
+            to.short = Some(short.to_string());
+            Ok(())
+        }
+        ("closing", Some(closing)) => {
+            to.closing_token = Some(closing.to_string());
+            Ok(())
+        }
+        ("inject", Some(language)) => {
+            to.inject = Some(language.to_string());
+            Ok(())
+        }
+        ("separator", None) => Err(format!("`@{name}` takes a string argument")),
+        ("separator", Some(separator)) => {
+            to.separator = Some(separator.to_string());
+            Ok(())
+        }
+        ("noexpand", None) => {
+            to.no_autoexpand = true;
+            Ok(())
+        }
+        ("block", None) => {
+            to.block = true;
+            Ok(())
+        }
+        ("snippet", None) => {
+            to.snippet = true;
+            Ok(())
+        }
+        ("noexpand" | "block" | "snippet", Some(_)) => {
+            Err(format!("`@{name}` doesn't take any arguments"))
+        }
+        _ => Err(format!("unknown annotation `@{name}`")),
+    }
+}
+
+/// cuts all annotations out of `src`, anything malformed ends up in `errors`
+pub(crate) fn extract_annotations(
+    src: &str,
+    source_map: &SourceMap,
+    errors: &mut Vec<GrammarError>,
+) -> Annotated {
+    let tokens = tokenize(src);
+    let mut text = src.to_string();
+    let mut annotations = Vec::new();
+    let mut error = |msg: String, start: usize, end: usize| {
+        errors.push(GrammarError::new(
+            GrammarErrorKind::InvalidAnnotation(msg),
+            source_map.rule_at(start).map(|rule| rule.name.as_str()),
+            Some(source_map.span(start, end - start)),
+        ));
+    };
+
+    let mut i = 0;
+    while i < tokens.len() {
+        if !tokens[i].is_punct("@") {
+            i += 1;
+            continue;
+        }
+        let literal = i
+            .checked_sub(1)
+            .map(|prev| tokens[prev])
+            .filter(|prev| prev.kind == TokenKind::Str);
+        let mut annotation = KeywordAnnotation::default();
+        let start = tokens[i].offset;
+        let mut end = start;
+        let mut valid = true;
+        while i < tokens.len() && tokens[i].is_punct("@") {
+            let (parsed, len) = parse_annotation(&tokens[i..]);
+            end = tokens[i + len - 1].end();
+            if let Err(msg) = parsed.and_then(|parsed| apply(&parsed, &mut annotation)) {
+                error(msg, tokens[i].offset, end);
+                valid = false;
+            }
+            i += len;
+        }
+        blank(&mut text, start..end);
+
+        let Some(literal) = literal else {
+            error(
+                "annotations have to follow a string literal".to_string(),
+                start,
+                end,
+            );
+            continue;
+        };
+        if valid && annotation.block && annotation.closing_token.is_none() {
+            error(
+                "`@block` needs a `@closing` token to know where the block ends".to_string(),
+                start,
+                end,
+            );
+            valid = false;
+        }
+        if !valid {
+            continue;
+        }
+        // move the closing quote back to make room for the tag, the blanked annotation is
+        // always longer than the tag
+        let tag = format!("{ANNOTATION_MARKER}{}", annotations.len());
+        let quote = &literal.text[literal.text.len() - 1..];
+        let tagged = format!("{tag}{quote}");
+        let range = literal.end() - 1..end;
+        assert!(tagged.len() <= range.len(), "annotation tag doesn't fit");
+        let padded = format!("{tagged}{}", " ".repeat(range.len() - tagged.len()));
+        text.replace_range(range, &padded);
+        annotations.push(annotation);
+    }
+    Annotated {
+        text,
+        annotations: Annotations {
+            keywords: annotations,
+            ..Default::default()
+        },
+    }
+}
@@ -0,0 +1,973 @@
+//! ANTLR4 grammars (`.g4`), as far as they describe what can be typed
+//!
+//! ```text
+//! select : SELECT column (',' column)* FROM ID ;
+//! SELECT : S E L E C T ;
+//! ID     : [a-zA-Z_] [a-zA-Z_0-9]* ;
+//! WS     : [ \t\r\n]+ -> skip ;
+//! fragment S : [sS] ;
+//! ```
+//!
+//! Parser rules keep their structure, lexer rules turn into regexes with the fragments they use
+//! inlined. Lexer rules matching a single string, or a string in any case like `S E L E C T`,
+//! become keywords instead; the latter make the grammar's keywords case-insensitive, just like
+//! `options { caseInsensitive = true; }` does. Lexer rules sent to a hidden channel or skipped
+//! become the grammar's layout. Actions and semantic predicates are code in the target language
+//! and get left out with a warning, so do labels, rule arguments and lexer modes, silently.
+//! Grammars split into a lexer and a parser grammar can be compiled by concatenating both files,
+//! the first parser rule is the default entry either way.
+use ebnf::{Node, RegexExtKind};
+
+use super::error::{GrammarError, GrammarErrorKind, GrammarWarning, GrammarWarningKind};
+use super::syntax::{GrammarFrontend, GrammarIr, Scanner, alternation, literal, sequence};
+use crate::fsm::Casing;
+
+#[derive(Debug)]
+pub struct Antlr;
+
+impl GrammarFrontend for Antlr {
+    fn name(&self) -> &'static str {
+        "antlr4"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["g4"]
+    }
+
+    fn lower(
+        &self,
+        source: &str,
+        _dialect: Option<&str>,
+        errors: &mut Vec<GrammarError>,
+        warnings: &mut Vec<GrammarWarning>,
+    ) -> Option<GrammarIr> {
+        let errors_before = errors.len();
+        let mut parser = Parser {
+            scanner: Scanner::new(source, errors),
+            warnings,
+            rules: Vec::new(),
+            tokens: Vec::new(),
+            case_insensitive: false,
+            hidden: false,
+        };
+        parser.grammar();
+        if parser.scanner.errors.len() > errors_before {
+            return None;
+        }
+        let ir = parser.lower();
+        if parser.scanner.errors.len() > errors_before {
+            return None;
+        }
+        Some(ir)
+    }
+}
+
+/// an element of a rule, before it's known whether it ends up in a regex or in the grammar
+#[derive(Debug, Clone)]
+enum Expr {
+    Empty,
+    Literal(String),
+    /// the contents of a character class, `a-z_` for `[a-z_]`
+    Set(String),
+    NotSet(String),
+    Any,
+    Ref(String),
+    Alt(Vec<Expr>),
+    Seq(Vec<Expr>),
+    /// `?`, `*` or `+`
+    Op(Box<Expr>, char),
+}
+
+struct Rule {
+    name: String,
+    /// where the rule starts, for diagnostics
+    start: usize,
+    fragment: bool,
+    /// skipped or sent to a hidden channel by a lexer command
+    hidden: bool,
+    body: Expr,
+}
+
+impl Rule {
+    fn is_lexer_rule(&self) -> bool {
+        self.name.starts_with(|c: char| c.is_ascii_uppercase())
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// `c` as it has to be written inside a regex character class
+fn class_char(c: char) -> String {
+    match c {
+        '\\' | '[' | ']' | '^' | '-' | '&' | '~' => format!("\\{c}"),
+        c if c.is_control() => format!("\\x{{{:X}}}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+    warnings: &'a mut Vec<GrammarWarning>,
+    rules: Vec<Rule>,
+    /// tokens declared in a `tokens { .. }` block
+    tokens: Vec<String>,
+    /// set by `options { caseInsensitive = true; }`
+    case_insensitive: bool,
+    /// set once the alternative being parsed turns out to be skipped
+    hidden: bool,
+}
+
+impl Parser<'_> {
+    fn skip_trivia(&mut self) {
+        loop {
+            self.scanner.skip_trivia("/*", "*/");
+            if !self.scanner.rest().starts_with("//") {
+                return;
+            }
+            self.scanner.take_while(|c| c != '\n');
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_trivia();
+        self.scanner.peek()
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        self.skip_trivia();
+        self.scanner.eat(text)
+    }
+
+    fn expect(&mut self, text: &str) -> Option<()> {
+        if self.eat(text) {
+            return Some(());
+        }
+        self.scanner.unexpected(&format!("`{text}`"));
+        None
+    }
+
+    fn peek_ident(&mut self) -> Option<&str> {
+        self.skip_trivia();
+        let rest = self.scanner.rest();
+        if !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        let len = rest.find(|c| !is_ident_char(c)).unwrap_or(rest.len());
+        Some(&rest[..len])
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        let ident = self.peek_ident()?.to_string();
+        self.scanner.pos += ident.len();
+        Some(ident)
+    }
+
+    /// whether the next identifier is `keyword` used as one, not as the name of a rule
+    fn at_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_ident() != Some(keyword) {
+            return false;
+        }
+        let after = self.scanner.rest()[keyword.len()..].trim_start();
+        !after.starts_with(':')
+    }
+
+    fn warn_ignored(&mut self, what: String, start: usize) {
+        let span = self.scanner.span(start, self.scanner.pos - start);
+        self.warnings.push(GrammarWarning::new(
+            GrammarWarningKind::Ignored(what),
+            self.scanner.rule.as_deref(),
+            Some(span),
+        ));
+    }
+
+    /// skips a block delimited by `open` and `close` at the current position, along with
+    /// everything nested in it; strings inside of it may contain the delimiters
+    fn skip_block(&mut self, open: char, close: char) -> Option<()> {
+        let start = self.scanner.pos;
+        let mut depth = 0;
+        loop {
+            match self.scanner.peek() {
+                Some(c) if c == open => depth += 1,
+                Some(c) if c == close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.scanner.bump();
+                        return Some(());
+                    }
+                }
+                Some(quote @ ('\'' | '"')) => {
+                    self.scanner.bump();
+                    while let Some(c) = self.scanner.bump() {
+                        if c == '\\' {
+                            self.scanner.bump();
+                        } else if c == quote || c == '\n' {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                Some(_) => {}
+                None => {
+                    self.scanner
+                        .error_at(start, 1, &format!("unterminated `{open}`"));
+                    return None;
+                }
+            }
+            self.scanner.bump();
+        }
+    }
+
+    /// an action at the current position, or a semantic predicate if it's followed by `?`
+    fn skip_action(&mut self) -> Option<()> {
+        let start = self.scanner.pos;
+        self.skip_block('{', '}')?;
+        let what = if self.scanner.eat("?") {
+            "semantic predicate"
+        } else {
+            "action"
+        };
+        self.warn_ignored(what.to_string(), start);
+        Some(())
+    }
+
+    /// `@members { .. }` or `@lexer::header { .. }`
+    fn skip_named_action(&mut self) -> Option<()> {
+        let start = self.scanner.pos;
+        self.scanner.bump();
+        let mut name = self.ident()?;
+        if self.eat("::") {
+            name.push_str("::");
+            name.push_str(&self.ident()?);
+        }
+        if self.peek() != Some('{') {
+            self.scanner.unexpected("`{`");
+            return None;
+        }
+        self.skip_block('{', '}')?;
+        self.warn_ignored(format!("action `@{name}`"), start);
+        Some(())
+    }
+
+    /// skips everything up to and including the next `;`
+    fn skip_statement(&mut self) {
+        self.scanner.take_while(|c| c != ';');
+        self.scanner.bump();
+    }
+
+    fn grammar(&mut self) {
+        loop {
+            self.skip_trivia();
+            if self.scanner.at_end() {
+                return;
+            }
+            let start = self.scanner.pos;
+            let ok = if self.at_keyword("lexer") || self.at_keyword("parser") {
+                self.ident();
+                self.at_keyword("grammar").then(|| self.skip_statement())
+            } else if self.at_keyword("grammar") || self.at_keyword("mode") {
+                self.skip_statement();
+                Some(())
+            } else if self.at_keyword("import") {
+                self.skip_statement();
+                self.warn_ignored("import".to_string(), start);
+                Some(())
+            } else if self.at_keyword("options") {
+                self.options()
+            } else if self.at_keyword("tokens") {
+                self.tokens()
+            } else if self.at_keyword("channels") {
+                self.ident();
+                self.peek();
+                self.skip_block('{', '}')
+            } else if self.scanner.peek() == Some('@') {
+                self.skip_named_action()
+            } else {
+                self.rule().map(|rule| self.rules.push(rule))
+            };
+            if ok.is_none() {
+                // skip to the next rule, so every broken rule gets reported at once
+                self.skip_statement();
+            }
+            self.scanner.rule = None;
+        }
+    }
+
+    /// `options { .. }`, only `caseInsensitive` matters
+    fn options(&mut self) -> Option<()> {
+        self.ident();
+        self.expect("{")?;
+        while !self.eat("}") {
+            let name = self.ident();
+            self.expect("=")?;
+            self.skip_trivia();
+            let value = self.scanner.take_while(|c| c != ';' && c != '}');
+            if name.as_deref() == Some("caseInsensitive") && value.trim() == "true" {
+                self.case_insensitive = true;
+            }
+            self.eat(";");
+            if self.scanner.at_end() {
+                self.scanner.unexpected("`}`");
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    /// `tokens { A, B }`
+    fn tokens(&mut self) -> Option<()> {
+        self.ident();
+        self.expect("{")?;
+        while !self.eat("}") {
+            let Some(token) = self.ident() else {
+                self.scanner.unexpected("a token name");
+                return None;
+            };
+            self.tokens.push(token);
+            self.eat(",");
+        }
+        Some(())
+    }
+
+    fn rule(&mut self) -> Option<Rule> {
+        let start = self.scanner.pos;
+        let mut fragment = false;
+        for modifier in ["fragment", "public", "private", "protected"] {
+            if self.at_keyword(modifier) {
+                fragment |= modifier == "fragment";
+                self.ident();
+            }
+        }
+        let Some(name) = self.ident() else {
+            self.scanner.unexpected("a rule name");
+            return None;
+        };
+        self.scanner.rule = Some(name.clone());
+        // arguments, return values and locals only exist in the generated code
+        if self.peek() == Some('[') {
+            self.skip_block('[', ']')?;
+        }
+        for clause in ["returns", "locals"] {
+            if self.at_keyword(clause) {
+                self.ident();
+                self.peek();
+                self.skip_block('[', ']')?;
+            }
+        }
+        if self.at_keyword("throws") {
+            self.scanner.take_while(|c| c != ':' && c != '@');
+        }
+        loop {
+            if self.at_keyword("options") {
+                self.options()?;
+            } else if self.peek() == Some('@') {
+                self.skip_named_action()?;
+            } else {
+                break;
+            }
+        }
+        self.expect(":")?;
+        self.hidden = false;
+        let body = self.alternatives()?;
+        self.expect(";")?;
+        while self.at_keyword("catch") || self.at_keyword("finally") {
+            let start = self.scanner.pos;
+            if self.ident().as_deref() == Some("catch") {
+                self.peek();
+                self.skip_block('[', ']')?;
+            }
+            self.peek();
+            self.skip_block('{', '}')?;
+            self.warn_ignored("exception handler".to_string(), start);
+        }
+        Some(Rule {
+            name,
+            start,
+            fragment,
+            hidden: self.hidden,
+            body,
+        })
+    }
+
+    fn alternatives(&mut self) -> Option<Expr> {
+        let mut alts = vec![self.alternative()?];
+        while self.eat("|") {
+            alts.push(self.alternative()?);
+        }
+        Some(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Expr::Alt(alts)
+        })
+    }
+
+    fn alternative(&mut self) -> Option<Expr> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some('|' | ';' | ')') => break,
+                // alternative labels only name a node in the parse tree
+                Some('#') => {
+                    self.scanner.bump();
+                    self.ident();
+                }
+                Some('-') if self.scanner.rest().starts_with("->") => self.lexer_commands()?,
+                Some('{') => self.skip_action()?,
+                // element options like `<assoc=right>`
+                Some('<') => self.skip_block('<', '>')?,
+                _ => items.push(self.element()?),
+            }
+        }
+        Some(match items.len() {
+            0 => Expr::Empty,
+            1 => items.pop().unwrap(),
+            _ => Expr::Seq(items),
+        })
+    }
+
+    /// `-> skip`, `-> channel(HIDDEN), mode(X)`
+    fn lexer_commands(&mut self) -> Option<()> {
+        self.scanner.eat("->");
+        loop {
+            let Some(command) = self.ident() else {
+                self.scanner.unexpected("a lexer command");
+                return None;
+            };
+            if command == "skip" || command == "channel" {
+                self.hidden = true;
+            }
+            if self.peek() == Some('(') {
+                self.skip_block('(', ')')?;
+            }
+            if !self.eat(",") {
+                return Some(());
+            }
+        }
+    }
+
+    /// an atom, followed by `?`, `*` or `+` and optionally a `?` making that non-greedy
+    fn element(&mut self) -> Option<Expr> {
+        let atom = self.atom()?;
+        let Some(op @ ('?' | '*' | '+')) = self.peek() else {
+            return Some(atom);
+        };
+        self.scanner.bump();
+        self.scanner.eat("?");
+        Some(Expr::Op(Box::new(atom), op))
+    }
+
+    fn atom(&mut self) -> Option<Expr> {
+        let Some(c) = self.peek() else {
+            self.scanner.unexpected("an element");
+            return None;
+        };
+        match c {
+            '\'' => {
+                let start = self.scanner.pos;
+                let first = self.string()?;
+                if !self.eat("..") {
+                    return Some(Expr::Literal(first));
+                }
+                self.skip_trivia();
+                let last = self.string()?;
+                let (mut first_chars, mut last_chars) = (first.chars(), last.chars());
+                match (
+                    first_chars.next(),
+                    first_chars.next(),
+                    last_chars.next(),
+                    last_chars.next(),
+                ) {
+                    (Some(first), None, Some(last), None) => Some(Expr::Set(format!(
+                        "{}-{}",
+                        class_char(first),
+                        class_char(last)
+                    ))),
+                    _ => {
+                        let len = self.scanner.pos - start;
+                        self.scanner.error_at(
+                            start,
+                            len,
+                            "ranges have to be between two characters",
+                        );
+                        None
+                    }
+                }
+            }
+            '[' => self.set(),
+            '(' => {
+                self.scanner.bump();
+                let inner = self.alternatives()?;
+                self.expect(")")?;
+                Some(inner)
+            }
+            '~' => {
+                let start = self.scanner.pos;
+                self.scanner.bump();
+                let atom = self.atom()?;
+                match class(&atom) {
+                    Some(class) => Some(Expr::NotSet(class)),
+                    None => {
+                        let len = self.scanner.pos - start;
+                        self.scanner.error_at_kind(
+                            GrammarErrorKind::UnsupportedConstruct(
+                                "`~` in front of something else than a set of characters"
+                                    .to_string(),
+                            ),
+                            start,
+                            len,
+                        );
+                        None
+                    }
+                }
+            }
+            '.' => {
+                self.scanner.bump();
+                Some(Expr::Any)
+            }
+            c if c.is_ascii_alphabetic() => {
+                let name = self.ident()?;
+                // labels only name a node in the parse tree
+                self.skip_trivia();
+                let rest = self.scanner.rest();
+                if rest.starts_with("+=") || (rest.starts_with('=') && !rest.starts_with("=>")) {
+                    self.scanner.eat("+");
+                    self.scanner.eat("=");
+                    return self.atom();
+                }
+                Some(Expr::Ref(name))
+            }
+            _ => {
+                self.scanner.unexpected("an element");
+                None
+            }
+        }
+    }
+
+    /// a `'string'` at the current position, its escape sequences resolved
+    fn string(&mut self) -> Option<String> {
+        let start = self.scanner.pos;
+        self.scanner.bump();
+        let mut ret = String::new();
+        loop {
+            match self.scanner.bump() {
+                Some('\'') => return Some(ret),
+                Some('\\') => ret.push(self.escape(start)?),
+                Some('\n') | None => {
+                    self.scanner.error_at(start, 1, "unterminated string");
+                    return None;
+                }
+                Some(c) => ret.push(c),
+            }
+        }
+    }
+
+    /// the character an escape sequence stands for, the backslash already being consumed
+    fn escape(&mut self, start: usize) -> Option<char> {
+        let c = match self.scanner.bump() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('u') => {
+                let digits = if self.scanner.eat("{") {
+                    let digits = self.scanner.take_while(|c| c != '}');
+                    self.scanner.bump();
+                    digits
+                } else {
+                    let rest = self.scanner.rest();
+                    let len = rest
+                        .char_indices()
+                        .take(4)
+                        .take_while(|(_, c)| c.is_ascii_hexdigit())
+                        .count();
+                    self.scanner.pos += len;
+                    &rest[..len]
+                };
+                match u32::from_str_radix(digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                {
+                    Some(c) => c,
+                    None => {
+                        let len = self.scanner.pos - start;
+                        self.scanner
+                            .error_at(start, len, "invalid unicode escape sequence");
+                        return None;
+                    }
+                }
+            }
+            Some(c) => c,
+            None => {
+                self.scanner.error_at(start, 1, "unterminated string");
+                return None;
+            }
+        };
+        Some(c)
+    }
+
+    /// `[a-z_À]` at the current position
+    fn set(&mut self) -> Option<Expr> {
+        let start = self.scanner.pos;
+        self.scanner.bump();
+        let mut class = String::new();
+        loop {
+            match self.scanner.bump() {
+                Some(']') => return Some(Expr::Set(class)),
+                // a dash between two characters is a range, anywhere else it's itself
+                Some('-') if !class.is_empty() && self.scanner.peek() != Some(']') => {
+                    class.push('-')
+                }
+                Some('\\') if matches!(self.scanner.peek(), Some('p' | 'P')) => {
+                    class.push('\\');
+                    class.push_str(self.scanner.take_while(|c| c != '}'));
+                    class.push(self.scanner.bump()?);
+                }
+                Some('\\') => {
+                    let c = self.escape(start)?;
+                    class.push_str(&class_char(c));
+                }
+                Some(c) => class.push_str(&class_char(c)),
+                None => {
+                    self.scanner.error_at(start, 1, "unterminated `[`");
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// the parsed rules as nightfury rules, parser rules first
+    fn lower(&mut self) -> GrammarIr {
+        let rules = std::mem::take(&mut self.rules);
+        let mut lowered = Vec::new();
+        let mut layout = Vec::new();
+        let (lexer_rules, parser_rules): (Vec<&Rule>, Vec<&Rule>) =
+            rules.iter().partition(|rule| rule.is_lexer_rule());
+        for rule in parser_rules {
+            self.scanner.rule = Some(rule.name.clone());
+            match self.node(&rule.body) {
+                Ok(node) => lowered.push((rule.name.clone(), node)),
+                Err(msg) => self.error(rule, msg),
+            }
+        }
+        for rule in lexer_rules.iter().filter(|rule| !rule.fragment) {
+            self.scanner.rule = Some(rule.name.clone());
+            if let Some((text, case_insensitive)) = string_of(&rule.body, &rules) {
+                self.case_insensitive |= case_insensitive;
+                if !rule.hidden {
+                    lowered.push((rule.name.clone(), literal(&text)));
+                    continue;
+                }
+            }
+            match regex(&rule.body, &rules, &mut vec![&rule.name]) {
+                Ok(regex) if rule.hidden => layout.push(regex),
+                Ok(regex) => lowered.push((rule.name.clone(), Node::RegexString(regex))),
+                Err(msg) => self.error(rule, msg),
+            }
+        }
+        self.scanner.rule = None;
+        for token in &self.tokens {
+            if lowered.iter().all(|(rule, _)| rule != token) {
+                self.warnings.push(GrammarWarning::new(
+                    GrammarWarningKind::Ignored(format!("token `{token}` without a lexer rule")),
+                    None,
+                    None,
+                ));
+                lowered.push((token.clone(), Node::Multiple(Vec::new())));
+            }
+        }
+        let mut ir = GrammarIr::new(lowered);
+        if !layout.is_empty() {
+            ir.layout = Some(format!("(?:{})*", layout.join("|")));
+        }
+        if self.case_insensitive {
+            ir.annotations.case = Some(Casing::Keep);
+        }
+        ir
+    }
+
+    fn error(&mut self, rule: &Rule, msg: String) {
+        self.scanner.error_at_kind(
+            GrammarErrorKind::UnsupportedConstruct(msg),
+            rule.start,
+            rule.name.len(),
+        );
+    }
+
+    /// `expr` in a parser rule
+    fn node(&self, expr: &Expr) -> Result<Node, String> {
+        Ok(match expr {
+            Expr::Empty => Node::Multiple(Vec::new()),
+            Expr::Literal(text) if text.is_empty() => Node::Multiple(Vec::new()),
+            Expr::Literal(text) => literal(text),
+            Expr::Ref(name) if name == "EOF" => Node::Multiple(Vec::new()),
+            Expr::Ref(name) => Node::Terminal(name.clone()),
+            Expr::Seq(items) => sequence(
+                items
+                    .iter()
+                    .map(|item| self.node(item))
+                    .filter(|node| !matches!(node, Ok(Node::Multiple(nodes)) if nodes.is_empty()))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Alt(alts) => {
+                let mut nodes = Vec::new();
+                let mut optional = false;
+                for alt in alts {
+                    match self.node(alt)? {
+                        Node::Multiple(items) if items.is_empty() => optional = true,
+                        node => nodes.push(node),
+                    }
+                }
+                match (nodes.is_empty(), optional) {
+                    (true, _) => Node::Multiple(Vec::new()),
+                    (false, true) => Node::Optional(Box::new(alternation(nodes))),
+                    (false, false) => alternation(nodes),
+                }
+            }
+            Expr::Op(inner, op) => {
+                let inner = Box::new(self.node(inner)?);
+                match op {
+                    '?' => Node::Optional(inner),
+                    '*' => Node::Repeat(inner),
+                    _ => Node::RegexExt(inner, RegexExtKind::Repeat1),
+                }
+            }
+            Expr::Set(_) | Expr::NotSet(_) => {
+                return Err("sets of characters outside of lexer rules".to_string());
+            }
+            Expr::Any => return Err("`.` in parser rules".to_string()),
+        })
+    }
+}
+
+/// the contents of the character class matching the same as `expr`, if there is one
+fn class(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Set(class) => Some(class.clone()),
+        Expr::Literal(text) if text.chars().count() == 1 => Some(class_char(text.chars().next()?)),
+        Expr::Alt(alts) => alts.iter().map(class).collect(),
+        _ => None,
+    }
+}
+
+/// the string the lexer rule `expr` matches along with whether it's case-insensitive, if it only
+/// matches that string
+fn string_of(expr: &Expr, rules: &[Rule]) -> Option<(String, bool)> {
+    match expr {
+        Expr::Literal(text) if !text.is_empty() => Some((text.clone(), false)),
+        // `[sS]`
+        Expr::Set(class) => {
+            let mut chars = class.chars();
+            let (c1, c2) = (chars.next()?, chars.next()?);
+            let is_letter_in_any_case =
+                chars.next().is_none() && c1 != c2 && c1.to_lowercase().eq(c2.to_lowercase());
+            is_letter_in_any_case.then(|| (c1.to_uppercase().chain(None).collect(), true))
+        }
+        Expr::Seq(items) => items.iter().map(|item| string_of(item, rules)).try_fold(
+            (String::new(), false),
+            |(text, insensitive), item| {
+                let (item, item_insensitive) = item?;
+                Some((text + &item, insensitive || item_insensitive))
+            },
+        ),
+        Expr::Ref(name) => {
+            let rule = rules.iter().find(|rule| &rule.name == name)?;
+            rule.fragment.then(|| string_of(&rule.body, rules))?
+        }
+        _ => None,
+    }
+}
+
+/// `expr` in a lexer rule as a regex, `stack` being the lexer rules it's nested in
+fn regex<'r>(
+    expr: &'r Expr,
+    rules: &'r [Rule],
+    stack: &mut Vec<&'r str>,
+) -> Result<String, String> {
+    Ok(match expr {
+        Expr::Empty => String::new(),
+        Expr::Literal(text) => regex::escape(text),
+        Expr::Set(class) => format!("[{class}]"),
+        Expr::NotSet(class) => format!("[^{class}]"),
+        Expr::Any => "(?s:.)".to_string(),
+        Expr::Ref(name) => {
+            if stack.contains(&name.as_str()) {
+                return Err(format!("the recursive lexer rule `{name}`"));
+            }
+            let Some(rule) = rules.iter().find(|rule| &rule.name == name) else {
+                return Err(format!("the lexer rule `{name}` isn't defined"));
+            };
+            if !rule.is_lexer_rule() {
+                return Err(format!("the lexer rule using the parser rule `{name}`"));
+            }
+            stack.push(&rule.name);
+            let regex = regex(&rule.body, rules, stack)?;
+            stack.pop();
+            format!("(?:{regex})")
+        }
+        Expr::Seq(items) => items
+            .iter()
+            .map(|item| regex(item, rules, stack))
+            .collect::<Result<_, _>>()?,
+        Expr::Alt(alts) => format!(
+            "(?:{})",
+            alts.iter()
+                .map(|alt| regex(alt, rules, stack))
+                .collect::<Result<Vec<_>, _>>()?
+                .join("|")
+        ),
+        Expr::Op(inner, op) => format!("(?:{}){op}", regex(inner, rules, stack)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FSMCursor;
+    use crate::frontend::{CompileOptions, compile_ebnf_with};
+
+    const GRAMMAR: &str = r#"grammar Query;
+        options { caseInsensitive = false; }
+        @header { package query; }
+
+        query : statement (';' statement)* EOF ;
+        statement
+            : SELECT columns+=ID (',' columns+=ID)* FROM table=ID  # select
+            | DELETE FROM ID {System.out.println("delete");}     # delete
+            ;
+
+        SELECT : S E L E C T ;
+        DELETE : 'DELETE' ;
+        FROM : F R O M ;
+        ID : [a-zA-Z_] [a-zA-Z_0-9]* ;
+        WS : [ \t\r\n]+ -> skip ;
+        COMMENT : '--' ~[\r\n]* -> channel(HIDDEN) ;
+        fragment S : [sS] ;
+        fragment E : [eE] ;
+        fragment L : [lL] ;
+        fragment C : [cC] ;
+        fragment T : [tT] ;
+        fragment F : [fF] ;
+        fragment R : [rR] ;
+        fragment O : [oO] ;
+        fragment M : [mM] ;
+    "#;
+
+    fn lower(source: &str) -> (Option<GrammarIr>, Vec<GrammarError>, Vec<GrammarWarning>) {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+        let ir = Antlr.lower(source, None, &mut errors, &mut warnings);
+        (ir, errors, warnings)
+    }
+
+    #[test]
+    fn test_lower() {
+        let (ir, errors, warnings) = lower(GRAMMAR);
+        assert!(errors.is_empty(), "{errors:?}");
+        let ir = ir.unwrap();
+        let rules: Vec<(&str, String)> = ir
+            .grammar
+            .expressions
+            .iter()
+            .map(|expr| (expr.lhs.as_str(), format!("{:?}", expr.rhs)))
+            .collect();
+        let names: Vec<&str> = rules.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            vec!["query", "statement", "SELECT", "DELETE", "FROM", "ID"],
+            names
+        );
+        assert_eq!(
+            r#"Multiple([Terminal("statement"), Repeat(Multiple([String(";"), Terminal("statement")]))])"#,
+            rules[0].1
+        );
+        assert_eq!(
+            r#"Symbol(Multiple([Terminal("SELECT"), Terminal("ID"), Repeat(Multiple([String(","), Terminal("ID")])), Terminal("FROM"), Terminal("ID")]), Alternation, Multiple([Terminal("DELETE"), Terminal("FROM"), Terminal("ID")]))"#,
+            rules[1].1
+        );
+        assert_eq!(r#"String("SELECT")"#, rules[2].1);
+        assert_eq!(r#"String("FROM")"#, rules[4].1);
+        assert_eq!(r#"RegexString("[a-zA-Z_](?:[a-zA-Z_0-9])*")"#, rules[5].1);
+        assert_eq!(
+            Some(r"(?:(?:[ \x{9}\x{D}\x{A}])+|\-\-(?:[^\x{D}\x{A}])*)*"),
+            ir.layout.as_deref()
+        );
+        assert_eq!(Some(Casing::Keep), ir.annotations.case);
+        let warnings: Vec<String> = warnings.iter().map(|w| w.kind.to_string()).collect();
+        assert_eq!(
+            vec!["action `@header` was left out", "action was left out"],
+            warnings
+        );
+    }
+
+    #[test]
+    fn test_predicates_and_escapes() {
+        let (ir, _, warnings) = lower(
+            r#"lexer grammar L;
+            tokens { VIRTUAL }
+            ARROW : '->' | '→' | '\'' ;
+            NUM : {isNumber()}? '0'..'9'+ ;
+            "#,
+        );
+        let rules: Vec<String> = ir
+            .unwrap()
+            .grammar
+            .expressions
+            .iter()
+            .map(|expr| format!("{}: {:?}", expr.lhs, expr.rhs))
+            .collect();
+        assert_eq!(
+            vec![
+                r#"ARROW: RegexString("(?:\\->|→|')")"#,
+                r#"NUM: RegexString("(?:[0-9])+")"#,
+                "VIRTUAL: Multiple([])",
+            ],
+            rules
+        );
+        assert_eq!(
+            GrammarWarningKind::Ignored("semantic predicate".to_string()),
+            warnings[0].kind
+        );
+        assert_eq!(4, warnings[0].span.as_ref().unwrap().line);
+    }
+
+    #[test]
+    fn test_errors() {
+        let (ir, errors, _) =
+            lower("a : 'x' ;\nb : 'y' ( ;\nC : 'c' C ;\nd : [abc] ;\nE : 'a'..'zz' ;\nf : ~a ;");
+        assert!(ir.is_none());
+        let errors: Vec<(Option<&str>, usize)> = errors
+            .iter()
+            .map(|err| (err.rule.as_deref(), err.span.as_ref().unwrap().line))
+            .collect();
+        assert_eq!(vec![(Some("b"), 2), (Some("E"), 5), (Some("f"), 6)], errors);
+
+        let (_, errors, _) = lower("C : 'c' C ;\nd : [abc] ;");
+        let errors: Vec<String> = errors.iter().map(|err| err.kind.to_string()).collect();
+        assert_eq!(
+            vec![
+                "unsupported construct: sets of characters outside of lexer rules",
+                "unsupported construct: the recursive lexer rule `C`",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_compile() {
+        let compiled = compile_ebnf_with(
+            GRAMMAR,
+            &CompileOptions {
+                frontend: Some(&Antlr),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut cursor =
+            FSMCursor::new(compiled.entries.default_root()).with_layout(compiled.entries.layout());
+        assert_eq!("SELECT", cursor.advance('s').unwrap());
+        assert_eq!(None, cursor.advance(' '));
+        assert_eq!(None, cursor.advance('x'));
+        assert_eq!(",", cursor.advance(',').unwrap());
+    }
+}
@@ -0,0 +1,152 @@
+//! dialect sections like `@if(postgres) 'RETURNING' column_list @end`
+//!
+//! A section is kept when compiling for one of the dialects it names and blanked out otherwise,
+//! before any other pass gets to see the source. `@else` starts the part used by every other
+//! dialect. Sections can be nested and may span whole rules.
+use std::ops::Range;
+
+use super::error::{GrammarError, GrammarErrorKind};
+use super::source::{SourceMap, Token, TokenKind, blank, tokenize};
+
+enum Directive<'a> {
+    If(Vec<&'a str>),
+    Else,
+    End,
+}
+
+/// parses the directive starting at `tokens[0]`, along with the number of tokens it took up
+/// Returns None if the tokens aren't a dialect directive at all, e.g. annotations.
+fn parse_directive<'a>(tokens: &[Token<'a>]) -> Option<(Result<Directive<'a>, String>, usize)> {
+    let [at, name, ..] = tokens else {
+        return None;
+    };
+    if !at.is_punct("@") || name.kind != TokenKind::Ident || name.offset != at.end() {
+        return None;
+    }
+    match name.text {
+        "else" => Some((Ok(Directive::Else), 2)),
+        "end" => Some((Ok(Directive::End), 2)),
+        "if" => {
+            let malformed = Err("expected `@if(dialect, ...)`".to_string());
+            if !tokens.get(2).is_some_and(|open| open.is_punct("(")) {
+                return Some((malformed, 2));
+            }
+            // an identifier followed by `,` or `)`, one pair after the other
+            let mut dialects = Vec::new();
+            for (i, pair) in tokens[3..].chunks(2).enumerate() {
+                let end = 3 + 2 * i;
+                match pair {
+                    [dialect, sep] if dialect.kind == TokenKind::Ident => {
+                        dialects.push(dialect.text);
+                        if sep.is_punct(")") {
+                            return Some((Ok(Directive::If(dialects)), end + 2));
+                        } else if !sep.is_punct(",") {
+                            return Some((malformed, end + 1));
+                        }
+                    }
+                    _ => return Some((malformed, end)),
+                }
+            }
+            Some((malformed, tokens.len()))
+        }
+        _ => None,
+    }
+}
+
+/// every dialect named by a section in `ebnf`, in the order they first appear
+pub fn dialects(ebnf: &str) -> Vec<String> {
+    let tokens = tokenize(ebnf);
+    let mut ret: Vec<String> = Vec::new();
+    for i in 0..tokens.len() {
+        if let Some((Ok(Directive::If(dialects)), _)) = parse_directive(&tokens[i..]) {
+            for dialect in dialects {
+                if !ret.iter().any(|known| known == dialect) {
+                    ret.push(dialect.to_string());
+                }
+            }
+        }
+    }
+    ret
+}
+
+struct Section {
+    /// whether the text around the section is kept
+    outer: bool,
+    /// whether the `@if` part is kept
+    taken: bool,
+    seen_else: bool,
+    /// the `@if` directive, for diagnostics
+    range: Range<usize>,
+}
+
+/// blanks out every section not meant for `dialect`, as well as the directives themselves
+pub(crate) fn select_dialect(
+    src: &str,
+    dialect: Option<&str>,
+    source_map: &SourceMap,
+    errors: &mut Vec<GrammarError>,
+) -> String {
+    let tokens = tokenize(src);
+    let mut text = src.to_string();
+    let mut error = |msg: &str, range: Range<usize>| {
+        errors.push(GrammarError::new(
+            GrammarErrorKind::InvalidDirective(msg.to_string()),
+            source_map
+                .rule_at(range.start)
+                .map(|rule| rule.name.as_str()),
+            Some(source_map.span(range.start, range.len())),
+        ));
+    };
+    let mut sections: Vec<Section> = Vec::new();
+    let mut active = true;
+    // where the text currently being dropped starts
+    let mut dropped_from = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        let Some((directive, len)) = parse_directive(&tokens[i..]) else {
+            i += 1;
+            continue;
+        };
+        let range = tokens[i].offset..tokens[i + len - 1].end();
+        let was_active = active;
+        match directive {
+            Ok(Directive::If(dialects)) => {
+                let taken = dialect.is_some_and(|dialect| dialects.contains(&dialect));
+                sections.push(Section {
+                    outer: active,
+                    taken,
+                    seen_else: false,
+                    range: range.clone(),
+                });
+                active &= taken;
+            }
+            Ok(Directive::Else) => match sections.last_mut() {
+                Some(section) if !section.seen_else => {
+                    section.seen_else = true;
+                    active = section.outer && !section.taken;
+                }
+                Some(_) => error("`@if` already has an `@else`", range.clone()),
+                None => error("`@else` without `@if`", range.clone()),
+            },
+            Ok(Directive::End) => match sections.pop() {
+                Some(section) => active = section.outer,
+                None => error("`@end` without `@if`", range.clone()),
+            },
+            Err(msg) => error(&msg, range.clone()),
+        }
+        match (was_active, active) {
+            (true, false) => dropped_from = range.start,
+            (false, true) => blank(&mut text, dropped_from..range.start),
+            _ => {}
+        }
+        blank(&mut text, range);
+        i += len;
+    }
+    if !active {
+        blank(&mut text, dropped_from..src.len());
+    }
+    for section in sections {
+        error("`@if` is missing its `@end`", section.range);
+    }
+    text
+}
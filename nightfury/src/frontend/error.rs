@@ -0,0 +1,329 @@
+use std::fmt::Display;
+
+use nom::error::{VerboseError, VerboseErrorKind};
+
+use super::source::{MarkedSource, SourceMap};
+
+/// location of a construct inside the grammar source; lines and columns start at 1, columns and
+/// lengths are counted in characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GrammarErrorKind {
+    /// the input isn't valid in the syntax it was lowered from
+    Syntax(String),
+    UndefinedNonterminal(String),
+    InvalidRegex {
+        regex: String,
+        reason: String,
+    },
+    UnsupportedConstruct(String),
+    /// a string literal with a backslash in front of a character that can't be escaped
+    InvalidEscape(char),
+    EmptyGrammar,
+    /// an entry rule was requested that the grammar doesn't define
+    UnknownEntryRule(String),
+    /// a dialect was requested that no section of the grammar is meant for
+    UnknownDialect(String),
+    /// the rule is left-recursive in a way that couldn't be rewritten
+    LeftRecursion(String),
+    /// an imported grammar file couldn't be read
+    Import {
+        path: String,
+        reason: String,
+    },
+    /// grammar files importing each other, in the order they were imported
+    ImportCycle(Vec<String>),
+    /// a rule defined more than once without one of them extending the other
+    DuplicateRule {
+        name: String,
+        /// where the rule was defined first
+        previous: String,
+    },
+    InvalidDirective(String),
+    /// an `@layout` or `@separator` rule that doesn't make sense
+    InvalidLayout(String),
+    /// a keyword annotation like `@short("..")` that doesn't make sense
+    InvalidAnnotation(String),
+    /// a parameterized rule was used with the wrong number of arguments
+    MacroArity {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// a name in the body of a parameterized rule that's neither a parameter nor a rule
+    UnboundParameter(String),
+    InvalidMacro(String),
+}
+
+impl Display for GrammarErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Syntax(msg) => write!(f, "syntax error: {msg}"),
+            Self::UndefinedNonterminal(name) => write!(f, "undefined nonterminal `{name}`"),
+            Self::InvalidRegex { regex, reason } => write!(f, "invalid regex `{regex}`: {reason}"),
+            Self::UnsupportedConstruct(what) => write!(f, "unsupported construct: {what}"),
+            Self::InvalidEscape(c) => write!(f, "unknown escape sequence `\\{c}`"),
+            Self::EmptyGrammar => write!(f, "grammar doesn't contain any rules"),
+            Self::UnknownEntryRule(name) => write!(f, "entry rule `{name}` is not defined"),
+            Self::UnknownDialect(name) => write!(f, "grammar has no sections for dialect `{name}`"),
+            Self::LeftRecursion(reason) => write!(f, "unresolvable left recursion: {reason}"),
+            Self::Import { path, reason } => write!(f, "can't import `{path}`: {reason}"),
+            Self::ImportCycle(files) => write!(f, "import cycle: {}", files.join(" -> ")),
+            Self::DuplicateRule { name, previous } => {
+                write!(f, "rule `{name}` is already defined at {previous}")
+            }
+            Self::InvalidDirective(msg) => write!(f, "invalid directive: {msg}"),
+            Self::InvalidLayout(msg) => write!(f, "invalid layout rule: {msg}"),
+            Self::MacroArity {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{name}` takes {expected} argument{} but {found} {} given",
+                if *expected == 1 { "" } else { "s" },
+                if *found == 1 { "was" } else { "were" }
+            ),
+            Self::UnboundParameter(name) => {
+                write!(f, "`{name}` is neither a parameter nor a rule")
+            }
+            Self::InvalidMacro(msg) => write!(f, "invalid parameterized rule: {msg}"),
+            Self::InvalidAnnotation(msg) => write!(f, "invalid annotation: {msg}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrammarError {
+    pub kind: GrammarErrorKind,
+    /// rule the error occurred in, if any
+    pub rule: Option<String>,
+    pub span: Option<Span>,
+    /// file `span` points into, only set for grammars spread over several files
+    pub file: Option<String>,
+}
+
+impl GrammarError {
+    pub fn new(kind: GrammarErrorKind, rule: Option<&str>, span: Option<Span>) -> Self {
+        Self {
+            kind,
+            rule: rule.map(str::to_string),
+            span,
+            file: None,
+        }
+    }
+
+    pub(crate) fn from_parse_error(
+        err: &nom::Err<VerboseError<&str>>,
+        parsed: &MarkedSource,
+        source_map: &SourceMap,
+    ) -> Self {
+        let (rest, kind) = match err {
+            nom::Err::Error(e) | nom::Err::Failure(e) => match e.errors.first() {
+                Some((rest, kind)) => (Some(*rest), kind.clone()),
+                None => (None, VerboseErrorKind::Context("unknown error")),
+            },
+            nom::Err::Incomplete(_) => (None, VerboseErrorKind::Context("unexpected end of input")),
+        };
+        let msg = match kind {
+            VerboseErrorKind::Context(ctx) => ctx.to_string(),
+            VerboseErrorKind::Char(c) => format!("expected '{c}'"),
+            VerboseErrorKind::Nom(kind) => format!("unexpected input ({kind:?})"),
+        };
+        // nom hands out slices of the original input, so their position can be recovered
+        let offset = rest
+            .map(|rest| (rest.as_ptr() as usize).wrapping_sub(parsed.text.as_ptr() as usize))
+            .filter(|offset| *offset <= parsed.text.len())
+            .map(|offset| parsed.original_offset(offset));
+        Self::new(
+            GrammarErrorKind::Syntax(msg),
+            offset
+                .and_then(|offset| source_map.rule_at(offset))
+                .map(|rule| rule.name.as_str()),
+            offset.map(|offset| source_map.span(offset, 1)),
+        )
+    }
+}
+
+fn write_location(
+    f: &mut std::fmt::Formatter<'_>,
+    file: Option<&str>,
+    span: Option<Span>,
+) -> std::fmt::Result {
+    if let Some(Span { line, column, .. }) = span {
+        write!(f, " at ")?;
+        if let Some(file) = file {
+            write!(f, "{file}:")?;
+        }
+        write!(f, "{line}:{column}")?;
+    }
+    Ok(())
+}
+
+impl Display for GrammarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(rule) = &self.rule {
+            write!(f, " in rule `{rule}`")?;
+        }
+        write_location(f, self.file.as_deref(), self.span)
+    }
+}
+
+impl std::error::Error for GrammarError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GrammarWarningKind {
+    /// the rule was left-recursive and got rewritten into a repetition
+    LeftRecursionRewritten,
+    /// a repetition had an upper bound too large to spell out and got compiled without one
+    RepetitionUnbounded { max: usize },
+    /// something in the grammar that can't be compiled into an FSM and was left out, e.g. a token
+    /// produced by an external scanner
+    Ignored(String),
+}
+
+impl Display for GrammarWarningKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LeftRecursionRewritten => {
+                write!(f, "left recursion was rewritten into a repetition")
+            }
+            Self::RepetitionUnbounded { max } => {
+                write!(
+                    f,
+                    "at most {max} repetitions were relaxed to any number of them"
+                )
+            }
+            Self::Ignored(what) => write!(f, "{what} was left out"),
+        }
+    }
+}
+
+/// something the grammar got away with, but that changed how it was compiled
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrammarWarning {
+    pub kind: GrammarWarningKind,
+    pub rule: Option<String>,
+    pub span: Option<Span>,
+    /// see [GrammarError::file]
+    pub file: Option<String>,
+}
+
+impl GrammarWarning {
+    pub fn new(kind: GrammarWarningKind, rule: Option<&str>, span: Option<Span>) -> Self {
+        Self {
+            kind,
+            rule: rule.map(str::to_string),
+            span,
+            file: None,
+        }
+    }
+}
+
+impl Display for GrammarWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(rule) = &self.rule {
+            write!(f, " in rule `{rule}`")?;
+        }
+        write_location(f, self.file.as_deref(), self.span)
+    }
+}
+
+fn render(
+    ret: &mut String,
+    severity: &str,
+    msg: &impl Display,
+    rule: Option<&str>,
+    span: Option<Span>,
+    file: Option<&str>,
+    sources: &[(&str, &str)],
+) {
+    // diagnostics without a file point into the first source
+    let (file_name, source) = match file {
+        None => sources[0],
+        Some(file) => sources
+            .iter()
+            .find(|(name, _)| *name == file)
+            .copied()
+            .unwrap_or((file, "")),
+    };
+    ret.push_str(&format!("{severity}: {msg}\n"));
+    if let Some(Span { line, column, len }) = span {
+        let gutter = " ".repeat(line.to_string().len());
+        ret.push_str(&format!("{gutter}--> {file_name}:{line}:{column}\n"));
+        if let Some(src_line) = source.lines().nth(line - 1) {
+            ret.push_str(&format!("{gutter} |\n"));
+            ret.push_str(&format!("{line} | {src_line}\n"));
+            ret.push_str(&format!(
+                "{gutter} | {}{}\n",
+                " ".repeat(column - 1),
+                "^".repeat(len.max(1))
+            ));
+        }
+        if let Some(rule) = rule {
+            ret.push_str(&format!("{gutter} = in rule `{rule}`\n"));
+        }
+    } else if let Some(rule) = rule {
+        ret.push_str(&format!(" --> {file_name}\n  = in rule `{rule}`\n"));
+    }
+    ret.push('\n');
+}
+
+/// renders `errors` in a compiler-like style, quoting the offending source lines
+pub fn report(errors: &[GrammarError], source: &str, file_name: &str) -> String {
+    report_sources(errors, &[(file_name, source)])
+}
+
+/// like [report], but for errors spread over several files, given as `(file name, source)`
+pub(crate) fn report_sources(errors: &[GrammarError], sources: &[(&str, &str)]) -> String {
+    let mut ret = String::new();
+    for err in errors {
+        render(
+            &mut ret,
+            "error",
+            &err.kind,
+            err.rule.as_deref(),
+            err.span,
+            err.file.as_deref(),
+            sources,
+        );
+    }
+    let cnt = errors.len();
+    ret.push_str(&format!(
+        "error: could not compile grammar due to {cnt} previous error{}\n",
+        if cnt == 1 { "" } else { "s" }
+    ));
+    ret
+}
+
+/// like [report], but for warnings; returns an empty string if there are none
+pub fn report_warnings(warnings: &[GrammarWarning], source: &str, file_name: &str) -> String {
+    report_warnings_sources(warnings, &[(file_name, source)])
+}
+
+pub(crate) fn report_warnings_sources(
+    warnings: &[GrammarWarning],
+    sources: &[(&str, &str)],
+) -> String {
+    let mut ret = String::new();
+    for warning in warnings {
+        render(
+            &mut ret,
+            "warning",
+            &warning.kind,
+            warning.rule.as_deref(),
+            warning.span,
+            warning.file.as_deref(),
+            sources,
+        );
+    }
+    ret
+}
@@ -0,0 +1,159 @@
+//! resolves the exception operator (`a - b`) on the grammar AST
+//!
+//! `-` binds like juxtaposition, so `a | b - c` means `( a | b ) - c`, just like the ebnf crate
+//! reads `a | b c` as `( a | b ) c`. Every alternative of the left operand that also is an
+//! alternative of the right one gets removed. Anything that can't be subtracted, e.g. a keyword
+//! from a regex, is kept as is; completing a little too much is better than not completing at all.
+use std::collections::HashSet;
+
+use ebnf::{Grammar, Node, RegexExtKind, SymbolKind};
+
+use super::error::{GrammarError, GrammarErrorKind};
+use super::find_terminal;
+use super::source::{SourceMap, TokenKind, is_exception_marker as is_marker};
+
+// ebnf::Node doesn't implement PartialEq
+fn same_node(n1: &Node, n2: &Node) -> bool {
+    match (n1, n2) {
+        (Node::String(s1), Node::String(s2))
+        | (Node::RegexString(s1), Node::RegexString(s2))
+        | (Node::Terminal(s1), Node::Terminal(s2)) => s1 == s2,
+        (Node::Multiple(nodes1), Node::Multiple(nodes2)) => {
+            nodes1.len() == nodes2.len()
+                && nodes1.iter().zip(nodes2).all(|(n1, n2)| same_node(n1, n2))
+        }
+        (Node::RegexExt(n1, kind1), Node::RegexExt(n2, kind2)) => {
+            matches!(
+                (kind1, kind2),
+                (RegexExtKind::Repeat0, RegexExtKind::Repeat0)
+                    | (RegexExtKind::Repeat1, RegexExtKind::Repeat1)
+                    | (RegexExtKind::Optional, RegexExtKind::Optional)
+            ) && same_node(n1, n2)
+        }
+        (Node::Symbol(l1, kind1, r1), Node::Symbol(l2, kind2, r2)) => {
+            matches!(
+                (kind1, kind2),
+                (SymbolKind::Concatenation, SymbolKind::Concatenation)
+                    | (SymbolKind::Alternation, SymbolKind::Alternation)
+            ) && same_node(l1, l2)
+                && same_node(r1, r2)
+        }
+        (Node::Group(n1), Node::Group(n2))
+        | (Node::Optional(n1), Node::Optional(n2))
+        | (Node::Repeat(n1), Node::Repeat(n2)) => same_node(n1, n2),
+        (Node::Unknown, Node::Unknown) => true,
+        _ => false,
+    }
+}
+
+/// flattens alternations, groups and rule references into a list of alternatives
+fn alternatives(grammar: &Grammar, node: &Node, visited: &mut HashSet<String>) -> Vec<Node> {
+    match node {
+        Node::Symbol(n1, SymbolKind::Alternation, n2) => {
+            let mut ret = alternatives(grammar, n1, visited);
+            ret.extend(alternatives(grammar, n2, visited));
+            ret
+        }
+        Node::Group(node) => alternatives(grammar, node, visited),
+        Node::Terminal(name)
+            if let Some(expr) = find_terminal(grammar, name)
+                && visited.insert(name.to_string()) =>
+        {
+            alternatives(grammar, &expr.rhs, visited)
+        }
+        _ => vec![node.clone()],
+    }
+}
+
+struct Resolver<'a, 'b> {
+    grammar: &'a Grammar,
+    source_map: &'a SourceMap<'b>,
+    errors: &'a mut Vec<GrammarError>,
+}
+
+impl Resolver<'_, '_> {
+    fn error(&mut self, rule: &str, msg: &str) {
+        self.errors.push(GrammarError::new(
+            GrammarErrorKind::UnsupportedConstruct(msg.to_string()),
+            Some(rule),
+            self.source_map.find_in_rule(rule, TokenKind::Punct, "-"),
+        ));
+    }
+
+    fn except(&mut self, rule: &str, minuend: Node, subtrahend: &Node) -> Node {
+        let excluded = alternatives(self.grammar, subtrahend, &mut HashSet::new());
+        let alts = alternatives(self.grammar, &minuend, &mut HashSet::new());
+        let mut kept: Vec<Node> = alts
+            .iter()
+            .filter(|alt| !excluded.iter().any(|ex| same_node(alt, ex)))
+            .cloned()
+            .collect();
+        if kept.len() == alts.len() {
+            return minuend;
+        }
+        let Some(last) = kept.pop() else {
+            self.error(rule, "exception excludes every alternative");
+            return minuend;
+        };
+        let alternation = kept.into_iter().rev().fold(last, |acc, alt| {
+            Node::Symbol(Box::new(alt), SymbolKind::Alternation, Box::new(acc))
+        });
+        Node::Group(Box::new(alternation))
+    }
+
+    fn resolve(&mut self, rule: &str, node: &mut Node) {
+        match node {
+            Node::Multiple(nodes) => {
+                nodes
+                    .iter_mut()
+                    .filter(|node| !is_marker(node))
+                    .for_each(|node| self.resolve(rule, node));
+                while let Some(i) = nodes.iter().position(is_marker) {
+                    if i == 0 || i + 1 == nodes.len() {
+                        self.error(rule, "exception is missing an operand");
+                        nodes.remove(i);
+                        continue;
+                    }
+                    let subtrahend = nodes.remove(i + 1);
+                    nodes.remove(i);
+                    let minuend = std::mem::replace(&mut nodes[i - 1], Node::Unknown);
+                    nodes[i - 1] = self.except(rule, minuend, &subtrahend);
+                }
+                if nodes.len() == 1 {
+                    *node = nodes.pop().unwrap();
+                }
+            }
+            node if is_marker(node) => {
+                self.error(rule, "exception is missing an operand");
+                // keeps handle_node away from the marker
+                *node = Node::Multiple(Vec::new());
+            }
+            Node::RegexExt(node, _)
+            | Node::Optional(node)
+            | Node::Group(node)
+            | Node::Repeat(node) => self.resolve(rule, node),
+            Node::Symbol(n1, _, n2) => {
+                self.resolve(rule, n1);
+                self.resolve(rule, n2);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// replaces every exception in `grammar` with the alternatives that are left over
+pub(super) fn resolve_exceptions(
+    grammar: &mut Grammar,
+    source_map: &SourceMap,
+    errors: &mut Vec<GrammarError>,
+) {
+    let orig = grammar.clone();
+    let mut resolver = Resolver {
+        grammar: &orig,
+        source_map,
+        errors,
+    };
+    for expr in grammar.expressions.iter_mut() {
+        resolver.resolve(&expr.lhs, &mut expr.rhs);
+    }
+}
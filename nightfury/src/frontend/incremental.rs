@@ -0,0 +1,437 @@
+//! recompiling a grammar after some of its rules changed
+//!
+//! Every rule gets a fingerprint of its own definition and one covering every rule it can reach.
+//! Rules call each other by name, so the template handle_node builds for a rule only depends on
+//! its own definition. Templates are kept around and handed back out by later compilations as
+//! long as the rule they were built for didn't change. The graphs built this way are the same as
+//! the ones a full rebuild would create, as handle_node would have built the exact same templates
+//! again. Minifying the graph of each entry still happens every time.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use ebnf::{Grammar, Node};
+
+use super::annotation::Annotations;
+use super::{CompileOptions, CompiledGrammar, GrammarError, build_entries, parse_grammar};
+use crate::{FSMLock, FSMNode, FSMRc};
+
+type Template = FSMRc<FSMLock<FSMNode>>;
+
+#[derive(Debug, Clone, PartialEq)]
+struct RuleFingerprint {
+    /// covers the rule's own definition, including the annotations of its keywords
+    own: u64,
+    /// covers every rule it can reach, itself included
+    closure: u64,
+}
+
+/// the templates handle_node may take instead of building a rule again
+#[derive(Default)]
+pub(crate) struct RuleCache {
+    rules: HashMap<String, RuleFingerprint>,
+    /// along with the own fingerprint of the rule they were built for
+    templates: HashMap<String, (u64, Template)>,
+}
+
+impl RuleCache {
+    /// the template stored for `name`, as long as the rule didn't change
+    pub fn template(&self, name: &str) -> Option<&Template> {
+        let (own, template) = self.templates.get(name)?;
+        (*own == self.rules.get(name)?.own).then_some(template)
+    }
+
+    /// keeps the template built for `name`
+    pub fn store(&mut self, name: &str, template: Template) {
+        if let Some(rule) = self.rules.get(name) {
+            self.templates
+                .insert(name.to_string(), (rule.own, template));
+        }
+    }
+
+    /// drops every template a rule's change made useless
+    fn evict(&mut self) {
+        let rules = &self.rules;
+        self.templates
+            .retain(|name, (own, _)| rules.get(name).is_some_and(|rule| rule.own == *own));
+    }
+}
+
+fn hash_node(node: &Node, annotations: &Annotations, hasher: &mut DefaultHasher) {
+    std::mem::discriminant(node).hash(hasher);
+    match node {
+        // the literal's annotation tag is an index, which shifts whenever an earlier annotation
+        // gets added or removed
+        Node::String(str) => format!("{:?}", annotations.keyword(str)).hash(hasher),
+        Node::RegexString(str) | Node::Terminal(str) => str.hash(hasher),
+        Node::Multiple(nodes) => {
+            nodes.len().hash(hasher);
+            for node in nodes {
+                hash_node(node, annotations, hasher);
+            }
+        }
+        Node::RegexExt(node, kind) => {
+            format!("{kind:?}").hash(hasher);
+            hash_node(node, annotations, hasher);
+        }
+        Node::Symbol(n1, kind, n2) => {
+            format!("{kind:?}").hash(hasher);
+            hash_node(n1, annotations, hasher);
+            hash_node(n2, annotations, hasher);
+        }
+        Node::Group(node) | Node::Optional(node) | Node::Repeat(node) => {
+            hash_node(node, annotations, hasher)
+        }
+        Node::Unknown => {}
+    }
+}
+
+fn referenced_rules<'a>(node: &'a Node, ret: &mut Vec<&'a str>) {
+    match node {
+        Node::Terminal(name) => ret.push(name),
+        Node::Multiple(nodes) => nodes.iter().for_each(|node| referenced_rules(node, ret)),
+        Node::RegexExt(node, _) | Node::Group(node) | Node::Optional(node) | Node::Repeat(node) => {
+            referenced_rules(node, ret)
+        }
+        Node::Symbol(n1, _, n2) => {
+            referenced_rules(n1, ret);
+            referenced_rules(n2, ret);
+        }
+        Node::String(_) | Node::RegexString(_) | Node::Unknown => {}
+    }
+}
+
+/// fingerprints of the rules' own definitions, see [RuleFingerprint::own]
+fn own_fingerprints(grammar: &Grammar, annotations: &Annotations) -> HashMap<String, u64> {
+    let mut ret = HashMap::new();
+    // handle_node only ever looks at the first definition of a rule
+    for expr in &grammar.expressions {
+        ret.entry(expr.lhs.clone()).or_insert_with(|| {
+            let mut hasher = DefaultHasher::new();
+            hash_node(&expr.rhs, annotations, &mut hasher);
+            hasher.finish()
+        });
+    }
+    ret
+}
+
+fn fingerprint_rules(
+    grammar: &Grammar,
+    annotations: &Annotations,
+) -> HashMap<String, RuleFingerprint> {
+    let own = own_fingerprints(grammar, annotations);
+    let mut deps = HashMap::new();
+    for expr in &grammar.expressions {
+        deps.entry(expr.lhs.as_str()).or_insert_with(|| {
+            let mut referenced = Vec::new();
+            referenced_rules(&expr.rhs, &mut referenced);
+            referenced
+        });
+    }
+    own.iter()
+        .map(|(name, own_hash)| {
+            let mut reached = HashSet::from([name.as_str()]);
+            let mut stack = vec![name.as_str()];
+            while let Some(rule) = stack.pop() {
+                for dep in deps.get(rule).into_iter().flatten() {
+                    if reached.insert(dep) {
+                        stack.push(dep);
+                    }
+                }
+            }
+            let mut reaches: Vec<&str> = reached.into_iter().collect();
+            reaches.sort();
+            let mut hasher = DefaultHasher::new();
+            for rule in &reaches {
+                rule.hash(&mut hasher);
+                own.get(*rule).hash(&mut hasher);
+            }
+            let fingerprint = RuleFingerprint {
+                own: *own_hash,
+                closure: hasher.finish(),
+            };
+            (name.clone(), fingerprint)
+        })
+        .collect()
+}
+
+/// what changed between two compilations of an [IncrementalCompiler], rule names being sorted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleChanges {
+    /// rules whose definition in the grammar got edited, added or removed
+    pub changed: Vec<String>,
+    /// rules whose graphs behave differently now, being the changed ones and every rule
+    /// depending on one
+    /// Rules introduced by the compiler itself, e.g. to eliminate left recursion, show up here as
+    /// well, while rules that got inlined into others, like lexical ones, don't.
+    pub affected: Vec<String>,
+}
+
+impl RuleChanges {
+    fn between(
+        (old_written, old_rules): (&HashMap<String, u64>, &HashMap<String, RuleFingerprint>),
+        (new_written, new_rules): (&HashMap<String, u64>, &HashMap<String, RuleFingerprint>),
+    ) -> Self {
+        let mut changed: Vec<String> = new_written
+            .iter()
+            .filter(|(name, own)| old_written.get(*name) != Some(own))
+            .map(|(name, _)| name.clone())
+            .chain(
+                old_written
+                    .keys()
+                    .filter(|name| !new_written.contains_key(*name))
+                    .cloned(),
+            )
+            .collect();
+        changed.sort();
+        let mut affected: Vec<String> = new_rules
+            .iter()
+            .filter(|(name, rule)| {
+                old_rules
+                    .get(*name)
+                    .is_none_or(|old| old.closure != rule.closure)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        affected.sort();
+        Self { changed, affected }
+    }
+}
+
+/// compiles successive versions of a grammar, only building the rules affected by a change again
+///
+/// The result of [IncrementalCompiler::compile] is the same as the one of
+/// [super::compile_ebnf_with], and [IncrementalCompiler::changes] tells which rules changed since
+/// the last successful compilation.
+#[derive(Default)]
+pub struct IncrementalCompiler {
+    cache: RuleCache,
+    /// [own_fingerprints] of the rules as written
+    written: HashMap<String, u64>,
+    changes: RuleChanges,
+}
+
+impl IncrementalCompiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// compiles `ebnf`, reusing whatever the previous compilations built for unchanged rules
+    /// A grammar with errors leaves the compiler as it was.
+    pub fn compile(
+        &mut self,
+        ebnf: &str,
+        options: &CompileOptions,
+    ) -> Result<CompiledGrammar, Vec<GrammarError>> {
+        let mut warnings = Vec::new();
+        let parsed = parse_grammar(ebnf, options, &mut warnings)?;
+        let written = own_fingerprints(&parsed.written, &parsed.annotations);
+        let rules = fingerprint_rules(&parsed.grammar, &parsed.annotations);
+        let changes = RuleChanges::between((&self.written, &self.cache.rules), (&written, &rules));
+        let previous = std::mem::replace(&mut self.cache.rules, rules);
+        match build_entries(parsed, options.entries, Some(&mut self.cache)) {
+            Ok(entries) => {
+                self.cache.evict();
+                self.written = written;
+                self.changes = changes;
+                Ok(CompiledGrammar { entries, warnings })
+            }
+            Err(errors) => {
+                self.cache.rules = previous;
+                Err(errors)
+            }
+        }
+    }
+
+    /// the rules that changed with the last successful [IncrementalCompiler::compile], every rule
+    /// counting as changed the first time
+    pub fn changes(&self) -> &RuleChanges {
+        &self.changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::{EntryPoints, ToCSV};
+
+    const GRAMMAR: &str = r#"
+    main ::= { statement };
+    statement ::= ( assign ';' ) | block;
+    assign ::= ident '=' value;
+    value ::= number | ident | 'null';
+    ident ::= #'^[a-z]+$';
+    number ::= #'^[0-9]+$';
+    block ::= '{' @closing("}") { statement } '}';
+    "#;
+
+    /// the graph with node ids replaced by the order they are reached in
+    fn canonical(entries: &EntryPoints) -> Vec<String> {
+        let mut ret = Vec::new();
+        for name in entries.names() {
+            let root = entries.get(Some(name)).unwrap().clone();
+            let mut ids = HashMap::from([(root.borrow().id(), 0)]);
+            let mut queue = VecDeque::from([root]);
+            while let Some(node) = queue.pop_front() {
+                let node = node.borrow();
+                let mut line = format!("{name} {} {}", node.is_done(), node.value.to_csv().trim());
+                // a call's callee comes first, just like in the csv
+                for child in node.callee.iter().chain(&node.children) {
+                    let next = ids.len();
+                    let id = *ids.entry(child.borrow().id()).or_insert_with(|| {
+                        queue.push_back(child.clone());
+                        next
+                    });
+                    line.push_str(&format!(" {id}"));
+                }
+                ret.push(line);
+            }
+        }
+        ret
+    }
+
+    fn full_rebuild(ebnf: &str, entries: &[&str]) -> Vec<String> {
+        canonical(&super::super::compile_ebnf(ebnf, entries).unwrap().entries)
+    }
+
+    #[test]
+    fn test_same_as_full_rebuild() {
+        let options = CompileOptions {
+            entries: &["main", "statement"],
+            ..Default::default()
+        };
+        let mut compiler = IncrementalCompiler::new();
+        let first = compiler.compile(GRAMMAR, &options).unwrap();
+        assert_eq!(
+            full_rebuild(GRAMMAR, options.entries),
+            canonical(&first.entries)
+        );
+        let edited = GRAMMAR.replace("'null'", "'null' | 'true'");
+        let second = compiler.compile(&edited, &options).unwrap();
+        assert_eq!(
+            full_rebuild(&edited, options.entries),
+            canonical(&second.entries)
+        );
+        assert_ne!(canonical(&first.entries), canonical(&second.entries));
+    }
+
+    #[test]
+    fn test_unchanged_rules_reused() {
+        let mut compiler = IncrementalCompiler::new();
+        compiler
+            .compile(GRAMMAR, &CompileOptions::default())
+            .unwrap();
+        let template =
+            |compiler: &IncrementalCompiler, name: &str| compiler.cache.templates[name].1.clone();
+        let number = template(&compiler, "number");
+        let value = template(&compiler, "value");
+        let assign = template(&compiler, "assign");
+        let edited = GRAMMAR.replace("'=' value", "':=' value");
+        compiler
+            .compile(&edited, &CompileOptions::default())
+            .unwrap();
+        assert!(FSMRc::ptr_eq(&number, &template(&compiler, "number")));
+        assert!(FSMRc::ptr_eq(&value, &template(&compiler, "value")));
+        assert!(!FSMRc::ptr_eq(&assign, &template(&compiler, "assign")));
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut compiler = IncrementalCompiler::new();
+        compiler
+            .compile(GRAMMAR, &CompileOptions::default())
+            .unwrap();
+        assert_eq!(7, compiler.changes().changed.len());
+        assert_eq!(compiler.changes().changed, compiler.changes().affected);
+
+        compiler
+            .compile(GRAMMAR, &CompileOptions::default())
+            .unwrap();
+        assert_eq!(&RuleChanges::default(), compiler.changes());
+
+        let edited = GRAMMAR.replace("'null'", "'null' | 'true'");
+        compiler
+            .compile(&edited, &CompileOptions::default())
+            .unwrap();
+        assert_eq!(
+            &RuleChanges {
+                changed: vec!["value".to_string()],
+                affected: ["assign", "block", "main", "statement", "value"]
+                    .map(str::to_string)
+                    .to_vec(),
+            },
+            compiler.changes()
+        );
+
+        // shifting the annotation indices of other keywords doesn't change them
+        let annotated = edited.replace("'true'", "'true' @short(\"tr\")");
+        let annotated = annotated.replace("'{' @closing", "'{' @short(\"b\") @closing");
+        compiler
+            .compile(&annotated, &CompileOptions::default())
+            .unwrap();
+        assert_eq!(
+            vec!["block".to_string(), "value".to_string()],
+            compiler.changes().changed
+        );
+
+        let removed = annotated
+            .replace("number | ", "")
+            .replace("number ::= #'^[0-9]+$';", "");
+        compiler
+            .compile(&removed, &CompileOptions::default())
+            .unwrap();
+        assert_eq!(
+            vec!["number".to_string(), "value".to_string()],
+            compiler.changes().changed
+        );
+    }
+
+    #[test]
+    fn test_errors_keep_state() {
+        let mut compiler = IncrementalCompiler::new();
+        compiler
+            .compile(GRAMMAR, &CompileOptions::default())
+            .unwrap();
+        let broken = GRAMMAR.replace("number | ident", "number | undefined");
+        assert!(
+            compiler
+                .compile(&broken, &CompileOptions::default())
+                .is_err()
+        );
+        let unknown_entry = CompileOptions {
+            entries: &["nope"],
+            ..Default::default()
+        };
+        assert!(compiler.compile(GRAMMAR, &unknown_entry).is_err());
+        compiler
+            .compile(GRAMMAR, &CompileOptions::default())
+            .unwrap();
+        assert_eq!(&RuleChanges::default(), compiler.changes());
+    }
+
+    #[test]
+    fn test_js_edits() {
+        let js = include_str!("../../../js.ebnf");
+        let mut compiler = IncrementalCompiler::new();
+        compiler.compile(js, &CompileOptions::default()).unwrap();
+        let edits = [
+            ("'/=';", "'/=' | '%=';"),
+            ("'null' | 'undefined'", "'null' | 'undefined' | 'NaN'"),
+            ("#'[_a-zA-Z][_a-zA-Z0-9]*'", "#'[_$a-zA-Z][_$a-zA-Z0-9]*'"),
+        ];
+        let mut edited = js.to_string();
+        for (from, to) in edits {
+            assert!(edited.contains(from));
+            edited = edited.replace(from, to);
+            let compiled = compiler
+                .compile(&edited, &CompileOptions::default())
+                .unwrap();
+            assert_eq!(full_rebuild(&edited, &[]), canonical(&compiled.entries));
+        }
+        assert_eq!(vec!["identifier".to_string()], compiler.changes().changed);
+        assert!(!compiler.changes().affected.contains(&"bool".to_string()));
+        assert!(compiler.changes().affected.contains(&"main".to_string()));
+    }
+}
@@ -0,0 +1,352 @@
+//! ISO/IEC 14977 EBNF, the syntax of many grammars published along with standards
+//!
+//! ```text
+//! letter = "a" | "b" | "c";
+//! identifier = letter, { letter | digit }-;
+//! assignment = identifier, [ ":", type ], "=", expression - "nil";
+//! ```
+//!
+//! Meta identifiers may contain spaces, `syntax rule` becomes the rule `syntax_rule`. Besides the
+//! standard's `{ .. }-` for one or more repetitions, all of its alternative representations
+//! (`/` and `!`, `(/ /)`, `(: :)`, `.`) are understood. Special sequences (`? .. ?`) can't be
+//! turned into anything a cursor could complete and are reported as unsupported.
+use ebnf::{Node, RegexExtKind};
+
+use super::error::{GrammarError, GrammarErrorKind, GrammarWarning};
+use super::syntax::{
+    GrammarFrontend, GrammarIr, Scanner, alternation, exception, literal, sequence,
+};
+
+#[derive(Debug)]
+pub struct Iso14977;
+
+impl GrammarFrontend for Iso14977 {
+    fn name(&self) -> &'static str {
+        "iso14977"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["iso.ebnf", "iso14977"]
+    }
+
+    fn lower(
+        &self,
+        source: &str,
+        _dialect: Option<&str>,
+        errors: &mut Vec<GrammarError>,
+        _warnings: &mut Vec<GrammarWarning>,
+    ) -> Option<GrammarIr> {
+        let errors_before = errors.len();
+        let mut parser = Parser {
+            scanner: Scanner::new(source, errors),
+        };
+        let rules = parser.rules();
+        if parser.scanner.errors.len() > errors_before {
+            return None;
+        }
+        Some(GrammarIr::new(rules))
+    }
+}
+
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+}
+
+impl Parser<'_> {
+    fn skip_trivia(&mut self) {
+        self.scanner.skip_trivia("(*", "*)");
+    }
+
+    /// the next character after whitespace and comments
+    fn peek(&mut self) -> Option<char> {
+        self.skip_trivia();
+        self.scanner.peek()
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        self.skip_trivia();
+        self.scanner.eat(text)
+    }
+
+    fn rules(&mut self) -> Vec<(String, Node)> {
+        let mut rules = Vec::new();
+        while self.peek().is_some() {
+            match self.rule() {
+                Some(rule) => rules.push(rule),
+                // skip to the next rule, so every broken rule gets reported at once
+                None => {
+                    self.scanner.take_while(|c| c != ';' && c != '.');
+                    self.scanner.bump();
+                }
+            }
+            self.scanner.rule = None;
+        }
+        rules
+    }
+
+    fn rule(&mut self) -> Option<(String, Node)> {
+        let Some(name) = self.meta_identifier() else {
+            self.scanner.unexpected("a meta identifier");
+            return None;
+        };
+        self.scanner.rule = Some(name.clone());
+        if !self.eat("=") {
+            self.scanner.unexpected("`=`");
+            return None;
+        }
+        let rhs = self.definitions_list()?;
+        if !self.eat(";") && !self.eat(".") {
+            self.scanner.unexpected("`;`");
+            return None;
+        }
+        Some((name, rhs))
+    }
+
+    /// a letter followed by letters, digits and spaces, which are replaced by `_`
+    fn meta_identifier(&mut self) -> Option<String> {
+        if !self.peek()?.is_ascii_alphabetic() {
+            return None;
+        }
+        let words: Vec<&str> = self
+            .scanner
+            .take_while(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '\t')
+            .split_whitespace()
+            .collect();
+        Some(words.join("_"))
+    }
+
+    fn definitions_list(&mut self) -> Option<Node> {
+        let mut alts = Vec::new();
+        let mut has_empty = false;
+        loop {
+            let definition = self.single_definition()?;
+            match definition {
+                Node::Multiple(nodes) if nodes.is_empty() => has_empty = true,
+                definition => alts.push(definition),
+            }
+            if !self.eat("|") && !self.eat("!") {
+                // `/)` closes an optional sequence instead
+                if self.scanner.rest().starts_with("/)") || !self.eat("/") {
+                    break;
+                }
+            }
+        }
+        // the empty sequence only makes sense as an alternative, an alternation having it as one
+        // of its branches is an optional one
+        Some(match (alts.is_empty(), has_empty) {
+            (true, _) => Node::Multiple(Vec::new()),
+            (false, true) => Node::Optional(Box::new(alternation(alts))),
+            (false, false) => alternation(alts),
+        })
+    }
+
+    fn single_definition(&mut self) -> Option<Node> {
+        let mut terms = Vec::new();
+        if self.ends_definition() {
+            return Some(Node::Multiple(terms));
+        }
+        loop {
+            terms.push(self.syntactic_term()?);
+            if !self.eat(",") {
+                break;
+            }
+        }
+        Some(sequence(terms))
+    }
+
+    /// whether the current definition is the empty sequence
+    fn ends_definition(&mut self) -> bool {
+        match self.peek() {
+            Some('|' | '/' | '!' | ';' | '.' | ']' | '}' | ')') | None => true,
+            Some(':') => self.scanner.rest().starts_with(":)"),
+            _ => false,
+        }
+    }
+
+    fn syntactic_term(&mut self) -> Option<Node> {
+        let factor = self.syntactic_factor()?;
+        if self.eat("-") {
+            let subtrahend = self.syntactic_factor()?;
+            return Some(exception(factor, subtrahend));
+        }
+        Some(factor)
+    }
+
+    /// a primary, optionally preceded by how often it's repeated, e.g. `3 * digit`
+    fn syntactic_factor(&mut self) -> Option<Node> {
+        if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            let start = self.scanner.pos;
+            let count = self.scanner.take_while(|c| c.is_ascii_digit());
+            let Ok(count) = count.parse::<usize>() else {
+                self.scanner
+                    .error_at(start, count.len(), "repetition count out of range");
+                return None;
+            };
+            if !self.eat("*") {
+                self.scanner.unexpected("`*`");
+                return None;
+            }
+            let primary = self.syntactic_primary()?;
+            return Some(Node::Multiple(vec![primary; count]));
+        }
+        self.syntactic_primary()
+    }
+
+    fn syntactic_primary(&mut self) -> Option<Node> {
+        let Some(c) = self.peek() else {
+            self.scanner.unexpected("a syntactic primary");
+            return None;
+        };
+        if self.eat("(/") {
+            return self.bracketed("/)", |node| Node::Optional(Box::new(node)));
+        }
+        if self.eat("(:") {
+            return self.bracketed(":)", |node| Node::Repeat(Box::new(node)));
+        }
+        match c {
+            '[' => {
+                self.scanner.bump();
+                self.bracketed("]", |node| Node::Optional(Box::new(node)))
+            }
+            '{' => {
+                self.scanner.bump();
+                let repeat = self.bracketed("}", |node| node)?;
+                // `{ x }-` is the standard's way of writing one or more repetitions, as opposed to
+                // `{ x } - y`
+                if self.scanner.eat("-") {
+                    Some(Node::RegexExt(Box::new(repeat), RegexExtKind::Repeat1))
+                } else {
+                    Some(Node::Repeat(Box::new(repeat)))
+                }
+            }
+            '(' => {
+                self.scanner.bump();
+                self.bracketed(")", |node| Node::Group(Box::new(node)))
+            }
+            '\'' | '"' => {
+                let start = self.scanner.pos;
+                let text = self.scanner.quoted(c)?;
+                if text.is_empty() {
+                    self.scanner
+                        .error_at(start, 2, "terminal strings can't be empty");
+                    return None;
+                }
+                Some(literal(text))
+            }
+            '?' => {
+                let start = self.scanner.pos;
+                let text = self.scanner.quoted('?')?;
+                self.scanner.error_at_kind(
+                    GrammarErrorKind::UnsupportedConstruct(format!("special sequence `?{text}?`")),
+                    start,
+                    text.len() + 2,
+                );
+                None
+            }
+            c if c.is_ascii_alphabetic() => {
+                let name = self.meta_identifier()?;
+                Some(Node::Terminal(name))
+            }
+            _ => {
+                self.scanner.unexpected("a syntactic primary");
+                None
+            }
+        }
+    }
+
+    /// the definitions list up to `close`, wrapped by `wrap`
+    fn bracketed(&mut self, close: &str, wrap: impl Fn(Node) -> Node) -> Option<Node> {
+        let inner = self.definitions_list()?;
+        if !self.eat(close) {
+            self.scanner.unexpected(&format!("`{close}`"));
+            return None;
+        }
+        Some(wrap(inner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FSMCursor;
+    use crate::frontend::{CompileOptions, compile_ebnf_with};
+
+    fn lower(source: &str) -> Result<Vec<(String, String)>, Vec<GrammarError>> {
+        let mut errors = Vec::new();
+        match Iso14977.lower(source, None, &mut errors, &mut Vec::new()) {
+            Some(ir) => Ok(ir
+                .grammar
+                .expressions
+                .into_iter()
+                .map(|expr| (expr.lhs, format!("{:?}", expr.rhs)))
+                .collect()),
+            None => Err(errors),
+        }
+    }
+
+    #[test]
+    fn test_lower() {
+        let rules = lower(
+            r#"(* a (* nested *) comment *)
+            syntax rule = "a", [ 'b' ] | { c }- ;
+            c = (/ "c" /), (: "d" :), 2 * "e" / ;
+            "#,
+        )
+        .unwrap();
+        assert_eq!("syntax_rule", rules[0].0);
+        assert_eq!(
+            r#"Symbol(Multiple([String("a"), Optional(String("b"))]), Alternation, RegexExt(Terminal("c"), Repeat1))"#,
+            rules[0].1
+        );
+        assert_eq!("c", rules[1].0);
+        assert_eq!(
+            r#"Optional(Multiple([Optional(String("c")), Repeat(String("d")), Multiple([String("e"), String("e")])]))"#,
+            rules[1].1
+        );
+    }
+
+    #[test]
+    fn test_exception() {
+        let rules = lower("letter = 'a' | 'b'. vowel = letter - 'b'.").unwrap();
+        assert_eq!(
+            r#"Multiple([Terminal("letter"), String("\u{2}"), String("b")])"#,
+            rules[1].1
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = lower("a = 'x';\nb = 'y' 'z';\nc = ? any character ?;").unwrap_err();
+        assert_eq!(2, errors.len());
+        assert_eq!(
+            GrammarErrorKind::Syntax("expected `;`, found `'`".to_string()),
+            errors[0].kind
+        );
+        assert_eq!(Some("b"), errors[0].rule.as_deref());
+        assert_eq!(2, errors[0].span.as_ref().unwrap().line);
+        assert!(matches!(
+            errors[1].kind,
+            GrammarErrorKind::UnsupportedConstruct(_)
+        ));
+        assert_eq!(3, errors[1].span.as_ref().unwrap().line);
+        assert!(lower("a = 'x").is_err());
+        assert!(lower("a = (* unterminated").is_err());
+    }
+
+    #[test]
+    fn test_compile() {
+        let compiled = compile_ebnf_with(
+            "statement = 'begin', { 'skip', ';' }, 'end' | 'exit' ;",
+            &CompileOptions {
+                frontend: Some(&Iso14977),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut cursor = FSMCursor::new(compiled.entries.default_root());
+        assert_eq!("begin", cursor.advance('b').unwrap());
+        assert_eq!("skip", cursor.advance('s').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert_eq!("end", cursor.advance('e').unwrap());
+    }
+}
@@ -0,0 +1,136 @@
+//! layout rules like `@layout ::= #'[ \t\n]*';` and `@separator ::= ' ';`
+//!
+//! `@layout` is what the user may type between any two tokens without the cursor complaining,
+//! `@separator` is the output style: what gets inserted after every keyword ending in a word
+//! character, so keywords don't have to carry their own whitespace. Keywords can pick their own
+//! separator with `@separator("..")`, see [super::annotation]. `@indent` is what one level of
+//! indentation inside a `@block` keyword looks like, e.g. `'    '`. `@case` makes keywords
+//! case-insensitive and says how they're written once expanded: `'upper'`, `'lower'`, `'keep'`
+//! (as in the grammar) or `'typed'` (like the user typed them).
+//!
+//! All of them are cut out of the source before any other pass apart from dialect selection gets to
+//! see it.
+use regex::Regex;
+
+use super::error::{GrammarError, GrammarErrorKind};
+use super::source::{SourceMap, Token, TokenKind, blank, tokenize};
+use crate::esc_seq::try_resolve_escape_sequences;
+use crate::fsm::Casing;
+
+const LAYOUT_RULE: &str = "layout";
+const SEPARATOR_RULE: &str = "separator";
+const INDENT_RULE: &str = "indent";
+const CASE_RULE: &str = "case";
+
+/// the layout rules of a grammar along with its source, which has them blanked out
+#[derive(Debug, Default)]
+pub(crate) struct Layout {
+    pub text: String,
+    /// regex matching whatever may go between two tokens
+    pub layout: Option<String>,
+    /// inserted after keywords ending in a word character
+    pub separator: String,
+    /// one level of indentation
+    pub indent: Option<String>,
+    /// set if keywords are case-insensitive
+    pub case: Option<Casing>,
+}
+
+/// cuts `@layout`, `@separator`, `@indent` and `@case` rules out of `src`, anything malformed ends
+/// up in `errors`
+pub(crate) fn extract_layout(
+    src: &str,
+    source_map: &SourceMap,
+    errors: &mut Vec<GrammarError>,
+) -> Layout {
+    let tokens = tokenize(src);
+    let mut ret = Layout {
+        text: src.to_string(),
+        ..Default::default()
+    };
+    let mut separator = None;
+    let mut i = 0;
+    while i < tokens.len() {
+        let [at, name, assign, ..] = &tokens[i..] else {
+            break;
+        };
+        if !at.is_punct("@")
+            || name.kind != TokenKind::Ident
+            || name.offset != at.end()
+            || !(assign.is_punct("::=") || assign.is_punct("="))
+        {
+            i += 1;
+            continue;
+        }
+        let rhs_start = i + 3;
+        let end = tokens[rhs_start..]
+            .iter()
+            .position(|token| token.is_punct(";"))
+            .map_or(tokens.len(), |end| rhs_start + end);
+        let rhs = &tokens[rhs_start..end];
+        let range = at.offset..tokens.get(end).map_or(src.len(), Token::end);
+        let mut error = |kind: GrammarErrorKind| {
+            errors.push(GrammarError::new(
+                kind,
+                Some(&format!("@{}", name.text)),
+                Some(source_map.span(range.start, range.len())),
+            ));
+        };
+        let defined = match name.text {
+            LAYOUT_RULE => ret.layout.is_some(),
+            SEPARATOR_RULE => separator.is_some(),
+            INDENT_RULE => ret.indent.is_some(),
+            CASE_RULE => ret.case.is_some(),
+            _ => false,
+        };
+        match (name.text, rhs) {
+            _ if defined => error(GrammarErrorKind::InvalidLayout(format!(
+                "`@{}` is defined more than once",
+                name.text
+            ))),
+            (LAYOUT_RULE, [regex]) if regex.kind == TokenKind::Regex => {
+                match Regex::new(regex.content()) {
+                    Ok(_) => ret.layout = Some(regex.content().to_string()),
+                    Err(err) => error(GrammarErrorKind::InvalidRegex {
+                        regex: regex.content().to_string(),
+                        reason: err.to_string(),
+                    }),
+                }
+            }
+            (LAYOUT_RULE, _) => error(GrammarErrorKind::InvalidLayout(
+                "`@layout` has to be a single regex, e.g. #'[ \\t\\n]*'".to_string(),
+            )),
+            (SEPARATOR_RULE | INDENT_RULE, [literal]) if literal.kind == TokenKind::Str => {
+                match try_resolve_escape_sequences(literal.content()) {
+                    Ok(resolved) if name.text == SEPARATOR_RULE => separator = Some(resolved),
+                    Ok(resolved) => ret.indent = Some(resolved),
+                    Err(c) => error(GrammarErrorKind::InvalidEscape(c)),
+                }
+            }
+            (CASE_RULE, [literal]) if literal.kind == TokenKind::Str => {
+                match Casing::from_name(literal.content()) {
+                    Some(case) => ret.case = Some(case),
+                    None => error(GrammarErrorKind::InvalidLayout(format!(
+                        "`@case` has to be one of {}",
+                        Casing::ALL
+                            .map(|case| format!("'{}'", case.name()))
+                            .join(", ")
+                    ))),
+                }
+            }
+            (SEPARATOR_RULE | INDENT_RULE | CASE_RULE, _) => {
+                error(GrammarErrorKind::InvalidLayout(format!(
+                    "`@{}` has to be a single string literal",
+                    name.text
+                )))
+            }
+            (other, _) => error(GrammarErrorKind::InvalidLayout(format!(
+                "unknown layout rule `@{other}`"
+            ))),
+        }
+        blank(&mut ret.text, range);
+        i = end + 1;
+    }
+    ret.separator = separator.unwrap_or_default();
+    ret
+}
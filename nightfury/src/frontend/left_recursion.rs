@@ -0,0 +1,355 @@
+//! rewrites left recursion into repetition
+//!
+//! handle_node descends into rules in the order their symbols appear, so a rule that starts with
+//! itself, directly or by way of other rules, never gets past its first symbol. Grammars copied
+//! from language specs are full of those, e.g. `expr ::= ( expr '+' term ) | term;`.
+//! Every group of rules that can reach each other through their leftmost symbols is run through
+//! Paull's algorithm, which turns `a ::= ( a x ) | b;` into `a ::= b { x };`.
+use std::collections::HashSet;
+
+use ebnf::{Grammar, Node, RegexExtKind, SymbolKind};
+
+use super::error::{GrammarError, GrammarErrorKind, GrammarWarning, GrammarWarningKind};
+use super::source::SourceMap;
+
+/// a rule body split up into its alternatives, each of them a flat sequence
+type Alternatives = Vec<Vec<Node>>;
+
+/// nonterminals `node` may start with and whether it may match nothing at all
+fn left_corners(node: &Node, nullable: &HashSet<String>) -> (HashSet<String>, bool) {
+    match node {
+        Node::String(_) | Node::RegexString(_) | Node::Unknown => (HashSet::new(), false),
+        Node::Terminal(name) => (HashSet::from([name.clone()]), nullable.contains(name)),
+        Node::Multiple(nodes) => seq_left_corners(nodes, nullable),
+        Node::Symbol(n1, SymbolKind::Concatenation, n2) => {
+            seq_left_corners([n1.as_ref(), n2.as_ref()], nullable)
+        }
+        Node::Symbol(n1, SymbolKind::Alternation, n2) => {
+            let (mut corners, empty1) = left_corners(n1, nullable);
+            let (corners2, empty2) = left_corners(n2, nullable);
+            corners.extend(corners2);
+            (corners, empty1 || empty2)
+        }
+        Node::Group(node) | Node::RegexExt(node, RegexExtKind::Repeat1) => {
+            left_corners(node, nullable)
+        }
+        Node::Optional(node) | Node::Repeat(node) | Node::RegexExt(node, _) => {
+            (left_corners(node, nullable).0, true)
+        }
+    }
+}
+
+fn seq_left_corners<'a>(
+    nodes: impl IntoIterator<Item = &'a Node>,
+    nullable: &HashSet<String>,
+) -> (HashSet<String>, bool) {
+    let mut ret = HashSet::new();
+    for node in nodes {
+        let (corners, empty) = left_corners(node, nullable);
+        ret.extend(corners);
+        if !empty {
+            return (ret, false);
+        }
+    }
+    (ret, true)
+}
+
+/// rules that may match nothing at all
+fn nullable_rules(grammar: &Grammar) -> HashSet<String> {
+    let mut ret = HashSet::new();
+    loop {
+        let before = ret.len();
+        for expr in &grammar.expressions {
+            if !ret.contains(&expr.lhs) && left_corners(&expr.rhs, &ret).1 {
+                ret.insert(expr.lhs.clone());
+            }
+        }
+        if ret.len() == before {
+            return ret;
+        }
+    }
+}
+
+/// groups of rules that can start with each other, rules that aren't part of such a cycle are
+/// left out; both the groups and their rules are in grammar order
+fn left_recursive_groups(grammar: &Grammar, nullable: &HashSet<String>) -> Vec<Vec<String>> {
+    let names: Vec<&str> = grammar
+        .expressions
+        .iter()
+        .map(|expr| expr.lhs.as_str())
+        .collect();
+    let edges: Vec<Vec<usize>> = grammar
+        .expressions
+        .iter()
+        .map(|expr| {
+            let corners = left_corners(&expr.rhs, nullable).0;
+            (0..names.len())
+                .filter(|i| corners.contains(names[*i]))
+                .collect()
+        })
+        .collect();
+    let reachable: Vec<HashSet<usize>> = (0..names.len())
+        .map(|start| {
+            let mut seen = HashSet::new();
+            let mut todo = edges[start].clone();
+            while let Some(cur) = todo.pop() {
+                if seen.insert(cur) {
+                    todo.extend(&edges[cur]);
+                }
+            }
+            seen
+        })
+        .collect();
+
+    let mut grouped: HashSet<usize> = HashSet::new();
+    let mut ret = Vec::new();
+    for i in 0..names.len() {
+        if grouped.contains(&i) || !reachable[i].contains(&i) {
+            continue;
+        }
+        let group: Vec<usize> = (0..names.len())
+            .filter(|j| reachable[i].contains(j) && reachable[*j].contains(&i))
+            .collect();
+        grouped.extend(&group);
+        ret.push(group.into_iter().map(|j| names[j].to_string()).collect());
+    }
+    ret
+}
+
+fn alternatives(node: Node) -> Vec<Node> {
+    match node {
+        Node::Symbol(n1, SymbolKind::Alternation, n2) => {
+            let mut ret = alternatives(*n1);
+            ret.extend(alternatives(*n2));
+            ret
+        }
+        node => vec![node],
+    }
+}
+
+/// splits the leading constructs of `seq` up into alternatives until every alternative either
+/// starts with a plain symbol or can't start with a rule of `group` anymore
+fn expose(
+    mut seq: Vec<Node>,
+    group: &[String],
+    nullable: &HashSet<String>,
+    seen: &mut HashSet<String>,
+    out: &mut Alternatives,
+) {
+    // skipping a nullable repetition leads right back to where it started
+    if !seen.insert(format!("{seq:?}")) {
+        return;
+    }
+    let reaches_group = seq_left_corners(&seq, nullable)
+        .0
+        .iter()
+        .any(|corner| group.contains(corner));
+    if !reaches_group
+        || matches!(
+            seq.first(),
+            None | Some(Node::String(_) | Node::RegexString(_) | Node::Terminal(_) | Node::Unknown)
+        )
+    {
+        out.push(seq);
+        return;
+    }
+    let rest = seq.split_off(1);
+    let with_rest = |nodes: Vec<Node>| nodes.into_iter().chain(rest.iter().cloned()).collect();
+    let expanded: Vec<Vec<Node>> = match seq.pop().unwrap() {
+        Node::Multiple(nodes) => vec![with_rest(nodes)],
+        Node::Symbol(n1, SymbolKind::Concatenation, n2) => vec![with_rest(vec![*n1, *n2])],
+        node @ Node::Symbol(_, SymbolKind::Alternation, _) => alternatives(node)
+            .into_iter()
+            .map(|alt| with_rest(vec![alt]))
+            .collect(),
+        Node::Group(node) => vec![with_rest(vec![*node])],
+        Node::Optional(node) | Node::RegexExt(node, RegexExtKind::Optional) => {
+            vec![with_rest(vec![*node]), rest.clone()]
+        }
+        Node::Repeat(node) | Node::RegexExt(node, RegexExtKind::Repeat0) => vec![
+            with_rest(vec![(*node).clone(), Node::Repeat(node)]),
+            rest.clone(),
+        ],
+        Node::RegexExt(node, RegexExtKind::Repeat1) => {
+            vec![with_rest(vec![(*node).clone(), Node::Repeat(node)])]
+        }
+        Node::String(_) | Node::RegexString(_) | Node::Terminal(_) | Node::Unknown => {
+            unreachable!("plain symbols are never expanded")
+        }
+    };
+    for seq in expanded {
+        expose(seq, group, nullable, seen, out);
+    }
+}
+
+fn sequence(mut nodes: Vec<Node>) -> Node {
+    if nodes.len() == 1 {
+        nodes.pop().unwrap()
+    } else {
+        Node::Multiple(nodes)
+    }
+}
+
+/// turns `alts` back into a single node, None if every alternative is empty
+fn alternation(alts: &Alternatives) -> Option<Node> {
+    let nodes: Vec<Node> = alts
+        .iter()
+        .filter(|alt| !alt.is_empty())
+        .map(|alt| sequence(alt.clone()))
+        .collect();
+    let cnt = nodes.len();
+    let node = nodes
+        .into_iter()
+        .rev()
+        .reduce(|acc, node| Node::Symbol(Box::new(node), SymbolKind::Alternation, Box::new(acc)))?;
+    let node = if cnt > 1 {
+        Node::Group(Box::new(node))
+    } else {
+        node
+    };
+    Some(if alts.iter().any(Vec::is_empty) {
+        Node::Optional(Box::new(node))
+    } else {
+        node
+    })
+}
+
+/// rewrites every left-recursive rule of `grammar` into one using repetition, reporting each of
+/// them as a warning; left recursion that can't be rewritten ends up in `errors`
+pub(crate) fn eliminate_left_recursion(
+    grammar: &mut Grammar,
+    source_map: &SourceMap,
+    errors: &mut Vec<GrammarError>,
+    warnings: &mut Vec<GrammarWarning>,
+) {
+    let nullable = nullable_rules(grammar);
+    let mut failed = HashSet::new();
+    for group in left_recursive_groups(grammar, &nullable) {
+        // rules of the group already processed, in a form that doesn't start with any earlier
+        // rule of the group
+        let mut processed: Vec<(String, Alternatives)> = Vec::new();
+        for name in &group {
+            let expr = grammar
+                .expressions
+                .iter_mut()
+                .find(|expr| &expr.lhs == name)
+                .unwrap();
+            let mut alts = Vec::new();
+            expose(
+                vec![expr.rhs.clone()],
+                &group,
+                &nullable,
+                &mut HashSet::new(),
+                &mut alts,
+            );
+            // substitute earlier rules until none of them leads anymore, each round only leaves
+            // rules behind that come later in the group
+            while alts.iter().any(|alt| {
+                matches!(alt.first(), Some(Node::Terminal(lead))
+                    if processed.iter().any(|(name, _)| name == lead))
+            }) {
+                let mut seen = HashSet::new();
+                let mut substituted = Vec::new();
+                for alt in alts {
+                    let lead_alts = match alt.first() {
+                        Some(Node::Terminal(lead)) => processed
+                            .iter()
+                            .find(|(name, _)| name == lead)
+                            .map(|(_, alts)| alts),
+                        _ => None,
+                    };
+                    match lead_alts {
+                        Some(lead_alts) => {
+                            for lead_alt in lead_alts {
+                                let seq = lead_alt.iter().chain(&alt[1..]).cloned().collect();
+                                expose(seq, &group, &nullable, &mut seen, &mut substituted);
+                            }
+                        }
+                        None => substituted.push(alt),
+                    }
+                }
+                alts = substituted;
+            }
+
+            let (recursive, bases): (Alternatives, Alternatives) = alts
+                .into_iter()
+                .partition(|alt| matches!(alt.first(), Some(Node::Terminal(lead)) if lead == name));
+            if recursive.is_empty() {
+                processed.push((name.clone(), bases));
+                continue;
+            }
+            if bases.is_empty() {
+                errors.push(GrammarError::new(
+                    GrammarErrorKind::LeftRecursion(format!(
+                        "every alternative of `{name}` starts with `{name}` again"
+                    )),
+                    Some(name),
+                    source_map.rule_span(name),
+                ));
+                failed.insert(name.clone());
+                processed.push((name.clone(), recursive));
+                continue;
+            }
+            let tails: Alternatives = recursive
+                .into_iter()
+                .map(|alt| alt[1..].to_vec())
+                .filter(|tail| !tail.is_empty())
+                .collect();
+            let repeat = alternation(&tails).map(|tail| Node::Repeat(Box::new(tail)));
+            let rhs = match (alternation(&bases), &repeat) {
+                (Some(base), Some(repeat)) => Node::Multiple(vec![base, repeat.clone()]),
+                (None, Some(repeat)) => repeat.clone(),
+                // only `a ::= a | b;`, the `a` alternative can simply be dropped
+                (Some(base), None) => base,
+                (None, None) => {
+                    errors.push(GrammarError::new(
+                        GrammarErrorKind::LeftRecursion(format!(
+                            "`{name}` doesn't match anything besides itself"
+                        )),
+                        Some(name),
+                        source_map.rule_span(name),
+                    ));
+                    failed.insert(name.clone());
+                    processed.push((name.clone(), bases));
+                    continue;
+                }
+            };
+            let bases = bases
+                .into_iter()
+                .map(|mut alt| {
+                    alt.extend(repeat.clone());
+                    alt
+                })
+                .collect();
+            expr.rhs = rhs;
+            warnings.push(GrammarWarning::new(
+                GrammarWarningKind::LeftRecursionRewritten,
+                Some(name),
+                source_map.rule_span(name),
+            ));
+            processed.push((name.clone(), bases));
+        }
+    }
+
+    // whatever is left can only start with itself by skipping over a nullable rule
+    for group in left_recursive_groups(grammar, &nullable) {
+        if group.iter().any(|name| failed.contains(name)) {
+            continue;
+        }
+        let names = group
+            .iter()
+            .map(|name| format!("`{name}`"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let reason = if group.len() == 1 {
+            format!("{names} can start with itself without consuming any input")
+        } else {
+            format!("{names} can start with each other without consuming any input")
+        };
+        errors.push(GrammarError::new(
+            GrammarErrorKind::LeftRecursion(reason),
+            Some(&group[0]),
+            source_map.rule_span(&group[0]),
+        ));
+    }
+}
@@ -0,0 +1,303 @@
+//! collapses purely lexical rules into a single regex
+//!
+//! Rules like `string_literal ::= "'" { character } "'";` describe what a token looks like rather
+//! than the structure of the language. Built like any other rule, every character in them turns
+//! into its own keyword, all of them fighting over the same shorts. Instead such a rule becomes a
+//! single regex, ending up as one UserDefinedCombo in the graph, just like a hand written
+//! `#'...'` would.
+//! A rule is lexical if it's made of nothing but single characters, regexes and other lexical
+//! rules. Only lexical rules used by other rules are collapsed, and only if they contain a regex or
+//! repeat a choice of characters: `'x' | 'y'` works just fine as keywords and a rule that is a single
+//! regex already is one node anyway. Apart from rules that are just another name for a token, like
+//! `column_name ::= identifier`, every element of a collapsed rule has to match a single
+//! character: a rule like `list ::= ident { ',' ident }` strings whole tokens together and shows
+//! the structure of the language after all.
+use std::collections::{HashMap, HashSet};
+
+use ebnf::{Grammar, Node, RegexExtKind, SymbolKind};
+use regex::Regex;
+use regex_syntax::hir::{Hir, HirKind, Repetition};
+
+use super::find_terminal;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    InProgress,
+    Lexical,
+    NotLexical,
+}
+
+fn is_lexical_rule(grammar: &Grammar, name: &str, states: &mut HashMap<String, State>) -> bool {
+    match states.get(name) {
+        Some(state) => return *state == State::Lexical,
+        None => {
+            states.insert(name.to_string(), State::InProgress);
+        }
+    }
+    // recursive rules can't be expressed as a regex, they stay InProgress until here and
+    // therefore count as not lexical
+    let lexical = find_terminal(grammar, name)
+        .is_some_and(|expr| is_lexical_node(grammar, &expr.rhs, states));
+    states.insert(
+        name.to_string(),
+        if lexical {
+            State::Lexical
+        } else {
+            State::NotLexical
+        },
+    );
+    lexical
+}
+
+fn is_lexical_node(grammar: &Grammar, node: &Node, states: &mut HashMap<String, State>) -> bool {
+    match node {
+        Node::String(str) => str.chars().count() == 1,
+        Node::RegexString(_) => true,
+        Node::Terminal(name) => is_lexical_rule(grammar, name, states),
+        Node::Multiple(nodes) => nodes
+            .iter()
+            .all(|node| is_lexical_node(grammar, node, states)),
+        Node::Symbol(n1, _, n2) => {
+            is_lexical_node(grammar, n1, states) && is_lexical_node(grammar, n2, states)
+        }
+        Node::RegexExt(node, _) | Node::Optional(node) | Node::Group(node) | Node::Repeat(node) => {
+            is_lexical_node(grammar, node, states)
+        }
+        Node::Unknown => false,
+    }
+}
+
+/// whether `node` contains a regex, following references to other rules, or repeats a choice of
+/// characters; anything else, like `'x' | 'y'` or `term { '+' term }`, is better off as keywords
+fn is_token_like(grammar: &Grammar, node: &Node) -> bool {
+    match node {
+        Node::String(_) | Node::Unknown => false,
+        Node::RegexString(_) => true,
+        Node::RegexExt(node, RegexExtKind::Repeat0 | RegexExtKind::Repeat1)
+        | Node::Repeat(node)
+            if is_char_choice(node) =>
+        {
+            true
+        }
+        Node::Terminal(name) => {
+            find_terminal(grammar, name).is_some_and(|expr| is_token_like(grammar, &expr.rhs))
+        }
+        Node::Multiple(nodes) => nodes.iter().any(|node| is_token_like(grammar, node)),
+        Node::Symbol(n1, _, n2) => is_token_like(grammar, n1) || is_token_like(grammar, n2),
+        Node::RegexExt(node, _) | Node::Optional(node) | Node::Group(node) | Node::Repeat(node) => {
+            is_token_like(grammar, node)
+        }
+    }
+}
+
+/// whether every element of `node` matches a single character, following references to other
+/// rules
+fn is_char_level(grammar: &Grammar, node: &Node) -> bool {
+    match node {
+        Node::String(_) | Node::RegexString(_) | Node::Terminal(_) => is_char(grammar, node),
+        Node::Multiple(nodes) => nodes.iter().all(|node| is_char_level(grammar, node)),
+        Node::Symbol(n1, _, n2) => is_char_level(grammar, n1) && is_char_level(grammar, n2),
+        Node::RegexExt(node, _) | Node::Optional(node) | Node::Group(node) | Node::Repeat(node) => {
+            is_char_level(grammar, node)
+        }
+        Node::Unknown => false,
+    }
+}
+
+/// whether `node` matches exactly one character, like `'a'`, `#'[0-9]'` or `sign` for
+/// `sign ::= '+' | '-'`
+fn is_char(grammar: &Grammar, node: &Node) -> bool {
+    match node {
+        Node::String(str) => str.chars().count() == 1,
+        Node::RegexString(r) => {
+            let hir = strip(&regex_syntax::parse(r).expect("checked by check_grammar"));
+            match hir.kind() {
+                HirKind::Class(_) => true,
+                HirKind::Literal(lit) => {
+                    std::str::from_utf8(&lit.0).is_ok_and(|str| str.chars().count() == 1)
+                }
+                _ => false,
+            }
+        }
+        Node::Terminal(name) => {
+            find_terminal(grammar, name).is_some_and(|expr| is_char(grammar, &expr.rhs))
+        }
+        Node::Symbol(n1, SymbolKind::Alternation, n2) => {
+            is_char(grammar, n1) && is_char(grammar, n2)
+        }
+        Node::Group(node) => is_char(grammar, node),
+        _ => false,
+    }
+}
+
+/// `'a'` or `( 'a' | 'b' | 'c' )`
+fn is_char_choice(node: &Node) -> bool {
+    match node {
+        Node::String(_) => true,
+        Node::Symbol(n1, SymbolKind::Alternation, n2) => is_char_choice(n1) && is_char_choice(n2),
+        Node::Group(node) => is_char_choice(node),
+        _ => false,
+    }
+}
+
+fn references(node: &Node, out: &mut HashSet<String>) {
+    match node {
+        Node::Terminal(name) => {
+            out.insert(name.clone());
+        }
+        Node::Multiple(nodes) => nodes.iter().for_each(|node| references(node, out)),
+        Node::Symbol(n1, _, n2) => {
+            references(n1, out);
+            references(n2, out);
+        }
+        Node::RegexExt(node, _) | Node::Optional(node) | Node::Group(node) | Node::Repeat(node) => {
+            references(node, out)
+        }
+        Node::String(_) | Node::RegexString(_) | Node::Unknown => {}
+    }
+}
+
+fn repetition(min: u32, max: Option<u32>, sub: Hir) -> Hir {
+    Hir::repetition(Repetition {
+        min,
+        max,
+        greedy: true,
+        sub: Box::new(sub),
+    })
+}
+
+/// drops anchors and capture groups, neither of them make sense once the regex is embedded in
+/// a bigger one
+fn strip(hir: &Hir) -> Hir {
+    match hir.kind() {
+        HirKind::Look(_) => Hir::empty(),
+        HirKind::Capture(capture) => strip(&capture.sub),
+        HirKind::Repetition(rep) => repetition(rep.min, rep.max, strip(&rep.sub)),
+        HirKind::Concat(subs) => Hir::concat(subs.iter().map(strip).collect()),
+        HirKind::Alternation(subs) => Hir::alternation(subs.iter().map(strip).collect()),
+        HirKind::Empty | HirKind::Literal(_) | HirKind::Class(_) => hir.clone(),
+    }
+}
+
+/// the regex matching exactly what `node` matches, only called on lexical nodes
+fn to_hir(grammar: &Grammar, node: &Node) -> Hir {
+    match node {
+        Node::String(str) => Hir::literal(str.as_bytes()),
+        Node::RegexString(r) => strip(&regex_syntax::parse(r).expect("checked by check_grammar")),
+        Node::Terminal(name) => to_hir(grammar, &find_terminal(grammar, name).unwrap().rhs),
+        Node::Multiple(nodes) => {
+            Hir::concat(nodes.iter().map(|node| to_hir(grammar, node)).collect())
+        }
+        Node::Symbol(n1, SymbolKind::Concatenation, n2) => {
+            Hir::concat(vec![to_hir(grammar, n1), to_hir(grammar, n2)])
+        }
+        Node::Symbol(n1, SymbolKind::Alternation, n2) => {
+            Hir::alternation(vec![to_hir(grammar, n1), to_hir(grammar, n2)])
+        }
+        Node::Group(node) => to_hir(grammar, node),
+        Node::Optional(node) | Node::RegexExt(node, RegexExtKind::Optional) => {
+            repetition(0, Some(1), to_hir(grammar, node))
+        }
+        Node::Repeat(node) | Node::RegexExt(node, RegexExtKind::Repeat0) => {
+            repetition(0, None, to_hir(grammar, node))
+        }
+        Node::RegexExt(node, RegexExtKind::Repeat1) => repetition(1, None, to_hir(grammar, node)),
+        Node::Unknown => unreachable!("never lexical"),
+    }
+}
+
+/// the regex matching every prefix of what `hir` matches
+///
+/// UserDefinedCombos check the input after every key press, so the regex has to accept
+/// unfinished tokens too, e.g. the lone `'` starting a string literal.
+fn prefixes(hir: &Hir) -> Hir {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => Hir::empty(),
+        HirKind::Literal(lit) => {
+            let parts: Vec<Hir> = match std::str::from_utf8(&lit.0) {
+                Ok(str) => str
+                    .chars()
+                    .map(|c| Hir::literal(c.to_string().into_bytes()))
+                    .collect(),
+                Err(_) => lit.0.iter().map(|b| Hir::literal([*b])).collect(),
+            };
+            // `abc` turns into `(?:a(?:b(?:c)?)?)?`
+            parts.into_iter().rev().fold(Hir::empty(), |acc, part| {
+                repetition(0, Some(1), Hir::concat(vec![part, acc]))
+            })
+        }
+        HirKind::Class(_) => repetition(0, Some(1), hir.clone()),
+        HirKind::Repetition(rep) => match rep.max {
+            Some(0) => Hir::empty(),
+            max => Hir::concat(vec![
+                repetition(0, max.map(|max| max - 1), (*rep.sub).clone()),
+                prefixes(&rep.sub),
+            ]),
+        },
+        HirKind::Capture(capture) => prefixes(&capture.sub),
+        // `abc` turns into `a?|a(?:b?|bc?)`, all with prefixes() applied
+        HirKind::Concat(subs) => match subs.split_first() {
+            None => Hir::empty(),
+            Some((first, [])) => prefixes(first),
+            Some((first, rest)) => Hir::alternation(vec![
+                prefixes(first),
+                Hir::concat(vec![first.clone(), prefixes(&Hir::concat(rest.to_vec()))]),
+            ]),
+        },
+        HirKind::Alternation(subs) => Hir::alternation(subs.iter().map(prefixes).collect()),
+    }
+}
+
+/// the graph's CSV representation can't deal with raw control characters in regexes
+fn escape_controls(regex: &str) -> String {
+    let mut ret = String::with_capacity(regex.len());
+    for c in regex.chars() {
+        match c {
+            '\t' => ret.push_str(r"\t"),
+            '\n' => ret.push_str(r"\n"),
+            '\r' => ret.push_str(r"\r"),
+            c if c.is_control() => ret.push_str(&format!(r"\x{{{:x}}}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// replaces the body of every lexical rule with a single regex
+pub(crate) fn collapse_lexical_rules(grammar: &mut Grammar) {
+    // rules nobody else refers to are where the user starts typing, their structure is exactly
+    // what the graph is supposed to show
+    let mut referenced = HashSet::new();
+    for expr in &grammar.expressions {
+        let mut refs = HashSet::new();
+        references(&expr.rhs, &mut refs);
+        refs.remove(&expr.lhs);
+        referenced.extend(refs);
+    }
+    let mut states = HashMap::new();
+    let collapsed: Vec<(String, String)> = grammar
+        .expressions
+        .iter()
+        .filter(|expr| {
+            referenced.contains(&expr.lhs)
+                && !matches!(expr.rhs, Node::RegexString(_))
+                && is_lexical_rule(grammar, &expr.lhs, &mut states)
+                && is_token_like(grammar, &expr.rhs)
+                && (matches!(expr.rhs, Node::Terminal(_)) || is_char_level(grammar, &expr.rhs))
+        })
+        .filter_map(|expr| {
+            let hir = prefixes(&to_hir(grammar, &expr.rhs));
+            let regex = format!("^(?:{})$", escape_controls(&hir.to_string()));
+            // huge rules might blow past the regex crate's size limits, they'll just stay as
+            // they are
+            Regex::new(&regex)
+                .is_ok()
+                .then(|| (expr.lhs.clone(), regex))
+        })
+        .collect();
+    for (name, regex) in collapsed {
+        if let Some(expr) = grammar.expressions.iter_mut().find(|expr| expr.lhs == name) {
+            expr.rhs = Node::RegexString(regex);
+        }
+    }
+}
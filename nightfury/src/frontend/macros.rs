@@ -0,0 +1,395 @@
+//! parameterized rules like `list<X> ::= X { ',' X };`
+//!
+//! The ebnf crate knows nothing about parameters, so they're taken care of in two steps. Before
+//! parsing, definitions lose their parameter list and calls like `list<identifier>` are rewritten
+//! into `(list __ (identifier))`, which parses just fine. expand_macros then turns every distinct
+//! call into a rule of its own, named after the call (`list<identifier>`), and drops the macro
+//! definitions, so none of the later passes ever see a parameter.
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use ebnf::{Expression, Grammar, Node, RegexExtKind, SymbolKind};
+
+use super::annotation::split_tag;
+use super::error::{GrammarError, GrammarErrorKind};
+use super::source::{SourceMap, TokenKind, is_exception_marker, tokenize};
+
+/// rule name marking a sequence as a macro call
+const MACRO_CALL_MARKER: &str = "__";
+
+/// how many calls may be nested while expanding, protects against macros growing their own
+/// arguments, e.g. `grow<X> ::= X | grow<( X X )>`
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+pub(crate) struct Macros {
+    /// parameters of every macro, by name
+    params: HashMap<String, Vec<String>>,
+    /// turns calls into something the ebnf crate understands, see [super::source::rewrite]
+    pub replacements: Vec<(Range<usize>, String)>,
+}
+
+fn error(
+    errors: &mut Vec<GrammarError>,
+    source_map: &SourceMap,
+    kind: GrammarErrorKind,
+    range: Range<usize>,
+) {
+    errors.push(GrammarError::new(
+        kind,
+        source_map
+            .rule_at(range.start)
+            .map(|rule| rule.name.as_str()),
+        Some(source_map.span(range.start, range.len())),
+    ));
+}
+
+/// finds macro definitions and calls in `src`
+pub(crate) fn extract_macros(
+    src: &str,
+    source_map: &SourceMap,
+    errors: &mut Vec<GrammarError>,
+) -> Macros {
+    let tokens = tokenize(src);
+    let mut params = HashMap::new();
+    let mut replacements = Vec::new();
+    // bracket depth each unclosed call was opened at, along with the range of its name
+    let mut calls: Vec<(usize, Range<usize>)> = Vec::new();
+    let mut depth = 0usize;
+    let mut statement_start = true;
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        let opens_call = tokens.get(i + 1).is_some_and(|next| next.is_punct("<"));
+        if token.kind == TokenKind::Ident && opens_call && statement_start {
+            let close = tokens[i + 2..]
+                .iter()
+                .position(|token| token.is_punct(">") || token.is_punct(";"))
+                .map(|pos| i + 2 + pos)
+                .filter(|close| tokens[*close].is_punct(">"));
+            let Some(close) = close else {
+                let kind = GrammarErrorKind::InvalidMacro("missing `>`".to_string());
+                error(errors, source_map, kind, token.offset..token.end());
+                i += 2;
+                statement_start = false;
+                continue;
+            };
+            let list = &tokens[i + 2..close];
+            let mut names = Vec::new();
+            for param in list.split(|token| token.is_punct(",")) {
+                match param {
+                    [name] if name.kind == TokenKind::Ident => {
+                        if names.contains(&name.text.to_string()) {
+                            let kind = GrammarErrorKind::InvalidMacro(format!(
+                                "parameter `{}` is declared twice",
+                                name.text
+                            ));
+                            error(errors, source_map, kind, name.offset..name.end());
+                        }
+                        names.push(name.text.to_string());
+                    }
+                    _ => {
+                        let range = tokens[i + 1].offset..tokens[close].end();
+                        let kind = GrammarErrorKind::InvalidMacro(
+                            "macro parameters have to be plain names".to_string(),
+                        );
+                        error(errors, source_map, kind, range);
+                        break;
+                    }
+                }
+            }
+            params.insert(token.text.to_string(), names);
+            let range = tokens[i + 1].offset..tokens[close].end();
+            replacements.push((range.clone(), " ".repeat(range.len())));
+            i = close + 1;
+            statement_start = false;
+            continue;
+        }
+        statement_start = false;
+        match token.text {
+            _ if token.kind == TokenKind::Ident && opens_call => {
+                replacements.push((token.offset..token.end(), format!("({}", token.text)));
+                let open = tokens[i + 1];
+                let no_args = tokens.get(i + 2).is_some_and(|next| next.is_punct(">"));
+                let marker = if no_args {
+                    format!(" {MACRO_CALL_MARKER}")
+                } else {
+                    format!(" {MACRO_CALL_MARKER} (")
+                };
+                replacements.push((open.offset..open.end(), marker));
+                calls.push((depth, token.offset..token.end()));
+                i += 1;
+            }
+            "(" | "[" | "{" if token.kind == TokenKind::Punct => depth += 1,
+            ")" | "]" | "}" if token.kind == TokenKind::Punct => depth = depth.saturating_sub(1),
+            "," if token.kind == TokenKind::Punct
+                && calls.last().is_some_and(|(open, _)| *open == depth) =>
+            {
+                replacements.push((token.offset..token.end(), ") (".to_string()));
+            }
+            ">" if token.kind == TokenKind::Punct
+                && calls.last().is_some_and(|(open, _)| *open == depth) =>
+            {
+                calls.pop();
+                let closing = if tokens[i - 1].is_punct("<") {
+                    " )"
+                } else {
+                    ") )"
+                };
+                replacements.push((token.offset..token.end(), closing.to_string()));
+            }
+            ";" if token.kind == TokenKind::Punct => {
+                for (_, name) in calls.drain(..) {
+                    let kind = GrammarErrorKind::InvalidMacro("missing `>`".to_string());
+                    error(errors, source_map, kind, name);
+                }
+                depth = 0;
+                statement_start = true;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    Macros {
+        params,
+        replacements,
+    }
+}
+
+/// `name` and the arguments if `node` is a macro call
+fn as_call(node: &Node) -> Option<(&str, Vec<&Node>)> {
+    let Node::Group(node) = node else {
+        return None;
+    };
+    let Node::Multiple(nodes) = node.as_ref() else {
+        return None;
+    };
+    match nodes.as_slice() {
+        [Node::Terminal(name), Node::Terminal(marker), args @ ..]
+            if marker == MACRO_CALL_MARKER =>
+        {
+            let args = args
+                .iter()
+                .map(|arg| match arg {
+                    Node::Group(arg) => arg.as_ref(),
+                    arg => arg,
+                })
+                .collect();
+            Some((name, args))
+        }
+        _ => None,
+    }
+}
+
+/// prints `node` the way it'd be written in a grammar, used to name macro instances
+fn display(node: &Node) -> String {
+    match node {
+        node if is_exception_marker(node) => "-".to_string(),
+        Node::String(str) => format!("'{}'", split_tag(str).0),
+        Node::RegexString(r) => format!("#'{r}'"),
+        Node::Terminal(name) => name.clone(),
+        Node::Multiple(nodes) => nodes.iter().map(display).collect::<Vec<_>>().join(" "),
+        Node::RegexExt(node, kind) => format!(
+            "{}{}",
+            display(node),
+            match kind {
+                RegexExtKind::Repeat0 => "*",
+                RegexExtKind::Repeat1 => "+",
+                RegexExtKind::Optional => "?",
+            }
+        ),
+        Node::Symbol(n1, SymbolKind::Concatenation, n2) => {
+            format!("{} {}", display(n1), display(n2))
+        }
+        Node::Symbol(n1, SymbolKind::Alternation, n2) => {
+            format!("{} | {}", display(n1), display(n2))
+        }
+        Node::Group(node) => format!("( {} )", display(node)),
+        Node::Optional(node) => format!("[ {} ]", display(node)),
+        Node::Repeat(node) => format!("{{ {} }}", display(node)),
+        Node::Unknown => "?".to_string(),
+    }
+}
+
+fn map_children(node: Node, mut f: impl FnMut(Node) -> Node) -> Node {
+    match node {
+        Node::Multiple(nodes) => Node::Multiple(nodes.into_iter().map(f).collect()),
+        Node::Symbol(n1, kind, n2) => Node::Symbol(Box::new(f(*n1)), kind, Box::new(f(*n2))),
+        Node::RegexExt(node, kind) => Node::RegexExt(Box::new(f(*node)), kind),
+        Node::Group(node) => Node::Group(Box::new(f(*node))),
+        Node::Optional(node) => Node::Optional(Box::new(f(*node))),
+        Node::Repeat(node) => Node::Repeat(Box::new(f(*node))),
+        node => node,
+    }
+}
+
+struct Expander<'a, 'b> {
+    /// (parameters, body) of every macro
+    macros: HashMap<String, (Vec<String>, Node)>,
+    rules: HashSet<String>,
+    source_map: &'a SourceMap<'b>,
+    errors: &'a mut Vec<GrammarError>,
+    /// instances created so far, in the order they were created
+    instances: Vec<Expression>,
+    created: HashSet<String>,
+}
+
+impl Expander<'_, '_> {
+    fn error(&mut self, rule: &str, kind: GrammarErrorKind, name: &str) {
+        let err = GrammarError::new(
+            kind,
+            Some(rule),
+            self.source_map.find_in_rule(rule, TokenKind::Ident, name),
+        );
+        if !self.errors.contains(&err) {
+            self.errors.push(err);
+        }
+    }
+
+    /// reports every name in the body of `macro_name` that's neither a rule nor a parameter
+    fn check_unbound(&mut self, macro_name: &str, params: &[String], node: &Node) {
+        match node {
+            Node::Terminal(name)
+                if !params.contains(name)
+                    && !self.rules.contains(name)
+                    && !self.macros.contains_key(name)
+                    && name != MACRO_CALL_MARKER =>
+            {
+                self.error(
+                    macro_name,
+                    GrammarErrorKind::UnboundParameter(name.clone()),
+                    name,
+                );
+            }
+            Node::Multiple(nodes) => nodes
+                .iter()
+                .for_each(|node| self.check_unbound(macro_name, params, node)),
+            Node::Symbol(n1, _, n2) => {
+                self.check_unbound(macro_name, params, n1);
+                self.check_unbound(macro_name, params, n2);
+            }
+            Node::RegexExt(node, _)
+            | Node::Group(node)
+            | Node::Optional(node)
+            | Node::Repeat(node) => self.check_unbound(macro_name, params, node),
+            _ => {}
+        }
+    }
+
+    fn arity_error(&mut self, rule: &str, name: &str, expected: usize, found: usize) -> Node {
+        let kind = GrammarErrorKind::MacroArity {
+            name: name.to_string(),
+            expected,
+            found,
+        };
+        self.error(rule, kind, name);
+        Node::Unknown
+    }
+
+    /// substitutes the parameters in `args` and replaces every call by a reference to its
+    /// instance; `rule` is the rule `node` was written in, used for diagnostics
+    fn expand(
+        &mut self,
+        node: Node,
+        args: &HashMap<String, Node>,
+        rule: &str,
+        depth: usize,
+    ) -> Node {
+        if let Some((name, call_args)) = as_call(&node) {
+            let name = name.to_string();
+            let call_args: Vec<Node> = call_args
+                .into_iter()
+                .map(|arg| self.expand(arg.clone(), args, rule, depth))
+                .collect();
+            return self.instantiate(&name, call_args, rule, depth);
+        }
+        match node {
+            Node::Terminal(name) if args.contains_key(&name) => args[&name].clone(),
+            Node::Terminal(name) if self.macros.contains_key(&name) => {
+                let expected = self.macros[&name].0.len();
+                self.arity_error(rule, &name, expected, 0)
+            }
+            node => map_children(node, |node| self.expand(node, args, rule, depth)),
+        }
+    }
+
+    fn instantiate(&mut self, name: &str, args: Vec<Node>, rule: &str, depth: usize) -> Node {
+        let Some((params, body)) = self.macros.get(name).cloned() else {
+            if self.rules.contains(name) {
+                return self.arity_error(rule, name, 0, args.len());
+            }
+            let kind = GrammarErrorKind::UndefinedNonterminal(name.to_string());
+            self.error(rule, kind, name);
+            return Node::Unknown;
+        };
+        if params.len() != args.len() {
+            return self.arity_error(rule, name, params.len(), args.len());
+        }
+        let instance = format!(
+            "{name}<{}>",
+            args.iter().map(display).collect::<Vec<_>>().join(", ")
+        );
+        if self.created.insert(instance.clone()) {
+            if depth >= MAX_EXPANSION_DEPTH {
+                let kind = GrammarErrorKind::InvalidMacro(format!(
+                    "expanding `{name}` never ends, it keeps calling itself with new arguments"
+                ));
+                self.error(name, kind, name);
+                return Node::Unknown;
+            }
+            let args = params.into_iter().zip(args).collect();
+            let rhs = self.expand(body, &args, name, depth + 1);
+            self.instances.push(Expression {
+                lhs: instance.clone(),
+                rhs,
+            });
+        }
+        Node::Terminal(instance)
+    }
+}
+
+/// replaces every macro call in `grammar` by a reference to a rule with the macro's parameters
+/// filled in, dropping the macro definitions
+pub(crate) fn expand_macros(
+    grammar: &mut Grammar,
+    macros: &Macros,
+    source_map: &SourceMap,
+    errors: &mut Vec<GrammarError>,
+) {
+    let (definitions, rules): (Vec<_>, Vec<_>) = std::mem::take(&mut grammar.expressions)
+        .into_iter()
+        .partition(|expr| macros.params.contains_key(&expr.lhs));
+    let mut expander = Expander {
+        macros: definitions
+            .into_iter()
+            .map(|expr| {
+                (
+                    expr.lhs.clone(),
+                    (macros.params[&expr.lhs].clone(), expr.rhs),
+                )
+            })
+            .collect(),
+        rules: rules.iter().map(|expr| expr.lhs.clone()).collect(),
+        source_map,
+        errors,
+        instances: Vec::new(),
+        created: HashSet::new(),
+    };
+    // report every unbound name once, instead of once per instance
+    let mut bodies: Vec<_> = expander
+        .macros
+        .iter()
+        .map(|(name, (params, body))| (name.clone(), params.clone(), body.clone()))
+        .collect();
+    bodies.sort_by_key(|(name, _, _)| source_map.rule(name).map_or(usize::MAX, |rule| rule.offset));
+    for (name, params, body) in &bodies {
+        expander.check_unbound(name, params, body);
+    }
+    grammar.expressions = rules
+        .into_iter()
+        .map(|expr| Expression {
+            rhs: expander.expand(expr.rhs, &HashMap::new(), &expr.lhs, 0),
+            lhs: expr.lhs,
+        })
+        .collect();
+    grammar.expressions.extend(expander.instances);
+}
@@ -0,0 +1,663 @@
+//! grammars spread over several files
+//!
+//! `import "common.ebnf";` pulls in every rule of another grammar file, `extends "base.ebnf";`
+//! does the same, but lets the extending file replace any of the base grammar's rules by simply
+//! defining them again. Paths are resolved relative to the file containing the directive.
+//! All files get flattened into a single source before compiling: directives and replaced rules
+//! are blanked out, everything else stays where it was line by line, so diagnostics can be mapped
+//! back to the file they belong to.
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+use super::dialect::dialects;
+use super::error::{
+    GrammarError, GrammarErrorKind, GrammarWarning, Span, report_sources, report_warnings_sources,
+};
+use super::source::{SourceMap, Token, TokenKind, tokenize};
+use super::{CompileOptions, CompiledGrammar, GrammarFrontend, compile_ebnf_with};
+
+struct ModuleFile {
+    /// path as shown in diagnostics
+    name: String,
+    path: PathBuf,
+    text: String,
+    /// byte ranges left out of the flattened source
+    blanked: Vec<Range<usize>>,
+    /// files pulled in via `import`
+    imports: Vec<usize>,
+    /// file pulled in via `extends`
+    extends: Option<usize>,
+}
+
+impl ModuleFile {
+    fn is_blanked(&self, offset: usize) -> bool {
+        self.blanked.iter().any(|range| range.contains(&offset))
+    }
+}
+
+/// a grammar and every file it imports, see the module documentation
+pub struct GrammarModules {
+    /// the file everything got loaded from comes first
+    files: Vec<ModuleFile>,
+    /// problems found while loading, handed out by [GrammarModules::compile]
+    errors: Vec<GrammarError>,
+    source: String,
+    /// (first line in `source`, index into `files`)
+    sections: Vec<(usize, usize)>,
+    default_entry: Option<String>,
+}
+
+/// resolves `.` and `..` without touching the file system, so the same file imported via
+/// different paths is still recognized
+fn normalize(path: &Path) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(ret.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                ret.pop();
+            }
+            component => ret.push(component),
+        }
+    }
+    ret
+}
+
+/// splits `tokens` into `;` terminated statements, the `;` being part of the statement
+fn statements<'a, 'b>(tokens: &'b [Token<'a>]) -> impl Iterator<Item = &'b [Token<'a>]> {
+    tokens.split_inclusive(|token| token.is_punct(";"))
+}
+
+struct Loader<'a> {
+    files: Vec<ModuleFile>,
+    errors: Vec<GrammarError>,
+    read: &'a mut dyn FnMut(&Path) -> std::io::Result<String>,
+}
+
+impl Loader<'_> {
+    fn error_at(&mut self, kind: GrammarErrorKind, file: usize, range: Range<usize>) {
+        let file = &self.files[file];
+        let mut err = GrammarError::new(
+            kind,
+            None,
+            Some(SourceMap::new(&file.text).span(range.start, range.len())),
+        );
+        err.file = Some(file.name.clone());
+        self.errors.push(err);
+    }
+
+    fn add_file(&mut self, name: String, path: PathBuf, text: String) -> usize {
+        self.files.push(ModuleFile {
+            name,
+            path,
+            text,
+            blanked: Vec::new(),
+            imports: Vec::new(),
+            extends: None,
+        });
+        self.files.len() - 1
+    }
+
+    /// loads the file at `path` unless it's already been loaded, `from` being the file and
+    /// range of the directive asking for it
+    fn load(
+        &mut self,
+        path: PathBuf,
+        from: (usize, Range<usize>),
+        stack: &mut Vec<usize>,
+    ) -> Option<usize> {
+        if let Some(idx) = self.files.iter().position(|file| file.path == path) {
+            if let Some(pos) = stack.iter().position(|on_stack| *on_stack == idx) {
+                let cycle = stack[pos..]
+                    .iter()
+                    .chain(std::iter::once(&idx))
+                    .map(|idx| self.files[*idx].name.clone())
+                    .collect();
+                self.error_at(GrammarErrorKind::ImportCycle(cycle), from.0, from.1);
+                return None;
+            }
+            return Some(idx);
+        }
+        match (self.read)(&path) {
+            Ok(text) => {
+                let idx = self.add_file(path.display().to_string(), path, text);
+                self.process(idx, stack);
+                Some(idx)
+            }
+            Err(err) => {
+                self.error_at(
+                    GrammarErrorKind::Import {
+                        path: path.display().to_string(),
+                        reason: err.to_string(),
+                    },
+                    from.0,
+                    from.1,
+                );
+                None
+            }
+        }
+    }
+
+    /// resolves the directives of the file `idx`
+    fn process(&mut self, idx: usize, stack: &mut Vec<usize>) {
+        stack.push(idx);
+        let text = self.files[idx].text.clone();
+        let dir = self.files[idx]
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let tokens = tokenize(&text);
+        for statement in statements(&tokens) {
+            let (keyword, target) = match statement {
+                [keyword, target] | [keyword, target, _] => (keyword, target),
+                _ => continue,
+            };
+            let is_extends = match keyword.text {
+                "import" => false,
+                "extends" => true,
+                _ => continue,
+            };
+            if keyword.kind != TokenKind::Ident || target.kind != TokenKind::Str {
+                continue;
+            }
+            let range = keyword.offset..statement[statement.len() - 1].end();
+            self.files[idx].blanked.push(range.clone());
+            if is_extends && self.files[idx].extends.is_some() {
+                self.error_at(
+                    GrammarErrorKind::InvalidDirective(
+                        "a grammar can only extend a single other grammar".to_string(),
+                    ),
+                    idx,
+                    range,
+                );
+                continue;
+            }
+            let path = normalize(&dir.join(target.content()));
+            if let Some(target) = self.load(path, (idx, range), stack) {
+                if is_extends {
+                    self.files[idx].extends = Some(target);
+                } else {
+                    self.files[idx].imports.push(target);
+                }
+            }
+        }
+        stack.pop();
+    }
+
+    /// every file reachable from `idx`, including itself
+    fn reachable(&self, idx: usize, out: &mut Vec<usize>) {
+        if out.contains(&idx) {
+            return;
+        }
+        out.push(idx);
+        let file = &self.files[idx];
+        for next in file.imports.iter().chain(file.extends.iter()) {
+            self.reachable(*next, out);
+        }
+    }
+
+    /// blanks out every rule of a base grammar that the grammar extending it defines again
+    fn apply_overrides(&mut self, rules: &[Vec<(String, Range<usize>)>]) {
+        for idx in 0..self.files.len() {
+            let Some(base) = self.files[idx].extends else {
+                continue;
+            };
+            let mut base_files = Vec::new();
+            self.reachable(base, &mut base_files);
+            for (name, _) in &rules[idx] {
+                for file in &base_files {
+                    let overridden = rules[*file]
+                        .iter()
+                        .filter(|(base_name, _)| base_name == name)
+                        .map(|(_, range)| range.clone());
+                    self.files[*file].blanked.extend(overridden);
+                }
+            }
+        }
+    }
+
+    fn check_duplicates(&mut self, rules: &[Vec<(String, Range<usize>)>]) {
+        let mut defined: HashMap<&str, String> = HashMap::new();
+        let mut errors = Vec::new();
+        for (idx, file_rules) in rules.iter().enumerate() {
+            let source_map = SourceMap::new(&self.files[idx].text);
+            for (name, range) in file_rules {
+                if self.files[idx].is_blanked(range.start) {
+                    continue;
+                }
+                let span = source_map.span(range.start, name.len());
+                if let Some(previous) = defined.get(name.as_str()) {
+                    let kind = GrammarErrorKind::DuplicateRule {
+                        name: name.clone(),
+                        previous: previous.clone(),
+                    };
+                    let mut err = GrammarError::new(kind, Some(name), Some(span));
+                    err.file = Some(self.files[idx].name.clone());
+                    errors.push(err);
+                } else {
+                    let location =
+                        format!("{}:{}:{}", self.files[idx].name, span.line, span.column);
+                    defined.insert(name, location);
+                }
+            }
+        }
+        self.errors.extend(errors);
+    }
+
+    /// the rule a cursor starts at by default: a grammar extending another one is that grammar
+    /// with a few changes, so it starts where its base starts
+    fn default_entry(&self, idx: usize, rules: &[Vec<(String, Range<usize>)>]) -> Option<String> {
+        match self.files[idx].extends {
+            Some(base) => self.default_entry(base, rules),
+            // layout rules like `@layout` can't be entered
+            None => rules[idx]
+                .iter()
+                .find(|(name, _)| !name.starts_with('@'))
+                .map(|(name, _)| name.clone()),
+        }
+    }
+
+    fn finish(mut self) -> GrammarModules {
+        let rules: Vec<Vec<(String, Range<usize>)>> = self
+            .files
+            .iter()
+            .map(|file| {
+                SourceMap::new(&file.text)
+                    .rules()
+                    .iter()
+                    .map(|rule| (rule.name.clone(), rule.offset..rule.end))
+                    .collect()
+            })
+            .collect();
+        self.apply_overrides(&rules);
+        self.check_duplicates(&rules);
+        let default_entry = self.default_entry(0, &rules);
+
+        let mut source = String::new();
+        let mut sections = Vec::with_capacity(self.files.len());
+        let mut line = 1;
+        for (idx, file) in self.files.iter().enumerate() {
+            sections.push((line, idx));
+            // one space per character keeps the columns of everything else intact
+            let flattened: String = file
+                .text
+                .char_indices()
+                .map(|(offset, c)| {
+                    if c != '\n' && file.is_blanked(offset) {
+                        ' '
+                    } else {
+                        c
+                    }
+                })
+                .collect();
+            line += flattened.lines().count();
+            source.push_str(&flattened);
+            if !flattened.is_empty() && !flattened.ends_with('\n') {
+                source.push('\n');
+            }
+        }
+        GrammarModules {
+            files: self.files,
+            errors: self.errors,
+            source,
+            sections,
+            default_entry,
+        }
+    }
+}
+
+impl GrammarModules {
+    /// loads the grammar at `path` along with everything it imports
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        Self::load_with(path, |path| std::fs::read_to_string(path))
+    }
+
+    /// like [GrammarModules::load], but reads files through `read`
+    pub fn load_with(
+        path: impl AsRef<Path>,
+        mut read: impl FnMut(&Path) -> std::io::Result<String>,
+    ) -> Self {
+        let path = normalize(path.as_ref());
+        let name = path.display().to_string();
+        let text = read(&path);
+        let mut loader = Loader {
+            files: Vec::new(),
+            errors: Vec::new(),
+            read: &mut read,
+        };
+        match text {
+            Ok(text) => {
+                let idx = loader.add_file(name, path, text);
+                loader.process(idx, &mut Vec::new());
+            }
+            Err(err) => {
+                loader.errors.push(GrammarError::new(
+                    GrammarErrorKind::Import {
+                        path: name.clone(),
+                        reason: err.to_string(),
+                    },
+                    None,
+                    None,
+                ));
+                loader.add_file(name, path, String::new());
+            }
+        }
+        loader.finish()
+    }
+
+    /// takes an already read grammar, its imports are resolved relative to `dir`
+    pub fn from_source(name: &str, source: &str, dir: impl AsRef<Path>) -> Self {
+        let mut read = |path: &Path| std::fs::read_to_string(path);
+        let mut loader = Loader {
+            files: Vec::new(),
+            errors: Vec::new(),
+            read: &mut read,
+        };
+        // the path only has to point into `dir`, it's never read
+        let path = normalize(&dir.as_ref().join(name));
+        let idx = loader.add_file(name.to_string(), path, source.to_string());
+        loader.process(idx, &mut Vec::new());
+        loader.finish()
+    }
+
+    /// a grammar on its own, without resolving any imports; grammars written in another syntax than
+    /// nightfury's can't import anything
+    pub fn single(name: &str, source: &str) -> Self {
+        Self {
+            files: vec![ModuleFile {
+                name: name.to_string(),
+                path: PathBuf::from(name),
+                text: source.to_string(),
+                blanked: Vec::new(),
+                imports: Vec::new(),
+                extends: None,
+            }],
+            errors: Vec::new(),
+            source: source.to_string(),
+            sections: vec![(1, 0)],
+            default_entry: None,
+        }
+    }
+
+    /// loads the grammar at `path` written in the syntax of `frontend`, along with everything it
+    /// imports if the syntax has imports at all
+    pub fn load_as(path: impl AsRef<Path>, frontend: &dyn GrammarFrontend) -> Self {
+        if frontend.supports_imports() {
+            Self::load(path)
+        } else {
+            Self::load_single(path)
+        }
+    }
+
+    /// like [GrammarModules::load_as], but takes an already read grammar whose imports are
+    /// resolved relative to `dir`
+    pub fn from_source_as(
+        name: &str,
+        source: &str,
+        dir: impl AsRef<Path>,
+        frontend: &dyn GrammarFrontend,
+    ) -> Self {
+        if frontend.supports_imports() {
+            Self::from_source(name, source, dir)
+        } else {
+            Self::single(name, source)
+        }
+    }
+
+    /// like [GrammarModules::single], but reads the grammar at `path`
+    pub fn load_single(path: impl AsRef<Path>) -> Self {
+        let name = path.as_ref().display().to_string();
+        match std::fs::read_to_string(&path) {
+            Ok(source) => Self::single(&name, &source),
+            Err(err) => {
+                let mut ret = Self::single(&name, "");
+                ret.errors.push(GrammarError::new(
+                    GrammarErrorKind::Import {
+                        path: name,
+                        reason: err.to_string(),
+                    },
+                    None,
+                    None,
+                ));
+                ret
+            }
+        }
+    }
+
+    /// all files flattened into one, this is what actually gets compiled
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// maps a span in [GrammarModules::source] back to the file it came from
+    fn locate(&self, span: Option<Span>) -> (Option<String>, Option<Span>) {
+        let Some(span) = span else {
+            return (None, None);
+        };
+        let (first_line, idx) = self.sections[self
+            .sections
+            .partition_point(|(first_line, _)| *first_line <= span.line)
+            - 1];
+        let span = Span {
+            line: span.line - first_line + 1,
+            ..span
+        };
+        (Some(self.files[idx].name.clone()), Some(span))
+    }
+
+    /// like [super::compile_ebnf], the default entry of a grammar extending another one is the one of
+    /// its base grammar
+    pub fn compile(&self, entries: &[&str]) -> Result<CompiledGrammar, Vec<GrammarError>> {
+        self.compile_with(&CompileOptions {
+            entries,
+            ..Default::default()
+        })
+    }
+
+    /// like [compile_ebnf_with], see [GrammarModules::compile]
+    pub fn compile_with(
+        &self,
+        options: &CompileOptions,
+    ) -> Result<CompiledGrammar, Vec<GrammarError>> {
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+        let default_entry: Vec<&str> = self.default_entry.iter().map(String::as_str).collect();
+        let options = CompileOptions {
+            entries: if options.entries.is_empty() {
+                &default_entry
+            } else {
+                options.entries
+            },
+            ..*options
+        };
+        match compile_ebnf_with(&self.source, &options) {
+            Ok(mut compiled) => {
+                for warning in &mut compiled.warnings {
+                    (warning.file, warning.span) = self.locate(warning.span);
+                }
+                Ok(compiled)
+            }
+            Err(mut errors) => {
+                for err in &mut errors {
+                    (err.file, err.span) = self.locate(err.span);
+                }
+                Err(errors)
+            }
+        }
+    }
+
+    /// see [dialects]
+    pub fn dialects(&self) -> Vec<String> {
+        dialects(&self.source)
+    }
+
+    fn sources(&self) -> Vec<(&str, &str)> {
+        self.files
+            .iter()
+            .map(|file| (file.name.as_str(), file.text.as_str()))
+            .collect()
+    }
+
+    /// see [super::report]
+    pub fn report(&self, errors: &[GrammarError]) -> String {
+        report_sources(errors, &self.sources())
+    }
+
+    /// see [super::report_warnings]
+    pub fn report_warnings(&self, warnings: &[GrammarWarning]) -> String {
+        report_warnings_sources(warnings, &self.sources())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FSMCursor;
+
+    fn load(files: &[(&str, &str)], path: &str) -> GrammarModules {
+        GrammarModules::load_with(path, |path| {
+            files
+                .iter()
+                .find(|(name, _)| Path::new(name) == path)
+                .map(|(_, text)| text.to_string())
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        })
+    }
+
+    const FILES: &[(&str, &str)] = &[
+        ("common.ebnf", "value ::= 'x' | 'y';\n"),
+        (
+            "lang/base.ebnf",
+            "import \"../common.ebnf\";\nstmt ::= 'let' value ';';\n",
+        ),
+        (
+            "lang/derived.ebnf",
+            "extends \"base.ebnf\";\nvalue ::= 'z';\n",
+        ),
+    ];
+
+    #[test]
+    fn test_extends() {
+        let modules = load(FILES, "lang/derived.ebnf");
+        let compiled = modules.compile(&[]).unwrap();
+        let mut cursor = FSMCursor::new(compiled.entries.default_root());
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!(None, cursor.advance('x'));
+        assert_eq!("z", cursor.advance('z').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+
+        let compiled = load(FILES, "lang/base.ebnf").compile(&[]).unwrap();
+        let mut cursor = FSMCursor::new(compiled.entries.default_root());
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!("x", cursor.advance('x').unwrap());
+    }
+
+    #[test]
+    fn test_single() {
+        // `import` means nothing to other syntaxes, and rule names may be spelled differently
+        let modules =
+            GrammarModules::single("uri.iso.ebnf", "import = \"x\";\nSome Rule = import;");
+        let compiled = modules
+            .compile_with(&CompileOptions {
+                frontend: Some(&super::super::Iso14977),
+                ..Default::default()
+            })
+            .unwrap();
+        let mut cursor = FSMCursor::new(compiled.entries.default_root());
+        assert_eq!("x", cursor.advance('x').unwrap());
+    }
+
+    #[test]
+    fn test_imports_by_syntax() {
+        let source = "import = \"x\";\nSome Rule = import;";
+        let iso = &super::super::Iso14977;
+        let modules = GrammarModules::from_source_as("uri.iso.ebnf", source, ".", iso);
+        let compiled = modules
+            .compile_with(&CompileOptions {
+                frontend: Some(iso),
+                ..Default::default()
+            })
+            .unwrap();
+        let mut cursor = FSMCursor::new(compiled.entries.default_root());
+        assert_eq!("x", cursor.advance('x').unwrap());
+
+        // nightfury's own syntax resolves the import, which doesn't exist
+        let source = "import \"missing.ebnf\";\nstmt ::= 'x';\n";
+        let modules = GrammarModules::from_source_as("main.ebnf", source, ".", &super::super::Ebnf);
+        let errors = modules.compile(&[]).unwrap_err();
+        assert!(matches!(errors[0].kind, GrammarErrorKind::Import { .. }));
+    }
+
+    #[test]
+    fn test_errors_located() {
+        let files = &[
+            ("base.ebnf", "stmt ::= 'let' value ';';\nvalue ::= 'x';\n"),
+            ("derived.ebnf", "extends \"base.ebnf\";\nvalue ::= other;\n"),
+        ];
+        let modules = load(files, "derived.ebnf");
+        let errors = modules.compile(&[]).unwrap_err();
+        assert_eq!(1, errors.len());
+        assert_eq!(Some("derived.ebnf".to_string()), errors[0].file);
+        assert_eq!(Some(2), errors[0].span.map(|span| span.line));
+        assert!(modules.report(&errors).contains("--> derived.ebnf:2:11"));
+    }
+
+    #[test]
+    fn test_import_cycle() {
+        let files = &[
+            ("a.ebnf", "import \"b.ebnf\";\na ::= 'a';\n"),
+            ("b.ebnf", "import \"a.ebnf\";\nb ::= 'b';\n"),
+        ];
+        let errors = load(files, "a.ebnf").compile(&[]).unwrap_err();
+        assert_eq!(
+            vec![GrammarError {
+                kind: GrammarErrorKind::ImportCycle(vec![
+                    "a.ebnf".to_string(),
+                    "b.ebnf".to_string(),
+                    "a.ebnf".to_string()
+                ]),
+                rule: None,
+                span: Some(Span {
+                    line: 1,
+                    column: 1,
+                    len: 16
+                }),
+                file: Some("b.ebnf".to_string()),
+            }],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_duplicate_rule() {
+        let files = &[
+            ("main.ebnf", "import \"common.ebnf\";\nvalue ::= 'z';\n"),
+            ("common.ebnf", "value ::= 'x';\n"),
+        ];
+        let errors = load(files, "main.ebnf").compile(&[]).unwrap_err();
+        assert_eq!(1, errors.len());
+        assert_eq!(
+            GrammarErrorKind::DuplicateRule {
+                name: "value".to_string(),
+                previous: "main.ebnf:2:1".to_string()
+            },
+            errors[0].kind
+        );
+        assert_eq!(Some("common.ebnf".to_string()), errors[0].file);
+    }
+
+    #[test]
+    fn test_missing_import() {
+        let files = &[("main.ebnf", "import \"nope.ebnf\";\nmain ::= 'z';\n")];
+        let errors = load(files, "main.ebnf").compile(&[]).unwrap_err();
+        assert!(matches!(
+            &errors[0].kind,
+            GrammarErrorKind::Import { path, .. } if path == "nope.ebnf"
+        ));
+    }
+}
@@ -0,0 +1,310 @@
+//! lenient tokenizer and rule locator for grammar sources
+//!
+//! The ebnf crate doesn't keep track of where it found a node, so this module re-scans the source
+//! to be able to attach line/column information to diagnostics.
+use std::ops::Range;
+
+use ebnf::Node;
+
+use super::error::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    Ident,
+    /// quoted string, both `'...'` and `"..."`
+    Str,
+    /// `#'...'` or `#"..."`
+    Regex,
+    Punct,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Token<'a> {
+    pub kind: TokenKind,
+    /// the token as it appears in the source, including quotes
+    pub text: &'a str,
+    /// byte offset into the source
+    pub offset: usize,
+}
+
+impl<'a> Token<'a> {
+    /// the token text without its delimiters
+    pub fn content(&self) -> &'a str {
+        let text = self.text;
+        match self.kind {
+            TokenKind::Str if text.len() >= 2 => &text[1..text.len() - 1],
+            TokenKind::Regex if text.len() >= 3 => &text[2..text.len() - 1],
+            _ => text,
+        }
+    }
+    pub fn is_punct(&self, punct: &str) -> bool {
+        self.kind == TokenKind::Punct && self.text == punct
+    }
+    pub fn end(&self) -> usize {
+        self.offset + self.text.len()
+    }
+}
+
+fn quoted_len(rest: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in rest.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            c if c == quote && !escaped => return i + 1,
+            _ => escaped = false,
+        }
+    }
+    // unterminated, the ebnf crate will complain about this later on
+    rest.len()
+}
+
+/// splits `src` into tokens, never fails; whitespace is dropped
+pub(crate) fn tokenize(src: &str) -> Vec<Token<'_>> {
+    let mut ret = Vec::new();
+    let mut offset = 0;
+    while let Some(c) = src[offset..].chars().next() {
+        let rest = &src[offset..];
+        let (kind, len) = match c {
+            c if c.is_whitespace() => {
+                offset += c.len_utf8();
+                continue;
+            }
+            '\'' | '"' => (TokenKind::Str, quoted_len(rest, c)),
+            '#' if rest[1..].starts_with(['\'', '"']) => (
+                TokenKind::Regex,
+                1 + quoted_len(&rest[1..], rest[1..].chars().next().unwrap()),
+            ),
+            c if c.is_alphanumeric() || c == '_' => (
+                TokenKind::Ident,
+                rest.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len()),
+            ),
+            _ if rest.starts_with("::=") => (TokenKind::Punct, 3),
+            c => (TokenKind::Punct, c.len_utf8()),
+        };
+        ret.push(Token {
+            kind,
+            text: &rest[..len],
+            offset,
+        });
+        offset += len;
+    }
+    ret
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RuleSource {
+    pub name: String,
+    /// byte offset of the rule name
+    pub offset: usize,
+    /// byte offset right after the terminating semicolon
+    pub end: usize,
+    /// index range into [SourceMap::tokens] covering the right hand side
+    pub rhs: std::ops::Range<usize>,
+}
+
+/// maps rules and tokens back to their location in the grammar source
+pub(crate) struct SourceMap<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+    tokens: Vec<Token<'a>>,
+    rules: Vec<RuleSource>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let tokens = tokenize(source);
+        let mut rules = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let start = i;
+            while i < tokens.len() && !tokens[i].is_punct(";") {
+                i += 1;
+            }
+            let statement = &tokens[start..i];
+            let assign = statement
+                .iter()
+                .position(|token| token.is_punct("::=") || token.is_punct("="));
+            // macro definitions carry their parameters between the name and the `::=`, and
+            // dialect directives may come before the name
+            let name = assign.and_then(|assign| match statement[..assign] {
+                [.., ref before] if before.is_punct(">") => statement[..assign]
+                    .iter()
+                    .rposition(|token| token.is_punct("<"))
+                    .and_then(|open| open.checked_sub(1)),
+                _ => assign.checked_sub(1),
+            });
+            if let (Some(assign), Some(name)) = (assign, name)
+                && statement[name].kind == TokenKind::Ident
+            {
+                // layout rules like `@layout ::= ...` are named after the `@`
+                let at = name
+                    .checked_sub(1)
+                    .map(|at| &statement[at])
+                    .filter(|at| at.is_punct("@") && at.end() == statement[name].offset);
+                let name = &statement[name];
+                rules.push(RuleSource {
+                    name: match at {
+                        Some(_) => format!("@{}", name.text),
+                        None => name.text.to_string(),
+                    },
+                    offset: at.unwrap_or(name).offset,
+                    end: tokens.get(i).map_or(source.len(), Token::end),
+                    rhs: start + assign + 1..i,
+                });
+            }
+            i += 1;
+        }
+        Self {
+            source,
+            line_starts,
+            tokens,
+            rules,
+        }
+    }
+
+    /// converts a byte range into a line/column span
+    pub fn span(&self, offset: usize, len: usize) -> Span {
+        let offset = offset.min(self.source.len());
+        let line = self.line_starts.partition_point(|start| *start <= offset);
+        let line_start = self.line_starts[line - 1];
+        let end = (offset + len).min(self.source.len());
+        Span {
+            line,
+            column: self.source[line_start..offset].chars().count() + 1,
+            len: self.source[offset..end].chars().count(),
+        }
+    }
+
+    pub fn rules(&self) -> &[RuleSource] {
+        &self.rules
+    }
+
+    pub fn rule(&self, name: &str) -> Option<&RuleSource> {
+        self.rules.iter().find(|rule| rule.name == name)
+    }
+
+    pub fn rule_at(&self, offset: usize) -> Option<&RuleSource> {
+        self.rules
+            .iter()
+            .find(|rule| (rule.offset..rule.end).contains(&offset))
+    }
+
+    pub fn rule_span(&self, name: &str) -> Option<Span> {
+        self.rule(name)
+            .map(|rule| self.span(rule.offset, rule.name.len()))
+    }
+
+    pub fn rhs_tokens(&self, name: &str) -> &[Token<'a>] {
+        self.rule(name)
+            .map_or(&[], |rule| &self.tokens[rule.rhs.clone()])
+    }
+
+    /// finds the first token of `kind` in the rule `name` whose content is `content`
+    pub fn find_in_rule(&self, name: &str, kind: TokenKind, content: &str) -> Option<Span> {
+        self.rhs_tokens(name)
+            .iter()
+            .find(|token| token.kind == kind && token.content() == content)
+            .map(|token| self.span(token.offset, token.text.len()))
+    }
+}
+
+/// literal the exception operator `-` gets swapped out for before the source is handed to the
+/// ebnf crate, which doesn't know about exceptions
+/// Unlike a rule name it can't clash with anything written in a grammar, nobody types a raw
+/// control character into a string literal.
+pub(crate) const EXCEPTION_MARKER: &str = "\u{2}";
+
+/// whether `node` is an exception operator left by [exception_markers] or
+/// [super::syntax::exception]
+pub(crate) fn is_exception_marker(node: &Node) -> bool {
+    matches!(node, Node::String(str) if str == EXCEPTION_MARKER)
+}
+
+/// source text that got altered before parsing, remembers where it grew so offsets reported by
+/// the ebnf crate can be mapped back
+pub(crate) struct MarkedSource {
+    pub text: String,
+    /// (offset in the original source, number of bytes inserted there)
+    shifts: Vec<(usize, usize)>,
+}
+
+impl MarkedSource {
+    pub fn original_offset(&self, offset: usize) -> usize {
+        let mut added_before = 0;
+        for (orig, added) in &self.shifts {
+            let start = orig + added_before;
+            if offset < start {
+                break;
+            }
+            // inside a replacement
+            if offset <= start + added {
+                return *orig;
+            }
+            added_before += added;
+        }
+        offset - added_before
+    }
+}
+
+/// blanks out `range`, keeping line breaks and byte offsets so the text still lines up with the
+/// source
+pub(crate) fn blank(text: &mut String, range: Range<usize>) {
+    let blanked: String = text[range.clone()]
+        .chars()
+        .map(|c| {
+            if c == '\n' {
+                "\n".to_string()
+            } else {
+                " ".repeat(c.len_utf8())
+            }
+        })
+        .collect();
+    text.replace_range(range, &blanked);
+}
+
+/// replaces parts of `src`, each replacement has to be at least as long as the range it replaces
+/// and they have to be sorted by position
+pub(crate) fn rewrite(src: &str, replacements: &[(Range<usize>, String)]) -> MarkedSource {
+    let mut text = String::with_capacity(src.len());
+    let mut shifts = Vec::new();
+    let mut last = 0;
+    for (range, replacement) in replacements {
+        text.push_str(&src[last..range.start]);
+        text.push_str(replacement);
+        shifts.push((range.start, replacement.len() - range.len()));
+        last = range.end;
+    }
+    text.push_str(&src[last..]);
+    MarkedSource { text, shifts }
+}
+
+/// swaps every `-` out for the [EXCEPTION_MARKER], see [rewrite]
+pub(crate) fn exception_markers(src: &str) -> Vec<(Range<usize>, String)> {
+    tokenize(src)
+        .iter()
+        .filter(|token| token.is_punct("-"))
+        // the spaces stop the marker from getting glued to neighbouring identifiers
+        .map(|token| (token.offset..token.end(), format!(" '{EXCEPTION_MARKER}' ")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mark_exceptions() {
+        let src = "a ::= b-c '-' d;";
+        let marked = rewrite(src, &exception_markers(src));
+        assert_eq!("a ::= b '\u{2}' c '-' d;", marked.text);
+        assert_eq!(6, marked.original_offset(6));
+        assert_eq!(7, marked.original_offset(9));
+        assert_eq!(7, marked.original_offset(11));
+        assert_eq!(8, marked.original_offset(12));
+        assert_eq!(src.len(), marked.original_offset(marked.text.len()));
+    }
+}
@@ -0,0 +1,354 @@
+//! the syntaxes grammars can be written in
+//!
+//! Every syntax comes with a [GrammarFrontend] lowering it into a [GrammarIr], which all further
+//! passes and the FSM construction work on. Besides nightfury's own [Ebnf], grammars taken from
+//! standards or other tools can be used as they are, see [super::iso14977], [super::w3c],
+//! [super::abnf], [super::tree_sitter] and [super::antlr]. Nightfury's extensions like
+//! annotations, layout rules and dialect sections only exist in its own syntax.
+use std::fmt::Debug;
+
+use ebnf::{Expression, Grammar, Node, SymbolKind};
+
+use super::abnf::Abnf;
+use super::annotation::{self, Annotations};
+use super::antlr::Antlr;
+use super::error::{GrammarError, GrammarErrorKind, GrammarWarning, Span};
+use super::iso14977::Iso14977;
+use super::source::{self, EXCEPTION_MARKER, SourceMap};
+use super::tree_sitter::TreeSitter;
+use super::w3c::W3c;
+use super::{dialect, layout, macros};
+use crate::esc_seq::escape_sequences;
+
+/// turns the source of a grammar written in some syntax into a [GrammarIr]
+pub trait GrammarFrontend: Debug + Sync {
+    /// the name the frontend is picked by, e.g. via the cli's `--frontend`
+    fn name(&self) -> &'static str;
+    /// suffixes of the files written in this syntax, without the leading dot
+    fn extensions(&self) -> &'static [&'static str];
+    /// whether grammars in this syntax can pull in other files with `import` and `extends`, see
+    /// [super::GrammarModules]
+    fn supports_imports(&self) -> bool {
+        false
+    }
+    /// lowers `source`, keeping the `@if` sections for `dialect` if the syntax has any
+    /// Everything wrong with the grammar goes into `errors`. Returns None if nothing useful could
+    /// be made of it, which requires at least one error.
+    fn lower(
+        &self,
+        source: &str,
+        dialect: Option<&str>,
+        errors: &mut Vec<GrammarError>,
+        warnings: &mut Vec<GrammarWarning>,
+    ) -> Option<GrammarIr>;
+}
+
+/// the representation every syntax gets lowered into
+/// Rules refer to each other by name via [Node::Terminal], [literal] and [exception] build the
+/// nodes for keywords and exceptions.
+#[derive(Debug)]
+pub struct GrammarIr {
+    pub(crate) grammar: Grammar,
+    /// the rules as written, before any pass touched them
+    pub(crate) written: Grammar,
+    /// looked up by handle_node when building keywords
+    pub(crate) annotations: Annotations,
+    /// the `@layout` regex, if any
+    pub(crate) layout: Option<String>,
+    pub(crate) indent: Option<String>,
+}
+
+impl GrammarIr {
+    /// a grammar without any of nightfury's extensions, the first rule being the default entry
+    pub fn new(rules: Vec<(String, Node)>) -> Self {
+        let grammar = Grammar {
+            expressions: rules
+                .into_iter()
+                .map(|(lhs, rhs)| Expression { lhs, rhs })
+                .collect(),
+        };
+        Self {
+            written: grammar.clone(),
+            grammar,
+            annotations: Annotations::default(),
+            layout: None,
+            indent: None,
+        }
+    }
+}
+
+/// a keyword matching `text` exactly
+pub fn literal(text: &str) -> Node {
+    Node::String(escape_sequences(text))
+}
+
+/// `minuend` without the alternatives of `subtrahend`
+pub fn exception(minuend: Node, subtrahend: Node) -> Node {
+    Node::Multiple(vec![
+        minuend,
+        Node::String(EXCEPTION_MARKER.to_string()),
+        subtrahend,
+    ])
+}
+
+/// `nodes` one after another, nothing at all if empty
+pub(super) fn sequence(mut nodes: Vec<Node>) -> Node {
+    if nodes.len() == 1 {
+        nodes.pop().unwrap()
+    } else {
+        Node::Multiple(nodes)
+    }
+}
+
+/// a choice between `alts`, which must not be empty
+pub(super) fn alternation(mut alts: Vec<Node>) -> Node {
+    let last = alts.pop().expect("at least one alternative");
+    alts.into_iter().rev().fold(last, |acc, alt| {
+        Node::Symbol(Box::new(alt), SymbolKind::Alternation, Box::new(acc))
+    })
+}
+
+/// nightfury's own syntax, `rule ::= 'keyword' other_rule #'regex';` plus all its extensions
+#[derive(Debug)]
+pub struct Ebnf;
+
+impl GrammarFrontend for Ebnf {
+    fn name(&self) -> &'static str {
+        "ebnf"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["ebnf"]
+    }
+
+    fn supports_imports(&self) -> bool {
+        true
+    }
+
+    fn lower(
+        &self,
+        source: &str,
+        dialect: Option<&str>,
+        errors: &mut Vec<GrammarError>,
+        _warnings: &mut Vec<GrammarWarning>,
+    ) -> Option<GrammarIr> {
+        let source_map = SourceMap::new(source);
+        let errors_before = errors.len();
+        let selected = dialect::select_dialect(source, dialect, &source_map, errors);
+        // whatever follows a broken directive can't be trusted
+        if errors.len() > errors_before {
+            return None;
+        }
+        let layout = layout::extract_layout(&selected, &source_map, errors);
+        let mut annotated = annotation::extract_annotations(&layout.text, &source_map, errors);
+        annotated.annotations.separator = layout.separator;
+        annotated.annotations.case = layout.case;
+        let macros = macros::extract_macros(&annotated.text, &source_map, errors);
+        let mut replacements = source::exception_markers(&annotated.text);
+        replacements.extend(macros.replacements.iter().cloned());
+        replacements.sort_by_key(|(range, _)| range.start);
+        let marked = source::rewrite(&annotated.text, &replacements);
+        let mut grammar = match ebnf::get_grammar(&marked.text) {
+            Ok(grammar) => grammar,
+            Err(err) => {
+                errors.push(GrammarError::from_parse_error(&err, &marked, &source_map));
+                return None;
+            }
+        };
+        let written = grammar.clone();
+        let errors_before = errors.len();
+        macros::expand_macros(&mut grammar, &macros, &source_map, errors);
+        // whatever failed to expand would only cause follow-up errors
+        if errors.len() > errors_before {
+            return None;
+        }
+        Some(GrammarIr {
+            grammar,
+            written,
+            annotations: annotated.annotations,
+            layout: layout.layout,
+            indent: layout.indent,
+        })
+    }
+}
+
+static FRONTENDS: [&dyn GrammarFrontend; 6] = [&Ebnf, &Iso14977, &W3c, &Abnf, &TreeSitter, &Antlr];
+
+/// every syntax nightfury knows about
+pub fn frontends() -> &'static [&'static dyn GrammarFrontend] {
+    &FRONTENDS
+}
+
+/// the frontend called `name`, see [GrammarFrontend::name]
+pub fn frontend(name: &str) -> Option<&'static dyn GrammarFrontend> {
+    FRONTENDS
+        .iter()
+        .find(|frontend| frontend.name() == name)
+        .copied()
+}
+
+/// the frontend for the file `file_name` along with the file name stripped of its suffix, going
+/// by the longest matching suffix, e.g. `xml.w3c.ebnf` is W3C rather than nightfury's syntax
+pub fn frontend_for_file(file_name: &str) -> Option<(&'static dyn GrammarFrontend, &str)> {
+    FRONTENDS
+        .iter()
+        .flat_map(|frontend| {
+            frontend
+                .extensions()
+                .iter()
+                .map(move |ext| (*frontend, ext))
+        })
+        .filter_map(|(frontend, ext)| {
+            file_name
+                .strip_suffix(ext)
+                .and_then(|stem| stem.strip_suffix('.'))
+                .filter(|stem| !stem.is_empty())
+                .map(|stem| (frontend, stem))
+        })
+        .min_by_key(|(_, stem)| stem.len())
+}
+
+/// a cursor over a grammar source, shared by the hand written frontends
+pub(super) struct Scanner<'a> {
+    pub source: &'a str,
+    pub pos: usize,
+    source_map: SourceMap<'a>,
+    /// rule currently being parsed, attached to errors
+    pub rule: Option<String>,
+    pub errors: &'a mut Vec<GrammarError>,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str, errors: &'a mut Vec<GrammarError>) -> Self {
+        Self {
+            source,
+            pos: 0,
+            source_map: SourceMap::new(source),
+            rule: None,
+            errors,
+        }
+    }
+
+    pub fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.source.len()
+    }
+
+    pub fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    pub fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// consumes `text` if the source continues with it
+    pub fn eat(&mut self, text: &str) -> bool {
+        let found = self.rest().starts_with(text);
+        if found {
+            self.pos += text.len();
+        }
+        found
+    }
+
+    /// consumes characters as long as `pred` holds, handing them out
+    pub fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(&pred) {
+            self.bump();
+        }
+        &self.source[start..self.pos]
+    }
+
+    /// skips whitespace and comments delimited by `open` and `close`, which may be nested
+    pub fn skip_trivia(&mut self, open: &str, close: &str) {
+        loop {
+            self.take_while(char::is_whitespace);
+            if !self.rest().starts_with(open) {
+                return;
+            }
+            let start = self.pos;
+            self.pos += open.len();
+            let mut depth = 1;
+            while depth > 0 {
+                if self.eat(close) {
+                    depth -= 1;
+                } else if self.eat(open) {
+                    depth += 1;
+                } else if self.bump().is_none() {
+                    self.error_at(start, open.len(), "unterminated comment");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// the contents of the string starting at the current position with `quote`, which can't
+    /// contain the quote itself
+    pub fn quoted(&mut self, quote: char) -> Option<&'a str> {
+        let start = self.pos;
+        self.bump();
+        let content = self.take_while(|c| c != quote && c != '\n');
+        if self.peek() == Some(quote) {
+            self.bump();
+            Some(content)
+        } else {
+            self.error_at(start, 1, "unterminated string");
+            None
+        }
+    }
+
+    pub fn span(&self, offset: usize, len: usize) -> Span {
+        self.source_map.span(offset, len)
+    }
+
+    pub fn error_at_kind(&mut self, kind: GrammarErrorKind, offset: usize, len: usize) {
+        let span = self.span(offset, len);
+        self.errors
+            .push(GrammarError::new(kind, self.rule.as_deref(), Some(span)));
+    }
+
+    pub fn error_at(&mut self, offset: usize, len: usize, msg: &str) {
+        self.error_at_kind(GrammarErrorKind::Syntax(msg.to_string()), offset, len);
+    }
+
+    /// complains about whatever comes next
+    pub fn unexpected(&mut self, expected: &str) {
+        let msg = match self.peek() {
+            Some(c) => format!("expected {expected}, found `{c}`"),
+            None => format!("expected {expected}, found the end of the grammar"),
+        };
+        let len = self.peek().map_or(0, char::len_utf8);
+        self.error_at(self.pos, len, &msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frontend_for_file() {
+        let name = |file| frontend_for_file(file).map(|(frontend, stem)| (frontend.name(), stem));
+        assert_eq!(Some(("ebnf", "sql")), name("sql.ebnf"));
+        assert_eq!(Some(("w3c", "xml")), name("xml.w3c.ebnf"));
+        assert_eq!(Some(("w3c", "xml")), name("xml.w3c"));
+        assert_eq!(Some(("iso14977", "pascal")), name("pascal.iso.ebnf"));
+        assert_eq!(Some(("iso14977", "pascal")), name("pascal.iso14977"));
+        assert_eq!(Some(("abnf", "uri")), name("uri.abnf"));
+        assert_eq!(
+            Some(("tree-sitter", "src/grammar")),
+            name("src/grammar.json")
+        );
+        assert_eq!(Some(("antlr4", "SQLite")), name("SQLite.g4"));
+        assert_eq!(None, name("sql.fsm"));
+        assert_eq!(None, name(".ebnf"));
+        assert_eq!(Some("w3c"), frontend("w3c").map(|frontend| frontend.name()));
+        assert!(frontend("yacc").is_none());
+    }
+}
@@ -0,0 +1,498 @@
+//! the `src/grammar.json` tree-sitter generates for every grammar
+//!
+//! Rules keep their names, the first one is the default entry. `STRING`s turn into keywords,
+//! `PATTERN`s and whatever is wrapped in `TOKEN` into user-defined tokens, unless a token is just
+//! a string. Precedences, fields and aliases only matter for the syntax tree and are looked
+//! through. The `extras` (whitespace and comments by default) become the grammar's layout. Tokens
+//! of an external scanner can't be known without running it, rules refer to them as if they
+//! matched nothing.
+use std::fmt;
+
+use ebnf::{Node, RegexExtKind};
+use regex::Regex;
+use serde::de::{Deserialize, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_json::Value;
+
+use super::error::{GrammarError, GrammarErrorKind, GrammarWarning, GrammarWarningKind, Span};
+use super::syntax::{GrammarFrontend, GrammarIr, alternation, literal, sequence};
+
+#[derive(Debug)]
+pub struct TreeSitter;
+
+impl GrammarFrontend for TreeSitter {
+    fn name(&self) -> &'static str {
+        "tree-sitter"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["json"]
+    }
+
+    fn lower(
+        &self,
+        source: &str,
+        _dialect: Option<&str>,
+        errors: &mut Vec<GrammarError>,
+        warnings: &mut Vec<GrammarWarning>,
+    ) -> Option<GrammarIr> {
+        let grammar: GrammarJson = match serde_json::from_str(source) {
+            Ok(grammar) => grammar,
+            Err(err) => {
+                let span = Span {
+                    line: err.line(),
+                    column: err.column(),
+                    len: 1,
+                };
+                errors.push(GrammarError::new(
+                    GrammarErrorKind::Syntax(err.to_string()),
+                    None,
+                    Some(span),
+                ));
+                return None;
+            }
+        };
+        let errors_before = errors.len();
+        let mut rules = Vec::with_capacity(grammar.rules.len());
+        for (name, rule) in &grammar.rules {
+            match lower_rule(rule) {
+                Ok(rhs) => rules.push((name.clone(), rhs)),
+                Err(msg) => errors.push(GrammarError::new(
+                    GrammarErrorKind::Syntax(msg),
+                    Some(name),
+                    None,
+                )),
+            }
+        }
+        for external in &grammar.externals {
+            let Some(name) = symbol(external) else {
+                continue;
+            };
+            if rules.iter().all(|(rule, _)| rule != name) {
+                warnings.push(GrammarWarning::new(
+                    GrammarWarningKind::Ignored(format!("external token `{name}`")),
+                    None,
+                    None,
+                ));
+                rules.push((name.to_string(), Node::Multiple(Vec::new())));
+            }
+        }
+        let layout = layout(&grammar, errors, warnings);
+        if errors.len() > errors_before {
+            return None;
+        }
+        let mut ir = GrammarIr::new(rules);
+        ir.layout = layout;
+        Some(ir)
+    }
+}
+
+/// the parts of grammar.json that matter for completion
+struct GrammarJson {
+    /// in the order they were defined in, serde_json's maps would sort them by name
+    rules: Vec<(String, Value)>,
+    extras: Option<Vec<Value>>,
+    externals: Vec<Value>,
+}
+
+impl<'de> Deserialize<'de> for GrammarJson {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(GrammarVisitor)
+    }
+}
+
+struct GrammarVisitor;
+
+impl<'de> Visitor<'de> for GrammarVisitor {
+    type Value = GrammarJson;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a tree-sitter grammar")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<GrammarJson, A::Error> {
+        let mut rules = None;
+        let mut extras = None;
+        let mut externals = Vec::new();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "rules" => rules = Some(map.next_value::<OrderedRules>()?.0),
+                "extras" => extras = Some(map.next_value()?),
+                "externals" => externals = map.next_value()?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let rules = rules.ok_or_else(|| serde::de::Error::missing_field("rules"))?;
+        Ok(GrammarJson {
+            rules,
+            extras,
+            externals,
+        })
+    }
+}
+
+struct OrderedRules(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for OrderedRules {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(OrderedRulesVisitor)
+    }
+}
+
+struct OrderedRulesVisitor;
+
+impl<'de> Visitor<'de> for OrderedRulesVisitor {
+    type Value = OrderedRules;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rules by name")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<OrderedRules, A::Error> {
+        let mut rules = Vec::new();
+        while let Some(rule) = map.next_entry()? {
+            rules.push(rule);
+        }
+        Ok(OrderedRules(rules))
+    }
+}
+
+fn kind(rule: &Value) -> Result<&str, String> {
+    rule["type"]
+        .as_str()
+        .ok_or_else(|| format!("expected a rule with a type, found `{rule}`"))
+}
+
+fn field<'a>(rule: &'a Value, name: &str) -> Result<&'a Value, String> {
+    rule.get(name)
+        .ok_or_else(|| format!("{} is missing its `{name}`", kind(rule).unwrap_or("rule")))
+}
+
+fn string<'a>(rule: &'a Value, name: &str) -> Result<&'a str, String> {
+    field(rule, name)?.as_str().ok_or_else(|| {
+        format!(
+            "the `{name}` of {} has to be a string",
+            kind(rule).unwrap_or("rule")
+        )
+    })
+}
+
+fn members(rule: &Value) -> Result<&Vec<Value>, String> {
+    field(rule, "members")?.as_array().ok_or_else(|| {
+        format!(
+            "the members of {} have to be a list",
+            kind(rule).unwrap_or("rule")
+        )
+    })
+}
+
+/// the name of the rule `rule` refers to, if it's a `SYMBOL`
+fn symbol(rule: &Value) -> Option<&str> {
+    (rule["type"] == "SYMBOL").then(|| rule["name"].as_str())?
+}
+
+/// the rule types that wrap a single rule without changing what it matches
+fn is_wrapper(kind: &str) -> bool {
+    matches!(
+        kind,
+        "PREC" | "PREC_LEFT" | "PREC_RIGHT" | "PREC_DYNAMIC" | "FIELD" | "ALIAS" | "RESERVED"
+    )
+}
+
+fn lower_rule(rule: &Value) -> Result<Node, String> {
+    Ok(match kind(rule)? {
+        "BLANK" => Node::Multiple(Vec::new()),
+        "STRING" => match string(rule, "value")? {
+            "" => Node::Multiple(Vec::new()),
+            value => literal(value),
+        },
+        "PATTERN" => Node::RegexString(pattern(rule)?),
+        "SYMBOL" => Node::Terminal(string(rule, "name")?.to_string()),
+        "SEQ" => sequence(
+            members(rule)?
+                .iter()
+                .map(lower_rule)
+                .filter(|node| !matches!(node, Ok(Node::Multiple(nodes)) if nodes.is_empty()))
+                .collect::<Result<_, _>>()?,
+        ),
+        "CHOICE" => {
+            let mut alts = Vec::new();
+            let mut optional = false;
+            for member in members(rule)? {
+                match lower_rule(member)? {
+                    Node::Multiple(nodes) if nodes.is_empty() => optional = true,
+                    alt => alts.push(alt),
+                }
+            }
+            match (alts.is_empty(), optional) {
+                (true, _) => Node::Multiple(Vec::new()),
+                (false, true) => Node::Optional(Box::new(alternation(alts))),
+                (false, false) => alternation(alts),
+            }
+        }
+        "REPEAT" => Node::Repeat(Box::new(lower_rule(field(rule, "content")?)?)),
+        "REPEAT1" => Node::RegexExt(
+            Box::new(lower_rule(field(rule, "content")?)?),
+            RegexExtKind::Repeat1,
+        ),
+        "TOKEN" | "IMMEDIATE_TOKEN" => {
+            let content = field(rule, "content")?;
+            match token_string(content) {
+                Some(value) => literal(value),
+                None => Node::RegexString(token_regex(content)?),
+            }
+        }
+        kind if is_wrapper(kind) => lower_rule(field(rule, "content")?)?,
+        kind => return Err(format!("unknown rule type `{kind}`")),
+    })
+}
+
+/// a `PATTERN` along with its flags, as far as they mean anything to the regex crate
+fn pattern(rule: &Value) -> Result<String, String> {
+    let value = string(rule, "value")?;
+    let flags: String = rule["flags"]
+        .as_str()
+        .unwrap_or_default()
+        .chars()
+        .filter(|flag| matches!(flag, 'i' | 's' | 'm'))
+        .collect();
+    Ok(if flags.is_empty() {
+        value.to_string()
+    } else {
+        format!("(?{flags}:{value})")
+    })
+}
+
+/// the string a token consists of, if it's nothing else
+fn token_string(rule: &Value) -> Option<&str> {
+    match rule["type"].as_str()? {
+        "STRING" => rule["value"].as_str(),
+        kind if is_wrapper(kind) => token_string(&rule["content"]),
+        _ => None,
+    }
+}
+
+/// the regex matching the same as the content of a `TOKEN`
+fn token_regex(rule: &Value) -> Result<String, String> {
+    let content = || token_regex(field(rule, "content")?);
+    Ok(match kind(rule)? {
+        "BLANK" => String::new(),
+        "STRING" => regex::escape(string(rule, "value")?),
+        "PATTERN" => format!("(?:{})", pattern(rule)?),
+        "SEQ" => members(rule)?
+            .iter()
+            .map(token_regex)
+            .collect::<Result<_, _>>()?,
+        "CHOICE" => {
+            let mut alts = Vec::new();
+            let mut optional = false;
+            for member in members(rule)? {
+                match token_regex(member)? {
+                    alt if alt.is_empty() => optional = true,
+                    alt => alts.push(alt),
+                }
+            }
+            format!("(?:{}){}", alts.join("|"), if optional { "?" } else { "" })
+        }
+        "REPEAT" => format!("(?:{})*", content()?),
+        "REPEAT1" => format!("(?:{})+", content()?),
+        "TOKEN" | "IMMEDIATE_TOKEN" => content()?,
+        kind if is_wrapper(kind) => content()?,
+        "SYMBOL" => {
+            return Err(format!(
+                "tokens can't refer to rules like `{}`",
+                string(rule, "name")?
+            ));
+        }
+        kind => return Err(format!("unknown rule type `{kind}`")),
+    })
+}
+
+/// the layout regex made of the grammar's `extras`, which default to whitespace
+fn layout(
+    grammar: &GrammarJson,
+    errors: &mut Vec<GrammarError>,
+    warnings: &mut Vec<GrammarWarning>,
+) -> Option<String> {
+    let default = [serde_json::json!({ "type": "PATTERN", "value": "\\s" })];
+    let extras = grammar.extras.as_deref().unwrap_or(&default);
+    let mut alts = Vec::new();
+    for extra in extras {
+        let regex = match symbol(extra) {
+            Some(name) => grammar
+                .rules
+                .iter()
+                .find(|(rule, _)| rule == name)
+                .and_then(|(_, rule)| token_regex(rule).ok()),
+            None => token_regex(extra).ok(),
+        };
+        match regex {
+            Some(regex) => alts.push(regex),
+            None => warnings.push(GrammarWarning::new(
+                GrammarWarningKind::Ignored(format!("the extra `{extra}`")),
+                None,
+                None,
+            )),
+        }
+    }
+    if alts.is_empty() {
+        return None;
+    }
+    let layout = format!("(?:{})*", alts.join("|"));
+    if let Err(err) = Regex::new(&layout) {
+        errors.push(GrammarError::new(
+            GrammarErrorKind::InvalidRegex {
+                regex: layout,
+                reason: err.to_string(),
+            },
+            None,
+            None,
+        ));
+        return None;
+    }
+    Some(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FSMCursor;
+    use crate::frontend::{CompileOptions, compile_ebnf_with};
+
+    const GRAMMAR: &str = r##"{
+      "name": "calc",
+      "word": "identifier",
+      "rules": {
+        "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "statement"}},
+        "statement": {
+          "type": "CHOICE",
+          "members": [
+            {"type": "SEQ", "members": [
+              {"type": "STRING", "value": "let"},
+              {"type": "FIELD", "name": "name", "content": {"type": "SYMBOL", "name": "identifier"}},
+              {"type": "STRING", "value": "="},
+              {"type": "SYMBOL", "name": "number"},
+              {"type": "CHOICE", "members": [
+                {"type": "SYMBOL", "name": "_semicolon"},
+                {"type": "BLANK"}
+              ]}
+            ]},
+            {"type": "PREC", "value": 1, "content": {"type": "STRING", "value": "print"}}
+          ]
+        },
+        "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+        "number": {"type": "TOKEN", "content": {"type": "SEQ", "members": [
+          {"type": "PATTERN", "value": "\\d+"},
+          {"type": "CHOICE", "members": [{"type": "STRING", "value": ".5"}, {"type": "BLANK"}]}
+        ]}},
+        "comment": {"type": "TOKEN", "content": {"type": "SEQ", "members": [
+          {"type": "STRING", "value": "#"},
+          {"type": "PATTERN", "value": "[^\\n]*"}
+        ]}}
+      },
+      "extras": [{"type": "PATTERN", "value": "\\s"}, {"type": "SYMBOL", "name": "comment"}],
+      "externals": [{"type": "SYMBOL", "name": "_semicolon"}]
+    }"##;
+
+    fn lower(source: &str) -> (Option<GrammarIr>, Vec<GrammarError>, Vec<GrammarWarning>) {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+        let ir = TreeSitter.lower(source, None, &mut errors, &mut warnings);
+        (ir, errors, warnings)
+    }
+
+    #[test]
+    fn test_lower() {
+        let (ir, _, warnings) = lower(GRAMMAR);
+        let ir = ir.unwrap();
+        let rules: Vec<(&str, String)> = ir
+            .grammar
+            .expressions
+            .iter()
+            .map(|expr| (expr.lhs.as_str(), format!("{:?}", expr.rhs)))
+            .collect();
+        let names: Vec<&str> = rules.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            vec![
+                "program",
+                "statement",
+                "identifier",
+                "number",
+                "comment",
+                "_semicolon"
+            ],
+            names
+        );
+        assert_eq!(
+            r#"Symbol(Multiple([String("let"), Terminal("identifier"), String("="), Terminal("number"), Optional(Terminal("_semicolon"))]), Alternation, String("print"))"#,
+            rules[1].1
+        );
+        assert_eq!(r#"RegexString("(?:\\d+)(?:\\.5)?")"#, rules[3].1);
+        assert_eq!(r#"Multiple([])"#, rules[5].1);
+        assert_eq!(Some(r"(?:(?:\s)|\#(?:[^\n]*))*"), ir.layout.as_deref());
+        assert_eq!(
+            vec![GrammarWarningKind::Ignored(
+                "external token `_semicolon`".to_string()
+            )],
+            warnings.into_iter().map(|w| w.kind).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let (ir, errors, _) = lower("{\n  \"rules\": {\n    \"a\": }\n}");
+        assert!(ir.is_none());
+        assert_eq!(3, errors[0].span.as_ref().unwrap().line);
+
+        let (_, errors, _) = lower(r#"{"name": "x"}"#);
+        assert!(matches!(&errors[0].kind, GrammarErrorKind::Syntax(msg) if msg.contains("rules")));
+
+        let (_, errors, _) = lower(
+            r#"{"rules": {
+              "a": {"type": "MAGIC"},
+              "b": {"type": "TOKEN", "content": {"type": "SYMBOL", "name": "a"}},
+              "c": {"type": "SEQ"}
+            }}"#,
+        );
+        let errors: Vec<(Option<&str>, String)> = errors
+            .iter()
+            .map(|err| (err.rule.as_deref(), err.kind.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    Some("a"),
+                    "syntax error: unknown rule type `MAGIC`".to_string()
+                ),
+                (
+                    Some("b"),
+                    "syntax error: tokens can't refer to rules like `a`".to_string()
+                ),
+                (
+                    Some("c"),
+                    "syntax error: SEQ is missing its `members`".to_string()
+                ),
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_compile() {
+        let compiled = compile_ebnf_with(
+            GRAMMAR,
+            &CompileOptions {
+                frontend: Some(&TreeSitter),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut cursor =
+            FSMCursor::new(compiled.entries.default_root()).with_layout(compiled.entries.layout());
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!(None, cursor.advance(' '));
+        assert_eq!(None, cursor.advance('x'));
+        assert_eq!("=", cursor.advance('=').unwrap());
+    }
+}
@@ -0,0 +1,407 @@
+//! the EBNF notation of W3C specifications, e.g. XML's
+//!
+//! ```text
+//! [4] NameStartChar ::= ":" | [A-Z] | "_" | [a-z] | [#xC0-#xD6]
+//! [5] NameChar ::= NameStartChar | "-" | "." | [0-9] | #xB7
+//! [6] Name ::= NameStartChar (NameChar)*
+//! ```
+//!
+//! Rules aren't terminated, one ends where the next `Name ::=` or production number starts.
+//! Character classes turn into regexes, constraint notes like `[ wfc: ... ]` are skipped.
+use ebnf::{Node, RegexExtKind};
+
+use super::error::{GrammarError, GrammarWarning};
+use super::syntax::{
+    GrammarFrontend, GrammarIr, Scanner, alternation, exception, literal, sequence,
+};
+
+#[derive(Debug)]
+pub struct W3c;
+
+impl GrammarFrontend for W3c {
+    fn name(&self) -> &'static str {
+        "w3c"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["w3c.ebnf", "w3c"]
+    }
+
+    fn lower(
+        &self,
+        source: &str,
+        _dialect: Option<&str>,
+        errors: &mut Vec<GrammarError>,
+        _warnings: &mut Vec<GrammarWarning>,
+    ) -> Option<GrammarIr> {
+        let errors_before = errors.len();
+        let mut parser = Parser {
+            scanner: Scanner::new(source, errors),
+        };
+        let rules = parser.rules();
+        if parser.scanner.errors.len() > errors_before {
+            return None;
+        }
+        Some(GrammarIr::new(rules))
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':')
+}
+
+/// rule names may only consist of letters, digits and `_`
+fn rule_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// the character a `#xN` reference stands for, `digits` being the `N`
+fn char_ref(digits: &str) -> Option<char> {
+    u32::from_str_radix(digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+}
+
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+}
+
+impl Parser<'_> {
+    /// skips whitespace, comments and constraint notes
+    fn skip_trivia(&mut self) {
+        loop {
+            self.scanner.skip_trivia("/*", "*/");
+            let rest = self.scanner.rest();
+            let note = rest
+                .strip_prefix('[')
+                .map(str::trim_start)
+                .is_some_and(|note| {
+                    ["wfc:", "vc:", "WFC:", "VC:"]
+                        .iter()
+                        .any(|kind| note.starts_with(kind))
+                });
+            if !note {
+                return;
+            }
+            self.scanner.take_while(|c| c != ']');
+            self.scanner.bump();
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_trivia();
+        self.scanner.peek()
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        self.skip_trivia();
+        self.scanner.eat(text)
+    }
+
+    /// skips a production number like `[12]` or `[12a]`, if there is one
+    fn production_number(&mut self) -> bool {
+        let rest = self.scanner.rest();
+        let Some(number) = rest.strip_prefix('[') else {
+            return false;
+        };
+        let digits = number.len()
+            - number
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .len();
+        let end = number[digits..].trim_start_matches(char::is_alphabetic);
+        if digits == 0 || !end.starts_with(']') {
+            return false;
+        }
+        self.scanner.pos += rest.len() - end.len() + 1;
+        true
+    }
+
+    /// whether the next rule starts here
+    fn at_rule_start(&mut self) -> bool {
+        self.skip_trivia();
+        let pos = self.scanner.pos;
+        self.production_number();
+        self.skip_trivia();
+        let rest = self.scanner.rest();
+        self.scanner.pos = pos;
+        let name_len = rest.len() - rest.trim_start_matches(is_name_char).len();
+        name_len > 0 && rest[name_len..].trim_start().starts_with("::=")
+    }
+
+    fn rules(&mut self) -> Vec<(String, Node)> {
+        let mut rules = Vec::new();
+        while self.peek().is_some() {
+            match self.rule() {
+                Some(rule) => rules.push(rule),
+                // skip to the next rule, so every broken rule gets reported at once
+                None => {
+                    while !self.scanner.at_end() && !self.at_rule_start() {
+                        self.scanner.bump();
+                    }
+                }
+            }
+            self.scanner.rule = None;
+        }
+        rules
+    }
+
+    fn rule(&mut self) -> Option<(String, Node)> {
+        self.production_number();
+        self.skip_trivia();
+        let name = self.scanner.take_while(is_name_char);
+        if name.is_empty() {
+            self.scanner.unexpected("a rule name");
+            return None;
+        }
+        let name = rule_name(name);
+        self.scanner.rule = Some(name.clone());
+        if !self.eat("::=") {
+            self.scanner.unexpected("`::=`");
+            return None;
+        }
+        let rhs = self.choice()?;
+        if !self.scanner.at_end() && !self.at_rule_start() {
+            self.scanner.unexpected("the next rule");
+            return None;
+        }
+        Some((name, rhs))
+    }
+
+    fn choice(&mut self) -> Option<Node> {
+        let mut alts = vec![self.difference()?];
+        while self.eat("|") {
+            alts.push(self.difference()?);
+        }
+        Some(alternation(alts))
+    }
+
+    fn difference(&mut self) -> Option<Node> {
+        let minuend = self.sequence()?;
+        if self.eat("-") {
+            let subtrahend = self.sequence()?;
+            return Some(exception(minuend, subtrahend));
+        }
+        Some(minuend)
+    }
+
+    fn sequence(&mut self) -> Option<Node> {
+        let mut items = vec![self.item()?];
+        while !self.ends_sequence() {
+            items.push(self.item()?);
+        }
+        Some(sequence(items))
+    }
+
+    fn ends_sequence(&mut self) -> bool {
+        matches!(self.peek(), None | Some('|' | '-' | ')')) || self.at_rule_start()
+    }
+
+    /// a primary, optionally followed by `?`, `*` or `+`
+    fn item(&mut self) -> Option<Node> {
+        let primary = self.primary()?;
+        // the operators have to follow the primary directly, `a ?` isn't a thing
+        let kind = match self.scanner.peek() {
+            Some('?') => RegexExtKind::Optional,
+            Some('*') => RegexExtKind::Repeat0,
+            Some('+') => RegexExtKind::Repeat1,
+            _ => return Some(primary),
+        };
+        self.scanner.bump();
+        Some(Node::RegexExt(Box::new(primary), kind))
+    }
+
+    fn primary(&mut self) -> Option<Node> {
+        let Some(c) = self.peek() else {
+            self.scanner.unexpected("an expression");
+            return None;
+        };
+        match c {
+            '(' => {
+                self.scanner.bump();
+                let inner = self.choice()?;
+                if !self.eat(")") {
+                    self.scanner.unexpected("`)`");
+                    return None;
+                }
+                Some(Node::Group(Box::new(inner)))
+            }
+            '\'' | '"' => {
+                let start = self.scanner.pos;
+                let text = self.scanner.quoted(c)?;
+                if text.is_empty() {
+                    self.scanner.error_at(start, 2, "strings can't be empty");
+                    return None;
+                }
+                Some(literal(text))
+            }
+            '#' => {
+                let c = self.char_ref()?;
+                Some(literal(&c.to_string()))
+            }
+            '[' => self.char_class(),
+            c if is_name_char(c) => Some(Node::Terminal(rule_name(
+                self.scanner.take_while(is_name_char),
+            ))),
+            _ => {
+                self.scanner.unexpected("an expression");
+                None
+            }
+        }
+    }
+
+    /// `#xN` at the current position
+    fn char_ref(&mut self) -> Option<char> {
+        let start = self.scanner.pos;
+        if !self.scanner.eat("#x") {
+            self.scanner.unexpected("`#x`");
+            return None;
+        }
+        let digits = self.scanner.take_while(|c| c.is_ascii_hexdigit());
+        let c = char_ref(digits);
+        if c.is_none() {
+            self.scanner.error_at(
+                start,
+                digits.len() + 2,
+                "`#x` has to be followed by a unicode scalar value",
+            );
+        }
+        c
+    }
+
+    /// `[a-z#x80]` or `[^"]`, as a regex
+    fn char_class(&mut self) -> Option<Node> {
+        let start = self.scanner.pos;
+        self.scanner.bump();
+        let mut regex = String::from("[");
+        if self.scanner.eat("^") {
+            regex.push('^');
+        }
+        loop {
+            match self.scanner.peek() {
+                Some(']') => break,
+                // a dash between two characters is a range, anywhere else it's itself
+                Some('-') if regex.len() > 1 && !regex.ends_with('^') => {
+                    self.scanner.bump();
+                    if self.scanner.peek() == Some(']') {
+                        regex.push_str("\\-");
+                    } else {
+                        regex.push('-');
+                    }
+                }
+                Some('#') if self.scanner.rest().starts_with("#x") => {
+                    let c = self.char_ref()?;
+                    regex.push_str(&format!("\\x{{{:X}}}", c as u32));
+                }
+                Some(c) => {
+                    self.scanner.bump();
+                    if matches!(c, '\\' | '[' | ']' | '^' | '-' | '&' | '~') {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                None => {
+                    self.scanner
+                        .error_at(start, 1, "unterminated character class");
+                    return None;
+                }
+            }
+        }
+        self.scanner.bump();
+        regex.push(']');
+        Some(Node::RegexString(regex))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FSMCursor;
+    use crate::frontend::{CompileOptions, GrammarErrorKind, compile_ebnf_with};
+
+    fn lower(source: &str) -> Result<Vec<(String, String)>, Vec<GrammarError>> {
+        let mut errors = Vec::new();
+        match W3c.lower(source, None, &mut errors, &mut Vec::new()) {
+            Some(ir) => Ok(ir
+                .grammar
+                .expressions
+                .into_iter()
+                .map(|expr| (expr.lhs, format!("{:?}", expr.rhs)))
+                .collect()),
+            None => Err(errors),
+        }
+    }
+
+    #[test]
+    fn test_lower() {
+        let rules = lower(
+            r#"/* from the XML spec */
+            [4] NameStartChar ::= ":" | [A-Z_] | [#xC0-#xD6]
+            [5] NameChar ::= NameStartChar | "-" | #xB7
+            [6] Name ::= NameStartChar (NameChar)*
+            Char ::= [^<&] - ']'   [ wfc: No Recursion ]
+            Names ::= Name (#x20 Name)+ S?
+            S ::= [ #x9#xA-]
+            "#,
+        )
+        .unwrap();
+        let names: Vec<&str> = rules.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            vec!["NameStartChar", "NameChar", "Name", "Char", "Names", "S"],
+            names
+        );
+        assert_eq!(
+            r#"Symbol(String(":"), Alternation, Symbol(RegexString("[A-Z_]"), Alternation, RegexString("[\\x{C0}-\\x{D6}]")))"#,
+            rules[0].1
+        );
+        assert_eq!(
+            r#"Symbol(Terminal("NameStartChar"), Alternation, Symbol(String("-"), Alternation, String("·")))"#,
+            rules[1].1
+        );
+        assert_eq!(
+            r#"Multiple([Terminal("NameStartChar"), RegexExt(Group(Terminal("NameChar")), Repeat0)])"#,
+            rules[2].1
+        );
+        assert_eq!(
+            r#"Multiple([RegexString("[^<\\&]"), String("\u{2}"), String("]")])"#,
+            rules[3].1
+        );
+        assert_eq!(
+            r#"Multiple([Terminal("Name"), RegexExt(Group(Multiple([String(" "), Terminal("Name")])), Repeat1), RegexExt(Terminal("S"), Optional)])"#,
+            rules[4].1
+        );
+        assert_eq!(r#"RegexString("[ \\x{9}\\x{A}\\-]")"#, rules[5].1);
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = lower("a ::= 'x'\nb ::= ( 'y'\nc ::= #xD800").unwrap_err();
+        assert_eq!(2, errors.len());
+        assert_eq!(Some("b"), errors[0].rule.as_deref());
+        assert_eq!(3, errors[0].span.as_ref().unwrap().line);
+        assert!(matches!(&errors[0].kind, GrammarErrorKind::Syntax(msg) if msg.contains("`)`")));
+        assert_eq!(Some("c"), errors[1].rule.as_deref());
+        assert!(lower("a ::= [abc").is_err());
+        assert!(lower("::= 'x'").is_err());
+    }
+
+    #[test]
+    fn test_compile() {
+        let compiled = compile_ebnf_with(
+            r#"
+            document ::= '<?xml' S 'version="1.0"' S? '?>'
+            S ::= (#x20 | #x9)+
+            "#,
+            &CompileOptions {
+                frontend: Some(&W3c),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut cursor = FSMCursor::new(compiled.entries.default_root());
+        assert_eq!("<?xml", cursor.advance('<').unwrap());
+        assert_eq!(" ", cursor.advance(' ').unwrap());
+        assert_eq!("version=\"1.0\"", cursor.advance('v').unwrap());
+    }
+}
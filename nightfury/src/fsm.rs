@@ -3,11 +3,14 @@ use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::read_to_string;
 use std::str::pattern::Pattern;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use unicode_segmentation::UnicodeSegmentation;
 
 use super::FSMLock;
-use super::get_id;
 use crate::NameShortener;
-use crate::esc_seq::resolve_escape_sequences;
+use crate::compiled::{CallGraph, CompiledFsm, NodeIdx};
+use crate::esc_seq::{escape_sequences, resolve_escape_sequences};
 
 pub type FSMNodeWrapper = FSMRc<FSMLock<FSMNode>>;
 trait FSMOp = FnMut(&mut HashSet<NodeId>, &FSMNodeWrapper, &FSMNodeWrapper, &mut isize) -> bool;
@@ -27,17 +30,6 @@ pub trait CycleAwareOp<T> {
     fn walk_fsm_depth(&self, op: &mut T, greedy: bool) -> Option<FSMNodeWrapper> {
         self.walk_fsm(op, greedy, true)
     }
-    // no this is not a workaround I swear
-    // Good things *can* come out of having something like this as constructs like Repeats need
-    // to loop back
-    fn walk_fsm_allow_cycle_to_self(
-        &self,
-        op: &mut T,
-        greedy: bool,
-        depth_search: bool,
-    ) -> Option<FSMNodeWrapper> {
-        self.walk_fsm_internal(op, greedy, depth_search, &mut HashSet::new())
-    }
 }
 
 // TODO: refactor
@@ -154,22 +146,163 @@ where
     }
 }
 
+/// how a case-insensitive keyword is written once it's expanded, see the grammar's `@case`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Casing {
+    Upper,
+    Lower,
+    /// as written in the grammar
+    Keep,
+    /// like what the user typed, as written in the grammar if that's mixed
+    Typed,
+}
+
+impl Casing {
+    pub const ALL: [Casing; 4] = [Casing::Upper, Casing::Lower, Casing::Keep, Casing::Typed];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Casing::Upper => "upper",
+            Casing::Lower => "lower",
+            Casing::Keep => "keep",
+            Casing::Typed => "typed",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|casing| casing.name() == name)
+    }
+}
+
 // FIXME: the strcpys take up a decent amount of time, maybe expanded can be made a reference?
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct Keyword {
     pub short: String,
     pub expanded: String,
     pub closing_token: Option<String>,
+    /// set by the grammar, conflicts with other keywords won't change it
+    pub fixed_short: bool,
+    /// the keyword has to be typed out in full instead of being expanded from its short
+    /// A keyword ending a UserDefinedCombo is still picked by its first character.
+    pub no_autoexpand: bool,
+    /// inserted after the keyword once it's expanded, e.g. a space after `let`
+    pub separator: String,
+    /// everything up to the closing token is indented one level deeper
+    pub block: bool,
+    /// the keyword expands to a snippet covering the fixed shape following it
+    pub snippet: bool,
+    /// language whatever follows the keyword is written in, optionally followed by `;entry`
+    pub inject: Option<String>,
+    /// the keyword is matched regardless of case and written out like this, its short is kept in
+    /// lower case
+    pub casing: Option<Casing>,
 }
 
 impl Keyword {
+    const FIXED_SHORT_FLAG: &str = "short";
+    const NO_AUTOEXPAND_FLAG: &str = "noexpand";
+    const BLOCK_FLAG: &str = "block";
+    const SNIPPET_FLAG: &str = "snippet";
+    /// followed by the injected language, e.g. `inject=sql`
+    const INJECT_FLAG: &str = "inject=";
+    /// followed by the casing, e.g. `case=upper`
+    const CASE_FLAG: &str = "case=";
+
+    /// comma separated list of the keyword's flags, as written to the CSV
+    fn csv_flags(&self) -> String {
+        let mut flags = Vec::new();
+        if self.fixed_short {
+            flags.push(Self::FIXED_SHORT_FLAG.to_string());
+        }
+        if self.no_autoexpand {
+            flags.push(Self::NO_AUTOEXPAND_FLAG.to_string());
+        }
+        if self.block {
+            flags.push(Self::BLOCK_FLAG.to_string());
+        }
+        if self.snippet {
+            flags.push(Self::SNIPPET_FLAG.to_string());
+        }
+        if let Some(language) = &self.inject {
+            flags.push(format!("{}{language}", Self::INJECT_FLAG));
+        }
+        if let Some(casing) = self.casing {
+            flags.push(format!("{}{}", Self::CASE_FLAG, casing.name()));
+        }
+        flags.join(",")
+    }
     pub fn new(expanded: String, closing_token: Option<String>) -> Self {
         Self {
-            short: expanded.chars().nth(0).unwrap().to_string(),
+            short: NameShortener::expand(None, &expanded),
             expanded,
             closing_token,
+            ..Default::default()
+        }
+    }
+    /// `text` the way it's compared to the short
+    pub fn fold(&self, text: &str) -> String {
+        match self.casing {
+            Some(_) => text.to_lowercase(),
+            None => text.to_string(),
+        }
+    }
+    /// whether `typed` is the start of the short, or of the whole keyword if it can't be
+    /// expanded
+    pub fn accepts(&self, typed: &str) -> bool {
+        let full = if self.no_autoexpand {
+            &self.expanded
+        } else {
+            &self.short
+        };
+        self.fold(full).starts_with(&self.fold(typed))
+    }
+    /// the keyword written the way its casing asks for, `typed` is what the user typed to get it
+    fn cased(&self, typed: &str) -> String {
+        match self.casing {
+            None | Some(Casing::Keep) => self.expanded.clone(),
+            Some(Casing::Upper) => self.expanded.to_uppercase(),
+            Some(Casing::Lower) => self.expanded.to_lowercase(),
+            // typed out in full
+            Some(Casing::Typed) if self.fold(typed) == self.fold(&self.expanded) => {
+                typed.to_string()
+            }
+            Some(Casing::Typed) => {
+                let letters: Vec<char> = typed.chars().filter(|c| c.is_alphabetic()).collect();
+                if letters.is_empty() {
+                    self.expanded.clone()
+                } else if letters.iter().all(|c| c.is_uppercase()) {
+                    self.expanded.to_uppercase()
+                } else if letters.iter().all(|c| c.is_lowercase()) {
+                    self.expanded.to_lowercase()
+                } else {
+                    self.expanded.clone()
+                }
+            }
         }
     }
+    /// whether a keyword with the short `short` can't be told apart from this one
+    fn conflicts_with(&self, short: &str) -> bool {
+        short.starts_with(&self.short)
+            // a single grapheme can still be the start of another one, e.g. `e` and `é` written
+            // as `e\u{301}`
+            || self.short.graphemes(true).count() == 1 && self.short.starts_with(short)
+            // fixed shorts never grow, so shorter ones have to get out of their way
+            || self.fixed_short && self.short.starts_with(short)
+    }
+    /// grows the short after another keyword turned out to conflict with it, returns whether it
+    /// changed
+    fn expand_on_conflict(&mut self) -> bool {
+        if self.fixed_short {
+            return false;
+        }
+        let new_short = NameShortener::expand(Some(&self.short), &self.fold(&self.expanded));
+        let changed = new_short != self.short;
+        self.short = new_short;
+        changed
+    }
+    /// what the cursor hands out once the keyword got expanded from `typed`
+    pub fn output(&self, typed: &str) -> String {
+        format!("{}{}", self.cased(typed), self.separator)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -177,6 +310,9 @@ pub enum NodeType {
     Keyword(Keyword),
     UserDefinedCombo(Regex, Vec<char>),
     Null,
+    /// goes through the graph of the rule named here, the node's [FSMNode::callee], before
+    /// going on with its children
+    Call(String),
 }
 
 impl PartialEq for NodeType {
@@ -191,6 +327,7 @@ impl PartialEq for NodeType {
                 _ => false,
             },
             Null => matches!(other, Null),
+            Call(name) => matches!(other, Call(name2) if name == name2),
         }
     }
 }
@@ -200,21 +337,27 @@ use debug_print::debug_println;
 use regex::Regex;
 
 type NodeId = usize;
+/// source of the ids of nodes that haven't been numbered yet, see [FSMNode::number]
+/// It starts in the upper half, so new nodes can't collide with the ones of numbered graphs.
+static PROVISIONAL_ID: AtomicUsize = AtomicUsize::new(usize::MAX / 2 + 1);
 #[derive(Debug, Clone, PartialEq)]
 pub struct FSMNode {
     id: NodeId,
     is_done: bool,
     pub value: NodeType,
     pub children: Vec<FSMRc<FSMLock<FSMNode>>>,
+    /// entry of the graph a [NodeType::Call] goes through
+    pub callee: Option<FSMNodeWrapper>,
 }
 
 impl Default for FSMNode {
     fn default() -> Self {
         Self {
-            id: get_id(),
+            id: PROVISIONAL_ID.fetch_add(1, Ordering::Relaxed),
             is_done: false,
             value: Null,
             children: Vec::new(),
+            callee: None,
         }
     }
 }
@@ -241,27 +384,34 @@ impl FSMNode {
     pub fn is_userdef(&self) -> bool {
         matches!(self.value, UserDefinedCombo(_, _))
     }
+    #[inline]
+    pub fn is_call(&self) -> bool {
+        matches!(self.value, Call(_))
+    }
     fn deep_clone_internal(
         stub: &FSMNodeWrapper,
         old: &FSMNode,
         visited_nodes: &mut HashMap<usize, FSMNodeWrapper>,
     ) -> FSMRc<FSMLock<Self>> {
-        for child in &old.children {
-            if let std::collections::hash_map::Entry::Vacant(e) =
-                visited_nodes.entry(child.borrow().id)
-            {
-                let clone = FSMRc::new(FSMLock::new(Self {
-                    value: child.borrow().value.clone(),
-                    ..Default::default()
-                }));
-                e.insert(clone.clone());
-                FSMNode::deep_clone_internal(&clone, &child.borrow(), visited_nodes);
-                stub.borrow_mut().children.push(clone);
-            } else {
-                stub.borrow_mut()
-                    .children
-                    .push(visited_nodes[&child.borrow().id].clone());
+        let clone = |node: &FSMNodeWrapper, visited_nodes: &mut HashMap<usize, FSMNodeWrapper>| {
+            if let Some(clone) = visited_nodes.get(&node.borrow().id) {
+                return clone.clone();
             }
+            let clone = FSMRc::new(FSMLock::new(Self {
+                value: node.borrow().value.clone(),
+                ..Default::default()
+            }));
+            visited_nodes.insert(node.borrow().id, clone.clone());
+            FSMNode::deep_clone_internal(&clone, &node.borrow(), visited_nodes);
+            clone
+        };
+        for child in &old.children {
+            let child = clone(child, visited_nodes);
+            stub.borrow_mut().children.push(child);
+        }
+        if let Some(callee) = &old.callee {
+            let callee = clone(callee, visited_nodes);
+            stub.borrow_mut().callee = Some(callee);
         }
         stub.clone()
     }
@@ -278,20 +428,75 @@ impl FSMNode {
         ret.borrow().dbg();
         ret
     }
+    /// clones the graph of the rule starting at `entry`, without the graphs its calls go through
+    /// Returns the clone of `entry` and the calls in it, whose callees are left to be set.
+    pub fn clone_rule(entry: &FSMNodeWrapper) -> (FSMNodeWrapper, Vec<FSMNodeWrapper>) {
+        let new_clone = |node: &FSMNodeWrapper| {
+            FSMRc::new(FSMLock::new(Self {
+                value: node.borrow().value.clone(),
+                ..Default::default()
+            }))
+        };
+        let ret = new_clone(entry);
+        let mut clones = HashMap::from([(entry.borrow().id, ret.clone())]);
+        let mut calls = Vec::new();
+        let mut todo = vec![(entry.clone(), ret.clone())];
+        while let Some((old, clone)) = todo.pop() {
+            if clone.borrow().is_call() {
+                calls.push(clone.clone());
+            }
+            for child in &old.borrow().children {
+                let child_clone = clones
+                    .entry(child.borrow().id)
+                    .or_insert_with(|| {
+                        let child_clone = new_clone(child);
+                        todo.push((child.clone(), child_clone.clone()));
+                        child_clone
+                    })
+                    .clone();
+                clone.borrow_mut().children.push(child_clone);
+            }
+        }
+        (ret, calls)
+    }
     fn has_direct_child(&self, id: usize) -> bool {
         self.children.iter().any(|c| c.borrow().id == id)
     }
-    pub fn node_cnt(&self) -> usize {
-        let mut ret = 1; // one root node
-        self.walk_fsm_depth(
-            &mut |_, parent, _, _| {
-                ret += parent.children.len();
-                false
-            },
-            true,
-        );
+    /// every node reachable from `roots`, the graphs of the calls included, in breadth first
+    /// order
+    pub fn all_nodes(roots: &[&FSMNodeWrapper]) -> Vec<FSMNodeWrapper> {
+        let mut visited = HashSet::new();
+        let mut ret: Vec<FSMNodeWrapper> = Vec::new();
+        for root in roots {
+            if visited.insert(root.borrow().id) {
+                ret.push((*root).clone());
+            }
+        }
+        let mut next = 0;
+        while let Some(node) = ret.get(next).cloned() {
+            next += 1;
+            let node = node.borrow();
+            for child in node.children.iter().chain(&node.callee) {
+                if visited.insert(child.borrow().id) {
+                    ret.push(child.clone());
+                }
+            }
+        }
         ret
     }
+    /// numbers the nodes reachable from `roots` in the order of [FSMNode::all_nodes]
+    /// New nodes get ids unique among all nodes created so far, which depend on whatever got
+    /// created before them. Once a graph is done, numbering it makes its ids the same whenever the
+    /// same grammar is compiled.
+    pub fn number(roots: &[&FSMNodeWrapper]) {
+        for (id, node) in Self::all_nodes(roots).iter().enumerate() {
+            node.borrow_mut().id = id;
+        }
+    }
+    /// number of nodes in the graph, the graphs of the rules it calls included
+    pub fn node_cnt(this: &FSMNodeWrapper) -> usize {
+        Self::all_nodes(&[this]).len()
+    }
     fn get_direct_child_dups(&self) -> Vec<usize> {
         let mut ids = HashSet::new();
         let mut ret = Vec::new();
@@ -304,41 +509,93 @@ impl FSMNode {
         });
         ret
     }
+    /// tells every UserDefinedCombo the first characters of the keywords that may follow it, no
+    /// matter where the rule it's in is called from
     pub fn set_userdef_links(this: &FSMRc<FSMLock<FSMNode>>) {
-        let mut userdefs = Vec::new();
-        // find all userdefs
-        this.walk_fsm_breadth(
-            &mut |_, p, _, _| {
-                if let UserDefinedCombo(_, _) = p.borrow().value {
-                    userdefs.push(FSMRc::clone(p));
-                }
-                false
-            },
-            true,
-        );
-        debug_println!("{}", userdefs.len());
-        for userdef in userdefs {
+        let nodes = Self::all_nodes(&[this]);
+        let fsm = CompiledFsm::from_roots(&[this]);
+        let calls = CallGraph::new(&fsm);
+        for (idx, userdef) in nodes.iter().enumerate() {
+            if !userdef.borrow().is_userdef() {
+                continue;
+            }
             debug_println!(
                 "{:?} {}",
                 userdef.borrow().value,
                 userdef.borrow().short_id()
             );
-            userdef.walk_fsm_depth(
-                &mut |_, _, c, _| {
-                    debug_println!("{:?} {}", c.borrow().value, c.borrow().short_id());
-                    if let Keyword(Keyword { short, .. }) = &c.borrow().value
-                        && let UserDefinedCombo(_, fcs) = &mut userdef.borrow_mut().value
-                    {
-                        fcs.push(short.chars().nth(0).unwrap()); // bad handling, only possible when
-                        // there aren't any conflicts
+            for (next, _) in fsm.successors_from_any_caller(idx as NodeIdx, &calls).nodes {
+                if let Keyword(Keyword { short, casing, .. }) = &nodes[next as usize].borrow().value
+                    && let UserDefinedCombo(_, fcs) = &mut userdef.borrow_mut().value
+                {
+                    // input comes in char by char, so the first one decides
+                    let first = short.chars().next().expect("shorts aren't empty");
+                    fcs.push(first); // bad handling, only possible when
+                    // there aren't any conflicts
+                    // shorts of case-insensitive keywords are lower case
+                    if casing.is_some() {
+                        fcs.extend(first.to_uppercase().filter(|upper| *upper != first));
                     }
-                    false
-                },
-                false,
-            );
+                }
+            }
+        }
+    }
+    /// makes the shorts of all keywords that may follow the same node unique, taking the ones
+    /// reached through calls into account
+    /// Keywords of the same rule are already told apart while it's built, but a rule's keywords
+    /// only meet the ones around its calls once it's done.
+    pub fn resolve_call_conflicts(this: &FSMNodeWrapper) {
+        let nodes = Self::all_nodes(&[this]);
+        // the conflicts only change keywords, so the structure compiled once stays the same
+        let fsm = CompiledFsm::from_roots(&[this]);
+        let calls = CallGraph::new(&fsm);
+        for idx in 0..nodes.len() as NodeIdx {
+            let keywords: Vec<FSMNodeWrapper> = fsm
+                .successors_from_any_caller(idx, &calls)
+                .nodes
+                .into_iter()
+                .map(|(next, _)| nodes[next as usize].clone())
+                .filter(|next| next.borrow().is_keyword())
+                .collect();
+            if keywords.len() < 2 {
+                continue;
+            }
+            for (i, keyword) in keywords.iter().enumerate() {
+                while Self::resolve_conflict(&keywords[..i], keyword) {}
+            }
         }
     }
+    /// expands the shorts of `keyword` and the first one of `others` it conflicts with, returns
+    /// whether it has to be checked again
+    fn resolve_conflict(others: &[FSMNodeWrapper], keyword: &FSMNodeWrapper) -> bool {
+        let Keyword(Keyword { short, .. }) = keyword.borrow().value.clone() else {
+            unreachable!("only keywords are checked for conflicts")
+        };
+        let Some(other) = others.iter().find(
+            |other| matches!(&other.borrow().value, Keyword(other) if other.conflicts_with(&short)),
+        ) else {
+            return false;
+        };
+        let Keyword(other) = &mut other.borrow_mut().value else {
+            unreachable!()
+        };
+        let changed = other.expand_on_conflict();
+        let Keyword(keyword) = &mut keyword.borrow_mut().value else {
+            unreachable!()
+        };
+        Self::expand_after_conflict(keyword, changed)
+    }
+    /// minifies the graph starting at `this` and the graphs of every rule it calls
     pub fn minify(this: &FSMRc<FSMLock<FSMNode>>) {
+        let mut graphs = vec![this.clone()];
+        for node in Self::all_nodes(&[this]) {
+            graphs.extend(node.borrow().callee.clone());
+        }
+        for graph in &graphs {
+            Self::minify_graph(graph);
+        }
+    }
+    fn minify_graph(this: &FSMRc<FSMLock<FSMNode>>) {
         debug_println!("before minify:");
         this.borrow().dbg();
         let mut cycle_translation_table = HashMap::new();
@@ -373,22 +630,37 @@ impl FSMNode {
             },
             true,
         );
-        // fix any broken pointers the last op may have created
-        this.walk_fsm_depth(
-            &mut |_, parent, child, childidx| {
-                if let Some(new_child) = cycle_translation_table.get(&child.borrow().id) {
-                    if new_child.borrow().id == parent.borrow().id
-                        || parent.borrow().has_direct_child(new_child.borrow().id)
-                    {
-                        parent.borrow_mut().children.remove(*childidx as usize);
-                        return false;
-                    }
-                    parent.borrow_mut().children[*childidx as usize] = new_child.clone();
+        // fix any broken pointers the last op may have created; this has to look at every edge,
+        // walk_fsm only hands out the first one leading to a node
+        let translate = |node: &FSMNodeWrapper| {
+            let mut node = node.clone();
+            loop {
+                let id = node.borrow().id;
+                match cycle_translation_table.get(&id) {
+                    Some(new_node) => node = new_node.clone(),
+                    None => return node,
                 }
-                false
-            },
-            true,
-        );
+            }
+        };
+        let mut visited = HashSet::from([this.borrow().id]);
+        let mut todo = vec![this.clone()];
+        while let Some(node) = todo.pop() {
+            let id = node.borrow().id;
+            let old_children = std::mem::take(&mut node.borrow_mut().children);
+            let mut children: Vec<FSMNodeWrapper> = Vec::with_capacity(old_children.len());
+            for child in old_children {
+                let child = translate(&child);
+                let child_id = child.borrow().id;
+                if child_id == id || children.iter().any(|c| c.borrow().id == child_id) {
+                    continue;
+                }
+                if visited.insert(child_id) {
+                    todo.push(child.clone());
+                }
+                children.push(child);
+            }
+            node.borrow_mut().children = children;
+        }
         debug_println!("after minify:");
         this.borrow().dbg();
     }
@@ -436,7 +708,7 @@ impl FSMNode {
     fn dbg_internal(&self, indent: usize, visited_nodes: &mut HashSet<usize>) {
         println!("{}{:?} {}", " ".repeat(indent), self.value, self.short_id());
         visited_nodes.insert(self.id);
-        for child in self.children.iter() {
+        for child in self.callee.iter().chain(&self.children) {
             if !visited_nodes.contains(&child.borrow().id) {
                 child.borrow().dbg_internal(indent + 4, visited_nodes);
             } else {
@@ -448,13 +720,14 @@ impl FSMNode {
             }
         }
     }
-    fn get_all_leaves(
-        this: &FSMNodeWrapper,
-        discovered_leaves: &mut HashMap<NodeId, FSMNodeWrapper>,
-    ) {
+    /// the leaves reachable from `this`, in the order they are found
+    /// The order matters, adding children to them may resolve conflicts between their shorts.
+    fn get_all_leaves(this: &FSMNodeWrapper) -> Vec<FSMNodeWrapper> {
+        let mut discovered_ids = HashSet::new();
+        let mut discovered_leaves = Vec::new();
         this.walk_fsm(
             &mut |visited_nodes, _, child, _| {
-                if discovered_leaves.contains_key(&child.borrow().id) {
+                if discovered_ids.contains(&child.borrow().id) {
                     return false;
                 }
                 if child.borrow().children.is_empty() {
@@ -463,7 +736,8 @@ impl FSMNode {
                         child.borrow().value,
                         child.borrow().short_id()
                     );
-                    discovered_leaves.insert(child.borrow().id, child.clone());
+                    discovered_ids.insert(child.borrow().id);
+                    discovered_leaves.push(child.clone());
                 } else {
                     let mut has_only_cycles = true;
                     for child in &child.borrow().children {
@@ -478,7 +752,8 @@ impl FSMNode {
                             child.borrow().value,
                             child.borrow().short_id()
                         );
-                        discovered_leaves.insert(child.borrow().id, child.clone());
+                        discovered_ids.insert(child.borrow().id);
+                        discovered_leaves.push(child.clone());
                     }
                 }
                 false
@@ -486,20 +761,27 @@ impl FSMNode {
             true,
             false,
         );
+        discovered_leaves
     }
     pub fn add_child_to_all_leaves(this: &FSMNodeWrapper, child: &FSMNodeWrapper) {
-        let mut leaves = HashMap::new();
-        FSMNode::get_all_leaves(this, &mut leaves);
-        let iter = leaves.values();
-        for node in iter {
-            FSMNode::add_child_cycle_safe(node, child);
-            // NOTE: hopefully this isn't needed anymore
-            // if node.borrow().children.is_empty() {
-            //     FSMNode::add_child_cycle_safe(&node, child);
-            // }
-        }
-        if this.borrow().children.is_empty() {
-            FSMNode::add_child_cycle_safe(this, child);
+        FSMNode::add_children_to_all_leaves(this, std::slice::from_ref(child));
+    }
+    /// like [FSMNode::add_child_to_all_leaves], but the leaves are only looked for once, so the
+    /// children added first don't become leaves the later ones get added to
+    pub fn add_children_to_all_leaves(this: &FSMNodeWrapper, children: &[FSMNodeWrapper]) {
+        let leaves = FSMNode::get_all_leaves(this);
+        let is_leaf = this.borrow().children.is_empty();
+        for child in children {
+            for node in &leaves {
+                FSMNode::add_child_cycle_safe(node, child);
+                // NOTE: hopefully this isn't needed anymore
+                // if node.borrow().children.is_empty() {
+                //     FSMNode::add_child_cycle_safe(&node, child);
+                // }
+            }
+            if is_leaf {
+                FSMNode::add_child_cycle_safe(this, child);
+            }
         }
     }
 
@@ -565,12 +847,12 @@ impl FSMNode {
     }
 
     pub fn new_keyword(expanded_name: String) -> FSMRc<FSMLock<Self>> {
+        Self::from_keyword(Keyword::new(expanded_name, None))
+    }
+
+    pub fn from_keyword(keyword: Keyword) -> FSMRc<FSMLock<Self>> {
         FSMRc::new(FSMLock::new(Self {
-            value: Keyword(Keyword {
-                short: expanded_name.chars().nth(0).unwrap().to_string(),
-                expanded: expanded_name,
-                ..Default::default()
-            }),
+            value: Keyword(keyword),
             children: Vec::new(),
             ..Default::default()
         }))
@@ -585,6 +867,15 @@ impl FSMNode {
         ret
     }
 
+    /// a call going through the graph of the rule `name`, which starts at `callee`
+    pub fn new_call(name: String, callee: &FSMNodeWrapper) -> FSMRc<FSMLock<Self>> {
+        FSMRc::new(FSMLock::new(Self {
+            value: Call(name),
+            callee: Some(callee.clone()),
+            ..Default::default()
+        }))
+    }
+
     pub fn check_for_conflicts(&self, short: &str) -> bool {
         for child in &self.children {
             let borrow = child.borrow();
@@ -604,19 +895,16 @@ impl FSMNode {
 
     fn get_conflicting_node(&self, short: &str) -> Option<FSMRc<FSMLock<FSMNode>>> {
         self.walk_fsm_breadth(
-            &mut |_, _, child, _| {
-                match &child.value {
-                    // shouldn't you also check nshort.starts_with(short)?
-                    Keyword(Keyword { short: nshort, .. }) if short.starts_with(nshort) => true,
-                    _ => false,
-                }
-            },
+            &mut |_, _, child, _| matches!(&child.value, Keyword(keyword) if keyword.conflicts_with(short)),
             false,
         )
     }
-    fn handle_potential_conflict_internal(&self, child: &FSMRc<FSMLock<FSMNode>>) -> bool {
+    /// expands the short of the keyword `child` conflicts with, if any
+    /// Returns None if there's no conflict, otherwise whether the other keyword's short changed,
+    /// which it can't if it has been fixed by the grammar
+    fn handle_potential_conflict_internal(&self, child: &FSMRc<FSMLock<FSMNode>>) -> Option<bool> {
         let child_borrow = child.borrow();
-        let mut ret = false;
+        let mut ret = None;
         if let Keyword(Keyword { short: cshort, .. }) = &child_borrow.value
             && let Some(node) = self.get_conflicting_node(cshort)
             && node.borrow().id != child_borrow.id
@@ -624,12 +912,7 @@ impl FSMNode {
             node.replace_with(|node| {
                     debug_println!("Old Node: {:?} {}", node.value, node.short_id());
                     if let Keyword(keyword_struct) = &mut node.value {
-                        let new_short = NameShortener::expand(
-                            Some(&keyword_struct.short),
-                            &keyword_struct.expanded,
-                        );
-                        keyword_struct.short = new_short;
-                        ret = true;
+                        ret = Some(keyword_struct.expand_on_conflict());
                         debug_println!("New Node: {:?} {}", node.value, node.short_id());
                         node.to_owned()
                     } else {
@@ -641,17 +924,27 @@ impl FSMNode {
         }
         ret
     }
+    /// expands `k`'s short after a conflict, fixed shorts stay as they are and only continue the
+    /// conflict resolution as long as the other side still `changed`
+    fn expand_after_conflict(k: &mut Keyword, changed: bool) -> bool {
+        if k.fixed_short {
+            changed
+        } else {
+            let full = k.fold(&k.expanded);
+            NameShortener::expand_existing(&mut k.short, &full)
+        }
+    }
     pub fn handle_potential_conflict(&self, child: &FSMNodeWrapper) -> bool {
         let child_borrow = child.borrow();
         if let Keyword(_) = &child_borrow.value {
             debug_println!("{:?}", self.value);
             debug_println!("{:?}", child.borrow().value);
-            if self.handle_potential_conflict_internal(child) {
+            if let Some(changed) = self.handle_potential_conflict_internal(child) {
                 drop(child_borrow);
                 let mut ret = false;
                 child.replace_with(|node| {
                     if let Keyword(k) = &mut node.value {
-                        ret = NameShortener::expand_existing(&mut k.short, &k.expanded);
+                        ret = Self::expand_after_conflict(k, changed);
                     } else {
                         unreachable!()
                     }
@@ -663,10 +956,10 @@ impl FSMNode {
             return child
                 .walk_fsm_breadth(
                     &mut |_, _, child, _| {
-                        if self.handle_potential_conflict_internal(child) {
+                        if let Some(changed) = self.handle_potential_conflict_internal(child) {
                             let mut mut_child = child.borrow_mut();
                             if let Keyword(k) = &mut mut_child.value {
-                                return NameShortener::expand_existing(&mut k.short, &k.expanded);
+                                return Self::expand_after_conflict(k, changed);
                             }
                             false
                         } else {
@@ -706,19 +999,37 @@ impl ToCSV for NodeType {
     fn to_csv(&self) -> String {
         let mut ret = match self {
             Null => "".to_owned(),
-            Keyword(Keyword {
-                short,
-                expanded,
-                closing_token,
-            }) => format!(
-                "{short}{}{expanded}{}",
-                Self::FIELD_DELIM,
-                if let Some(ct) = closing_token {
-                    Self::FIELD_DELIM.to_string() + ct
-                } else {
-                    "".to_owned()
+            Keyword(keyword) => {
+                let mut short = escape_sequences(&keyword.short);
+                // a leading slash would make it look like a UserDefinedCombo
+                if let Some(rest) = short.strip_prefix('/') {
+                    short = format!("\\x2F{rest}");
+                }
+                let mut ret = format!(
+                    "{}{}{}",
+                    short,
+                    Self::FIELD_DELIM,
+                    escape_sequences(&keyword.expanded)
+                );
+                let flags = keyword.csv_flags();
+                let separator = &keyword.separator;
+                if keyword.closing_token.is_some() || !flags.is_empty() || !separator.is_empty() {
+                    ret.push(Self::FIELD_DELIM);
+                    ret.push_str(&escape_sequences(
+                        keyword.closing_token.as_deref().unwrap_or_default(),
+                    ));
+                }
+                if !flags.is_empty() || !separator.is_empty() {
+                    ret.push(Self::FIELD_DELIM);
+                    ret.push_str(&flags);
                 }
-            ),
+                if !separator.is_empty() {
+                    ret.push(Self::FIELD_DELIM);
+                    ret.push_str(&escape_sequences(separator));
+                }
+                ret
+            }
+            Call(name) => format!("{}{}", Self::FIELD_DELIM, escape_sequences(name)),
             UserDefinedCombo(r, cts) => {
                 format!(
                     "/{}{}",
@@ -733,15 +1044,17 @@ impl ToCSV for NodeType {
         ret
     }
     fn from_csv(csv: &str) -> Self {
-        println!("csv: {csv}");
-        if csv.len() < 2 {
+        debug_println!("csv: {csv}");
+        if csv.chars().count() < 2 {
             Null
-        } else if csv.chars().nth(0).unwrap() == '/' {
+        } else if let Some(name) = csv.strip_prefix(Self::FIELD_DELIM) {
+            Call(resolve_escape_sequences(name))
+        } else if csv.starts_with('/') {
             let mut iter = csv.split(Self::FIELD_DELIM);
             let regex = Regex::new(&iter.next().expect("invalid NodeType format")[1..])
                 .expect("invalid Regex format");
             let mut final_tokens = Vec::with_capacity((csv.len() - regex.as_str().len()) / 2);
-            final_tokens.extend(iter.map(|s| s.chars().nth(0).expect("empty closing_token field")));
+            final_tokens.extend(iter.map(|s| s.chars().next().expect("empty closing_token field")));
             UserDefinedCombo(regex, final_tokens)
         } else {
             let mut parts = csv.split(Self::FIELD_DELIM).map(resolve_escape_sequences);
@@ -751,109 +1064,267 @@ impl ToCSV for NodeType {
             let expanded = parts
                 .next()
                 .expect("keyword from_csv: missing expanded field!");
+            let closing_token = parts.next().filter(|ct| !ct.is_empty());
+            let flags = parts.next().unwrap_or_default();
+            let flags: Vec<&str> = flags.split(',').collect();
             Keyword(Keyword {
                 short,
                 expanded,
-                closing_token: parts.next(),
+                closing_token,
+                fixed_short: flags.contains(&Keyword::FIXED_SHORT_FLAG),
+                no_autoexpand: flags.contains(&Keyword::NO_AUTOEXPAND_FLAG),
+                block: flags.contains(&Keyword::BLOCK_FLAG),
+                snippet: flags.contains(&Keyword::SNIPPET_FLAG),
+                inject: flags
+                    .iter()
+                    .find_map(|flag| flag.strip_prefix(Keyword::INJECT_FLAG))
+                    .map(str::to_string),
+                casing: flags
+                    .iter()
+                    .find_map(|flag| flag.strip_prefix(Keyword::CASE_FLAG))
+                    .and_then(Casing::from_name),
+                separator: parts.next().unwrap_or_default(),
             })
         }
     }
 }
 
-impl ToCSV for FSMNodeWrapper {
-    fn to_csv(&self) -> String {
-        let mut nodes = HashMap::new();
-        self.walk_fsm_breadth(
-            &mut |_, _, c, _| {
-                nodes.insert(c.borrow().id, c.clone());
-                false
-            },
-            true,
-        );
-        let mut ret = format!(
-            "{}{}{}",
-            self.borrow().id,
-            Self::FIELD_DELIM,
-            self.borrow().value.to_csv()
-        );
-        nodes.keys().for_each(|id| {
-            ret.push_str(&id.to_string());
-            ret.push(Self::FIELD_DELIM);
-            ret.push_str(&nodes.get(id).unwrap().borrow().value.to_csv());
-        });
-        ret.push(Self::ENTRY_DELIM);
+/// serializes the graphs spanned by `roots`, the first root is the one written first and thus
+/// the one [FSMNodeWrapper::from_csv] hands back
+/// Nodes are written in the order of [FSMNode::all_nodes], identical graphs give identical CSVs.
+/// Also returns the ids the nodes were written with.
+fn graph_to_csv(roots: &[&FSMNodeWrapper]) -> (String, HashMap<NodeId, usize>) {
+    const FIELD_DELIM: char = FSMNodeWrapper::FIELD_DELIM;
+    const ENTRY_DELIM: char = FSMNodeWrapper::ENTRY_DELIM;
+    let nodes = FSMNode::all_nodes(roots);
+    let pos = csv_ids(&nodes);
+    let mut ret = String::new();
+    nodes.iter().enumerate().for_each(|(i, node)| {
+        ret.push_str(&i.to_string());
+        ret.push(FIELD_DELIM);
+        ret.push_str(&node.borrow().value.to_csv());
+    });
+    ret.push(ENTRY_DELIM);
 
-        ret.push_str(&self.borrow().id.to_string());
-        self.borrow().children.iter().for_each(|el| {
-            ret.push(Self::FIELD_DELIM);
-            ret.push_str(&el.borrow().id.to_string());
+    nodes.iter().enumerate().for_each(|(i, node)| {
+        let node = node.borrow();
+        ret.push_str(&i.to_string());
+        // the callee of a call is written as its first child
+        node.callee.iter().chain(&node.children).for_each(|el| {
+            ret.push(FIELD_DELIM);
+            ret.push_str(&pos[&el.borrow().id].to_string());
         });
-        ret.push(Self::ENTRY_DELIM);
+        ret.push(ENTRY_DELIM);
+    });
+    (ret, pos)
+}
 
-        nodes.keys().for_each(|id| {
-            ret.push_str(&id.to_string());
-            let node = nodes.get(id).unwrap();
-            node.borrow().children.iter().for_each(|el| {
-                ret.push(Self::FIELD_DELIM);
-                ret.push_str(&el.borrow().id.to_string());
-            });
-            ret.push(Self::ENTRY_DELIM);
-        });
-        ret
-    }
-    fn from_csv(csv: &str) -> Self {
-        let mut iter = csv.split_indices(Self::ENTRY_DELIM);
-        let mut nodes = HashMap::new();
+/// the ids nodes are written with, their position in `nodes`
+/// The ids of the nodes themselves aren't used, so graphs that haven't been numbered come out the
+/// same each time as well.
+fn csv_ids(nodes: &[FSMNodeWrapper]) -> HashMap<NodeId, usize> {
+    nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.borrow().id, i))
+        .collect()
+}
+
+/// reads the node and children sections, leaving `iter` right after them
+fn graph_from_csv<'a>(
+    iter: &mut impl Iterator<Item = (&'a str, usize)>,
+) -> (FSMNodeWrapper, HashMap<NodeId, FSMNodeWrapper>) {
+    const FIELD_DELIM: char = FSMNodeWrapper::FIELD_DELIM;
+    let mut nodes = HashMap::new();
 
-        // TODO: refactor
-        let line = iter.next().unwrap();
-        println!("from_csv at line '{line:?}'");
-        let mut line_iter = line.0.split_indices(Self::FIELD_DELIM);
+    // TODO: refactor
+    let line = iter.next().unwrap();
+    debug_println!("from_csv at line '{line:?}'");
+    let mut line_iter = line.0.split_indices(FIELD_DELIM);
+    let id: usize = line_iter.next().unwrap().0.parse().unwrap();
+    let ntype = match line_iter.next() {
+        Some(nval) => NodeType::from_csv(&line.0[nval.1..]),
+        None => Null,
+    };
+
+    let root = FSMNode::new_id(ntype, id);
+    nodes.insert(id, root.clone());
+    while let Some(part) = iter.next()
+        && !part.0.is_empty()
+    {
+        debug_println!("from_csv at line '{part:?}'");
+        let mut line_iter = part.0.split_indices(FIELD_DELIM);
         let id: usize = line_iter.next().unwrap().0.parse().unwrap();
         let ntype = match line_iter.next() {
-            Some(nval) => NodeType::from_csv(&line.0[nval.1..]),
+            Some(nval) => NodeType::from_csv(&part.0[nval.1..]),
             None => Null,
         };
+        nodes.insert(id, FSMNode::new_id(ntype, id));
+    }
+    // iter.next(); // consume separator line
 
-        let root = FSMNode::new_id(ntype, id);
-        nodes.insert(id, root.clone());
-        while let Some(part) = iter.next()
-            && !part.0.is_empty()
-        {
-            println!("from_csv at line '{part:?}'");
-            let mut line_iter = part.0.split_indices(Self::FIELD_DELIM);
-            let id: usize = line_iter.next().unwrap().0.parse().unwrap();
-            let ntype = match line_iter.next() {
-                Some(nval) => NodeType::from_csv(&part.0[nval.1..]),
-                None => Null,
-            };
-            nodes.insert(id, FSMNode::new_id(ntype, id));
-        }
-        // iter.next(); // consume separator line
-
-        // children logic
-        while let Some(part) = iter.next()
-            && !part.0.is_empty()
-        {
-            let mut iter = part.0.split(Self::FIELD_DELIM);
-            let id: usize = iter.next().unwrap().parse().unwrap();
-            let parent = nodes.get(&id).unwrap();
-            for part in iter {
-                let c_id: NodeId = part.parse().unwrap();
-                #[cfg(not(debug_assertions))]
-                unsafe {
-                    parent
-                        .borrow_mut()
-                        .add_child_unsafe(nodes.get(&c_id).unwrap());
-                }
-                #[cfg(debug_assertions)]
-                FSMNode::add_child_cycle_safe(parent, nodes.get(&c_id).unwrap());
+    // children logic
+    while let Some(part) = iter.next()
+        && !part.0.is_empty()
+    {
+        let mut iter = part.0.split(FIELD_DELIM);
+        let id: usize = iter.next().unwrap().parse().unwrap();
+        let parent = nodes.get(&id).unwrap();
+        if parent.borrow().is_call() {
+            let c_id: NodeId = iter.next().unwrap().parse().unwrap();
+            parent.borrow_mut().callee = Some(nodes.get(&c_id).unwrap().clone());
+        }
+        for part in iter {
+            let c_id: NodeId = part.parse().unwrap();
+            #[cfg(not(debug_assertions))]
+            unsafe {
+                parent
+                    .borrow_mut()
+                    .add_child_unsafe(nodes.get(&c_id).unwrap());
             }
+            #[cfg(debug_assertions)]
+            FSMNode::add_child_cycle_safe(parent, nodes.get(&c_id).unwrap());
         }
+    }
+    (root, nodes)
+}
+
+impl ToCSV for FSMNodeWrapper {
+    fn to_csv(&self) -> String {
+        graph_to_csv(&[self]).0
+    }
+    fn from_csv(csv: &str) -> Self {
+        let mut iter = csv.split_indices(Self::ENTRY_DELIM);
+        let root = graph_from_csv(&mut iter).0;
+        FSMNode::number(&[&root]);
         root
     }
 }
 
+/// the FSMs of all entry rules of a grammar, the first entry is the default one
+#[derive(Debug, Clone)]
+pub struct EntryPoints {
+    entries: Vec<(String, FSMNodeWrapper)>,
+    /// the grammar's `@layout`, see [crate::FSMCursor::with_layout]
+    layout: Option<Regex>,
+    /// the grammar's `@indent`, see [crate::FSMCursor::with_indent]
+    indent: Option<String>,
+}
+
+impl EntryPoints {
+    /// name the layout is stored under in the CSV, can't clash with a rule name
+    const LAYOUT_ENTRY: &str = "@layout";
+    const INDENT_ENTRY: &str = "@indent";
+
+    /// # Panics
+    /// if `entries` is empty
+    pub fn new(entries: Vec<(String, FSMNodeWrapper)>) -> Self {
+        assert!(!entries.is_empty(), "need at least one entry");
+        Self {
+            entries,
+            layout: None,
+            indent: None,
+        }
+    }
+    pub fn with_layout(mut self, layout: Option<Regex>) -> Self {
+        self.layout = layout;
+        self
+    }
+    pub fn layout(&self) -> Option<&Regex> {
+        self.layout.as_ref()
+    }
+    pub fn with_indent(mut self, indent: Option<String>) -> Self {
+        self.indent = indent;
+        self
+    }
+    pub fn indent(&self) -> Option<&str> {
+        self.indent.as_deref()
+    }
+    pub fn default_root(&self) -> &FSMNodeWrapper {
+        &self.entries[0].1
+    }
+    /// root of the entry `name`, or of the default entry if `name` is None
+    pub fn get(&self, name: Option<&str>) -> Option<&FSMNodeWrapper> {
+        match name {
+            Some(name) => self
+                .entries
+                .iter()
+                .find(|(entry, _)| entry == name)
+                .map(|(_, root)| root),
+            None => Some(self.default_root()),
+        }
+    }
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+impl From<FSMNodeWrapper> for EntryPoints {
+    fn from(root: FSMNodeWrapper) -> Self {
+        Self::new(vec![(String::new(), root)])
+    }
+}
+
+/// Same format as [FSMNodeWrapper], followed by an empty line and one `name<TAB>node_id` line per
+/// entry. Readers that only know about single root FSMs stop at the empty line and end up with
+/// the default entry.
+impl ToCSV for EntryPoints {
+    fn to_csv(&self) -> String {
+        let roots: Vec<&FSMNodeWrapper> = self.entries.iter().map(|(_, root)| root).collect();
+        let (mut ret, pos) = graph_to_csv(&roots);
+        ret.push(Self::ENTRY_DELIM);
+        for (name, root) in &self.entries {
+            ret.push_str(name);
+            ret.push(Self::FIELD_DELIM);
+            ret.push_str(&pos[&root.borrow().id].to_string());
+            ret.push(Self::ENTRY_DELIM);
+        }
+        if let Some(layout) = &self.layout {
+            ret.push_str(Self::LAYOUT_ENTRY);
+            ret.push(Self::FIELD_DELIM);
+            ret.push_str(layout.as_str());
+            ret.push(Self::ENTRY_DELIM);
+        }
+        if let Some(indent) = &self.indent {
+            ret.push_str(Self::INDENT_ENTRY);
+            ret.push(Self::FIELD_DELIM);
+            ret.push_str(&escape_sequences(indent));
+            ret.push(Self::ENTRY_DELIM);
+        }
+        ret
+    }
+    fn from_csv(csv: &str) -> Self {
+        let mut iter = csv.split_indices(Self::ENTRY_DELIM);
+        let (root, nodes) = graph_from_csv(&mut iter);
+        let mut layout = None;
+        let mut indent = None;
+        let entries: Vec<(String, FSMNodeWrapper)> = iter
+            .filter(|line| !line.0.is_empty())
+            .filter_map(|line| {
+                let (name, id) = line.0.split_once(Self::FIELD_DELIM)?;
+                if name == Self::LAYOUT_ENTRY {
+                    layout = Some(Regex::new(id).expect("invalid layout regex"));
+                    return None;
+                }
+                if name == Self::INDENT_ENTRY {
+                    indent = Some(resolve_escape_sequences(id));
+                    return None;
+                }
+                let root = nodes.get(&id.parse().ok()?)?;
+                Some((name.to_string(), root.clone()))
+            })
+            .collect();
+        let ret = if entries.is_empty() {
+            Self::from(root)
+        } else {
+            Self::new(entries)
+        };
+        let roots: Vec<&FSMNodeWrapper> = ret.entries.iter().map(|(_, root)| root).collect();
+        FSMNode::number(&roots);
+        ret.with_layout(layout).with_indent(indent)
+    }
+}
+
 trait SplitIndicesExt {
     fn split_indices<P>(&self, pat: P) -> impl Iterator<Item = (&str, usize)>
     where
@@ -876,19 +1347,176 @@ impl SplitIndicesExt for &str {
 
 #[cfg(test)]
 mod tests {
-    use crate::dbg_id;
+    use std::thread;
 
     use super::*;
+    use crate::frontend::create_entry_points_from_ebnf;
 
     #[test]
     fn test_csv_simple() {
-        dbg_id();
         let root = FSMNode::new_keyword("int".to_string());
         let _other = FSMNode::new_keyword_with_parent("asdf".to_string(), root.clone());
+        FSMNode::number(&[&root]);
 
         let csv = root.to_csv();
         assert_eq!("0\ti\tint\n1\ta\tasdf\n\n0\t1\n1\n", csv);
         let new_root = FSMNodeWrapper::from_csv(&csv);
         assert_eq!(root, new_root);
     }
+
+    #[test]
+    fn test_csv_unicode() {
+        let root = FSMNode::new_keyword("≤".to_string());
+        let _other = FSMNode::new_keyword_with_parent("größer".to_string(), root.clone());
+        FSMNode::number(&[&root]);
+        let new_root = FSMNodeWrapper::from_csv(&root.to_csv());
+        assert_eq!(root, new_root);
+
+        let userdef = UserDefinedCombo(Regex::new("^[α-ω]+$").unwrap(), vec!['→', '𝔸']);
+        assert_eq!("/^[α-ω]+$\t→\t𝔸\n", userdef.to_csv());
+        assert_eq!(userdef, NodeType::from_csv("/^[α-ω]+$\t→\t𝔸"));
+        let keyword = Keyword(Keyword::new("é".to_string(), None));
+        assert_eq!(keyword, NodeType::from_csv("é\té"));
+    }
+
+    #[test]
+    fn test_csv_slash_short() {
+        let slash = Keyword(Keyword::new("/".to_string(), None));
+        assert_eq!("\\x2F\t/\n", slash.to_csv());
+        assert_eq!(slash, NodeType::from_csv("\\x2F\t/"));
+
+        let root =
+            create_entry_points_from_ebnf("expr ::= 'a' { ( '/' | '*' ) 'a' };", &[]).unwrap();
+        let csv = root.to_csv();
+        assert_eq!(csv, EntryPoints::from_csv(&csv).to_csv());
+    }
+
+    #[test]
+    fn test_csv_calls() {
+        let entry = FSMNode::new_null(None);
+        FSMNode::new_keyword_with_parent("k".to_string(), entry.clone());
+        let root = FSMNode::new_null(None);
+        let call = FSMNode::new_call("rule".to_string(), &entry);
+        FSMNode::add_child_cycle_safe(&root, &call);
+        FSMNode::new_keyword_with_parent("a".to_string(), call.clone());
+        FSMNode::number(&[&root]);
+
+        assert_eq!("\trule\n", call.borrow().value.to_csv());
+        assert_eq!(call.borrow().value, NodeType::from_csv("\trule"));
+        let new_root = FSMNodeWrapper::from_csv(&root.to_csv());
+        assert_eq!(root, new_root);
+        let new_call = new_root.borrow().children[0].clone();
+        assert_eq!(entry, new_call.borrow().callee.clone().unwrap());
+    }
+
+    #[test]
+    fn test_csv_entry_points() {
+        let root = FSMNode::new_keyword("int".to_string());
+        let other = FSMNode::new_keyword_with_parent("asdf".to_string(), root.clone());
+        let entries = EntryPoints::new(vec![
+            ("main".to_string(), root.clone()),
+            ("sub".to_string(), other.clone()),
+        ]);
+        FSMNode::number(&[&root, &other]);
+
+        let csv = entries.to_csv();
+        // single root readers still see the default entry
+        assert_eq!(root, FSMNodeWrapper::from_csv(&csv));
+        let new_entries = EntryPoints::from_csv(&csv);
+        assert_eq!(vec!["main", "sub"], new_entries.names().collect::<Vec<_>>());
+        assert_eq!(&root, new_entries.get(None).unwrap());
+        assert_eq!(&other, new_entries.get(Some("sub")).unwrap());
+
+        let layout = Regex::new(r"[ \t\n]*").unwrap();
+        let csv = entries
+            .with_layout(Some(layout.clone()))
+            .with_indent(Some("\t".to_string()))
+            .to_csv();
+        let new_entries = EntryPoints::from_csv(&csv);
+        assert_eq!(2, new_entries.names().count());
+        assert_eq!(
+            Some(layout.as_str()),
+            new_entries.layout().map(Regex::as_str)
+        );
+        assert_eq!(Some("\t"), new_entries.indent());
+
+        // files written before entry points existed only have the default entry
+        let old = EntryPoints::from_csv(&root.to_csv());
+        assert_eq!(&root, old.default_root());
+    }
+
+    #[test]
+    fn test_csv_deterministic() {
+        let bnf = r"
+        stmt ::= ( ident '=' expr ';' ) | ( '{' { stmt } '}' );
+        ident ::= #'[a-z]+';
+        expr ::= ident | #'[0-9]+' | ( '(' expr ')' );
+    ";
+        let compile = || create_entry_points_from_ebnf(bnf, &["stmt", "expr"]).unwrap();
+        let csv = compile().to_csv();
+        // other graphs built in between or on other threads don't change a thing
+        FSMNode::new_keyword("unrelated".to_string());
+        assert_eq!(csv, compile().to_csv());
+        assert_eq!(
+            csv,
+            thread::spawn(move || compile().to_csv()).join().unwrap()
+        );
+
+        let entries = compile();
+        let roots: Vec<&FSMNodeWrapper> = entries
+            .names()
+            .map(|name| entries.get(Some(name)).unwrap())
+            .collect();
+        for (pos, node) in FSMNode::all_nodes(&roots).iter().enumerate() {
+            assert_eq!(pos, node.borrow().id());
+        }
+        // loading it again gives back the same file
+        assert_eq!(csv, EntryPoints::from_csv(&csv).to_csv());
+    }
+
+    #[test]
+    fn test_csv_keyword_flags() {
+        let mut keyword = Keyword::new("insert".to_string(), None);
+        keyword.short = "ii".to_string();
+        keyword.fixed_short = true;
+        keyword.no_autoexpand = true;
+        let flagged = Keyword(keyword);
+        assert_eq!("ii\tinsert\t\tshort,noexpand\n", flagged.to_csv());
+        assert_eq!(flagged, NodeType::from_csv("ii\tinsert\t\tshort,noexpand"));
+
+        let mut keyword = Keyword::new("let".to_string(), None);
+        keyword.separator = " ".to_string();
+        let separated = Keyword(keyword);
+        assert_eq!("l\tlet\t\t\t \n", separated.to_csv());
+        assert_eq!(separated, NodeType::from_csv("l\tlet\t\t\t "));
+
+        let mut keyword = Keyword::new("{".to_string(), Some("}".to_string()));
+        keyword.separator = "\n".to_string();
+        keyword.block = true;
+        let block = Keyword(keyword);
+        assert_eq!("{\t{\t}\tblock\t\\n\n", block.to_csv());
+        assert_eq!(block, NodeType::from_csv("{\t{\t}\tblock\t\\n"));
+
+        let mut keyword = Keyword::new("for".to_string(), None);
+        keyword.snippet = true;
+        let snippet = Keyword(keyword);
+        assert_eq!("f\tfor\t\tsnippet\n", snippet.to_csv());
+        assert_eq!(snippet, NodeType::from_csv("f\tfor\t\tsnippet"));
+
+        let mut keyword = Keyword::new("`".to_string(), None);
+        keyword.inject = Some("sql;select".to_string());
+        let injecting = Keyword(keyword);
+        assert_eq!("`\t`\t\tinject=sql;select\n", injecting.to_csv());
+        assert_eq!(injecting, NodeType::from_csv("`\t`\t\tinject=sql;select"));
+
+        let mut keyword = Keyword::new("select".to_string(), None);
+        keyword.casing = Some(Casing::Upper);
+        let cased = Keyword(keyword);
+        assert_eq!("s\tselect\t\tcase=upper\n", cased.to_csv());
+        assert_eq!(cased, NodeType::from_csv("s\tselect\t\tcase=upper"));
+
+        let closing = Keyword(Keyword::new("(".to_string(), Some(")".to_string())));
+        assert_eq!("(\t(\t)\n", closing.to_csv());
+        assert_eq!(closing, NodeType::from_csv("(\t(\t)"));
+    }
 }
@@ -1,45 +1,36 @@
-#![feature(if_let_guard)]
 #![feature(trait_alias)]
 #![feature(impl_trait_in_bindings)]
-#![feature(lock_value_accessors)]
+#![cfg_attr(feature = "thread-safe", feature(lock_value_accessors))]
 #![feature(pattern)]
 #![feature(buf_read_has_data_left)]
-#![feature(test)]
 
+use compiled::{CallStack, Successors};
 use debug_print::debug_println;
+use fsm::Keyword;
 use fsm::NodeType::{self, *};
-use fsm::{CycleAwareOp, Keyword};
 pub use fsm::{FSMNode, ToCSV};
 use regex::Regex;
-use std::cell::RefCell;
 #[cfg(not(feature = "thread-safe"))]
-use std::cell::{Ref, RefMut};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 #[cfg(feature = "thread-safe")]
 use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use unicode_segmentation::UnicodeSegmentation;
 
 pub mod frontend;
 
 mod fsm;
-pub use fsm::FSMNodeWrapper;
+pub use fsm::{EntryPoints, FSMNodeWrapper};
+
+mod compiled;
+pub use compiled::{CompiledFsm, FormatError, NodeIdx, SharedBytes};
 
 pub mod protocol;
 
 mod esc_seq;
 
-thread_local! {
-    static CNT: RefCell<usize> = const { RefCell::new(0) };
-}
-
-fn get_id() -> usize {
-    let mut ret = 0;
-    CNT.with_borrow(|cnt| ret = *cnt);
-    CNT.with_borrow_mut(|cnt| *cnt += 1);
-    ret
-}
-fn dbg_id() {
-    debug_println!("{:?}", CNT);
-}
-
+#[allow(dead_code)]
 trait PartialMatch {
     fn partial_match(&self, hay: &str) -> bool;
 }
@@ -79,8 +70,13 @@ pub fn get_test_fsm() -> FSMNodeWrapper {
 }
 
 // ironic that this only expands names
+// shorts grow by whole graphemes, so `e\u{301}` (é) never gets split from its accent
 struct NameShortener;
 impl NameShortener {
+    /// the first `len` graphemes of `full`
+    fn prefix(full: &str, len: usize) -> String {
+        full.graphemes(true).take(len).collect()
+    }
     fn expand(old: Option<&str>, full: &str) -> String {
         if full.is_empty() {
             panic!("Cannot expand the void!")
@@ -91,18 +87,17 @@ impl NameShortener {
                 // well screw you past me! It's actually vital for collisions between s1 and s2
                 // where s2.starts_with(s1) applies
             }
-            let mut ret = old.to_string();
-            ret.push_str(&full[old.len()..old.len() + 1]);
-            ret
+            Self::prefix(full, old.graphemes(true).count() + 1)
         } else {
-            full[0..1].to_string()
+            Self::prefix(full, 1)
         };
         debug_println!("Got {} instead of {old:?}", ret);
         ret
     }
     fn expand_existing(old: &mut String, full: &str) -> bool {
-        if old.len() < full.len() {
-            old.push_str(&full[old.len()..old.len() + 1]);
+        let len = old.graphemes(true).count();
+        if len < full.graphemes(true).count() {
+            *old = Self::prefix(full, len + 1);
             true
         } else {
             // needed to stop
@@ -121,8 +116,6 @@ impl NameShortener {
 #[cfg(not(feature = "thread-safe"))]
 type FSMRc<T> = std::rc::Rc<T>;
 #[cfg(not(feature = "thread-safe"))]
-type FSMWeak<T> = std::rc::Weak<T>;
-#[cfg(not(feature = "thread-safe"))]
 #[derive(Debug, PartialEq)]
 pub struct FSMLock<T>(RefCell<T>);
 #[cfg(not(feature = "thread-safe"))]
@@ -144,8 +137,6 @@ impl<T> FSMLock<T> {
 #[cfg(feature = "thread-safe")]
 type FSMRc<T> = std::sync::Arc<T>;
 #[cfg(feature = "thread-safe")]
-type FSMWeak<T> = std::sync::Weak<T>;
-#[cfg(feature = "thread-safe")]
 #[derive(Debug)]
 pub struct FSMLock<T>(RwLock<T>);
 #[cfg(feature = "thread-safe")]
@@ -170,16 +161,127 @@ impl<T: PartialEq> PartialEq for FSMLock<T> {
         self.0.read().unwrap().eq(&other.0.read().unwrap())
     }
 }
-type InternalCursor = FSMWeak<FSMLock<FSMNode>>;
+/// a node the cursor may be at, along with the call stacks it may be nested in
+type Position = (NodeIdx, Vec<CallStack>);
 #[derive(Clone, Debug, Default)]
 pub struct FSMCursor {
-    root: InternalCursor,
-    cur_ast_pos: InternalCursor,
+    fsm: Arc<CompiledFsm>,
+    root: NodeIdx,
+    cur_ast_pos: NodeIdx,
     input_buf: String,
     did_revert: bool,
-    unfinished_nodes: Vec<InternalCursor>,
-    path: Vec<InternalCursor>,
+    /// the calls the cursor may be nested in, one stack per way of reading the input so far
+    /// Once the rule the cursor is in is done, it goes on right after the innermost call.
+    stacks: Vec<CallStack>,
+    /// other nodes the input may have led to, holding the same token as cur_ast_pos
+    /// Grammars may reach the same keyword in several ways, e.g. a parenthesis opening either a
+    /// condition or an expression, the cursor follows all of them until they part ways.
+    alternatives: Vec<Position>,
+    path: Vec<NodeIdx>,
     path_bufs: Vec<String>,
+    /// anchored version of the grammar's `@layout`
+    layout: Option<Regex>,
+    /// layout typed since the last token
+    layout_buf: String,
+    /// layout_buf of every node in path
+    path_layouts: Vec<String>,
+    /// one level of indentation
+    indent: String,
+    /// closing tokens of the blocks the cursor is in, innermost last
+    blocks: Vec<String>,
+    /// blocks of every node in path
+    path_blocks: Vec<Vec<String>>,
+    /// stacks of every node in path
+    path_stacks: Vec<Vec<CallStack>>,
+    /// alternatives of every node in path
+    path_alternatives: Vec<Vec<Position>>,
+    /// what may follow the cursor's position, walked once per position
+    successors: Arc<Successors>,
+    /// successors of every node in path
+    path_successors: Vec<Arc<Successors>>,
+    /// while inside a snippet, the nodes following the slot the cursor is in
+    snippet: Option<VecDeque<Position>>,
+    /// grammars keywords can hand over to with `@inject`, by name
+    languages: Arc<HashMap<String, Arc<CompiledFsm>>>,
+    /// the region of another language the cursor is in
+    injection: Option<Box<Injection>>,
+    /// injection of every node in path
+    path_injections: Vec<Option<Box<Injection>>>,
+}
+
+/// a region written in another language, see [FSMCursor::with_languages]
+#[derive(Clone, Debug)]
+struct Injection {
+    language: String,
+    cursor: FSMCursor,
+}
+
+/// indentation used if the grammar doesn't set its own
+const DEFAULT_INDENT: &str = "\t";
+
+/// whether `regex` matches all of `hay`
+fn full_match(regex: &Regex, hay: &str) -> bool {
+    regex.find(hay).is_some_and(|m| m.range() == (0..hay.len()))
+}
+
+/// the successors holding the same token as the first one `matches`, which is the only one if
+/// that's a UserDefinedCombo
+fn same_token(
+    fsm: &CompiledFsm,
+    successors: &[Position],
+    matches: impl Fn(&NodeType) -> bool,
+) -> Vec<Position> {
+    let Some(first) = successors
+        .iter()
+        .find(|(node, _)| matches(fsm.value(*node)))
+    else {
+        return Vec::new();
+    };
+    let token = fsm.value(first.0);
+    if !matches!(token, Keyword(_)) {
+        return vec![first.clone()];
+    }
+    successors
+        .iter()
+        .filter(|(node, _)| *fsm.value(*node) == *token)
+        .cloned()
+        .collect()
+}
+
+/// the single node following `positions`, None if there's more than one way to go on or the FSM
+/// may end right after it
+fn fixed_successor(fsm: &CompiledFsm, positions: &[Position]) -> Option<Position> {
+    let successors = fsm.successors(positions);
+    match successors.nodes.as_slice() {
+        [next] if !successors.can_end => Some(next.clone()),
+        _ => None,
+    }
+}
+
+/// the nodes following `positions` for as long as there's only one way to go on
+fn snippet_chain(fsm: &CompiledFsm, positions: &[Position]) -> Vec<Position> {
+    let mut chain: Vec<Position> = Vec::new();
+    let mut cur = positions.to_vec();
+    while let Some(next) = fixed_successor(fsm, &cur)
+        && !positions.iter().any(|(node, _)| next.0 == *node)
+        && !chain.iter().any(|known| known.0 == next.0)
+    {
+        chain.push(next.clone());
+        cur = vec![next];
+    }
+    chain
+}
+
+/// escapes the characters that mean something in LSP snippets
+fn escape_snippet(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '$' | '}') {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
 }
 
 /// offers more insight in what advancing the cursor did
@@ -191,55 +293,109 @@ pub enum AdvanceResult {
     ExpandedAfterUserdef(String),
     /// ordinary Keyword match
     Expanded(String),
+    /// a `@snippet` keyword expanded along with the fixed shape following it, in LSP snippet
+    /// syntax with `$1`, `$2`, ... for user-defined tokens; the cursor is in the first one and
+    /// moves on to the next one on `'\t'`
+    Snippet(String),
     /// dead_end detection triggered and the internal state did not update
     InvalidChar,
 }
 
 impl FSMCursor {
-    pub fn new(fsm_root: &FSMRc<FSMLock<FSMNode>>) -> Self {
-        Self {
-            root: FSMRc::downgrade(fsm_root),
-            cur_ast_pos: FSMRc::downgrade(fsm_root),
+    /// compiles the graph at `fsm_root` for the cursor alone, every call compiles it anew
+    /// Anything keeping a [CompiledFsm] around or creating more than one cursor for a graph should
+    /// compile it once and use [FSMCursor::from_compiled].
+    pub fn new(fsm_root: &FSMNodeWrapper) -> Self {
+        let fsm = CompiledFsm::new(&EntryPoints::from(FSMRc::clone(fsm_root)));
+        Self::from_compiled(&Arc::new(fsm), None).expect("the default entry always exists")
+    }
+    /// a cursor at the entry `entry` of `fsm`, or at its default entry if `entry` is None, with
+    /// the layout and indentation of its grammar
+    pub fn from_compiled(fsm: &Arc<CompiledFsm>, entry: Option<&str>) -> Option<Self> {
+        let root = fsm.entry(entry)?;
+        let mut ret = Self {
+            fsm: Arc::clone(fsm),
+            root,
+            cur_ast_pos: root,
+            stacks: vec![Vec::new()],
+            indent: DEFAULT_INDENT.to_string(),
             ..Default::default()
+        };
+        ret.update_successors();
+        Some(ret.with_layout(fsm.layout()).with_indent(fsm.indent()))
+    }
+    /// lets the user type anything matching `layout` between two tokens, see
+    /// [EntryPoints::layout]
+    pub fn with_layout(mut self, layout: Option<&Regex>) -> Self {
+        self.layout = layout.map(|layout| {
+            Regex::new(&format!("^(?:{})$", layout.as_str())).expect("layout is a valid regex")
+        });
+        self
+    }
+    /// indents blocks with `indent` instead of a tab, see [EntryPoints::indent]
+    pub fn with_indent(mut self, indent: Option<&str>) -> Self {
+        if let Some(indent) = indent {
+            self.indent = indent.to_string();
         }
+        self
+    }
+    /// registers the grammars `@inject` keywords may hand over to
+    /// Languages injected by a grammar but missing here are left to the host grammar. The map is
+    /// shared, cursors handing over to an injected language pass it on as is.
+    pub fn with_languages(mut self, languages: Arc<HashMap<String, Arc<CompiledFsm>>>) -> Self {
+        self.languages = languages;
+        self
+    }
+    /// the language of the injected region the cursor is in, the innermost one if they're nested
+    pub fn injected_language(&self) -> Option<&str> {
+        self.injection.as_ref().map(|injection| {
+            injection
+                .cursor
+                .injected_language()
+                .unwrap_or(&injection.language)
+        })
     }
     /// resets the cursor back to the FSM root as if new() has just been called
     pub fn reset(&mut self) {
-        self.cur_ast_pos = FSMWeak::clone(&self.root);
+        self.cur_ast_pos = self.root;
         self.input_buf.clear();
         self.did_revert = false;
-        self.unfinished_nodes.clear();
+        self.stacks = vec![Vec::new()];
+        self.alternatives.clear();
         self.path.clear();
+        self.path_stacks.clear();
+        self.path_alternatives.clear();
+        self.path_successors.clear();
+        self.update_successors();
+        self.layout_buf.clear();
+        self.path_layouts.clear();
+        self.blocks.clear();
+        self.path_blocks.clear();
+        self.snippet = None;
+        self.injection = None;
+        self.path_injections.clear();
+    }
+    /// number of blocks the cursor is in
+    pub fn depth(&self) -> usize {
+        self.blocks.len()
     }
     pub fn input_buf(&self) -> &str {
         &self.input_buf
     }
-    fn handle_userdefined_combo(&mut self, input: char, final_chars: &[char]) -> Option<String> {
-        let child_idx = final_chars.iter().position(|char| *char == input);
-        if child_idx.is_some() {
-            let strong_ref = self.get_cur_ast_binding();
-            // let borrow = strong_ref.borrow();
-            // let next_node = FSMRc::clone(&borrow.children[child_idx]);
-            let mut ret = None;
-            strong_ref.walk_fsm_depth(
-                &mut |_, _, c, _| {
-                    if let Keyword(Keyword {
-                        short, expanded, ..
-                    }) = &c.borrow().value
-                        && short.starts_with(input)
-                    {
-                        println!("handle_userdefined_combo: found another keyword!");
-                        self.update_cursor(c);
-                        self.input_buf.clear();
-                        ret = Some(expanded.clone());
-                        true
-                    } else {
-                        false
-                    }
-                },
-                false,
-            );
-            ret
+    fn handle_userdefined_combo(&mut self, input: char) -> Option<String> {
+        let finished =
+            matches!(self.get_current_nodeval(), UserDefinedCombo(_, f) if f.contains(&input));
+        if finished {
+            let input = input.to_string();
+            let next = same_token(&self.fsm, &self.successors().nodes, |value| {
+                matches!(value, Keyword(keyword)
+                    if keyword.fold(&keyword.short).starts_with(&keyword.fold(&input)))
+            });
+            let node = next.first()?.0;
+            println!("handle_userdefined_combo: found another keyword!");
+            self.update_cursor(&next);
+            self.input_buf.clear();
+            Some(self.expand(node, &input))
         } else {
             if let UserDefinedCombo(r, _) = self.get_current_nodeval()
                 && !r.is_match(&self.input_buf)
@@ -250,19 +406,22 @@ impl FSMCursor {
             None
         }
     }
+    #[allow(dead_code)]
     fn handle_userdefined(&mut self, input: char, final_chars: &[char]) -> Option<String> {
         let child_idx = final_chars.iter().position(|char| *char == input);
         if let Some(child_idx) = child_idx {
-            let strong_ref = self.get_cur_ast_binding();
-            let borrow = strong_ref.borrow();
-            let next_node = FSMRc::clone(&borrow.children[child_idx]);
-            self.update_cursor(&next_node);
+            let next_node = self
+                .fsm
+                .children(self.cur_ast_pos)
+                .nth(child_idx)
+                .expect("the final characters belong to the children");
+            self.update_cursor(&[(next_node, self.stacks.clone())]);
             let ret = if let NodeType::Keyword(Keyword {
                 short,
                 expanded,
                 closing_token: None,
                 ..
-            }) = &next_node.borrow().value
+            }) = self.fsm.value(next_node)
                 && *short == String::from(input)
             {
                 Some(expanded.clone())
@@ -279,83 +438,87 @@ impl FSMCursor {
     pub fn clear_inputbuf(&mut self) {
         self.input_buf.clear();
     }
-    fn search_for_userdefs(
-        &self,
-        treenode: &FSMRc<FSMLock<FSMNode>>,
-    ) -> Option<FSMRc<FSMLock<FSMNode>>> {
-        treenode.borrow().walk_fsm_breadth(
-            &mut |_, _, child, _| {
-                matches!(&child.value, UserDefinedCombo(regex, _) if regex.is_match(&self.input_buf))
-            },
-            false,
-        )
+    /// the nodes the cursor may be at, cur_ast_pos coming first
+    fn positions(&self) -> Vec<Position> {
+        let mut ret = vec![(self.cur_ast_pos, self.stacks.clone())];
+        ret.extend(self.alternatives.iter().cloned());
+        ret
     }
-    pub fn search_rec(
-        &mut self,
-        treenode: &FSMRc<FSMLock<FSMNode>>,
-    ) -> Option<FSMRc<FSMLock<FSMNode>>> {
-        self.search_rec_internal(treenode, false)
+    /// what may follow the cursor's position
+    fn successors(&self) -> &Successors {
+        &self.successors
+    }
+    fn update_successors(&mut self) {
+        self.successors = Arc::new(self.fsm.successors(&self.positions()));
+    }
+    /// the nodes the input typed since the cursor's position leads to, more than one if they hold
+    /// the same keyword
+    pub fn search_rec(&mut self) -> Option<Vec<Position>> {
+        self.search_rec_internal(false)
     }
     pub fn search_rec_internal(
         &mut self,
-        treenode: &FSMRc<FSMLock<FSMNode>>,
         best_effort: bool, // overcomplicates things (and is probably not even used)
-    ) -> Option<FSMRc<FSMLock<FSMNode>>> {
+    ) -> Option<Vec<Position>> {
         debug_println!(
             "search_rec at {:?} {}",
-            treenode.borrow().value,
-            treenode.borrow().short_id()
+            self.get_current_nodeval(),
+            self.cur_ast_pos
         );
         debug_println!("search_rec input buf: {}", self.input_buf);
-        let mut keyword_match = None;
-        let mut potential_matches = 0;
+        let successors = Arc::clone(&self.successors);
+        let successors = &successors.nodes;
         let mut visited_keywords = 0;
         let mut last_keyword = None;
-        treenode.walk_fsm_allow_cycle_to_self(
-            &mut |_, _, child, _| {
-                let node_val = &child.borrow().value;
-                debug_println!(
-                    "search_rec closure at {:?} {}",
-                    child.borrow().value,
-                    child.borrow().short_id()
-                );
-                match node_val {
-                    NodeType::Keyword(Keyword { short, .. }) => {
-                        if short.starts_with(&self.input_buf) {
-                            debug_println!("{:?}", child.borrow().value);
-                            debug_println!("{short} == {}", self.input_buf);
-                            keyword_match = Some(child.clone());
-                            potential_matches += 1;
-                            potential_matches > 1
-                        } else {
-                            // bandaid logic
-                            visited_keywords += 1;
-                            if visited_keywords == 1 {
-                                last_keyword = Some(child.clone());
-                            }
-                            false
-                        }
-                    }
-                    _ => false,
+        for next in successors {
+            debug_println!(
+                "search_rec closure at {:?} {}",
+                self.fsm.value(next.0),
+                next.0
+            );
+            if let NodeType::Keyword(keyword) = self.fsm.value(next.0)
+                && !keyword.accepts(&self.input_buf)
+            {
+                // bandaid logic
+                visited_keywords += 1;
+                if visited_keywords == 1 {
+                    last_keyword = Some(next.clone());
                 }
-            },
-            false,
-            false,
-        );
+            }
+        }
+        let accepted = |value: &NodeType| matches!(value, Keyword(keyword) if keyword.accepts(&self.input_buf));
+        let keyword_match = same_token(&self.fsm, successors, accepted);
+        // the same keyword reached in several ways is still just one match
+        let potential_matches = successors
+            .iter()
+            .filter(|(node, _)| accepted(self.fsm.value(*node)))
+            .count()
+            - keyword_match.len().saturating_sub(1);
         debug_println!("pm: {potential_matches}");
-        if keyword_match.is_some() && potential_matches == 1 {
-            return keyword_match;
+        if potential_matches == 1 {
+            // keywords that mustn't be expanded only match once they've been typed out
+            if let NodeType::Keyword(keyword) = self.fsm.value(keyword_match[0].0)
+                && keyword.no_autoexpand
+                && keyword.fold(&keyword.expanded) != keyword.fold(&self.input_buf)
+            {
+                return None;
+            }
+            return Some(keyword_match);
         }
 
         debug_println!("vk: {visited_keywords}");
         if visited_keywords == 1 && best_effort {
             // probably what the user wants
-            return last_keyword;
+            return last_keyword.map(|keyword| vec![keyword]);
         }
 
-        let userdef_match = self.search_for_userdefs(treenode);
-        if userdef_match.is_some() && potential_matches < 1 {
-            return userdef_match;
+        let userdef_match = same_token(
+            &self.fsm,
+            successors,
+            |value| matches!(value, UserDefinedCombo(regex, _) if regex.is_match(&self.input_buf)),
+        );
+        if !userdef_match.is_empty() && potential_matches < 1 {
+            return Some(userdef_match);
         }
 
         // TODO: look into whether potential userdefs also need to be checked here
@@ -367,43 +530,308 @@ impl FSMCursor {
         }
         None
     }
+    /// the text the keyword at `node` expands to from `typed`, with every line after the first
+    /// one indented to the depth the keyword leaves the cursor at
+    fn expand(&mut self, node: NodeIdx, typed: &str) -> String {
+        let Keyword(keyword) = self.fsm.value(node) else {
+            unreachable!("only keywords are expanded")
+        };
+        if self.blocks.last() == Some(&keyword.expanded) {
+            self.blocks.pop();
+        }
+        if keyword.block
+            && let Some(closing) = &keyword.closing_token
+        {
+            self.blocks.push(closing.clone());
+        }
+        let output = keyword.output(typed);
+        if !output.contains('\n') {
+            return output;
+        }
+        let indent = self.indent.repeat(self.blocks.len());
+        let mut lines = output.split('\n');
+        let mut ret = lines.next().unwrap_or_default().to_string();
+        for line in lines {
+            ret.push('\n');
+            ret.push_str(&indent);
+            ret.push_str(line.trim_start_matches([' ', '\t']));
+        }
+        ret
+    }
+    /// moves the cursor into the first slot of the snippet the keyword at `node` expands to, if
+    /// it has any
+    fn start_snippet(&mut self, node: NodeIdx, typed: &str) -> Option<String> {
+        let chain = snippet_chain(&self.fsm, &self.positions());
+        if !chain
+            .iter()
+            .any(|(next, _)| matches!(self.fsm.value(*next), UserDefinedCombo(..)))
+        {
+            return None;
+        }
+        // the text is built up front, the cursor only gets to the keywords as the user tabs along
+        let blocks = self.blocks.clone();
+        // the rest of the snippet is written like the keyword the user typed
+        let mut ret = escape_snippet(&self.expand(node, typed));
+        let mut slot = 0;
+        for (next, _) in &chain {
+            match self.fsm.value(*next) {
+                Keyword(_) => ret.push_str(&escape_snippet(&self.expand(*next, typed))),
+                _ => {
+                    slot += 1;
+                    ret.push_str(&format!("${slot}"));
+                }
+            }
+        }
+        ret.push_str("$0");
+        self.blocks = blocks;
+        self.expand(node, typed);
+        self.snippet = Some(chain.into());
+        // one revert takes back the whole snippet, like any other expansion
+        let path_len = self.path.len();
+        self.advance_snippet();
+        self.truncate_path(path_len);
+        Some(ret)
+    }
+    /// moves the cursor over the keywords of the snippet into its next slot, ends the snippet if
+    /// there's none left
+    fn advance_snippet(&mut self) {
+        let Some(mut rest) = self.snippet.take() else {
+            return;
+        };
+        while let Some(position) = rest.pop_front() {
+            let next = position.0;
+            self.update_cursor(&[position]);
+            self.input_buf.clear();
+            match self.fsm.value(next) {
+                Keyword(_) => {
+                    self.expand(next, "");
+                }
+                _ => {
+                    self.snippet = Some(rest);
+                    return;
+                }
+            }
+        }
+    }
+    /// finishes the snippet slot the cursor is in
+    fn next_slot(&mut self) -> Option<AdvanceResult> {
+        let finished = match self.get_current_nodeval() {
+            UserDefinedCombo(r, _) => full_match(r, &self.input_buf),
+            _ => false,
+        };
+        if !finished {
+            return Some(AdvanceResult::InvalidChar);
+        }
+        let path_len = self.path.len() + 1;
+        self.advance_snippet();
+        self.truncate_path(path_len);
+        None
+    }
+    /// forgets every step after the first `len` ones, so reverting jumps back over all of them
+    fn truncate_path(&mut self, len: usize) {
+        self.path.truncate(len);
+        self.path_bufs.truncate(len);
+        self.path_layouts.truncate(len);
+        self.path_blocks.truncate(len);
+        self.path_injections.truncate(len);
+        self.path_stacks.truncate(len);
+        self.path_alternatives.truncate(len);
+        self.path_successors.truncate(len);
+    }
+    /// the cursor for the region following `node`, if it's an `@inject` keyword for a known
+    /// language
+    fn inject(&self, node: NodeIdx) -> Option<Box<Injection>> {
+        let NodeType::Keyword(Keyword {
+            inject: Some(language),
+            ..
+        }) = self.fsm.value(node)
+        else {
+            return None;
+        };
+        let (name, entry) = match language.split_once(';') {
+            Some((name, entry)) => (name, Some(entry)),
+            None => (language.as_str(), None),
+        };
+        let mut cursor = FSMCursor::from_compiled(self.languages.get(name)?, entry)?;
+        cursor.languages = Arc::clone(&self.languages);
+        Some(Box::new(Injection {
+            language: language.clone(),
+            cursor,
+        }))
+    }
+    /// whether nothing has been typed since the cursor was created
+    fn at_start(&self) -> bool {
+        self.path.is_empty() && self.input_buf.is_empty() && self.layout_buf.is_empty()
+    }
+    /// takes `input` as layout if it's typed between two tokens and the layout allows it
+    fn skip_layout(&mut self, input: char) -> bool {
+        let Some(layout) = &self.layout else {
+            return false;
+        };
+        let between_tokens = match self.get_current_nodeval() {
+            // layout finishes a userdef, unless the userdef can take the character itself
+            UserDefinedCombo(r, f) => {
+                !f.contains(&input)
+                    && (!self.layout_buf.is_empty()
+                        || full_match(r, &self.input_buf)
+                            && !full_match(r, &format!("{}{input}", self.input_buf)))
+            }
+            _ => self.input_buf.is_empty(),
+        };
+        let mut layout_buf = self.layout_buf.clone();
+        layout_buf.push(input);
+        if between_tokens && layout.is_match(&layout_buf) {
+            self.layout_buf = layout_buf;
+            true
+        } else {
+            false
+        }
+    }
     /// advances the cursor's position, taking the key the user pressed last
     pub fn advancex(&mut self, input: char) -> Option<AdvanceResult> {
-        let binding = self.get_cur_ast_binding();
-        let borrow = binding.borrow();
+        // the injected language gets the first go, whatever it can't take has to be the host's
+        // token ending the region
+        if self.input_buf.is_empty()
+            && let Some(injection) = &self.injection
+        {
+            // once the region could end, a character both sides can take is held back until the
+            // next one tells whether it closes the region
+            if injection.cursor.can_end() && self.starts_token(input) {
+                if self.injection_takes(input) {
+                    self.input_buf.push(input);
+                    return None;
+                }
+            } else {
+                let injection = self.injection.as_mut().expect("checked above");
+                match injection.cursor.advancex(input) {
+                    Some(AdvanceResult::InvalidChar) => {}
+                    res => return res,
+                }
+            }
+        }
+        let res = self.advance_host(input);
+        if res == Some(AdvanceResult::InvalidChar)
+            && let Some(res) = self.hand_back(input).or_else(|| self.close_region(input))
+        {
+            return res;
+        }
+        res
+    }
+    fn injection_takes(&self, input: char) -> bool {
+        self.injection.as_ref().is_some_and(|injection| {
+            injection.cursor.clone().advancex(input) != Some(AdvanceResult::InvalidChar)
+        })
+    }
+    /// whether `input` can start one of the tokens following the cursor
+    fn starts_token(&self, input: char) -> bool {
+        let input = input.to_string();
+        self.successors()
+            .nodes
+            .iter()
+            .any(|(node, _)| match self.fsm.value(*node) {
+                Keyword(keyword) => keyword.accepts(&input),
+                UserDefinedCombo(regex, _) => regex.is_match(&input),
+                _ => false,
+            })
+    }
+    /// gives the characters held back at the end of an injected region to the injected language
+    /// once the host can't go on with `input`, they weren't the closing token after all.
+    /// Only done if the injected language takes them without expanding them into anything but
+    /// themselves, they're already on the screen as typed.
+    fn hand_back(&mut self, input: char) -> Option<Option<AdvanceResult>> {
+        if self.input_buf.is_empty() {
+            return None;
+        }
+        let mut cursor = self.injection.as_ref()?.cursor.clone();
+        for c in self.input_buf.chars() {
+            match cursor.advancex(c) {
+                None | Some(AdvanceResult::UserDefStarted) => {}
+                Some(AdvanceResult::Expanded(s) | AdvanceResult::ExpandedAfterUserdef(s))
+                    if s == c.to_string() => {}
+                _ => return None,
+            }
+        }
+        let res = cursor.advancex(input);
+        if res == Some(AdvanceResult::InvalidChar) {
+            return None;
+        }
+        self.input_buf.clear();
+        self.injection.as_mut()?.cursor = cursor;
+        Some(res)
+    }
+    /// closes the injected region with the characters held back and `input` if they're the start
+    /// of the host's closing token, as written out, not just its short
+    fn close_region(&mut self, input: char) -> Option<Option<AdvanceResult>> {
+        if self.input_buf.is_empty() || self.injection.is_none() {
+            return None;
+        }
+        let mut typed = std::mem::take(&mut self.input_buf);
+        typed.push(input);
+        let mut cursor = self.clone();
+        for c in typed.chars() {
+            match cursor.advance_host(c) {
+                None => {}
+                Some(AdvanceResult::Expanded(s)) if s.starts_with(&typed) => {
+                    *self = cursor;
+                    return Some(Some(AdvanceResult::Expanded(s)));
+                }
+                _ => break,
+            }
+        }
+        typed.pop();
+        self.input_buf = typed;
+        None
+    }
+    fn advance_host(&mut self, input: char) -> Option<AdvanceResult> {
+        if input == '\t' && self.snippet.is_some() {
+            return self.next_slot();
+        }
+        if self.skip_layout(input) {
+            return None;
+        }
         debug_println!(
             "advance with cursor {:?} {}",
-            borrow.value,
-            borrow.short_id()
+            self.get_current_nodeval(),
+            self.cur_ast_pos
         );
+        if let UserDefinedCombo(_, f) = self.get_current_nodeval() {
+            // a userdef followed by layout is done, only the token after it may come next
+            if !self.layout_buf.is_empty() && !f.contains(&input) {
+                return Some(AdvanceResult::InvalidChar);
+            }
+            // whatever follows a snippet slot is already there
+            if self.snippet.as_ref().is_some_and(|rest| !rest.is_empty()) && f.contains(&input) {
+                return Some(AdvanceResult::InvalidChar);
+            }
+            self.input_buf.push(input);
+            let ret = self.handle_userdefined_combo(input);
+            if ret.is_some() {
+                return ret.map(AdvanceResult::ExpandedAfterUserdef);
+            }
+            return self.check_for_revert(None);
+        }
         self.input_buf.push(input);
-        match &borrow.value {
-            UserDefinedCombo(_, f) => {
-                let ret = self.handle_userdefined_combo(input, f);
-                if ret.is_some() {
-                    return ret.map(AdvanceResult::ExpandedAfterUserdef);
+        if let Some(positions) = self.search_rec() {
+            let node = positions[0].0;
+            self.update_cursor(&positions);
+            return match self.fsm.value(node) {
+                NodeType::Keyword(keyword) => {
+                    let snippet = keyword.snippet;
+                    let typed = std::mem::take(&mut self.input_buf);
+                    if snippet && let Some(snippet) = self.start_snippet(node, &typed) {
+                        return Some(AdvanceResult::Snippet(snippet));
+                    }
+                    Some(AdvanceResult::Expanded(self.expand(node, &typed)))
                 }
-            }
-            _ => {
-                let res = self.search_rec(&binding);
-                if let Some(node) = res {
-                    self.update_cursor(&node);
-                    return match &node.borrow().value {
-                        NodeType::Keyword(Keyword { expanded, .. }) => {
-                            self.input_buf.clear();
-                            Some(AdvanceResult::Expanded(expanded.clone()))
-                        }
-                        NodeType::UserDefinedCombo(_, f) => {
-                            let res = self.handle_userdefined_combo(input, f);
-                            Some(
-                                self.check_for_revert(res.map(AdvanceResult::ExpandedAfterUserdef))
-                                    .unwrap_or(AdvanceResult::UserDefStarted),
-                            )
-                        }
-                        _ => unreachable!(),
-                    };
+                NodeType::UserDefinedCombo(..) => {
+                    let res = self.handle_userdefined_combo(input);
+                    Some(
+                        self.check_for_revert(res.map(AdvanceResult::ExpandedAfterUserdef))
+                            .unwrap_or(AdvanceResult::UserDefStarted),
+                    )
                 }
-            }
+                _ => unreachable!(),
+            };
         }
         self.check_for_revert(None)
     }
@@ -420,7 +848,9 @@ impl FSMCursor {
     /// simpler version of [advancex]
     pub fn advance(&mut self, input: char) -> Option<String> {
         self.advancex(input).and_then(|res| match res {
-            AdvanceResult::ExpandedAfterUserdef(str) | AdvanceResult::Expanded(str) => Some(str),
+            AdvanceResult::ExpandedAfterUserdef(str)
+            | AdvanceResult::Expanded(str)
+            | AdvanceResult::Snippet(str) => Some(str),
             _ => None,
         })
     }
@@ -428,10 +858,30 @@ impl FSMCursor {
     /// removes one character from the internal buffer, or jumps back to the previous node if the
     /// buffer is empty
     pub fn revert(&mut self) {
-        if self.input_buf.len() < 2 // avoid creating an empty input_buf
+        if self.input_buf.is_empty()
+            && let Some(injection) = &mut self.injection
+            && !injection.cursor.at_start()
+        {
+            injection.cursor.revert();
+            return;
+        }
+        // layout typed after a userdef comes after its input_buf, otherwise before
+        if (self.input_buf.is_empty() || self.is_in_userdefined_stage())
+            && self.layout_buf.pop().is_some()
+        {
+            return;
+        }
+        if self.input_buf.chars().count() < 2 // avoid creating an empty input_buf
             && let Some(new_cursor_pos) = self.path.pop()
         {
             self.cur_ast_pos = new_cursor_pos;
+            self.layout_buf = self.path_layouts.pop().unwrap_or_default();
+            self.blocks = self.path_blocks.pop().unwrap_or_default();
+            self.injection = self.path_injections.pop().flatten();
+            self.stacks = self.path_stacks.pop().unwrap_or_default();
+            self.alternatives = self.path_alternatives.pop().unwrap_or_default();
+            self.successors = self.path_successors.pop().unwrap_or_default();
+            self.snippet = None;
             self.input_buf = self
                 .path_bufs
                 .pop()
@@ -442,57 +892,56 @@ impl FSMCursor {
         }
     }
 
-    fn update_cursor(&mut self, node: &FSMRc<FSMLock<FSMNode>>) {
-        self.path.push(self.cur_ast_pos.clone());
+    /// moves the cursor to the first of `positions`, keeping the others as alternatives
+    fn update_cursor(&mut self, positions: &[Position]) {
+        let (node, stacks) = &positions[0];
+        self.path.push(self.cur_ast_pos);
+        self.path_stacks
+            .push(std::mem::replace(&mut self.stacks, stacks.clone()));
+        self.path_alternatives.push(std::mem::replace(
+            &mut self.alternatives,
+            positions[1..].to_vec(),
+        ));
+        self.path_successors
+            .push(std::mem::take(&mut self.successors));
         self.path_bufs.push(self.input_buf.clone());
-        self.cur_ast_pos = FSMRc::downgrade(&FSMRc::clone(node));
-        if let NodeType::Keyword(Keyword {
-            closing_token: Some(_),
-            ..
-        }) = &node.borrow().value
-        {
-            self.unfinished_nodes.push(FSMRc::downgrade(node));
-        } else if node.borrow().children.is_empty() && self.unfinished_nodes.len() > 1 {
-            // we don't need to jump back if only one remains
-            self.cur_ast_pos = self.unfinished_nodes.pop().unwrap();
-        }
-        debug_println!(
-            "uc: {:?} {}/{:?}",
-            self.get_cur_ast_binding().borrow().value,
-            self.get_cur_ast_binding().borrow().id(),
-            node.borrow().value
-        );
+        self.path_layouts.push(std::mem::take(&mut self.layout_buf));
+        self.path_blocks.push(self.blocks.clone());
+        self.path_injections.push(self.injection.take());
+        // moving on by any other means than advance_snippet leaves the snippet
+        self.snippet = None;
+        self.cur_ast_pos = *node;
+        self.update_successors();
+        self.injection = self.inject(*node);
+        debug_println!("uc: {:?} {}", self.get_current_nodeval(), self.cur_ast_pos);
     }
+    #[allow(dead_code)]
     fn dump(&self) {
-        println!(
-            "Last matched node: {:?}",
-            self.cur_ast_pos.upgrade().unwrap().borrow().value
-        );
+        println!("Last matched node: {:?}", self.get_current_nodeval());
         println!("Input buf: {}", self.input_buf);
     }
 
+    /// whether what has been typed so far is complete, even if more could follow
+    fn can_end(&self) -> bool {
+        let typed = match self.get_current_nodeval() {
+            UserDefinedCombo(r, _) => full_match(r, &self.input_buf),
+            _ => self.input_buf.is_empty(),
+        };
+        typed && self.successors().can_end
+    }
     pub fn is_done(&self) -> bool {
-        let ast_ref = self.cur_ast_pos.upgrade().unwrap();
-        let binding = ast_ref.borrow();
-        match &binding.value {
+        match self.get_current_nodeval() {
             UserDefinedCombo(r, _) if !r.is_match(&self.input_buf) => false,
-            _ => binding.children.is_empty() || !binding.has_useful_children(),
+            _ => self.successors().nodes.is_empty(),
         }
     }
 
-    fn get_cur_ast_binding(&self) -> FSMRc<FSMLock<FSMNode>> {
-        self.cur_ast_pos.upgrade().unwrap()
-    }
     pub fn is_in_userdefined_stage(&self) -> bool {
-        matches!(
-            self.get_cur_ast_binding().borrow().value,
-            NodeType::UserDefinedCombo(_, _)
-        )
+        matches!(self.get_current_nodeval(), NodeType::UserDefinedCombo(_, _))
     }
 
-    fn get_current_nodeval(&self) -> NodeType {
-        println!("{}", self.get_cur_ast_binding().borrow().id());
-        self.get_cur_ast_binding().borrow().value.clone()
+    fn get_current_nodeval(&self) -> &NodeType {
+        self.fsm.value(self.cur_ast_pos)
     }
 }
 
@@ -515,15 +964,15 @@ mod tests {
         let second = FSMNode::new_keyword_with_parent("int".to_string(), root.clone());
         FSMNode::new_keyword_with_parent("asdf".to_string(), second.clone());
         let mut cursor = FSMCursor::new(&root);
-        assert_eq!(cursor.get_current_nodeval(), Null);
+        assert_eq!(*cursor.get_current_nodeval(), Null);
         cursor.advance('i').unwrap();
         assert_eq!(
-            cursor.get_current_nodeval(),
+            *cursor.get_current_nodeval(),
             NodeType::Keyword(Keyword::new("int".to_string(), None)),
         );
         cursor.advance('a').unwrap();
         assert_eq!(
-            cursor.get_current_nodeval(),
+            *cursor.get_current_nodeval(),
             NodeType::Keyword(Keyword::new("asdf".to_string(), None)),
         );
     }
@@ -539,7 +988,7 @@ mod tests {
         let types = FSMNode::new_required(NodeType::Null, &child);
 
         let int = FSMNode::new_keyword_with_parent("int".to_string(), types.clone());
-        let float = FSMNode::new_keyword_with_parent("short".to_string(), types.clone());
+        let _float = FSMNode::new_keyword_with_parent("short".to_string(), types.clone());
         child.borrow_mut().add_child(&types);
         child2.borrow_mut().add_child(&types);
 
@@ -559,8 +1008,8 @@ mod tests {
         let child2 = FSMNode::new(sign_token, &root);
         let types = FSMNode::new_required(NodeType::Null, &child);
 
-        let int = FSMNode::new_keyword_with_parent("int".to_string(), types.clone());
-        let float = FSMNode::new_keyword_with_parent("short".to_string(), types.clone());
+        let _int = FSMNode::new_keyword_with_parent("int".to_string(), types.clone());
+        let _float = FSMNode::new_keyword_with_parent("short".to_string(), types.clone());
         root.borrow_mut().add_child(&types);
         child2.borrow_mut().add_child(&types);
 
@@ -579,7 +1028,7 @@ mod tests {
         let child = FSMNode::new(sign_token.clone(), &root);
         sign_token = NodeType::Keyword(Keyword::new("signed".to_string(), None));
 
-        let child2 = FSMNode::new(sign_token, &root);
+        let _child2 = FSMNode::new(sign_token, &root);
         let types = FSMNode::new_required(NodeType::Null, &child);
         println!("hi?");
         FSMNode::add_child_cycle_safe(&types, &root);
@@ -698,6 +1147,604 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_repeat0() {
+        let bnf = r"
+        t1 ::= 't' 'e'* 'st';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        for i in 0..=10 {
+            let mut cursor = FSMCursor::new(&root);
+            assert_eq!("t", cursor.advance('t').unwrap());
+            for _ in 0..i {
+                assert_eq!("e", cursor.advance('e').unwrap());
+            }
+            assert_eq!("st", cursor.advance('s').unwrap());
+            assert!(cursor.is_done());
+        }
+    }
+
+    #[test]
+    fn test_repeat1() {
+        let bnf = r"
+        t1 ::= 't' ( 'e' 'a' )+ 'st';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("t", cursor.advance('t').unwrap());
+        assert_eq!(
+            AdvanceResult::InvalidChar,
+            cursor.advancex('s').unwrap(),
+            "needs at least one repetition"
+        );
+        for i in 1..=10 {
+            let mut cursor = FSMCursor::new(&root);
+            assert_eq!("t", cursor.advance('t').unwrap());
+            for _ in 0..i {
+                assert_eq!("e", cursor.advance('e').unwrap());
+                assert_eq!("a", cursor.advance('a').unwrap());
+            }
+            assert_eq!("st", cursor.advance('s').unwrap());
+            assert!(cursor.is_done());
+        }
+    }
+
+    #[test]
+    fn test_exception() {
+        let bnf = r"
+        t1 ::= 'let' ( word - ( 'if' | 'for' ) ) ';';
+        word ::= 'if' | 'for' | 'xyz' | 'abc';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!(AdvanceResult::InvalidChar, cursor.advancex('i').unwrap());
+        assert_eq!(AdvanceResult::InvalidChar, cursor.advancex('f').unwrap());
+        assert_eq!("xyz", cursor.advance('x').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!("abc", cursor.advance('a').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_exception_underscore_rule() {
+        // `_` is an ordinary rule name, exceptions don't take it away
+        let bnf = r"
+        t1 ::= 'let' ( word - 'if' ) _ ';';
+        word ::= 'if' | 'xyz';
+        _ ::= '=';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!(AdvanceResult::InvalidChar, cursor.advancex('i').unwrap());
+        assert_eq!("xyz", cursor.advance('x').unwrap());
+        assert_eq!("=", cursor.advance('=').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_exception_regex() {
+        // keywords can't be removed from a regex, the exception must not break the graph though
+        let bnf = r"
+        t1 ::= ( #'[a-z]+' - 'if' ) '=';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!(None, cursor.advance('a'));
+        assert_eq!("=", cursor.advance('=').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_left_recursion() {
+        let bnf = r"
+        stmt ::= expr ';';
+        expr ::= ( expr '+' term ) | ( expr '-' term ) | term;
+        term ::= 'x' | 'y';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        util_check_str(&root, "x;");
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("x", cursor.advance('x').unwrap());
+        assert_eq!("+", cursor.advance('+').unwrap());
+        assert_eq!("y", cursor.advance('y').unwrap());
+        assert_eq!("-", cursor.advance('-').unwrap());
+        assert_eq!("x", cursor.advance('x').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_left_recursion_indirect() {
+        let bnf = r"
+        stmt ::= item ';';
+        list ::= ( item ',' ) | 'a';
+        item ::= ( list 'b' ) | 'c';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        util_check_str(&root, "c;");
+        util_check_str(&root, "ab;");
+        util_check_str(&root, "c,b,b;");
+        util_check_str(&root, "ab,b;");
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("c", cursor.advance('c').unwrap());
+        assert_eq!(AdvanceResult::InvalidChar, cursor.advancex('b').unwrap());
+    }
+
+    #[test]
+    fn test_lexical_rule() {
+        let bnf = r#"
+        t1 ::= 'let' string ';';
+        string ::= '"' { char } '"';
+        char ::= #'[a-z]' | special;
+        special ::= ' ' | '!' | '?';
+    "#;
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!(AdvanceResult::UserDefStarted, cursor.advancex('"').unwrap());
+        for c in "hi !?".chars() {
+            assert_eq!(None, cursor.advancex(c));
+        }
+        assert_eq!(AdvanceResult::InvalidChar, cursor.advancex('X').unwrap());
+        assert_eq!(None, cursor.advancex('"'));
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_annotation_short() {
+        let bnf = r#"
+        stmt ::= ( ( 'insert' @short("ii") ) | 'index' ) ';';
+    "#;
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!(None, cursor.advancex('i'));
+        assert_eq!("insert", cursor.advance('i').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!(None, cursor.advancex('i'));
+        assert_eq!("index", cursor.advance('n').unwrap());
+    }
+
+    #[test]
+    fn test_annotation_noexpand() {
+        let bnf = r"
+        stmt ::= 'select' @noexpand 'x' ';';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        for c in "selec".chars() {
+            assert_eq!(None, cursor.advancex(c));
+        }
+        assert_eq!(AdvanceResult::InvalidChar, cursor.advancex('x').unwrap());
+        assert_eq!("select", cursor.advance('t').unwrap());
+        assert_eq!("x", cursor.advance('x').unwrap());
+    }
+
+    #[test]
+    fn test_annotation_closing() {
+        let bnf = r#"
+        stmt ::= '(' @closing(")") 'x' ')';
+    "#;
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("(", cursor.advance('(').unwrap());
+        assert!(matches!(
+            cursor.get_current_nodeval(),
+            NodeType::Keyword(Keyword { closing_token: Some(ct), .. }) if ct == ")"
+        ));
+        assert_eq!("x", cursor.advance('x').unwrap());
+        assert_eq!(")", cursor.advance(')').unwrap());
+    }
+
+    #[test]
+    fn test_macros() {
+        let bnf = r"
+        stmt ::= 'let' list<'x'> ';';
+        list<X> ::= X { ',' X };
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!("x", cursor.advance('x').unwrap());
+        assert_eq!(",", cursor.advance(',').unwrap());
+        assert_eq!("x", cursor.advance('x').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_dialect() {
+        let bnf = r"
+        stmt ::= 'delete' @if(postgres) 'returning' @end ';';
+    ";
+        let compile = |dialect| {
+            frontend::compile_ebnf_with(
+                bnf,
+                &frontend::CompileOptions {
+                    dialect,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .entries
+        };
+        let entries = compile(Some("postgres"));
+        let mut cursor = FSMCursor::new(entries.get(None).unwrap());
+        assert_eq!("delete", cursor.advance('d').unwrap());
+        assert_eq!("returning", cursor.advance('r').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+
+        let entries = compile(None);
+        let mut cursor = FSMCursor::new(entries.get(None).unwrap());
+        assert_eq!("delete", cursor.advance('d').unwrap());
+        assert_eq!(AdvanceResult::InvalidChar, cursor.advancex('r').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_layout() {
+        let bnf = r#"
+        @layout ::= #'[ \t\n]*';
+        @separator ::= ' ';
+        stmt ::= 'let' ident '=' @separator(" ") 'x' ';';
+        ident ::= #'[a-z]+';
+    "#;
+        let entries = frontend::create_entry_points_from_ebnf(bnf, &[]).unwrap();
+        let mut cursor = FSMCursor::new(entries.default_root()).with_layout(entries.layout());
+        assert_eq!(None, cursor.advancex(' '));
+        assert_eq!("let ", cursor.advance('l').unwrap());
+        assert_eq!(None, cursor.advancex(' '));
+        cursor.revert();
+        assert_eq!(None, cursor.advancex('\n'));
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('a'));
+        assert_eq!(None, cursor.advancex('b'));
+        // layout finishes the identifier
+        assert_eq!(None, cursor.advancex(' '));
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex('c'));
+        assert_eq!(
+            Some(AdvanceResult::ExpandedAfterUserdef("= ".to_string())),
+            cursor.advancex('=')
+        );
+        assert_eq!("x ", cursor.advance('x').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+
+        // without a layout, whitespace is just another character
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex(' '));
+    }
+
+    #[test]
+    fn test_indentation() {
+        let bnf = r#"
+        @indent ::= '  ';
+        outer ::= '{' @closing("}") @block @separator("\n") 'x' ';' @separator("\n") inner '}';
+        inner ::= '[' @closing("]") @block @separator("\n") 'y' ';' @separator("\n") ']' @separator("\n");
+    "#;
+        let entries = frontend::create_entry_points_from_ebnf(bnf, &[]).unwrap();
+        let mut cursor = FSMCursor::new(entries.default_root()).with_indent(entries.indent());
+        assert_eq!("{\n  ", cursor.advance('{').unwrap());
+        assert_eq!(1, cursor.depth());
+        assert_eq!("x", cursor.advance('x').unwrap());
+        assert_eq!(";\n  ", cursor.advance(';').unwrap());
+        assert_eq!("[\n    ", cursor.advance('[').unwrap());
+        assert_eq!(2, cursor.depth());
+        assert_eq!("y", cursor.advance('y').unwrap());
+        assert_eq!(";\n    ", cursor.advance(';').unwrap());
+        assert_eq!("]\n  ", cursor.advance(']').unwrap());
+        assert_eq!(1, cursor.depth());
+        cursor.revert();
+        assert_eq!(2, cursor.depth());
+        assert_eq!("]\n  ", cursor.advance(']').unwrap());
+        assert_eq!("}", cursor.advance('}').unwrap());
+        assert_eq!(0, cursor.depth());
+
+        // tabs unless the grammar says otherwise
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!("{\n\t", cursor.advance('{').unwrap());
+    }
+
+    #[test]
+    fn test_snippet() {
+        let bnf = r"
+        stmt ::= ( 'for' @snippet '(' ident '=' num ')' 'do' ) | 'end';
+        ident ::= #'[a-z]+';
+        num ::= #'[0-9]+';
+    ";
+        let entries = frontend::create_entry_points_from_ebnf(bnf, &[]).unwrap();
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!(
+            Some(AdvanceResult::Snippet("for($1=$2)do$0".to_string())),
+            cursor.advancex('f')
+        );
+        // the cursor already is in the slot
+        assert_eq!(None, cursor.advancex('i'));
+        // the '=' is already there, the user tabs over it
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex('='));
+        assert_eq!(None, cursor.advancex('\t'));
+        // an empty slot can't be left
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex('\t'));
+        assert_eq!(None, cursor.advancex('4'));
+        assert_eq!(None, cursor.advancex('2'));
+        assert_eq!(None, cursor.advancex('\t'));
+        assert!(cursor.is_done());
+
+        // leaving the slot by reverting ends the snippet
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert!(matches!(
+            cursor.advancex('f'),
+            Some(AdvanceResult::Snippet(_))
+        ));
+        cursor.revert();
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex('\t'));
+        assert_eq!("end", cursor.advance('e').unwrap());
+
+        // `$` and `}` are escaped
+        assert_eq!("\\$a\\}", escape_snippet("$a}"));
+    }
+
+    #[test]
+    fn test_injection_ambiguous_close() {
+        let host = r#"
+        script ::= '<script>' @inject("e") '</script>';
+    "#;
+        let e = r"
+        e ::= num { ( '<' | '+' ) num };
+        num ::= #'^[0-9]+$';
+    ";
+        let host = frontend::create_entry_points_from_ebnf(host, &[]).unwrap();
+        let e = frontend::create_entry_points_from_ebnf(e, &[]).unwrap();
+        let languages = Arc::new(HashMap::from([(
+            "e".to_string(),
+            Arc::new(CompiledFsm::new(&e)),
+        )]));
+        let mut cursor = FSMCursor::new(host.default_root()).with_languages(languages);
+        assert_eq!("<script>", cursor.advance('<').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('1'));
+        // both the comparison and the closing token start with '<', the next key decides
+        assert_eq!(None, cursor.advancex('<'));
+        assert_eq!(Some("e"), cursor.injected_language());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('2'));
+        assert_eq!(Some("e"), cursor.injected_language());
+        assert_eq!("+", cursor.advance('+').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('3'));
+        assert_eq!(None, cursor.advancex('<'));
+        assert_eq!("</script>", cursor.advance('/').unwrap());
+        assert_eq!(None, cursor.injected_language());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_injection() {
+        let host = r#"
+        stmt ::= 'let' ident '=' '`' @inject("sql;query") '`' ';';
+        ident ::= #'[a-z]+';
+    "#;
+        let sql = r"
+        query ::= 'SELECT' '*' 'FROM' table;
+        table ::= #'^[a-z]+$';
+    ";
+        let host = frontend::create_entry_points_from_ebnf(host, &[]).unwrap();
+        let sql = frontend::create_entry_points_from_ebnf(sql, &[]).unwrap();
+        let languages = Arc::new(HashMap::from([(
+            "sql".to_string(),
+            Arc::new(CompiledFsm::new(&sql)),
+        )]));
+        let mut cursor = FSMCursor::new(host.default_root()).with_languages(languages);
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('x'));
+        assert_eq!("=", cursor.advance('=').unwrap());
+        assert_eq!(None, cursor.injected_language());
+        assert_eq!("`", cursor.advance('`').unwrap());
+        assert_eq!(Some("sql;query"), cursor.injected_language());
+        assert_eq!("SELECT", cursor.advance('S').unwrap());
+        assert_eq!("*", cursor.advance('*').unwrap());
+        assert_eq!("FROM", cursor.advance('F').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('t'));
+        // the injected grammar can't take the backtick, so it closes the region
+        assert_eq!("`", cursor.advance('`').unwrap());
+        assert_eq!(None, cursor.injected_language());
+        // reverting the closing token steps back into the region where it was left
+        cursor.revert();
+        assert_eq!(Some("sql;query"), cursor.injected_language());
+        cursor.revert();
+        assert_eq!(Some("sql;query"), cursor.injected_language());
+        cursor.revert();
+        cursor.revert();
+        cursor.revert();
+        // reverting at the start of the region leaves it
+        cursor.revert();
+        assert_eq!(None, cursor.injected_language());
+        assert_eq!("`", cursor.advance('`').unwrap());
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex(';'));
+        assert_eq!("SELECT", cursor.advance('S').unwrap());
+        assert_eq!("*", cursor.advance('*').unwrap());
+        assert_eq!("FROM", cursor.advance('F').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('t'));
+        assert_eq!("`", cursor.advance('`').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+
+        // without the language, the host grammar is on its own
+        let mut cursor = FSMCursor::new(host.default_root());
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('x'));
+        assert_eq!("=", cursor.advance('=').unwrap());
+        assert_eq!("`", cursor.advance('`').unwrap());
+        assert_eq!(None, cursor.injected_language());
+        assert_eq!("`", cursor.advance('`').unwrap());
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let bnf = |case: &str| {
+            format!(
+                r"
+        {case}
+        stmt ::= ( 'SELECT' '*' 'FROM' table ';' ) | ( 'set' table 'TO' 'x' );
+        table ::= #'^[a-z]+$';
+    "
+            )
+        };
+        let entries =
+            frontend::create_entry_points_from_ebnf(&bnf("@case ::= 'upper';"), &[]).unwrap();
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!(None, cursor.advance('s'));
+        assert_eq!(None, cursor.advance('E'));
+        assert_eq!("SELECT", cursor.advance('l').unwrap());
+        assert_eq!("*", cursor.advance('*').unwrap());
+        assert_eq!("FROM", cursor.advance('f').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('t'));
+        assert_eq!(";", cursor.advance(';').unwrap());
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!(None, cursor.advance('s'));
+        assert_eq!(None, cursor.advance('e'));
+        assert_eq!("SET", cursor.advance('t').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('u'));
+        // the keyword following a userdef is found no matter the case of its first character
+        assert_eq!("TO", cursor.advance('T').unwrap());
+
+        let entries =
+            frontend::create_entry_points_from_ebnf(&bnf("@case ::= 'lower';"), &[]).unwrap();
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!(None, cursor.advance('S'));
+        assert_eq!(None, cursor.advance('E'));
+        assert_eq!("select", cursor.advance('L').unwrap());
+
+        let entries =
+            frontend::create_entry_points_from_ebnf(&bnf("@case ::= 'typed';"), &[]).unwrap();
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!(None, cursor.advance('s'));
+        assert_eq!(None, cursor.advance('e'));
+        assert_eq!("select", cursor.advance('l').unwrap());
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!(None, cursor.advance('S'));
+        assert_eq!(None, cursor.advance('E'));
+        assert_eq!("SET", cursor.advance('T').unwrap());
+        // mixed case keeps the grammar's spelling, unless the keyword was typed out in full
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!(None, cursor.advance('S'));
+        assert_eq!(None, cursor.advance('e'));
+        assert_eq!("SELECT", cursor.advance('L').unwrap());
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!(None, cursor.advance('S'));
+        assert_eq!(None, cursor.advance('e'));
+        assert_eq!("Set", cursor.advance('t').unwrap());
+
+        // without `@case`, case matters
+        let entries = frontend::create_entry_points_from_ebnf(&bnf(""), &[]).unwrap();
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!("set", cursor.advance('s').unwrap());
+        let mut cursor = FSMCursor::new(entries.default_root());
+        assert_eq!("SELECT", cursor.advance('S').unwrap());
+    }
+
+    #[test]
+    fn test_unicode_shorts() {
+        assert_eq!("grö", NameShortener::expand(Some("gr"), "größer"));
+        assert_eq!("≤", NameShortener::expand(None, "≤x"));
+        // an accent stays with the letter it belongs to
+        assert_eq!("e\u{301}", NameShortener::expand(None, "e\u{301}tat"));
+        let mut short = "é".to_string();
+        assert!(NameShortener::expand_existing(&mut short, "été"));
+        assert_eq!("ét", short);
+        let mut short = "👍🏽".to_string();
+        assert!(!NameShortener::expand_existing(&mut short, "👍🏽"));
+        assert_eq!("🚀", Keyword::new("🚀".to_string(), None).short);
+    }
+
+    #[test]
+    fn test_unicode() {
+        let bnf = r"
+        cmp ::= ( 'x' ( '≤' | '→' | '𝔸' ) num ) | ( 'größer' num ) | 'grün';
+        num ::= #'^[0-9]+$';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!(None, cursor.advance('g'));
+        assert_eq!(None, cursor.advance('r'));
+        assert_eq!("größer", cursor.advance('ö').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('4'));
+        assert!(cursor.is_done());
+        let mut cursor = FSMCursor::new(&root);
+        cursor.advance('g');
+        cursor.advance('r');
+        assert_eq!("grün", cursor.advance('ü').unwrap());
+        assert!(cursor.is_done());
+
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("x", cursor.advance('x').unwrap());
+        assert_eq!("≤", cursor.advance('≤').unwrap());
+        cursor.revert();
+        assert_eq!("→", cursor.advance('→').unwrap());
+        cursor.revert();
+        assert_eq!("𝔸", cursor.advance('𝔸').unwrap());
+        assert_eq!(Some(AdvanceResult::UserDefStarted), cursor.advancex('1'));
+        assert!(cursor.is_done());
+
+        // a short made of a letter and a combining accent
+        let root = frontend::create_graph_from_ebnf("word ::= 'e\u{301}tat' | 'exit';").unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!(None, cursor.advance('e'));
+        assert_eq!("e\u{301}tat", cursor.advance('\u{301}').unwrap());
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!(None, cursor.advance('e'));
+        assert_eq!("exit", cursor.advance('x').unwrap());
+
+        // case folding beyond ASCII
+        let root =
+            frontend::create_graph_from_ebnf("@case ::= 'upper'; stmt ::= 'ändern' | 'öffnen';")
+                .unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("ÄNDERN", cursor.advance('Ä').unwrap());
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("ÖFFNEN", cursor.advance('ö').unwrap());
+    }
+
+    #[test]
+    fn test_entry_points() {
+        let bnf = r"
+        stmt ::= 'let' expr ';';
+        expr ::= 'x' '+' 'y';
+    ";
+        let entries = frontend::create_entry_points_from_ebnf(bnf, &["stmt", "expr"]).unwrap();
+        assert_eq!(vec!["stmt", "expr"], entries.names().collect::<Vec<_>>());
+
+        let mut cursor = FSMCursor::new(entries.get(None).unwrap());
+        assert_eq!("let", cursor.advance('l').unwrap());
+        assert_eq!("x", cursor.advance('x').unwrap());
+        assert_eq!("+", cursor.advance('+').unwrap());
+        assert_eq!("y", cursor.advance('y').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+
+        let mut cursor = FSMCursor::new(entries.get(Some("expr")).unwrap());
+        assert_eq!(AdvanceResult::InvalidChar, cursor.advancex('l').unwrap());
+        assert_eq!("x", cursor.advance('x').unwrap());
+        assert_eq!("+", cursor.advance('+').unwrap());
+        assert_eq!("y", cursor.advance('y').unwrap());
+        assert!(cursor.is_done());
+
+        assert!(entries.get(Some("nope")).is_none());
+        let errors = frontend::create_entry_points_from_ebnf(bnf, &["nope"]).unwrap_err();
+        assert_eq!(
+            frontend::GrammarErrorKind::UnknownEntryRule("nope".to_string()),
+            errors[0].kind
+        );
+    }
+
     #[test]
     fn test_terminal() {
         let terms: usize = 100;
@@ -714,6 +1761,124 @@ mod tests {
         assert!(cursor.is_done());
     }
 
+    #[test]
+    fn test_shared_rule() {
+        let bnf = r"
+        x ::= ( r 'a' ) | ( r 'b' );
+        r ::= 'k' 'q';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        // r is only built once, both references call it
+        let nodes = FSMNode::all_nodes(&[&root]);
+        let ks = nodes
+            .iter()
+            .filter(|node| matches!(&node.borrow().value, Keyword(k) if k.expanded == "k"))
+            .count();
+        assert_eq!(1, ks);
+        for (last, expanded) in [('a', "a"), ('b', "b")] {
+            let mut cursor = FSMCursor::new(&root);
+            assert_eq!("k", cursor.advance('k').unwrap());
+            assert_eq!("q", cursor.advance('q').unwrap());
+            assert_eq!(expanded, cursor.advance(last).unwrap());
+            assert!(cursor.is_done());
+        }
+    }
+
+    #[test]
+    fn test_recursive_rule() {
+        let bnf = r"
+        expr ::= ( '(' expr ')' ) | 'x';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        util_check_str(&root, "x");
+        util_check_str(&root, "(((x)))");
+        let mut cursor = FSMCursor::new(&root);
+        for char in "((x)".chars() {
+            cursor.advance(char);
+        }
+        assert!(!cursor.is_done());
+        assert_eq!(AdvanceResult::InvalidChar, cursor.advancex('(').unwrap());
+        assert_eq!(")", cursor.advance(')').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_conflict_across_calls() {
+        // 'sa' and 'sb' only meet once r returns
+        let bnf = r"
+        x ::= r 'sb';
+        r ::= 'k' [ 'sa' ];
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        assert_eq!("k", cursor.advance('k').unwrap());
+        assert_eq!(None, cursor.advance('s'));
+        assert_eq!("sb", cursor.advance('b').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    fn util_advance_str(cursor: &mut FSMCursor, str: &str) {
+        for char in str.chars() {
+            assert_ne!(
+                Some(AdvanceResult::InvalidChar),
+                cursor.advancex(char),
+                "at {char}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_nested_blocks() {
+        let js = include_str!("../../js.ebnf");
+        let entries = frontend::create_entry_points_from_ebnf(js, &[]).unwrap();
+        let mut cursor = FSMCursor::new(entries.default_root()).with_layout(entries.layout());
+        util_advance_str(&mut cursor, "w(a){w(b){w(c){");
+        assert_eq!(3, cursor.depth());
+        util_advance_str(&mut cursor, "lx=1;}");
+        assert_eq!(2, cursor.depth());
+        // each block goes on with the statements of the one around it
+        util_advance_str(&mut cursor, "lx=2;}");
+        assert_eq!(1, cursor.depth());
+        util_advance_str(&mut cursor, "lx=3;}lx=4;");
+        assert_eq!(0, cursor.depth());
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex('}'));
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        let bnf = r"
+        stmt ::= expr ';';
+        expr ::= term { '+' term };
+        term ::= #'[0-9]+' | ( '(' expr ')' ) | call;
+        call ::= 'f' '(' [ expr { ',' expr } ] ')';
+    ";
+        let root = frontend::create_graph_from_ebnf(bnf).unwrap();
+        util_check_str(&root, "f(1,(2+f(f(3))))+4;");
+        util_check_str(&root, "f(f(f()),f());");
+        let mut cursor = FSMCursor::new(&root);
+        util_advance_str(&mut cursor, "f(f(3)");
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex(';'));
+        assert_eq!(")", cursor.advance(')').unwrap());
+        // back inside the outer call
+        cursor.revert();
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex(';'));
+        assert_eq!(")", cursor.advance(')').unwrap());
+        assert_eq!(";", cursor.advance(';').unwrap());
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn test_nested_parens() {
+        // a parenthesis may open a condition as well as an expression
+        let sql = include_str!("../../sql.ebnf");
+        let root = frontend::create_graph_from_ebnf(sql).unwrap();
+        let mut cursor = FSMCursor::new(&root);
+        util_advance_str(&mut cursor, "S*Ft W(((a =(1+(2*b ))))");
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex(';'));
+        util_advance_str(&mut cursor, ");");
+        assert_eq!(Some(AdvanceResult::InvalidChar), cursor.advancex(')'));
+    }
+
     fn util_check_str(root: &FSMRc<FSMLock<FSMNode>>, str: &str) {
         let mut cursor = FSMCursor::new(root);
         for char in str.chars() {
@@ -778,7 +1943,7 @@ mod tests {
     fn test_minify() {
         let root = FSMNode::new_null(None);
         let child = FSMNode::new_null(Some(&root));
-        let child = FSMNode::new_keyword_with_parent("asdf".to_string(), child);
+        let _child = FSMNode::new_keyword_with_parent("asdf".to_string(), child);
         // minify
         root.borrow().dbg();
         FSMNode::minify(&root);
@@ -796,7 +1961,7 @@ mod tests {
         let root = FSMNode::new_null(None);
         let child = FSMNode::new_null(Some(&root));
         let child = FSMNode::new_null(Some(&child));
-        let child = FSMNode::new_keyword_with_parent("asdf".to_string(), child);
+        let _child = FSMNode::new_keyword_with_parent("asdf".to_string(), child);
         // minify
         root.borrow().dbg();
         FSMNode::minify(&root);
@@ -837,7 +2002,7 @@ mod tests {
         .unwrap();
         let mut cursor = FSMCursor::new(&root);
         for i in 0..=9 {
-            assert_eq!(None, cursor.advance(i.to_string().chars().nth(0).unwrap()));
+            assert_eq!(None, cursor.advance(i.to_string().chars().next().unwrap()));
         }
         let mut cursor2 = cursor.clone();
         assert_eq!("-", cursor.advance('-').unwrap());
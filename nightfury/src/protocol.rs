@@ -59,7 +59,8 @@ pub enum Request<'a> {
     InstallLanguage(&'a str, Option<String>),
     Revert,
     Reset,
-    Initialize(&'a str),
+    /// language and optionally the entry rule to start at
+    Initialize(&'a str, Option<&'a str>),
     SetCursor(u16),
     Advance(&'a str),
 }
@@ -124,7 +125,10 @@ impl<'a> TryFrom<&'a [u8]> for Request<'a> {
                     return Err(Error::Empty);
                 }
                 match str::from_utf8(&value[1..value.len() - 1]) {
-                    Ok(str) => Ok(Request::Initialize(str)),
+                    Ok(str) => Ok(match str.split_once(';') {
+                        Some((lang, entry)) => Request::Initialize(lang, Some(entry)),
+                        None => Request::Initialize(str, None),
+                    }),
                     Err(_) => Err(Error::InvalidEncoding),
                 }
             }
@@ -153,7 +157,10 @@ impl<'a> Request<'a> {
             writer.write_all(&[protocol_id])?;
         }
         match self {
-            Self::Initialize(str) | Self::Advance(str) => {
+            Self::Initialize(lang, Some(entry)) => {
+                writer.write_with_null(format!("{lang};{entry}").as_bytes())?;
+            }
+            Self::Initialize(str, None) | Self::Advance(str) => {
                 writer.write_with_null(str.as_bytes())?;
             }
             Self::SetCursor(handle) => {
@@ -178,6 +185,8 @@ pub enum Response<'a> {
     CursorHandle(u8),
     InvalidChar,
     RegexStart,
+    /// an expansion in LSP snippet syntax, see [crate::AdvanceResult::Snippet]
+    Snippet(&'a str),
     Expanded(&'a str),
 }
 
@@ -207,6 +216,7 @@ impl<'a> TryFrom<&'a [u8]> for Response<'a> {
                 .ok_or(Error::Empty),
             0x05 => Ok(Response::InvalidChar),
             0x06 => Ok(Response::RegexStart),
+            0x07 => from_utf8_trim(value).map(Response::Snippet),
             _ => from_utf8_trim(value).map(Response::Expanded),
         }
     }
@@ -219,7 +229,7 @@ impl<'a> Response<'a> {
     pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         debug_println!("res: {self:?}");
         let disc = self.discriminant();
-        if disc < 0x7 {
+        if disc < 0x8 {
             writer.write_all(&[disc])?;
         }
         match self {
@@ -236,7 +246,7 @@ impl<'a> Response<'a> {
                     .as_bytes(),
             ),
             Self::CursorHandle(handle) => writer.write(&[*handle]).map(|_| ()),
-            Self::Expanded(s) => writer.write_with_null(s.as_bytes()),
+            Self::Snippet(s) | Self::Expanded(s) => writer.write_with_null(s.as_bytes()),
             _ => Ok(()),
         }
     }